@@ -0,0 +1,40 @@
+//! Generates the gRPC service stubs for `src/grpc.rs`. The messages are
+//! hand-written prost types mirroring `proto/chatr.proto`, so no protoc is
+//! needed at build time.
+
+fn method(
+    name: &str,
+    route: &str,
+    input: &str,
+    output: &str,
+) -> tonic_build::manual::MethodBuilder {
+    tonic_build::manual::Method::builder()
+        .name(name)
+        .route_name(route)
+        .input_type(format!("crate::grpc::{}", input))
+        .output_type(format!("crate::grpc::{}", output))
+        .codec_path("tonic::codec::ProstCodec")
+}
+
+fn main() {
+    let chat = tonic_build::manual::Service::builder()
+        .name("Chat")
+        .package("chatr")
+        .method(
+            method("join", "Join", "JoinRequest", "Event")
+                .server_streaming()
+                .build(),
+        )
+        .method(method("send_message", "SendMessage", "SendRequest", "SendResponse").build())
+        .method(
+            method("stream", "Stream", "StreamRequest", "Event")
+                .client_streaming()
+                .server_streaming()
+                .build(),
+        )
+        .build();
+
+    tonic_build::manual::Builder::new()
+        .build_client(false)
+        .compile(&[chat]);
+}
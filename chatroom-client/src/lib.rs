@@ -0,0 +1,421 @@
+//! Async client for chatroom-rs servers.
+//!
+//! ```no_run
+//! use chatroom_client::{Client, Event};
+//!
+//! # async fn run() -> Result<(), chatroom_client::Error> {
+//! let mut session = Client::connect("ws://localhost:3000/ws")
+//!     .await?
+//!     .join("ferris", "lobby")
+//!     .await?;
+//! session.send("Hello!")?;
+//! while let Some(event) = session.next_event().await {
+//!     if let Event::Message { from, text } = event {
+//!         println!("{}: {}", from, text);
+//!     }
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use futures::{SinkExt, Stream, StreamExt};
+use log::{info, warn};
+use serde_json::json;
+use std::fmt;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio_tungstenite::tungstenite::{self, Message};
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const DEFAULT_MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Something that happened in the joined room, parsed from the server's text frames.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Event {
+    Message {
+        from: String,
+        text: String,
+    },
+    Joined {
+        username: String,
+    },
+    Left {
+        username: String,
+    },
+    Direct {
+        from: String,
+        text: String,
+    },
+    /// The room's members, sent right after joining and before any other
+    /// event, so later joins and leaves apply on top of it.
+    Roster {
+        usernames: Vec<String>,
+    },
+    /// The room's recent messages, oldest first, as [`Event::Message`]s.
+    /// Follows the roster, and is sent again after every rejoin.
+    History {
+        messages: Vec<Event>,
+    },
+    /// A frame that matches none of the above, e.g. a server error.
+    Notice(String),
+    /// The connection dropped and the session is trying to rejoin.
+    Reconnecting,
+    /// The session rejoined the room after a dropped connection.
+    Resumed,
+}
+
+impl Event {
+    /// Parses a text frame as sent by the server's `/ws` endpoint.
+    pub fn parse(frame: &str) -> Event {
+        if let Some(event) = Event::parse_snapshot(frame) {
+            return event;
+        }
+        if let Some((from, text)) = frame
+            .strip_prefix("[DM] ")
+            .and_then(|rest| rest.split_once(": "))
+        {
+            return Event::Direct {
+                from: from.to_owned(),
+                text: text.to_owned(),
+            };
+        }
+        if let Some((from, text)) = frame.split_once(": ") {
+            return Event::Message {
+                from: from.to_owned(),
+                text: text.to_owned(),
+            };
+        }
+        if let Some(username) = frame.strip_suffix(" joined the chat!") {
+            return Event::Joined {
+                username: username.to_owned(),
+            };
+        }
+        if let Some(username) = frame.strip_suffix(" left the chat!") {
+            return Event::Left {
+                username: username.to_owned(),
+            };
+        }
+        Event::Notice(frame.to_owned())
+    }
+
+    /// Parses a `{"type":"roster"}` or `{"type":"history"}` frame.
+    fn parse_snapshot(frame: &str) -> Option<Event> {
+        let value = serde_json::from_str::<serde_json::Value>(frame).ok()?;
+        let items = |field: &str| value[field].as_array().cloned().unwrap_or_default();
+        match value["type"].as_str()? {
+            "roster" => Some(Event::Roster {
+                usernames: items("users")
+                    .iter()
+                    .filter_map(|user| user.as_str().map(str::to_owned))
+                    .collect(),
+            }),
+            "history" => Some(Event::History {
+                messages: items("messages")
+                    .iter()
+                    .map(|message| Event::Message {
+                        from: message["from"].as_str().unwrap_or_default().to_owned(),
+                        text: message["text"].as_str().unwrap_or_default().to_owned(),
+                    })
+                    .collect(),
+            }),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    WebSocket(Box<tungstenite::Error>),
+    /// Someone in the room already uses the requested username.
+    UsernameTaken,
+    /// The server refused the join with the given message.
+    Rejected(String),
+    /// The connection is gone and will not come back.
+    Closed,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::WebSocket(err) => write!(f, "websocket error: {}", err),
+            Error::UsernameTaken => write!(f, "username already taken"),
+            Error::Rejected(message) => write!(f, "join rejected: {}", message),
+            Error::Closed => write!(f, "connection closed"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<tungstenite::Error> for Error {
+    fn from(err: tungstenite::Error) -> Self {
+        Error::WebSocket(Box::new(err))
+    }
+}
+
+/// An open connection to a server's `/ws` endpoint that has not joined a room yet.
+pub struct Client {
+    url: String,
+    socket: Socket,
+    auto_reconnect: bool,
+    max_backoff: Duration,
+}
+
+impl Client {
+    /// Opens a WebSocket connection, `url` points at the `/ws` endpoint.
+    pub async fn connect(url: impl Into<String>) -> Result<Client, Error> {
+        let url = url.into();
+        let (socket, _) = tokio_tungstenite::connect_async(url.as_str()).await?;
+        Ok(Client {
+            url,
+            socket,
+            auto_reconnect: true,
+            max_backoff: DEFAULT_MAX_BACKOFF,
+        })
+    }
+
+    /// Whether a dropped connection is reopened and the room rejoined, on by default.
+    pub fn auto_reconnect(mut self, enabled: bool) -> Self {
+        self.auto_reconnect = enabled;
+        self
+    }
+
+    /// Upper bound for the exponential backoff between reconnect attempts.
+    pub fn max_backoff(mut self, max_backoff: Duration) -> Self {
+        self.max_backoff = max_backoff;
+        self
+    }
+
+    /// Joins `room` as `username` and starts relaying events.
+    pub async fn join(
+        mut self,
+        username: impl Into<String>,
+        room: impl Into<String>,
+    ) -> Result<Session, Error> {
+        let username = username.into();
+        let room = room.into();
+        let (token, backlog) = handshake(&mut self.socket, &username, &room, None).await?;
+
+        let (outgoing, outgoing_rx) = mpsc::unbounded_channel();
+        let (events_tx, events) = mpsc::unbounded_channel();
+        for event in backlog {
+            let _ = events_tx.send(event);
+        }
+        let driver = Driver {
+            url: self.url,
+            username: username.clone(),
+            room: room.clone(),
+            token,
+            auto_reconnect: self.auto_reconnect,
+            max_backoff: self.max_backoff,
+            outgoing: outgoing_rx,
+            events: events_tx,
+        };
+        let task = tokio::spawn(driver.run(self.socket));
+
+        Ok(Session {
+            username,
+            room,
+            outgoing,
+            events,
+            task,
+        })
+    }
+}
+
+/// Sends the connect payload, with the token of the session to take over if
+/// any, and waits for the server to confirm the join with a new token.
+/// Returns it and any events that arrived in the meantime.
+async fn handshake(
+    socket: &mut Socket,
+    username: &str,
+    room: &str,
+    resume: Option<&str>,
+) -> Result<(String, Vec<Event>), Error> {
+    let connect = json!({ "username": username, "channel": room, "resume": resume }).to_string();
+    socket.send(Message::text(connect)).await?;
+
+    let mut backlog = Vec::new();
+    while let Some(message) = socket.next().await {
+        let Message::Text(frame) = message? else {
+            continue;
+        };
+        if let Some(err) = join_error(&frame) {
+            return Err(err);
+        }
+        if let Some(token) = session_token(&frame) {
+            return Ok((token, backlog));
+        }
+        backlog.push(Event::parse(&frame));
+    }
+    Err(Error::Closed)
+}
+
+/// The token of a `{"type":"session","token":...}` frame.
+fn session_token(frame: &str) -> Option<String> {
+    let value = serde_json::from_str::<serde_json::Value>(frame).ok()?;
+    if value["type"] != "session" {
+        return None;
+    }
+    value["token"].as_str().map(str::to_owned)
+}
+
+/// The error a frame answering the connect payload reports, if any: an
+/// `{"type":"error","code":...,"message":...}` frame, or the plain text one
+/// sent for a payload the server could not read.
+fn join_error(frame: &str) -> Option<Error> {
+    if frame == "Failed to connect to room!" {
+        return Some(Error::Rejected(frame.to_owned()));
+    }
+    let value = serde_json::from_str::<serde_json::Value>(frame).ok()?;
+    if value["type"] != "error" {
+        return None;
+    }
+    let message = value["message"].as_str().unwrap_or_default().to_owned();
+    match value["code"].as_str() {
+        Some("USERNAME_TAKEN") => Some(Error::UsernameTaken),
+        _ => Some(Error::Rejected(message)),
+    }
+}
+
+/// Background task owning the socket, reconnecting it when it drops.
+struct Driver {
+    url: String,
+    username: String,
+    room: String,
+    /// Of the current session, taking it over when rejoining.
+    token: String,
+    auto_reconnect: bool,
+    max_backoff: Duration,
+    outgoing: mpsc::UnboundedReceiver<String>,
+    events: mpsc::UnboundedSender<Event>,
+}
+
+impl Driver {
+    async fn run(mut self, mut socket: Socket) {
+        loop {
+            if !self.relay(&mut socket).await || !self.auto_reconnect {
+                let _ = socket.close(None).await;
+                return;
+            }
+            let _ = self.events.send(Event::Reconnecting);
+            match self.reconnect().await {
+                Some(resumed) => socket = resumed,
+                None => return,
+            }
+            let _ = self.events.send(Event::Resumed);
+        }
+    }
+
+    /// Pumps messages both ways until the connection drops (`true`) or the
+    /// session is dropped (`false`).
+    async fn relay(&mut self, socket: &mut Socket) -> bool {
+        loop {
+            tokio::select! {
+                text = self.outgoing.recv() => match text {
+                    Some(text) => {
+                        if socket.send(Message::text(text)).await.is_err() {
+                            return true;
+                        }
+                    }
+                    None => return false,
+                },
+                message = socket.next() => match message {
+                    Some(Ok(Message::Text(frame))) => {
+                        if self.events.send(Event::parse(&frame)).is_err() {
+                            return false;
+                        }
+                    }
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => return true,
+                    Some(Ok(_)) => {}
+                },
+            }
+        }
+    }
+
+    /// Reopens the connection and rejoins with the same username. The old
+    /// membership may linger until the server notices the drop, it is taken
+    /// over with the session token. A taken username is otherwise retried
+    /// like any other failure.
+    async fn reconnect(&mut self) -> Option<Socket> {
+        let mut backoff = INITIAL_BACKOFF;
+        loop {
+            tokio::time::sleep(backoff).await;
+            if self.events.is_closed() {
+                return None;
+            }
+            match self.try_rejoin().await {
+                Ok(socket) => {
+                    info!("Rejoined {} as {}", self.room, self.username);
+                    return Some(socket);
+                }
+                Err(err) => warn!("Reconnect to {} failed: {}", self.url, err),
+            }
+            backoff = (backoff * 2).min(self.max_backoff);
+        }
+    }
+
+    async fn try_rejoin(&mut self) -> Result<Socket, Error> {
+        let (mut socket, _) = tokio_tungstenite::connect_async(self.url.as_str()).await?;
+        let (token, backlog) =
+            handshake(&mut socket, &self.username, &self.room, Some(&self.token)).await?;
+        self.token = token;
+        for event in backlog {
+            let _ = self.events.send(event);
+        }
+        Ok(socket)
+    }
+}
+
+/// Membership in a room. Also a [`Stream`] of its [`Event`]s; the stream
+/// ends when the connection is closed for good.
+pub struct Session {
+    username: String,
+    room: String,
+    outgoing: mpsc::UnboundedSender<String>,
+    events: mpsc::UnboundedReceiver<Event>,
+    task: JoinHandle<()>,
+}
+
+impl Session {
+    pub fn username(&self) -> &str {
+        &self.username
+    }
+
+    pub fn room(&self) -> &str {
+        &self.room
+    }
+
+    /// Queues a message for the room. Messages sent while reconnecting
+    /// are delivered once the session has resumed.
+    pub fn send(&self, text: impl Into<String>) -> Result<(), Error> {
+        let frame = json!({ "type": "message", "text": text.into() }).to_string();
+        self.outgoing.send(frame).map_err(|_| Error::Closed)
+    }
+
+    pub async fn next_event(&mut self) -> Option<Event> {
+        self.events.recv().await
+    }
+
+    /// Leaves the room and closes the connection.
+    pub async fn close(self) {
+        let Session { outgoing, task, .. } = self;
+        drop(outgoing);
+        let _ = task.await;
+    }
+}
+
+impl Stream for Session {
+    type Item = Event;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Event>> {
+        self.events.poll_recv(cx)
+    }
+}
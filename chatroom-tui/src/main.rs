@@ -0,0 +1,354 @@
+//! Terminal client: room list, scrollback, input box and presence sidebar.
+//!
+//! `chatroom-tui [--server http://localhost:3000] <username> [room]`
+//!
+//! Type to chat, `/join <room>` switches rooms, `/quit` or Esc exits and
+//! PageUp/PageDown scroll the history.
+
+use chatroom_client::{Client, Event, Session};
+use crossterm::event::{Event as TermEvent, EventStream, KeyCode, KeyEventKind, KeyModifiers};
+use futures::StreamExt;
+use ratatui::layout::{Constraint, Layout, Position};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, List, ListItem, Paragraph};
+use ratatui::{DefaultTerminal, Frame};
+use serde_json::{json, Value};
+use std::time::Duration;
+
+const SCROLLBACK: usize = 1000;
+const REFRESH_INTERVAL: Duration = Duration::from_secs(5);
+
+struct RoomInfo {
+    name: String,
+    users: Vec<String>,
+}
+
+struct App {
+    server: String,
+    username: String,
+    room: String,
+    session: Option<Session>,
+    rooms: Vec<RoomInfo>,
+    members: Vec<String>,
+    lines: Vec<Line<'static>>,
+    input: String,
+    /// Lines scrolled up from the bottom of the scrollback.
+    scroll: usize,
+    /// Whether the current room's history was shown.
+    history_shown: bool,
+    http: reqwest::Client,
+}
+
+fn usage() -> ! {
+    eprintln!("usage: chatroom-tui [--server URL] <username> [room]");
+    std::process::exit(2);
+}
+
+#[tokio::main]
+async fn main() {
+    let mut args = std::env::args().skip(1);
+    let mut server = String::from("http://localhost:3000");
+    let mut positional = Vec::new();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--server" => server = args.next().unwrap_or_else(|| usage()),
+            "-h" | "--help" => usage(),
+            _ => positional.push(arg),
+        }
+    }
+    let mut positional = positional.into_iter();
+    let username = positional.next().unwrap_or_else(|| usage());
+    let room = positional.next().unwrap_or_else(|| String::from("lobby"));
+
+    let mut app = App {
+        server: server.trim_end_matches('/').to_owned(),
+        username,
+        room: String::new(),
+        session: None,
+        rooms: Vec::new(),
+        members: Vec::new(),
+        lines: Vec::new(),
+        input: String::new(),
+        scroll: 0,
+        history_shown: false,
+        http: reqwest::Client::builder()
+            .timeout(Duration::from_secs(2))
+            .build()
+            .unwrap(),
+    };
+
+    let mut terminal = ratatui::init();
+    app.join(room).await;
+    let result = app.run(&mut terminal).await;
+    ratatui::restore();
+    if let Err(err) = result {
+        eprintln!("{}", err);
+    }
+}
+
+impl App {
+    fn websocket_url(&self) -> String {
+        let base = if let Some(rest) = self.server.strip_prefix("https://") {
+            format!("wss://{}", rest)
+        } else if let Some(rest) = self.server.strip_prefix("http://") {
+            format!("ws://{}", rest)
+        } else {
+            self.server.clone()
+        };
+        format!("{}/ws", base)
+    }
+
+    fn system(&mut self, text: String) {
+        self.push(Line::from(Span::styled(
+            text,
+            Style::new().fg(Color::DarkGray),
+        )));
+    }
+
+    fn push(&mut self, line: Line<'static>) {
+        self.lines.push(line);
+        if self.lines.len() > SCROLLBACK {
+            self.lines.remove(0);
+        }
+    }
+
+    async fn join(&mut self, room: String) {
+        if let Some(session) = self.session.take() {
+            session.close().await;
+        }
+        self.lines.clear();
+        self.scroll = 0;
+        self.history_shown = false;
+        let session = match Client::connect(self.websocket_url()).await {
+            Ok(client) => client.join(self.username.clone(), room.clone()).await,
+            Err(err) => Err(err),
+        };
+        match session {
+            Ok(session) => {
+                self.session = Some(session);
+                self.room = room;
+                self.system(format!("Joined {} as {}", self.room, self.username));
+            }
+            Err(err) => self.system(format!("Could not join {}: {}", room, err)),
+        }
+        self.refresh().await;
+    }
+
+    /// Reloads the room list and the current room's members.
+    async fn refresh(&mut self) {
+        let Ok(rooms) = self.fetch_rooms().await else {
+            return;
+        };
+        self.rooms = rooms;
+        if let Some(room) = self.rooms.iter().find(|room| room.name == self.room) {
+            self.members = room.users.clone();
+        }
+    }
+
+    async fn fetch_rooms(&self) -> Result<Vec<RoomInfo>, reqwest::Error> {
+        let body: Value = self
+            .http
+            .post(format!("{}/graphql", self.server))
+            .json(&json!({ "query": "{ rooms { name users } }" }))
+            .send()
+            .await?
+            .json()
+            .await?;
+        let mut rooms = body["data"]["rooms"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .map(|room| RoomInfo {
+                name: room["name"].as_str().unwrap_or_default().to_owned(),
+                users: room["users"]
+                    .as_array()
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|user| user.as_str().map(str::to_owned))
+                    .collect(),
+            })
+            .collect::<Vec<_>>();
+        rooms.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(rooms)
+    }
+
+    fn on_event(&mut self, event: Event) {
+        match event {
+            Event::Message { from, text } => {
+                let style = if from == self.username {
+                    Style::new().fg(Color::Cyan).bold()
+                } else {
+                    Style::new().bold()
+                };
+                self.push(Line::from(vec![
+                    Span::styled(from, style),
+                    Span::raw(": "),
+                    Span::raw(text),
+                ]));
+            }
+            Event::Direct { from, text } => {
+                self.push(Line::from(vec![
+                    Span::styled(
+                        format!("[DM] {}", from),
+                        Style::new().fg(Color::Magenta).bold(),
+                    ),
+                    Span::raw(": "),
+                    Span::raw(text),
+                ]));
+            }
+            Event::Joined { username } => {
+                self.system(format!("{} joined the chat!", username));
+                if !self.members.contains(&username) {
+                    self.members.push(username);
+                }
+            }
+            Event::Left { username } => {
+                self.system(format!("{} left the chat!", username));
+                self.members.retain(|member| *member != username);
+            }
+            Event::Roster { usernames } => self.members = usernames,
+            // Sent again after a rejoin, when it is already on screen.
+            Event::History { messages } if !self.history_shown => {
+                self.history_shown = true;
+                for message in messages {
+                    self.on_event(message);
+                }
+            }
+            Event::History { .. } => {}
+            Event::Notice(text) => self.system(text),
+            Event::Reconnecting => self.system(String::from("Connection lost, reconnecting...")),
+            Event::Resumed => self.system(String::from("Reconnected.")),
+        }
+    }
+
+    /// Handles the submitted input line, returns `false` to quit.
+    async fn submit(&mut self) -> bool {
+        let input = std::mem::take(&mut self.input);
+        let input = input.trim();
+        if input.is_empty() {
+            return true;
+        }
+        if input == "/quit" {
+            return false;
+        }
+        if let Some(room) = input.strip_prefix("/join ") {
+            self.join(room.trim().to_owned()).await;
+            return true;
+        }
+        match &self.session {
+            Some(session) => {
+                if session.send(input).is_err() {
+                    self.system(String::from("Not connected."));
+                }
+            }
+            None => self.system(String::from("Not in a room, use /join <room>.")),
+        }
+        self.scroll = 0;
+        true
+    }
+
+    async fn run(&mut self, terminal: &mut DefaultTerminal) -> std::io::Result<()> {
+        let mut keys = EventStream::new();
+        let mut refresh = tokio::time::interval(REFRESH_INTERVAL);
+        loop {
+            terminal.draw(|frame| self.draw(frame))?;
+
+            let next_event = async {
+                match self.session.as_mut() {
+                    Some(session) => session.next_event().await,
+                    None => std::future::pending().await,
+                }
+            };
+            tokio::select! {
+                event = next_event => match event {
+                    Some(event) => self.on_event(event),
+                    None => {
+                        self.session = None;
+                        self.system(String::from("Disconnected, use /join <room> to reconnect."));
+                    }
+                },
+                _ = refresh.tick() => self.refresh().await,
+                key = keys.next() => {
+                    let Some(Ok(TermEvent::Key(key))) = key else {
+                        continue;
+                    };
+                    if key.kind != KeyEventKind::Press {
+                        continue;
+                    }
+                    match key.code {
+                        KeyCode::Esc => return Ok(()),
+                        KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => return Ok(()),
+                        KeyCode::Enter if !self.submit().await => return Ok(()),
+                        KeyCode::Backspace => {
+                            self.input.pop();
+                        }
+                        KeyCode::PageUp => self.scroll = (self.scroll + 10).min(self.lines.len()),
+                        KeyCode::PageDown => self.scroll = self.scroll.saturating_sub(10),
+                        KeyCode::Char(c) => self.input.push(c),
+                        _ => {}
+                    }
+                }
+            }
+        }
+    }
+
+    fn draw(&self, frame: &mut Frame) {
+        let [main, input] =
+            Layout::vertical([Constraint::Min(3), Constraint::Length(3)]).areas(frame.area());
+        let [rooms, chat, people] = Layout::horizontal([
+            Constraint::Length(24),
+            Constraint::Min(20),
+            Constraint::Length(24),
+        ])
+        .areas(main);
+
+        let room_items = self.rooms.iter().map(|room| {
+            let item = ListItem::new(format!("{} ({})", room.name, room.users.len()));
+            if room.name == self.room {
+                item.style(Style::new().fg(Color::Yellow).add_modifier(Modifier::BOLD))
+            } else {
+                item
+            }
+        });
+        frame.render_widget(
+            List::new(room_items).block(Block::bordered().title("Rooms")),
+            rooms,
+        );
+
+        let height = chat.height.saturating_sub(2) as usize;
+        let end = self.lines.len().saturating_sub(self.scroll);
+        let start = end.saturating_sub(height);
+        let title = match &self.session {
+            Some(_) => format!("#{}", self.room),
+            None => String::from("not connected"),
+        };
+        frame.render_widget(
+            Paragraph::new(self.lines[start..end].to_vec()).block(Block::bordered().title(title)),
+            chat,
+        );
+
+        let member_items = self.members.iter().map(|member| {
+            if *member == self.username {
+                ListItem::new(member.clone()).style(Style::new().fg(Color::Cyan))
+            } else {
+                ListItem::new(member.clone())
+            }
+        });
+        frame.render_widget(
+            List::new(member_items)
+                .block(Block::bordered().title(format!("Online ({})", self.members.len()))),
+            people,
+        );
+
+        frame.render_widget(
+            Paragraph::new(self.input.as_str())
+                .block(Block::bordered().title(self.username.as_str())),
+            input,
+        );
+        frame.set_cursor_position(Position::new(
+            input.x + 1 + self.input.chars().count() as u16,
+            input.y + 1,
+        ));
+    }
+}
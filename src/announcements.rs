@@ -0,0 +1,82 @@
+//! Server-wide announcements, such as maintenance notices before a restart.
+
+use axum::extract::State;
+use axum::http::{HeaderMap, StatusCode};
+use axum::Json;
+use log::info;
+use serde::Deserialize;
+use serde_json::json;
+use std::sync::Arc;
+
+use crate::events::ChatEvent;
+use crate::owners::{admin_forbidden, is_admin};
+use crate::{ApiResponse, AppState};
+
+const MAX_LEN: usize = 2000;
+
+/// Sends `text` as an `announcement` event to every room, or with `direct`
+/// to every member's own channel instead. Returns how many rooms or
+/// members it reached.
+pub fn announce(state: &AppState, text: &str, direct: bool) -> usize {
+    let event = ChatEvent::Announcement {
+        text: text.to_owned(),
+    };
+    let rooms = state.rooms.lock().unwrap();
+    let reached = if direct {
+        rooms
+            .values()
+            .map(|room| {
+                let users = room.users.lock().unwrap();
+                users
+                    .values()
+                    .filter(|direct| direct.send(event.clone()).is_ok())
+                    .count()
+            })
+            .sum()
+    } else {
+        rooms
+            .values()
+            .filter(|room| room.tx.send(event.clone()))
+            .count()
+    };
+    info!(
+        "Announced to {} {}: {}",
+        reached,
+        if direct { "members" } else { "rooms" },
+        text
+    );
+    reached
+}
+
+#[derive(Deserialize)]
+pub struct AnnouncementRequest {
+    text: String,
+    /// Reach each member's connection rather than each room.
+    #[serde(default)]
+    direct: bool,
+}
+
+/// `POST /admin/announcements`
+pub async fn post_announcement(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(body): Json<AnnouncementRequest>,
+) -> ApiResponse {
+    if !is_admin(&state, &headers) {
+        return admin_forbidden();
+    }
+    let text = body.text.trim();
+    if text.is_empty() || text.chars().count() > MAX_LEN {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({
+                "status": format!("Announcements are 1 to {} characters.", MAX_LEN),
+            })),
+        );
+    }
+    let reached = announce(&state, text, body.direct);
+    (
+        StatusCode::OK,
+        Json(json!({ "status": "Success!", "reached": reached })),
+    )
+}
@@ -0,0 +1,175 @@
+//! Archived rooms, which owners and admins take out of use instead of
+//! letting them go.
+//!
+//! Archiving disconnects the room's members and drops it from the directory,
+//! joins fail with `ARCHIVED` until it is unarchived, and its history stays
+//! readable with `GET /rooms/:name/archive`. Unarchiving brings the room back
+//! with that history and its tags. Archives are kept in memory.
+
+use axum::extract::{Path, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::Json;
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use crate::events::{unix_timestamp, ChatEvent, RoomEvent};
+use crate::owners::{forbidden, is_admin, is_owner};
+use crate::room_names::RoomName;
+use crate::rooms::{self, RoomState, Stored, StoredMessage};
+use crate::{ApiResponse, AppState};
+
+pub struct ArchivedRoom {
+    /// Unix time in seconds.
+    pub archived_at: u64,
+    /// The room's history when it was archived, oldest first.
+    pub history: Vec<StoredMessage>,
+    pub tags: Vec<String>,
+}
+
+/// Archived rooms by name.
+pub type Archive = Mutex<HashMap<String, ArchivedRoom>>;
+
+pub fn is_archived(state: &AppState, room: &str) -> bool {
+    state.archive.lock().unwrap().contains_key(room)
+}
+
+fn not_archived() -> ApiResponse {
+    (
+        StatusCode::NOT_FOUND,
+        Json(json!({ "status": "Room is not archived." })),
+    )
+}
+
+/// `POST /rooms/:name/archive`, for the owner or an admin. Archives an active
+/// room as it is and an inactive one with its stored history and tags.
+pub async fn archive_room(
+    Path(room): Path<RoomName>,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> ApiResponse {
+    if !is_owner(&state, &room, &headers) && !is_admin(&state, &headers) {
+        return forbidden();
+    }
+    let room = room.as_str();
+    // Like a room being created, an inactive one is archived with what the
+    // storage has, loaded without the lock and looked up again once it is back.
+    let mut stored = None;
+    let (archived_at, active) = loop {
+        {
+            let mut rooms = state.rooms.lock().unwrap();
+            let mut archive = state.archive.lock().unwrap();
+            if archive.contains_key(room) {
+                return (
+                    StatusCode::CONFLICT,
+                    Json(json!({ "status": "Room is already archived." })),
+                );
+            }
+            let archived = match rooms.remove(room) {
+                Some(room_state) => Some((archived(&state, room, &room_state), true)),
+                None => stored.take().map(|Stored { history, tags }| {
+                    let archived = ArchivedRoom {
+                        archived_at: unix_timestamp(),
+                        history,
+                        tags,
+                    };
+                    (archived, false)
+                }),
+            };
+            if let Some((archived, active)) = archived {
+                let archived_at = archived.archived_at;
+                archive.insert(room.to_owned(), archived);
+                break (archived_at, active);
+            }
+        }
+        stored = Some(Stored {
+            history: state.storage.load(room, rooms::HISTORY_LEN).await,
+            tags: state.storage.load_tags(room).await,
+        });
+    };
+    if active {
+        rooms::publish(
+            &state,
+            RoomEvent::RoomDeleted {
+                room: room.to_owned(),
+            },
+        );
+    }
+    (
+        StatusCode::OK,
+        Json(json!({ "status": "Success!", "archived_at": archived_at })),
+    )
+}
+
+/// Takes the history and tags of the active `room_state`, which is no longer
+/// in the rooms, and tells its members, whose connections end.
+fn archived(state: &AppState, room: &str, room_state: &RoomState) -> ArchivedRoom {
+    let users = room_state.users.lock().unwrap();
+    for username in users.keys() {
+        state.connections.remove(username, room);
+    }
+    room_state.broadcast(ChatEvent::Archived);
+    ArchivedRoom {
+        archived_at: unix_timestamp(),
+        history: room_state.history.lock().unwrap().iter().cloned().collect(),
+        tags: room_state.tags.lock().unwrap().clone(),
+    }
+}
+
+/// `DELETE /rooms/:name/archive`, for the owner or an admin. The room is
+/// active again, without members until someone joins.
+pub async fn unarchive_room(
+    Path(room): Path<RoomName>,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> ApiResponse {
+    if !is_owner(&state, &room, &headers) && !is_admin(&state, &headers) {
+        return forbidden();
+    }
+    let room = room.as_str();
+    {
+        let mut rooms = state.rooms.lock().unwrap();
+        let Some(archived) = state.archive.lock().unwrap().remove(room) else {
+            return not_archived();
+        };
+        let stored = Stored {
+            history: archived.history,
+            tags: archived.tags,
+        };
+        rooms.insert(room.to_owned(), RoomState::new(&state, room, stored));
+    }
+    rooms::publish(
+        &state,
+        RoomEvent::RoomCreated {
+            room: room.to_owned(),
+        },
+    );
+    (StatusCode::OK, Json(json!({ "status": "Success!" })))
+}
+
+/// `GET /rooms/:name/archive`, the history of an archived room without its
+/// expired messages.
+pub async fn get_archive(
+    Path(room): Path<RoomName>,
+    State(state): State<Arc<AppState>>,
+) -> ApiResponse {
+    let archive = state.archive.lock().unwrap();
+    let Some(archived) = archive.get(room.as_str()) else {
+        return not_archived();
+    };
+    let now = unix_timestamp();
+    let messages = archived
+        .history
+        .iter()
+        .filter(|message| message.expires_at.is_none_or(|at| at > now))
+        .collect::<Vec<_>>();
+    (
+        StatusCode::OK,
+        Json(json!({
+            "status": "Success!",
+            "archived_at": archived.archived_at,
+            "tags": archived.tags,
+            "messages": messages,
+        })),
+    )
+}
@@ -0,0 +1,308 @@
+//! An assistant answering in rooms through an OpenAI-compatible chat
+//! completions endpoint, `ASSISTANT_URL`, e.g. `https://api.openai.com/v1`,
+//! with `ASSISTANT_API_KEY` and `ASSISTANT_MODEL`.
+//!
+//! A member asks with `/ask <question>` or by mentioning the assistant,
+//! `@assistant` unless `ASSISTANT_NAME` names it otherwise. The assistant
+//! gets the room's `ASSISTANT_CONTEXT` latest messages, 20 by default, and
+//! `ASSISTANT_PROMPT` as its instructions. Its reply is posted right away
+//! and streams in as [edits](crate::events::ChatEvent::Edited) of it, the
+//! last of which is not `partial`. A room has one reply streaming at a time.
+
+use async_trait::async_trait;
+use log::{info, warn};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+
+use crate::bots::{Bot, BotContext};
+use crate::events::{App, ChatEvent};
+use crate::{tasks, AppState};
+
+const DEFAULT_MODEL: &str = "gpt-4o-mini";
+const DEFAULT_NAME: &str = "assistant";
+const DEFAULT_CONTEXT: usize = 20;
+/// How long a reply may take altogether.
+const TIMEOUT: Duration = Duration::from_secs(120);
+/// The least time between two edits of a reply.
+const EDIT_INTERVAL: Duration = Duration::from_millis(300);
+/// The text of a reply before any of it came.
+const PLACEHOLDER: &str = "…";
+
+#[derive(Clone, Debug)]
+pub struct AssistantConfig {
+    /// The API's base URL, which `/chat/completions` is appended to.
+    pub url: String,
+    pub api_key: Option<String>,
+    pub model: String,
+    /// Who the assistant speaks as and is mentioned as.
+    pub name: String,
+    /// The recent messages of the room it is given.
+    pub context: usize,
+    /// The system prompt, a default one naming the assistant and the room
+    /// when `None`.
+    pub prompt: Option<String>,
+}
+
+impl AssistantConfig {
+    pub fn new(url: impl Into<String>) -> Self {
+        Self {
+            url: url.into(),
+            api_key: None,
+            model: DEFAULT_MODEL.to_owned(),
+            name: DEFAULT_NAME.to_owned(),
+            context: DEFAULT_CONTEXT,
+            prompt: None,
+        }
+    }
+
+    pub fn from_env() -> Option<Self> {
+        let var = |name: &str| std::env::var(name).ok().filter(|val| !val.is_empty());
+        let defaults = Self::new(var("ASSISTANT_URL")?);
+        Some(Self {
+            api_key: var("ASSISTANT_API_KEY"),
+            model: var("ASSISTANT_MODEL").unwrap_or(defaults.model.clone()),
+            name: var("ASSISTANT_NAME").unwrap_or(defaults.name.clone()),
+            context: var("ASSISTANT_CONTEXT")
+                .and_then(|context| context.parse().ok())
+                .unwrap_or(defaults.context),
+            prompt: var("ASSISTANT_PROMPT"),
+            ..defaults
+        })
+    }
+}
+
+/// The assistant, shared by its bot with the replies streaming.
+struct Assistant {
+    config: AssistantConfig,
+    client: reqwest::Client,
+    state: Weak<AppState>,
+    /// The rooms with a reply streaming.
+    busy: Mutex<HashSet<String>>,
+}
+
+#[derive(Deserialize)]
+struct Chunk {
+    #[serde(default)]
+    choices: Vec<Choice>,
+}
+
+#[derive(Deserialize)]
+struct Choice {
+    #[serde(default)]
+    delta: Delta,
+}
+
+#[derive(Default, Deserialize)]
+struct Delta {
+    #[serde(default)]
+    content: Option<String>,
+}
+
+impl Assistant {
+    /// The chat so far, the room's latest messages and then the question.
+    fn messages(&self, state: &AppState, room: &str, from: &str, question: &str) -> Vec<Value> {
+        let prompt = self.config.prompt.clone().unwrap_or_else(|| {
+            format!(
+                "You are {}, an assistant in the chat room {}. Messages are given as \
+                 \"name: text\". Answer briefly, in plain text.",
+                self.config.name, room
+            )
+        });
+        let mut messages = vec![json!({ "role": "system", "content": prompt })];
+        let history = state
+            .rooms
+            .lock()
+            .unwrap()
+            .get(room)
+            .map(|room| room.history.lock().unwrap().clone())
+            .unwrap_or_default();
+        // A mention is in the history already.
+        let mut history = history.into_iter().collect::<Vec<_>>();
+        if history
+            .last()
+            .is_some_and(|last| last.from == from && last.text == question)
+        {
+            history.pop();
+        }
+        let skip = history.len().saturating_sub(self.config.context);
+        for message in history.iter().skip(skip) {
+            let message = if message.from == self.config.name {
+                json!({ "role": "assistant", "content": message.text })
+            } else {
+                json!({ "role": "user", "content": format!("{}: {}", message.from, message.text) })
+            };
+            messages.push(message);
+        }
+        messages.push(json!({ "role": "user", "content": format!("{}: {}", from, question) }));
+        messages
+    }
+
+    /// Streams the answer to `question` into a message of `room`.
+    async fn answer(&self, state: &AppState, room: &str, from: &str, question: &str) {
+        let messages = self.messages(state, room, from, question);
+        let Some(id) = post(state, room, &self.config.name) else {
+            return;
+        };
+        let mut request = self
+            .client
+            .post(format!(
+                "{}/chat/completions",
+                self.config.url.trim_end_matches('/')
+            ))
+            .json(&json!({
+                "model": self.config.model,
+                "messages": messages,
+                "stream": true,
+            }));
+        if let Some(api_key) = &self.config.api_key {
+            request = request.bearer_auth(api_key);
+        }
+        let mut text = String::new();
+        let result = async {
+            let mut res = request
+                .send()
+                .await
+                .and_then(|res| res.error_for_status())
+                .map_err(|err| err.to_string())?;
+            // Server-sent events, `data: {...}` lines until `data: [DONE]`.
+            let mut buffer = Vec::new();
+            let mut edited = Instant::now();
+            while let Some(chunk) = res.chunk().await.map_err(|err| err.to_string())? {
+                buffer.extend_from_slice(&chunk);
+                while let Some(end) = buffer.iter().position(|&byte| byte == b'\n') {
+                    let line = buffer.drain(..=end).collect::<Vec<_>>();
+                    let line = String::from_utf8_lossy(&line);
+                    let Some(data) = line.trim().strip_prefix("data:") else {
+                        continue;
+                    };
+                    let data = data.trim();
+                    if data == "[DONE]" {
+                        return Ok(());
+                    }
+                    let chunk =
+                        serde_json::from_str::<Chunk>(data).map_err(|err| err.to_string())?;
+                    for choice in chunk.choices {
+                        text.extend(choice.delta.content);
+                    }
+                }
+                if !text.is_empty() && edited.elapsed() >= EDIT_INTERVAL {
+                    edit(state, room, id, &text, true);
+                    edited = Instant::now();
+                }
+            }
+            Ok::<_, String>(())
+        }
+        .await;
+        if let Err(err) = &result {
+            warn!("The assistant could not answer in {}: {}", room, err);
+        }
+        let text = match (result, text.trim()) {
+            (Ok(()), "") => "I have no answer to that.",
+            (Ok(()), text) => text,
+            (Err(_), "") => "Sorry, I cannot answer right now.",
+            // What came before the error.
+            (Err(_), text) => text,
+        };
+        edit(state, room, id, text, false);
+        info!("The assistant answered {} in {}", from, room);
+    }
+
+    /// Answers `question` unless the room is waiting for another answer.
+    async fn ask(&self, ctx: &BotContext, from: &str, question: &str) {
+        let Some(state) = self.state.upgrade() else {
+            return;
+        };
+        if !self.busy.lock().unwrap().insert(ctx.room.clone()) {
+            ctx.dm(from, "Still answering, ask again in a moment.");
+            return;
+        }
+        self.answer(&state, &ctx.room, from, question).await;
+        self.busy.lock().unwrap().remove(&ctx.room);
+    }
+}
+
+/// Posts the placeholder of a reply and returns its id.
+fn post(state: &AppState, room: &str, from: &str) -> Option<u64> {
+    let rooms = state.rooms.lock().unwrap();
+    let room = rooms.get(room)?;
+    let id = room.next_id();
+    room.broadcast(ChatEvent::bot_message(
+        id,
+        from,
+        PLACEHOLDER,
+        App::new(from),
+    ));
+    Some(id)
+}
+
+fn edit(state: &AppState, room: &str, id: u64, text: &str, partial: bool) {
+    if let Some(room) = state.rooms.lock().unwrap().get(room) {
+        room.edit(id, text, partial);
+    }
+}
+
+pub struct AssistantBot {
+    assistant: Arc<Assistant>,
+}
+
+impl AssistantBot {
+    pub fn new(config: AssistantConfig, state: Weak<AppState>) -> Self {
+        let assistant = Assistant {
+            config,
+            client: reqwest::Client::builder().timeout(TIMEOUT).build().unwrap(),
+            state,
+            busy: Mutex::default(),
+        };
+        Self {
+            assistant: Arc::new(assistant),
+        }
+    }
+
+    /// Answers on a task of its own, so that the member's message is not
+    /// held up by the reply.
+    fn spawn_ask(&self, ctx: &BotContext, from: &str, question: &str) {
+        let (assistant, ctx, from, question) = (
+            self.assistant.clone(),
+            ctx.clone(),
+            from.to_owned(),
+            question.to_owned(),
+        );
+        tasks::spawn("assistant", async move {
+            assistant.ask(&ctx, &from, &question).await;
+        });
+    }
+}
+
+#[async_trait]
+impl Bot for AssistantBot {
+    fn name(&self) -> &str {
+        &self.assistant.config.name
+    }
+
+    fn commands(&self) -> &'static [(&'static str, &'static str)] {
+        &[("ask <question>", "asks the assistant")]
+    }
+
+    async fn on_command(&self, ctx: &BotContext, from: &str, command: &str, args: &str) -> bool {
+        if command != "ask" {
+            return false;
+        }
+        if args.is_empty() {
+            ctx.dm(from, "Usage: /ask <question>");
+            return true;
+        }
+        self.spawn_ask(ctx, from, args);
+        true
+    }
+
+    async fn on_message(&self, ctx: &BotContext, from: &str, text: &str) {
+        let name = &self.assistant.config.name;
+        let mention = format!("@{}", name.to_lowercase());
+        if from != name && text.to_lowercase().contains(&mention) {
+            self.spawn_ask(ctx, from, text);
+        }
+    }
+}
@@ -0,0 +1,520 @@
+//! File attachments uploaded to a room and referenced from messages.
+//!
+//! Uploads go to an [`AttachmentStore`]: a local directory set by
+//! `ATTACHMENTS_DIR`, or an S3-compatible bucket configured through the
+//! variables described in the [`s3`] module. An [`AttachmentPolicy`] limits
+//! what is accepted and a [`Scanner`] may quarantine files before they are
+//! served.
+
+mod audio;
+mod policy;
+pub mod s3;
+pub mod scan;
+mod thumbnails;
+
+pub use policy::{AttachmentPolicy, Usage};
+pub use scan::{Scanner, Verdict};
+
+use async_trait::async_trait;
+use axum::extract::multipart::MultipartError;
+use axum::extract::{Multipart, Path, Query, State};
+use axum::http::header::{CONTENT_DISPOSITION, CONTENT_TYPE, X_CONTENT_TYPE_OPTIONS};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Redirect, Response};
+use axum::Json;
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use crate::owners::generate_token;
+use crate::room_names::RoomName;
+use crate::sentry;
+use crate::sessions::is_session;
+use crate::{ApiResponse, AppState};
+
+/// Largest accepted upload unless the policy says otherwise.
+pub const MAX_BYTES: usize = 10 << 20;
+/// Attachments a single message may reference.
+pub const MAX_PER_MESSAGE: usize = 10;
+const MAX_NAME_LEN: usize = 255;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Attachment {
+    pub id: String,
+    pub room: String,
+    pub name: String,
+    pub content_type: String,
+    pub size: usize,
+    pub url: String,
+    /// Pixel dimensions of images.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub width: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub height: Option<u32>,
+    /// Preview of an image, the image itself when it is small enough.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub thumbnail: Option<Thumbnail>,
+    /// Length of audio clips in seconds.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration: Option<f64>,
+    /// Why a scanner flagged the file, which is then never served.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quarantined: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Thumbnail {
+    pub url: String,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// How an attachment's contents are handed to a client.
+pub enum Download {
+    Bytes(Vec<u8>),
+    /// Send the client elsewhere, e.g. to a presigned URL.
+    Redirect(String),
+}
+
+/// Keeps uploaded files and their metadata.
+#[async_trait]
+pub trait AttachmentStore: Send + Sync {
+    async fn save(&self, attachment: &Attachment, bytes: Vec<u8>) -> io::Result<()>;
+
+    async fn metadata(&self, id: &str) -> Option<Attachment>;
+
+    async fn download(&self, attachment: &Attachment) -> Option<Download>;
+}
+
+/// Stores each upload as `<id>` next to a `<id>.json` holding its metadata,
+/// so downloads survive restarts.
+pub struct DiskStore {
+    dir: PathBuf,
+}
+
+impl DiskStore {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+}
+
+#[async_trait]
+impl AttachmentStore for DiskStore {
+    async fn save(&self, attachment: &Attachment, bytes: Vec<u8>) -> io::Result<()> {
+        let metadata = serde_json::to_vec(attachment)?;
+        tokio::fs::create_dir_all(&self.dir).await?;
+        tokio::fs::write(self.dir.join(&attachment.id), bytes).await?;
+        tokio::fs::write(self.dir.join(format!("{}.json", attachment.id)), metadata).await
+    }
+
+    async fn metadata(&self, id: &str) -> Option<Attachment> {
+        let json = tokio::fs::read(self.dir.join(format!("{}.json", id)))
+            .await
+            .ok()?;
+        serde_json::from_slice(&json).ok()
+    }
+
+    async fn download(&self, attachment: &Attachment) -> Option<Download> {
+        let bytes = tokio::fs::read(self.dir.join(&attachment.id)).await.ok()?;
+        Some(Download::Bytes(bytes))
+    }
+}
+
+/// Works out the type from the file's contents, clients' claims are ignored.
+pub fn detect(bytes: &[u8]) -> Option<&'static str> {
+    const SIGNATURES: &[(&[u8], &str)] = &[
+        (b"\x89PNG\r\n\x1a\n", "image/png"),
+        (b"\xff\xd8\xff", "image/jpeg"),
+        (b"GIF87a", "image/gif"),
+        (b"GIF89a", "image/gif"),
+        (b"%PDF-", "application/pdf"),
+        (b"OggS", "audio/ogg"),
+        (b"fLaC", "audio/flac"),
+        (b"ID3", "audio/mpeg"),
+        (b"\x1a\x45\xdf\xa3", "audio/webm"),
+    ];
+    if let Some((_, content_type)) = SIGNATURES
+        .iter()
+        .find(|(signature, _)| bytes.starts_with(signature))
+    {
+        return Some(content_type);
+    }
+    if bytes.len() >= 12 && &bytes[..4] == b"RIFF" {
+        match &bytes[8..12] {
+            b"WEBP" => return Some("image/webp"),
+            b"WAVE" => return Some("audio/wav"),
+            _ => {}
+        }
+    }
+    if bytes.len() >= 12 && &bytes[4..8] == b"ftyp" && matches!(&bytes[8..12], b"M4A " | b"M4B ") {
+        return Some("audio/mp4");
+    }
+    // An MPEG audio layer III frame header without an ID3 tag in front.
+    if bytes.len() >= 2 && bytes[0] == 0xff && bytes[1] & 0xe6 == 0xe2 {
+        return Some("audio/mpeg");
+    }
+    std::str::from_utf8(bytes).ok().map(|_| "text/plain")
+}
+
+fn clean_name(name: &str) -> String {
+    let name = name.rsplit(['/', '\\']).next().unwrap_or_default();
+    let name = name
+        .chars()
+        .filter(|c| !c.is_control())
+        .take(MAX_NAME_LEN)
+        .collect::<String>();
+    if name.trim().is_empty() {
+        String::from("file")
+    } else {
+        name
+    }
+}
+
+/// `Content-Disposition` to serve an attachment with: images and audio are
+/// shown inline, everything else is downloaded.
+fn disposition(attachment: &Attachment) -> String {
+    let media = ["image/", "audio/"];
+    let kind = if media
+        .iter()
+        .any(|prefix| attachment.content_type.starts_with(prefix))
+    {
+        "inline"
+    } else {
+        "attachment"
+    };
+    let filename = attachment
+        .name
+        .chars()
+        .map(|c| {
+            if c.is_ascii() && !c.is_ascii_control() && c != '"' && c != '\\' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect::<String>();
+    format!("{}; filename=\"{}\"", kind, filename)
+}
+
+fn error(status: StatusCode, message: &str) -> ApiResponse {
+    (status, Json(json!({ "status": message })))
+}
+
+fn is_id(id: &str) -> bool {
+    !id.is_empty() && id.chars().all(|c| c.is_ascii_alphanumeric())
+}
+
+/// A servable attachment, quarantined ones are treated as missing.
+async fn metadata(state: &AppState, id: &str) -> Option<Attachment> {
+    if !is_id(id) {
+        return None;
+    }
+    let attachment = state.attachments.as_ref()?.metadata(id).await?;
+    attachment.quarantined.is_none().then_some(attachment)
+}
+
+/// Looks up the attachments a message of `room` refers to, skipping unknown
+/// ids and ones uploaded to another room.
+pub async fn resolve(state: &AppState, room: &str, ids: &[String]) -> Vec<Attachment> {
+    let mut attachments = Vec::new();
+    for id in ids.iter().take(MAX_PER_MESSAGE) {
+        if let Some(attachment) = metadata(state, id).await {
+            if attachment.room == room {
+                attachments.push(attachment);
+            }
+        }
+    }
+    attachments
+}
+
+/// Pixel dimensions of an image, `None` when it does not decode.
+pub async fn dimensions(bytes: &[u8]) -> Option<(u32, u32)> {
+    let image = bytes.to_vec();
+    let decoded = tokio::task::spawn_blocking(move || thumbnails::generate(&image))
+        .await
+        .ok()??;
+    Some((decoded.width, decoded.height))
+}
+
+/// Reads the multipart `file` field, returning its cleaned name and contents.
+pub async fn read_file(
+    multipart: &mut Multipart,
+    max_bytes: usize,
+) -> Result<(String, Vec<u8>), ApiResponse> {
+    let too_large = || {
+        error(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            &format!("Files are limited to {} bytes.", max_bytes),
+        )
+    };
+    let failed = |err: MultipartError| match err.status() {
+        StatusCode::PAYLOAD_TOO_LARGE => too_large(),
+        status => error(status, "Malformed multipart body."),
+    };
+    loop {
+        let mut field = match multipart.next_field().await {
+            Ok(Some(field)) => field,
+            Ok(None) => break,
+            Err(err) => return Err(failed(err)),
+        };
+        if field.name() != Some("file") {
+            continue;
+        }
+        let name = clean_name(field.file_name().unwrap_or_default());
+        let mut bytes = Vec::new();
+        loop {
+            match field.chunk().await {
+                Ok(Some(chunk)) if bytes.len() + chunk.len() > max_bytes => return Err(too_large()),
+                Ok(Some(chunk)) => bytes.extend_from_slice(&chunk),
+                Ok(None) => break,
+                Err(err) => return Err(failed(err)),
+            }
+        }
+        if !bytes.is_empty() {
+            return Ok((name, bytes));
+        }
+        break;
+    }
+    Err(error(
+        StatusCode::BAD_REQUEST,
+        "Expected a non-empty multipart file field.",
+    ))
+}
+
+/// Records an image's dimensions and stores its thumbnail.
+async fn add_thumbnail(
+    store: &dyn AttachmentStore,
+    attachment: &mut Attachment,
+    bytes: &[u8],
+) -> Result<(), ApiResponse> {
+    let image = bytes.to_vec();
+    let Ok(Some(decoded)) = tokio::task::spawn_blocking(move || thumbnails::generate(&image)).await
+    else {
+        return Err(error(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "Could not decode image.",
+        ));
+    };
+    attachment.width = Some(decoded.width);
+    attachment.height = Some(decoded.height);
+    attachment.thumbnail = Some(match decoded.thumbnail {
+        Some(scaled) => {
+            // Kept as an attachment of its own, so every store serves it.
+            let id = format!("{}thumb", attachment.id);
+            let thumbnail = Thumbnail {
+                url: format!("{}thumb", attachment.url),
+                width: scaled.width,
+                height: scaled.height,
+            };
+            let preview = Attachment {
+                url: thumbnail.url.clone(),
+                id,
+                room: attachment.room.clone(),
+                name: format!("thumbnail-{}", attachment.name),
+                content_type: scaled.content_type.to_owned(),
+                size: scaled.bytes.len(),
+                width: Some(scaled.width),
+                height: Some(scaled.height),
+                thumbnail: None,
+                duration: None,
+                quarantined: None,
+            };
+            if let Err(err) = store.save(&preview, scaled.bytes).await {
+                error!("Failed to store thumbnail {}: {}", preview.id, err);
+                let err = format!("Failed to store thumbnail {}: {}", preview.id, err);
+                sentry::report_error("attachments", err, Some(&preview.room), None);
+                return Err(error(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "Failed to store attachment.",
+                ));
+            }
+            thumbnail
+        }
+        None => Thumbnail {
+            url: attachment.url.clone(),
+            width: decoded.width,
+            height: decoded.height,
+        },
+    });
+    Ok(())
+}
+
+#[derive(Deserialize)]
+pub struct UploadQuery {
+    /// The uploading member, whose session token the request carries and
+    /// whose quota the file counts against.
+    username: Option<String>,
+}
+
+/// `POST /rooms/:name/attachments?username=`, takes a multipart `file` field.
+pub async fn upload(
+    Path(room): Path<RoomName>,
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<UploadQuery>,
+    headers: HeaderMap,
+    mut multipart: Multipart,
+) -> ApiResponse {
+    let Some(store) = &state.attachments else {
+        return error(StatusCode::NOT_FOUND, "Attachments are not enabled.");
+    };
+    let policy = &state.attachment_policy;
+    let Some(username) = &query.username else {
+        return error(
+            StatusCode::BAD_REQUEST,
+            "Uploads need the uploader's username.",
+        );
+    };
+    if !is_session(&state, &room, username, &headers) {
+        return error(StatusCode::FORBIDDEN, "Missing or invalid session token.");
+    }
+    {
+        let rooms = state.rooms.lock().unwrap();
+        let Some(room_state) = rooms.get(room.as_str()) else {
+            return error(StatusCode::NOT_FOUND, "Room not found.");
+        };
+        if !room_state.users.lock().unwrap().contains_key(username) {
+            return error(StatusCode::FORBIDDEN, "Not a member of this room.");
+        }
+    }
+
+    let (name, bytes) = match read_file(&mut multipart, policy.max_bytes).await {
+        Ok(file) => file,
+        Err(response) => return response,
+    };
+    let Some(content_type) = detect(&bytes).filter(|content_type| policy.allows(content_type))
+    else {
+        return error(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "This type of file is not allowed.",
+        );
+    };
+
+    let size = bytes.len() as u64;
+    if !state
+        .attachment_usage
+        .reserve(username, size, policy.user_quota)
+    {
+        return error(
+            StatusCode::FORBIDDEN,
+            &format!(
+                "Uploads are limited to {} bytes per member.",
+                policy.user_quota.unwrap_or_default()
+            ),
+        );
+    }
+    let id = generate_token();
+    let attachment = Attachment {
+        url: format!("{}/attachments/{}", state.base_path, id),
+        id,
+        room: room.into(),
+        name,
+        content_type: content_type.to_owned(),
+        size: bytes.len(),
+        width: None,
+        height: None,
+        thumbnail: None,
+        duration: None,
+        quarantined: None,
+    };
+    let attachment = match store_upload(&state, store.as_ref(), attachment, bytes).await {
+        Ok(attachment) => attachment,
+        Err(response) => {
+            state.attachment_usage.release(username, size);
+            return response;
+        }
+    };
+    // Quarantined files are kept in the store, so they stay counted.
+    if let Some(reason) = &attachment.quarantined {
+        return error(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            &format!("Attachment was quarantined: {}.", reason),
+        );
+    }
+    (
+        StatusCode::OK,
+        Json(json!({ "status": "Success!", "attachment": attachment })),
+    )
+}
+
+/// Scans an upload, extracts its metadata and stores it, quarantined or not.
+async fn store_upload(
+    state: &AppState,
+    store: &dyn AttachmentStore,
+    mut attachment: Attachment,
+    bytes: Vec<u8>,
+) -> Result<Attachment, ApiResponse> {
+    let verdict = match &state.attachment_scanner {
+        Some(scanner) => scanner.scan(&attachment, &bytes).await,
+        None => Ok(Verdict::Clean),
+    };
+    match verdict {
+        Ok(Verdict::Clean) => {}
+        Ok(Verdict::Quarantine(reason)) => {
+            warn!("Quarantined attachment {}: {}", attachment.id, reason);
+            attachment.quarantined = Some(reason);
+        }
+        Err(err) => {
+            error!("Failed to scan attachment {}: {}", attachment.id, err);
+            return Err(error(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "Could not scan attachment.",
+            ));
+        }
+    }
+
+    let content_type = attachment.content_type.clone();
+    if attachment.quarantined.is_none() && content_type.starts_with("image/") {
+        add_thumbnail(store, &mut attachment, &bytes).await?;
+    }
+    if attachment.quarantined.is_none() && content_type.starts_with("audio/") {
+        let max = state.attachment_policy.max_audio_duration;
+        let Some(duration) = audio::duration(&content_type, &bytes) else {
+            return Err(error(
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "Could not read the length of the audio clip.",
+            ));
+        };
+        if duration > max {
+            return Err(error(
+                StatusCode::BAD_REQUEST,
+                &format!("Audio clips are limited to {} seconds.", max.as_secs()),
+            ));
+        }
+        attachment.duration = Some((duration.as_secs_f64() * 1000.0).round() / 1000.0);
+    }
+    if let Err(err) = store.save(&attachment, bytes).await {
+        error!("Failed to store attachment {}: {}", attachment.id, err);
+        let err = format!("Failed to store attachment {}: {}", attachment.id, err);
+        sentry::report_error("attachments", err, Some(&attachment.room), None);
+        return Err(error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Failed to store attachment.",
+        ));
+    }
+    Ok(attachment)
+}
+
+/// `GET /attachments/:id`
+pub async fn download(Path(id): Path<String>, State(state): State<Arc<AppState>>) -> Response {
+    let not_found = || error(StatusCode::NOT_FOUND, "Attachment not found.").into_response();
+    let (Some(attachment), Some(store)) = (metadata(&state, &id).await, &state.attachments) else {
+        return not_found();
+    };
+    let bytes = match store.download(&attachment).await {
+        Some(Download::Bytes(bytes)) => bytes,
+        Some(Download::Redirect(url)) => return Redirect::temporary(&url).into_response(),
+        None => return not_found(),
+    };
+    (
+        [
+            (CONTENT_DISPOSITION, disposition(&attachment)),
+            (CONTENT_TYPE, attachment.content_type),
+            (X_CONTENT_TYPE_OPTIONS, String::from("nosniff")),
+        ],
+        bytes,
+    )
+        .into_response()
+}
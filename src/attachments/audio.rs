@@ -0,0 +1,178 @@
+//! Durations of uploaded audio clips, read from their container headers.
+
+use std::time::Duration;
+
+fn u16_le(bytes: &[u8], at: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(bytes.get(at..at + 2)?.try_into().ok()?))
+}
+
+fn u32_le(bytes: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(bytes.get(at..at + 4)?.try_into().ok()?))
+}
+
+fn u32_be(bytes: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(bytes.get(at..at + 4)?.try_into().ok()?))
+}
+
+fn u64_be(bytes: &[u8], at: usize) -> Option<u64> {
+    Some(u64::from_be_bytes(bytes.get(at..at + 8)?.try_into().ok()?))
+}
+
+fn find(bytes: &[u8], needle: &[u8]) -> Option<usize> {
+    bytes
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+fn seconds(value: f64) -> Option<Duration> {
+    (value.is_finite() && value >= 0.0).then(|| Duration::from_secs_f64(value))
+}
+
+/// How long a clip of the detected `content_type` plays, `None` when its
+/// headers do not say.
+pub fn duration(content_type: &str, bytes: &[u8]) -> Option<Duration> {
+    match content_type {
+        "audio/wav" => wav(bytes),
+        "audio/ogg" => ogg(bytes),
+        "audio/flac" => flac(bytes),
+        "audio/mpeg" => mp3(bytes),
+        "audio/mp4" => mp4(bytes),
+        "audio/webm" => webm(bytes),
+        _ => None,
+    }
+}
+
+fn wav(bytes: &[u8]) -> Option<Duration> {
+    let (mut byte_rate, mut at) = (None, 12);
+    while at + 8 <= bytes.len() {
+        let size = u32_le(bytes, at + 4)? as usize;
+        match &bytes[at..at + 4] {
+            b"fmt " => byte_rate = u32_le(bytes, at + 16).filter(|rate| *rate > 0),
+            // Streamed recordings may not fill in the size.
+            b"data" => {
+                let size = size.min(bytes.len() - at - 8);
+                return seconds(size as f64 / f64::from(byte_rate?));
+            }
+            _ => {}
+        }
+        at += 8 + size + size % 2;
+    }
+    None
+}
+
+/// Opus and Vorbis: the last page's granule position counts samples.
+fn ogg(bytes: &[u8]) -> Option<Duration> {
+    let segments = *bytes.get(26)? as usize;
+    let packet = bytes.get(27 + segments..)?;
+    let (rate, skip) = if packet.starts_with(b"OpusHead") {
+        (48_000, u16_le(packet, 10)?)
+    } else if packet.starts_with(b"\x01vorbis") {
+        (u32_le(packet, 12)?, 0)
+    } else {
+        return None;
+    };
+    let last = (0..bytes.len().saturating_sub(14))
+        .rev()
+        .find(|&at| bytes[at..].starts_with(b"OggS\0"))?;
+    let granule = u64::from_le_bytes(bytes[last + 6..last + 14].try_into().ok()?);
+    let samples = granule.checked_sub(u64::from(skip))?;
+    seconds(samples as f64 / f64::from(rate)).filter(|_| rate > 0)
+}
+
+fn flac(bytes: &[u8]) -> Option<Duration> {
+    // STREAMINFO is the first metadata block, right after the marker.
+    if bytes.get(4)? & 0x7f != 0 {
+        return None;
+    }
+    let info = u64_be(bytes, 8 + 10)?;
+    let rate = info >> 44;
+    let samples = info & 0xf_ffff_ffff;
+    (rate > 0).then(|| Duration::from_secs_f64(samples as f64 / rate as f64))
+}
+
+/// Adds up the frames, which also covers variable bitrates.
+fn mp3(bytes: &[u8]) -> Option<Duration> {
+    const MPEG1: [u32; 15] = [
+        0, 32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320,
+    ];
+    const MPEG2: [u32; 15] = [0, 8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160];
+    const RATES: [u32; 3] = [44_100, 48_000, 32_000];
+
+    let mut at = 0;
+    if bytes.starts_with(b"ID3") {
+        let size = bytes
+            .get(6..10)?
+            .iter()
+            .fold(0, |size, byte| (size << 7) | usize::from(byte & 0x7f));
+        let footer = if bytes[5] & 0x10 != 0 { 10 } else { 0 };
+        at = 10 + size + footer;
+    }
+    let (mut total, mut frames) = (0.0, 0);
+    while at + 4 <= bytes.len() {
+        let header = &bytes[at..at + 4];
+        let version = (header[1] >> 3) & 3;
+        let layer = (header[1] >> 1) & 3;
+        let bitrate = usize::from(header[2] >> 4);
+        let rate = usize::from((header[2] >> 2) & 3);
+        if header[0] != 0xff
+            || header[1] & 0xe0 != 0xe0
+            || version == 1
+            || layer != 1
+            || bitrate == 0
+            || bitrate == 15
+            || rate == 3
+        {
+            if frames > 0 && header.starts_with(b"TAG") {
+                break;
+            }
+            at += 1;
+            continue;
+        }
+        let padding = u32::from((header[2] >> 1) & 1);
+        let (bitrate, rate, samples) = match version {
+            3 => (MPEG1[bitrate] * 1000, RATES[rate], 1152),
+            2 => (MPEG2[bitrate] * 1000, RATES[rate] / 2, 576),
+            _ => (MPEG2[bitrate] * 1000, RATES[rate] / 4, 576),
+        };
+        let length = samples / 8 * bitrate / rate + padding;
+        total += f64::from(samples) / f64::from(rate);
+        frames += 1;
+        at += length as usize;
+    }
+    (frames > 0).then(|| Duration::from_secs_f64(total))
+}
+
+/// The movie header holds the duration in units of its timescale.
+fn mp4(bytes: &[u8]) -> Option<Duration> {
+    let at = find(bytes, b"mvhd")? + 4;
+    let (timescale, duration) = match *bytes.get(at)? {
+        0 => (u32_be(bytes, at + 12)?, u64::from(u32_be(bytes, at + 16)?)),
+        1 => (u32_be(bytes, at + 20)?, u64_be(bytes, at + 24)?),
+        _ => return None,
+    };
+    (timescale > 0).then(|| Duration::from_secs_f64(duration as f64 / f64::from(timescale)))
+}
+
+/// The segment info's Duration element, in units of its TimecodeScale.
+/// Live recordings such as Chrome's MediaRecorder output may leave it out.
+fn webm(bytes: &[u8]) -> Option<Duration> {
+    let head = &bytes[..bytes.len().min(64 << 10)];
+    let at = find(head, &[0x44, 0x89])? + 2;
+    let duration = match *head.get(at)? {
+        0x84 => f64::from(f32::from_bits(u32_be(head, at + 1)?)),
+        0x88 => f64::from_bits(u64_be(head, at + 1)?),
+        _ => return None,
+    };
+    let scale = find(head, &[0x2a, 0xd7, 0xb1])
+        .and_then(|at| {
+            let length = usize::from(head.get(at + 3)? & 0x0f);
+            let value = head.get(at + 4..at + 4 + length)?;
+            Some(
+                value
+                    .iter()
+                    .fold(0u64, |scale, byte| scale << 8 | u64::from(*byte)),
+            )
+        })
+        .unwrap_or(1_000_000);
+    seconds(duration * scale as f64 / 1e9)
+}
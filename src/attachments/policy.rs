@@ -0,0 +1,120 @@
+//! Deployment limits on what members may upload.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use super::MAX_BYTES;
+use crate::json_file::JsonFile;
+
+#[derive(Clone, Debug)]
+pub struct AttachmentPolicy {
+    /// Content types accepted, such as `image/*` or `application/pdf`. Every
+    /// detected type is accepted when `None`.
+    pub allowed_types: Option<Vec<String>>,
+    /// Largest accepted file.
+    pub max_bytes: usize,
+    /// Bytes each member may upload in total, unlimited when `None`.
+    pub user_quota: Option<u64>,
+    /// Longest accepted audio clip.
+    pub max_audio_duration: Duration,
+}
+
+impl Default for AttachmentPolicy {
+    fn default() -> Self {
+        Self {
+            allowed_types: None,
+            max_bytes: MAX_BYTES,
+            user_quota: None,
+            max_audio_duration: Duration::from_secs(120),
+        }
+    }
+}
+
+impl AttachmentPolicy {
+    /// The default policy with the overrides of `ATTACHMENT_TYPES`,
+    /// `ATTACHMENT_MAX_BYTES`, `ATTACHMENT_USER_QUOTA` and `MAX_AUDIO_SECONDS`.
+    pub fn from_env() -> Self {
+        let var = |name: &str| std::env::var(name).ok().filter(|val| !val.is_empty());
+        let defaults = Self::default();
+        Self {
+            allowed_types: var("ATTACHMENT_TYPES").map(|types| {
+                types
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|pattern| !pattern.is_empty())
+                    .map(str::to_owned)
+                    .collect()
+            }),
+            max_bytes: var("ATTACHMENT_MAX_BYTES")
+                .map_or(defaults.max_bytes, |bytes| bytes.parse().unwrap()),
+            user_quota: var("ATTACHMENT_USER_QUOTA").map(|bytes| bytes.parse().unwrap()),
+            max_audio_duration: var("MAX_AUDIO_SECONDS")
+                .map_or(defaults.max_audio_duration, |secs| {
+                    Duration::from_secs(secs.parse().unwrap())
+                }),
+        }
+    }
+
+    pub fn allows(&self, content_type: &str) -> bool {
+        let Some(patterns) = &self.allowed_types else {
+            return true;
+        };
+        patterns
+            .iter()
+            .any(|pattern| match pattern.strip_suffix("/*") {
+                Some(kind) => content_type
+                    .split_once('/')
+                    .is_some_and(|(prefix, _)| prefix == kind),
+                None => pattern == "*" || pattern == content_type,
+            })
+    }
+}
+
+/// Bytes uploaded per member, written through to a file if one is set so
+/// that a restart does not reset the quotas.
+#[derive(Default)]
+pub struct Usage {
+    used: Mutex<HashMap<String, u64>>,
+    file: Option<JsonFile>,
+}
+
+impl Usage {
+    /// Loads the totals from `file`, starting empty if it does not exist yet.
+    pub fn open(file: Option<PathBuf>) -> Self {
+        let file = file.map(|path| JsonFile::new(path, "attachment usage"));
+        let used = file.as_ref().map(JsonFile::load).unwrap_or_default();
+        Self {
+            used: Mutex::new(used),
+            file,
+        }
+    }
+
+    fn save(&self, used: &HashMap<String, u64>) {
+        if let Some(file) = &self.file {
+            file.save(used);
+        }
+    }
+
+    /// Counts `bytes` against `username` unless that would exceed `quota`.
+    pub fn reserve(&self, username: &str, bytes: u64, quota: Option<u64>) -> bool {
+        let mut usage = self.used.lock().unwrap();
+        let used = usage.entry(username.to_owned()).or_default();
+        if quota.is_some_and(|quota| *used + bytes > quota) {
+            return false;
+        }
+        *used += bytes;
+        self.save(&usage);
+        true
+    }
+
+    /// Gives back bytes reserved for an upload that did not go through.
+    pub fn release(&self, username: &str, bytes: u64) {
+        let mut usage = self.used.lock().unwrap();
+        if let Some(used) = usage.get_mut(username) {
+            *used = used.saturating_sub(bytes);
+            self.save(&usage);
+        }
+    }
+}
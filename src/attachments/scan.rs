@@ -0,0 +1,130 @@
+//! Scanning of uploads before they become downloadable.
+//!
+//! `CLAMD_ADDR` (e.g. `127.0.0.1:3310`) scans with ClamAV's daemon,
+//! `SCAN_WEBHOOK_URL` posts each file to a service of your own, which
+//! answers `{"clean": true}` or `{"clean": false, "reason": "..."}`.
+
+use async_trait::async_trait;
+use serde::Deserialize;
+use std::io;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use super::Attachment;
+
+const SCAN_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub enum Verdict {
+    Clean,
+    /// Kept in the store but never served, for the given reason.
+    Quarantine(String),
+}
+
+/// Checks uploads, e.g. for malware. An `Err` rejects the upload, so that
+/// nothing unscanned is served while the scanner is unavailable.
+#[async_trait]
+pub trait Scanner: Send + Sync {
+    async fn scan(&self, attachment: &Attachment, bytes: &[u8]) -> io::Result<Verdict>;
+}
+
+/// Streams files to clamd with its `INSTREAM` command.
+pub struct ClamdScanner {
+    addr: String,
+}
+
+impl ClamdScanner {
+    pub fn new(addr: impl Into<String>) -> Self {
+        Self { addr: addr.into() }
+    }
+
+    async fn instream(&self, bytes: &[u8]) -> io::Result<String> {
+        let mut stream = TcpStream::connect(&self.addr).await?;
+        stream.write_all(b"zINSTREAM\0").await?;
+        for chunk in bytes.chunks(64 << 10) {
+            stream
+                .write_all(&(chunk.len() as u32).to_be_bytes())
+                .await?;
+            stream.write_all(chunk).await?;
+        }
+        stream.write_all(&0u32.to_be_bytes()).await?;
+        let mut reply = Vec::new();
+        stream.read_to_end(&mut reply).await?;
+        Ok(String::from_utf8_lossy(&reply)
+            .trim_end_matches(['\0', '\n'])
+            .to_owned())
+    }
+}
+
+#[async_trait]
+impl Scanner for ClamdScanner {
+    async fn scan(&self, _attachment: &Attachment, bytes: &[u8]) -> io::Result<Verdict> {
+        let reply = tokio::time::timeout(SCAN_TIMEOUT, self.instream(bytes))
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "clamd timed out"))??;
+        let result = reply.strip_prefix("stream: ").unwrap_or(&reply);
+        if result == "OK" {
+            Ok(Verdict::Clean)
+        } else if let Some(signature) = result.strip_suffix(" FOUND") {
+            Ok(Verdict::Quarantine(signature.to_owned()))
+        } else {
+            Err(io::Error::other(format!("clamd answered {}", reply)))
+        }
+    }
+}
+
+/// Posts each file to a URL, with its metadata in `X-Attachment-*` headers.
+pub struct WebhookScanner {
+    url: String,
+    client: reqwest::Client,
+}
+
+impl WebhookScanner {
+    pub fn new(url: impl Into<String>) -> Self {
+        Self {
+            url: url.into(),
+            client: reqwest::Client::builder()
+                .timeout(SCAN_TIMEOUT)
+                .build()
+                .unwrap(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct WebhookVerdict {
+    clean: bool,
+    #[serde(default)]
+    reason: Option<String>,
+}
+
+#[async_trait]
+impl Scanner for WebhookScanner {
+    async fn scan(&self, attachment: &Attachment, bytes: &[u8]) -> io::Result<Verdict> {
+        let response = self
+            .client
+            .post(&self.url)
+            .header("content-type", &attachment.content_type)
+            .header("x-attachment-id", &attachment.id)
+            .header("x-attachment-room", &attachment.room)
+            .body(bytes.to_vec())
+            .send()
+            .await
+            .map_err(io::Error::other)?;
+        if !response.status().is_success() {
+            return Err(io::Error::other(format!(
+                "scan webhook answered {}",
+                response.status()
+            )));
+        }
+        let verdict = response
+            .json::<WebhookVerdict>()
+            .await
+            .map_err(io::Error::other)?;
+        Ok(if verdict.clean {
+            Verdict::Clean
+        } else {
+            Verdict::Quarantine(verdict.reason.unwrap_or_else(|| String::from("flagged")))
+        })
+    }
+}
@@ -0,0 +1,70 @@
+//! Bounded previews of image attachments, generated once at upload.
+
+use image::{DynamicImage, ImageFormat, ImageReader, Limits};
+use std::io::Cursor;
+
+/// Thumbnails fit in a square of this many pixels.
+pub const MAX_SIZE: u32 = 320;
+/// Larger images are not decoded at all.
+const MAX_DIMENSION: u32 = 12_000;
+const MAX_ALLOC: u64 = 512 << 20;
+
+pub struct Decoded {
+    pub width: u32,
+    pub height: u32,
+    /// Scaled-down copy, `None` when the image already fits.
+    pub thumbnail: Option<Thumbnail>,
+}
+
+pub struct Thumbnail {
+    pub bytes: Vec<u8>,
+    pub content_type: &'static str,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Decodes an uploaded image and scales it to fit [`MAX_SIZE`]. Blocking.
+pub fn generate(bytes: &[u8]) -> Option<Decoded> {
+    let mut reader = ImageReader::new(Cursor::new(bytes))
+        .with_guessed_format()
+        .ok()?;
+    let format = reader.format()?;
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_DIMENSION);
+    limits.max_image_height = Some(MAX_DIMENSION);
+    limits.max_alloc = Some(MAX_ALLOC);
+    reader.limits(limits);
+    let image = reader.decode().ok()?;
+
+    let (width, height) = (image.width(), image.height());
+    if width <= MAX_SIZE && height <= MAX_SIZE {
+        return Some(Decoded {
+            width,
+            height,
+            thumbnail: None,
+        });
+    }
+
+    let scaled = image.thumbnail(MAX_SIZE, MAX_SIZE);
+    // JPEG has no alpha channel, everything else keeps it as PNG.
+    let (scaled, format, content_type) = match format {
+        ImageFormat::Jpeg => (
+            DynamicImage::ImageRgb8(scaled.to_rgb8()),
+            ImageFormat::Jpeg,
+            "image/jpeg",
+        ),
+        _ => (scaled, ImageFormat::Png, "image/png"),
+    };
+    let mut encoded = Cursor::new(Vec::new());
+    scaled.write_to(&mut encoded, format).ok()?;
+    Some(Decoded {
+        width,
+        height,
+        thumbnail: Some(Thumbnail {
+            bytes: encoded.into_inner(),
+            content_type,
+            width: scaled.width(),
+            height: scaled.height(),
+        }),
+    })
+}
@@ -0,0 +1,143 @@
+//! What a room does when members cannot keep up with it, which room owners
+//! choose through `PUT /rooms/:name/backpressure`:
+//!
+//! - `drop_oldest`: members that fall too far behind skip the oldest events,
+//!   never disconnected, which suits busy rooms where the latest matters.
+//! - `block`: a member's message waits, up to [`BLOCK_TIMEOUT`], for the
+//!   slowest member to be less than the slow-consumer lag behind, so that
+//!   small rooms lose nothing to a brief stall. Past it, the oldest events
+//!   are skipped after all. Bridges, bots and announcements do not wait.
+//! - `disconnect`: members that fall behind are disconnected.
+//!
+//! Rooms without a strategy follow the server's [slow consumers] policy. A
+//! change applies to the members joining after it.
+//!
+//! [slow consumers]: crate::slow_consumers
+
+use axum::extract::{Path, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::Json;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::owners::{forbidden, is_owner};
+use crate::room_names::RoomName;
+use crate::slow_consumers::{Policy, SlowConsumers};
+use crate::{ApiResponse, AppState};
+
+/// The longest a message waits for slow members in a `block` room.
+pub const BLOCK_TIMEOUT: Duration = Duration::from_millis(500);
+const BLOCK_POLL: Duration = Duration::from_millis(5);
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Strategy {
+    DropOldest,
+    Block,
+    Disconnect,
+}
+
+/// The strategies rooms chose.
+#[derive(Default)]
+pub struct Backpressure {
+    rooms: Mutex<HashMap<String, Strategy>>,
+}
+
+impl Backpressure {
+    pub fn own(&self, room: &str) -> Option<Strategy> {
+        self.rooms.lock().unwrap().get(room).copied()
+    }
+
+    /// Sets the strategy of `room`, or goes back to the server's policy.
+    pub fn set_own(&self, room: &str, strategy: Option<Strategy>) {
+        let mut rooms = self.rooms.lock().unwrap();
+        match strategy {
+            Some(strategy) => rooms.insert(room.to_owned(), strategy),
+            None => rooms.remove(room),
+        };
+    }
+}
+
+/// How members of `room` that fall behind are treated.
+pub fn slow_consumers(state: &AppState, room: &str) -> SlowConsumers {
+    let server = state.slow_consumers;
+    let policy = match state.backpressure.own(room) {
+        None => server.policy,
+        Some(Strategy::DropOldest | Strategy::Block) => match server.policy {
+            Policy::Disconnect => Policy::Warn,
+            policy => policy,
+        },
+        Some(Strategy::Disconnect) => Policy::Disconnect,
+    };
+    SlowConsumers { policy, ..server }
+}
+
+/// Waits for the slowest member of `room` to catch up, if the room blocks.
+pub async fn wait(state: &AppState, room: &str) {
+    if state.backpressure.own(room) != Some(Strategy::Block) {
+        return;
+    }
+    let Some(tx) = state
+        .rooms
+        .lock()
+        .unwrap()
+        .get(room)
+        .map(|room| room.tx.clone())
+    else {
+        return;
+    };
+    let started = Instant::now();
+    let slow = state.slow_consumers.lag_within(tx.capacity());
+    while tx.lag() >= slow && started.elapsed() < BLOCK_TIMEOUT {
+        tokio::time::sleep(BLOCK_POLL).await;
+    }
+}
+
+/// `GET /rooms/:name/backpressure`
+pub async fn get_backpressure(
+    Path(room): Path<RoomName>,
+    State(state): State<Arc<AppState>>,
+) -> ApiResponse {
+    (
+        StatusCode::OK,
+        Json(json!({
+            "status": "Success!",
+            "strategy": state.backpressure.own(&room),
+        })),
+    )
+}
+
+#[derive(Deserialize)]
+pub struct SetBackpressure {
+    strategy: Strategy,
+}
+
+/// `PUT /rooms/:name/backpressure`
+pub async fn set_backpressure(
+    Path(room): Path<RoomName>,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(body): Json<SetBackpressure>,
+) -> ApiResponse {
+    if !is_owner(&state, &room, &headers) {
+        return forbidden();
+    }
+    state.backpressure.set_own(&room, Some(body.strategy));
+    (StatusCode::OK, Json(json!({ "status": "Success!" })))
+}
+
+/// `DELETE /rooms/:name/backpressure`, goes back to the server's policy.
+pub async fn reset_backpressure(
+    Path(room): Path<RoomName>,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> ApiResponse {
+    if !is_owner(&state, &room, &headers) {
+        return forbidden();
+    }
+    state.backpressure.set_own(&room, None);
+    (StatusCode::OK, Json(json!({ "status": "Success!" })))
+}
@@ -0,0 +1,115 @@
+//! Batches of room events for WebSocket clients that connect with
+//! `"batch": true`. Instead of a frame per event, such a client gets the
+//! events that are waiting for it at once, up to `BATCH_MAX_EVENTS` (50 by
+//! default), in one frame holding a JSON array of the frames they would have
+//! been. A lone event still comes as its own frame.
+//!
+//! By default only events already waiting are batched, which happens when the
+//! room talks faster than the connection sends. With `BATCH_WINDOW_MS`, a
+//! batch also waits that long after its first event for more.
+
+use std::time::Duration;
+use tokio::time::Instant;
+
+use crate::channels::Receiver;
+use crate::events::ChatEvent;
+use crate::slow_consumers::{Ended, Subscriber};
+
+const DEFAULT_MAX_EVENTS: usize = 50;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Batching {
+    /// How long a batch waits for more events after its first.
+    pub window: Duration,
+    pub max_events: usize,
+}
+
+impl Default for Batching {
+    fn default() -> Self {
+        Self {
+            window: Duration::ZERO,
+            max_events: DEFAULT_MAX_EVENTS,
+        }
+    }
+}
+
+impl Batching {
+    pub fn from_env() -> Self {
+        let var = |name| {
+            std::env::var(name)
+                .ok()
+                .and_then(|value| value.parse().ok())
+        };
+        let defaults = Self::default();
+        Self {
+            window: var("BATCH_WINDOW_MS").map_or(defaults.window, Duration::from_millis),
+            max_events: var("BATCH_MAX_EVENTS")
+                .map_or(defaults.max_events, |max: u64| (max as usize).max(1)),
+        }
+    }
+
+    /// Batches for a client, or one event at a time unless it asked for them.
+    pub fn batcher(&self, subscriber: Subscriber, enabled: bool) -> Batcher {
+        Batcher {
+            subscriber,
+            window: self.window,
+            max_events: if enabled { self.max_events } else { 1 },
+            batch: Vec::new(),
+            deadline: None,
+            next: None,
+        }
+    }
+}
+
+/// Whether `event` may share a frame. Those that end the connection come
+/// alone.
+fn batches(event: &ChatEvent) -> bool {
+    !matches!(event, ChatEvent::Archived | ChatEvent::Moved)
+}
+
+/// Gathers a subscriber's events into batches.
+pub struct Batcher {
+    pub subscriber: Subscriber,
+    window: Duration,
+    max_events: usize,
+    /// The batch so far, kept should `recv` be cancelled.
+    batch: Vec<ChatEvent>,
+    deadline: Option<Instant>,
+    /// What came after the batch, for the next one.
+    next: Option<Result<ChatEvent, Ended>>,
+}
+
+impl Batcher {
+    /// The next batch from `rx`, oldest first and never empty. Safe to
+    /// cancel, as in a `select!`.
+    pub async fn recv(&mut self, rx: &mut Receiver) -> Result<Vec<ChatEvent>, Ended> {
+        while self.batch.len() < self.max_events {
+            let next = match (self.next.take(), self.deadline) {
+                (Some(next), _) => next,
+                (None, None) => self.subscriber.recv(rx).await,
+                // Still polls for waiting events once the deadline passed.
+                (None, Some(deadline)) => {
+                    match tokio::time::timeout_at(deadline, self.subscriber.recv(rx)).await {
+                        Ok(next) => next,
+                        Err(_) => break,
+                    }
+                }
+            };
+            match next {
+                Ok(event) if batches(&event) => {
+                    self.batch.push(event);
+                    self.deadline
+                        .get_or_insert_with(|| Instant::now() + self.window);
+                }
+                Ok(event) if self.batch.is_empty() => return Ok(vec![event]),
+                Err(ended) if self.batch.is_empty() => return Err(ended),
+                next => {
+                    self.next = Some(next);
+                    break;
+                }
+            }
+        }
+        self.deadline = None;
+        Ok(std::mem::take(&mut self.batch))
+    }
+}
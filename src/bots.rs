@@ -0,0 +1,260 @@
+//! In-process bots that hook into a room's event stream.
+//!
+//! Implement [`Bot`] and hand it to [`register_bot`], either for a single room
+//! or for every room, instead of patching `handle_socket`.
+
+pub mod dice;
+pub mod helper;
+
+use async_trait::async_trait;
+use log::{debug, warn};
+use serde_json::{json, Value};
+use std::sync::Arc;
+use tokio::sync::broadcast;
+
+use crate::components::{self, Component, Interaction};
+use crate::events::{self, App, ChatEvent, RoomEvent};
+use crate::rate_limits::Sender;
+use crate::{tasks, AppState};
+
+/// Handle given to bot callbacks for talking back to the room.
+#[derive(Clone)]
+pub struct BotContext {
+    pub room: String,
+    bot_name: String,
+    avatar: Option<String>,
+    state: Arc<AppState>,
+}
+
+impl BotContext {
+    /// Broadcasts `text` to the whole room as the bot.
+    pub fn reply(&self, text: &str) {
+        self.reply_as(&self.bot_name, text);
+    }
+
+    /// Broadcasts `text` under another identity, for hosts speaking for
+    /// several bots such as the plugin runtime. Replies over the bot's [rate
+    /// limit](crate::rate_limits) are dropped.
+    pub fn reply_as(&self, from: &str, text: &str) {
+        self.post(from, text, Vec::new());
+    }
+
+    /// Broadcasts `text` with buttons or select menus as `from`, as
+    /// [`reply_as`](Self::reply_as) does, and returns the id of the message,
+    /// whose picks come back through [`Bot::on_interaction`]. Invalid
+    /// components post nothing.
+    pub fn reply_with_as(&self, from: &str, text: &str, components: Vec<Component>) -> Option<u64> {
+        if let Err(err) = components::validate(&components) {
+            warn!("Bot {} gave invalid components: {}", from, err);
+            return None;
+        }
+        self.post(from, text, components)
+    }
+
+    /// Replaces the text and components of message `id`, e.g. with no
+    /// components once a choice is made.
+    pub fn update(&self, id: u64, text: &str, components: Vec<Component>) -> bool {
+        if let Err(err) = components::validate(&components) {
+            warn!("Bot {} gave invalid components: {}", self.bot_name, err);
+            return false;
+        }
+        let rooms = self.state.rooms.lock().unwrap();
+        rooms
+            .get(&self.room)
+            .is_some_and(|room| room.update(id, text, components))
+    }
+
+    fn post(&self, from: &str, text: &str, components: Vec<Component>) -> Option<u64> {
+        if self.state.rate_limits.check(Sender::Bot(from)).is_err() {
+            debug!("Bot {} is over its rate limit in {}", from, self.room);
+            return None;
+        }
+        let app = App {
+            name: from.to_owned(),
+            avatar: self.avatar.clone().filter(|_| from == self.bot_name),
+        };
+        let rooms = self.state.rooms.lock().unwrap();
+        let room = rooms.get(&self.room)?;
+        Some(room.send_components(app, from, text, components))
+    }
+
+    /// Sends `text` to a single member of the room, returns false if they aren't connected.
+    pub fn dm(&self, username: &str, text: &str) -> bool {
+        self.dm_as(&self.bot_name, username, text)
+    }
+
+    /// Sends `text` to a single member under another identity, as
+    /// [`reply_as`](Self::reply_as) does.
+    pub fn dm_as(&self, from: &str, username: &str, text: &str) -> bool {
+        let rooms = self.state.rooms.lock().unwrap();
+        let Some(room) = rooms.get(&self.room) else {
+            return false;
+        };
+        let users = room.users.lock().unwrap();
+        match users.get(username) {
+            Some(direct) => direct.send(ChatEvent::direct(from, text)).is_ok(),
+            None => false,
+        }
+    }
+}
+
+#[async_trait]
+pub trait Bot: Send + Sync {
+    /// Identity the bot speaks as.
+    fn name(&self) -> &str;
+
+    /// URL of the image clients show beside the bot's messages.
+    fn avatar(&self) -> Option<&str> {
+        None
+    }
+
+    /// The commands the bot answers, as `/help` lists them, e.g.
+    /// `("roll [NdM]", "rolls dice")`.
+    fn commands(&self) -> &'static [(&'static str, &'static str)] {
+        &[]
+    }
+
+    async fn on_message(&self, _ctx: &BotContext, _from: &str, _text: &str) {}
+
+    async fn on_join(&self, _ctx: &BotContext, _username: &str) {}
+
+    /// Called when a member picks a component of a message, the bot's own
+    /// or another's, as `interaction.author` tells.
+    async fn on_interaction(&self, _ctx: &BotContext, _interaction: &Interaction) {}
+
+    /// Called for `/command args` messages. Return true if the command was
+    /// handled, unhandled commands are broadcast as regular messages.
+    async fn on_command(
+        &self,
+        _ctx: &BotContext,
+        _from: &str,
+        _command: &str,
+        _args: &str,
+    ) -> bool {
+        false
+    }
+}
+
+/// A registered bot, `room: None` listens to every room.
+pub struct Registration {
+    room: Option<String>,
+    bot: Arc<dyn Bot>,
+}
+
+pub fn register_bot(state: &AppState, room: Option<&str>, bot: impl Bot + 'static) {
+    state.bots.lock().unwrap().push(Registration {
+        room: room.map(str::to_owned),
+        bot: Arc::new(bot),
+    });
+}
+
+/// The bots and incoming webhooks posting to `room`, as rosters list them
+/// besides its members.
+pub fn roster(state: &AppState, room: &str) -> Vec<Value> {
+    let mut apps = state
+        .bots
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|registration| registration.room.as_deref().is_none_or(|r| r == room))
+        .map(|registration| App {
+            name: registration.bot.name().to_owned(),
+            avatar: registration.bot.avatar().map(str::to_owned),
+        })
+        .collect::<Vec<_>>();
+    apps.extend(
+        state
+            .webhooks
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|hook| hook.room == room)
+            .map(|hook| App {
+                name: hook.name.clone(),
+                avatar: hook.avatar.clone(),
+            }),
+    );
+    apps.sort_by(|a, b| a.name.cmp(&b.name));
+    apps.dedup_by(|a, b| a.name == b.name);
+    apps.into_iter()
+        .map(|app| json!({ "username": app.name, "is_bot": true, "app": app }))
+        .collect()
+}
+
+fn bots_for(state: &Arc<AppState>, room: &str) -> Vec<(BotContext, Arc<dyn Bot>)> {
+    state
+        .bots
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|registration| registration.room.as_deref().is_none_or(|r| r == room))
+        .map(|registration| {
+            let ctx = BotContext {
+                room: room.to_owned(),
+                bot_name: registration.bot.name().to_owned(),
+                avatar: registration.bot.avatar().map(str::to_owned),
+                state: state.clone(),
+            };
+            (ctx, registration.bot.clone())
+        })
+        .collect()
+}
+
+async fn dispatch_join(state: &Arc<AppState>, room: &str, username: &str) {
+    for (ctx, bot) in bots_for(state, room) {
+        bot.on_join(&ctx, username).await;
+    }
+}
+
+async fn dispatch_interaction(state: &Arc<AppState>, interaction: &Interaction) {
+    for (ctx, bot) in bots_for(state, &interaction.room) {
+        bot.on_interaction(&ctx, interaction).await;
+    }
+}
+
+async fn dispatch_message(state: &Arc<AppState>, room: &str, from: &str, text: &str) {
+    for (ctx, bot) in bots_for(state, room) {
+        bot.on_message(&ctx, from, text).await;
+    }
+}
+
+/// Runs the bots' `on_join` and `on_message` for events on the bus. Each
+/// event is handled in its own task so a slow bot does not hold up the rest.
+pub async fn subscriber(state: Arc<AppState>, mut events: broadcast::Receiver<RoomEvent>) {
+    while let Some(event) = events::next(&mut events, "Bots").await {
+        let state = state.clone();
+        tasks::spawn("bot-event", async move {
+            let exists = |room: &str| state.rooms.lock().unwrap().contains_key(room);
+            match &event {
+                RoomEvent::MessageSent { room, from, text } if exists(room) => {
+                    dispatch_message(&state, room, from, text).await;
+                }
+                RoomEvent::UserJoined { room, username } if exists(room) => {
+                    dispatch_join(&state, room, username).await;
+                }
+                RoomEvent::Interacted(interaction) if exists(&interaction.room) => {
+                    dispatch_interaction(&state, interaction).await;
+                }
+                _ => {}
+            }
+        });
+    }
+}
+
+/// Offers a `/command` to the room's bots, returns true once one handled it.
+pub async fn dispatch_command(state: &Arc<AppState>, room: &str, from: &str, text: &str) -> bool {
+    let Some(command) = text.strip_prefix('/') else {
+        return false;
+    };
+    let (command, args) = command.split_once(' ').unwrap_or((command, ""));
+    if command.is_empty() {
+        return false;
+    }
+
+    for (ctx, bot) in bots_for(state, room) {
+        if bot.on_command(&ctx, from, command, args.trim()).await {
+            return true;
+        }
+    }
+    false
+}
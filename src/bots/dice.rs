@@ -0,0 +1,72 @@
+use async_trait::async_trait;
+use rand::Rng;
+
+use super::{Bot, BotContext};
+
+/// Example bot answering `/roll [NdM]`, e.g. `/roll 2d6`.
+pub struct DiceBot;
+
+const MAX_DICE: u32 = 20;
+const MAX_SIDES: u32 = 1000;
+
+fn parse(args: &str) -> Option<(u32, u32)> {
+    if args.is_empty() {
+        return Some((1, 6));
+    }
+    let (count, sides) = args.split_once('d')?;
+    let count = if count.is_empty() {
+        1
+    } else {
+        count.parse().ok()?
+    };
+    let sides = sides.parse().ok()?;
+    if (1..=MAX_DICE).contains(&count) && (2..=MAX_SIDES).contains(&sides) {
+        Some((count, sides))
+    } else {
+        None
+    }
+}
+
+#[async_trait]
+impl Bot for DiceBot {
+    fn name(&self) -> &str {
+        "dicebot"
+    }
+
+    fn commands(&self) -> &'static [(&'static str, &'static str)] {
+        &[("roll [NdM]", "rolls dice, one six-sided die by default")]
+    }
+
+    async fn on_command(&self, ctx: &BotContext, from: &str, command: &str, args: &str) -> bool {
+        if command != "roll" {
+            return false;
+        }
+        match parse(args) {
+            Some((count, sides)) => {
+                let rolls = {
+                    let mut rng = rand::thread_rng();
+                    (0..count)
+                        .map(|_| rng.gen_range(1..=sides))
+                        .collect::<Vec<_>>()
+                };
+                let total: u32 = rolls.iter().sum();
+                let rolls = rolls
+                    .iter()
+                    .map(u32::to_string)
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                ctx.reply(&format!(
+                    "{} rolled {}d{}: {} (total {})",
+                    from, count, sides, rolls, total
+                ));
+            }
+            None => {
+                ctx.dm(
+                    from,
+                    &format!("Usage: /roll NdM, at most {}d{}", MAX_DICE, MAX_SIDES),
+                );
+            }
+        }
+        true
+    }
+}
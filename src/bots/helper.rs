@@ -0,0 +1,219 @@
+//! The helper bot, enabled with `HELPER_BOT=1`, which greets members as they
+//! join with the room's rules, and answers `/help` with the commands of the
+//! room's bots, `/rules` with the rules and the room's canned commands with
+//! their responses, e.g. `/faq`.
+//!
+//! Rooms without rules of their own get the server's, `HELPER_RULES`. Room
+//! owners set their room's rules and canned responses through
+//! `PUT /rooms/:name/helper`.
+
+use async_trait::async_trait;
+use axum::extract::{Path, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::Json;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex, Weak};
+
+use super::{bots_for, Bot, BotContext};
+use crate::owners::{forbidden, is_owner};
+use crate::room_names::RoomName;
+use crate::{ApiResponse, AppState};
+
+const MAX_LEN: usize = 2000;
+const MAX_RESPONSES: usize = 50;
+/// Commands of the bot itself, which responses cannot take.
+const RESERVED: [&str; 2] = ["help", "rules"];
+
+/// What the helper says in a room.
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct Config {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rules: Option<String>,
+    /// Canned responses by the command answered with them, without the `/`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub responses: BTreeMap<String, String>,
+}
+
+impl Config {
+    /// Why the config cannot be used, if it cannot.
+    pub fn invalid(&self) -> Option<String> {
+        let bad_len = |text: &str| text.trim().is_empty() || text.chars().count() > MAX_LEN;
+        if self.rules.as_deref().is_some_and(bad_len) {
+            return Some(format!("The rules are 1 to {} characters.", MAX_LEN));
+        }
+        if self.responses.len() > MAX_RESPONSES {
+            return Some(format!("At most {} responses.", MAX_RESPONSES));
+        }
+        for (command, response) in &self.responses {
+            let name = !command.is_empty()
+                && command
+                    .chars()
+                    .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-');
+            if !name || RESERVED.contains(&command.as_str()) {
+                return Some(format!("/{} cannot be a canned command.", command));
+            }
+            if bad_len(response) {
+                return Some(format!(
+                    "The response to /{} is 1 to {} characters.",
+                    command, MAX_LEN
+                ));
+            }
+        }
+        None
+    }
+}
+
+/// The server's rules and the rooms' configs.
+#[derive(Default)]
+pub struct Helper {
+    rules: Option<String>,
+    rooms: Mutex<HashMap<String, Config>>,
+}
+
+impl Helper {
+    pub fn new(rules: Option<String>) -> Self {
+        Self {
+            rules: rules.filter(|rules| !rules.trim().is_empty()),
+            rooms: Mutex::default(),
+        }
+    }
+
+    pub fn own(&self, room: &str) -> Option<Config> {
+        self.rooms.lock().unwrap().get(room).cloned()
+    }
+
+    /// Sets the config of `room`, or goes back to the server's rules.
+    pub fn set_own(&self, room: &str, config: Option<Config>) {
+        let mut rooms = self.rooms.lock().unwrap();
+        match config {
+            Some(config) => rooms.insert(room.to_owned(), config),
+            None => rooms.remove(room),
+        };
+    }
+
+    /// The rules of `room`, its own or else the server's.
+    fn rules(&self, room: &str) -> Option<String> {
+        let own = self.own(room).and_then(|config| config.rules);
+        own.or_else(|| self.rules.clone())
+    }
+}
+
+pub struct HelperBot {
+    pub state: Weak<AppState>,
+}
+
+#[async_trait]
+impl Bot for HelperBot {
+    fn name(&self) -> &str {
+        "helper"
+    }
+
+    fn commands(&self) -> &'static [(&'static str, &'static str)] {
+        &[
+            ("help", "lists the commands of the room"),
+            ("rules", "tells the rules of the room"),
+        ]
+    }
+
+    async fn on_join(&self, ctx: &BotContext, username: &str) {
+        let Some(state) = self.state.upgrade() else {
+            return;
+        };
+        let welcome = format!(
+            "Welcome to {}, {}! Type /help for the commands.",
+            ctx.room, username
+        );
+        match state.helper.rules(&ctx.room) {
+            Some(rules) => ctx.dm(username, &format!("{}\n\n{}", welcome, rules)),
+            None => ctx.dm(username, &welcome),
+        };
+    }
+
+    async fn on_command(&self, ctx: &BotContext, from: &str, command: &str, _args: &str) -> bool {
+        let Some(state) = self.state.upgrade() else {
+            return false;
+        };
+        match command {
+            "help" => {
+                let mut lines = vec!["Commands:".to_owned()];
+                for (_, bot) in bots_for(&state, &ctx.room) {
+                    for (usage, description) in bot.commands() {
+                        lines.push(format!("/{}: {}", usage, description));
+                    }
+                }
+                for (command, description) in state.slash_commands.list(&ctx.room) {
+                    match description {
+                        Some(description) => lines.push(format!("/{}: {}", command, description)),
+                        None => lines.push(format!("/{}", command)),
+                    }
+                }
+                let own = state.helper.own(&ctx.room).unwrap_or_default();
+                for command in own.responses.keys() {
+                    lines.push(format!("/{}", command));
+                }
+                ctx.dm(from, &lines.join("\n"));
+            }
+            "rules" => {
+                let rules = state.helper.rules(&ctx.room);
+                ctx.dm(from, rules.as_deref().unwrap_or("The room has no rules."));
+            }
+            command => {
+                let own = state.helper.own(&ctx.room).unwrap_or_default();
+                let Some(response) = own.responses.get(command) else {
+                    return false;
+                };
+                ctx.reply(response);
+            }
+        }
+        true
+    }
+}
+
+/// `GET /rooms/:name/helper`
+pub async fn get_helper(
+    Path(room): Path<RoomName>,
+    State(state): State<Arc<AppState>>,
+) -> ApiResponse {
+    let own = state.helper.own(&room);
+    (
+        StatusCode::OK,
+        Json(json!({
+            "status": "Success!",
+            "rules": state.helper.rules(&room),
+            "custom": own.is_some(),
+            "responses": own.map(|own| own.responses).unwrap_or_default(),
+        })),
+    )
+}
+
+/// `PUT /rooms/:name/helper`, the room's rules and canned responses.
+pub async fn set_helper(
+    Path(room): Path<RoomName>,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(config): Json<Config>,
+) -> ApiResponse {
+    if !is_owner(&state, &room, &headers) {
+        return forbidden();
+    }
+    if let Some(reason) = config.invalid() {
+        return (StatusCode::BAD_REQUEST, Json(json!({ "status": reason })));
+    }
+    state.helper.set_own(&room, Some(config));
+    (StatusCode::OK, Json(json!({ "status": "Success!" })))
+}
+
+/// `DELETE /rooms/:name/helper`, goes back to the server's rules.
+pub async fn reset_helper(
+    Path(room): Path<RoomName>,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> ApiResponse {
+    if !is_owner(&state, &room, &headers) {
+        return forbidden();
+    }
+    state.helper.set_own(&room, None);
+    (StatusCode::OK, Json(json!({ "status": "Success!" })))
+}
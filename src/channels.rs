@@ -0,0 +1,246 @@
+//! The channels rooms broadcast their events through, sized to the room.
+//!
+//! A member can fall as many events behind as their room's channel holds
+//! before skipping some. Rather than one capacity for every room, a channel
+//! holds two seconds' worth of the busiest ten seconds of the last minute,
+//! plus an event per subscriber, rounded up to a power of two within
+//! `CHANNEL_MIN_CAPACITY` and `CHANNEL_MAX_CAPACITY`, 16 and 1024 by default.
+//! Rarely used rooms stay tiny while busy ones get room for bursts.
+//!
+//! The capacity of a channel is fixed, so it is resized by recreating it,
+//! which waits for a quiet moment: every subscriber has received everything
+//! sent, and the room sends again. The subscribers then move to the new
+//! channel without missing an event. A channel shrinks only to a quarter of
+//! its capacity or less, so as not to be recreated back and forth.
+
+use log::debug;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::events::ChatEvent;
+
+pub const DEFAULT_MIN_CAPACITY: usize = 16;
+pub const DEFAULT_MAX_CAPACITY: usize = 1024;
+/// The largest capacity allowed at all, whatever is configured.
+const LIMIT: usize = 1 << 16;
+/// Events sent are counted per window of this long.
+const WINDOW: Duration = Duration::from_secs(10);
+/// The windows of recent throughput, a minute's.
+const WINDOWS: usize = 6;
+/// The stall a member of a room at its busiest can have without skipping.
+const STALL: Duration = Duration::from_secs(2);
+
+/// The capacities channels are sized within.
+#[derive(Clone, Copy, Debug)]
+pub struct Sizing {
+    pub min: usize,
+    pub max: usize,
+}
+
+impl Default for Sizing {
+    fn default() -> Self {
+        Self {
+            min: DEFAULT_MIN_CAPACITY,
+            max: DEFAULT_MAX_CAPACITY,
+        }
+    }
+}
+
+impl Sizing {
+    /// Rounds `min` and `max` up to powers of two, `max` being at least
+    /// `min`. The same for both makes the capacity fixed.
+    pub fn new(min: usize, max: usize) -> Self {
+        let min = min.clamp(1, LIMIT).next_power_of_two();
+        Self {
+            min,
+            max: max.clamp(min, LIMIT).next_power_of_two(),
+        }
+    }
+
+    pub fn from_env() -> Self {
+        let var = |name| {
+            std::env::var(name)
+                .ok()
+                .and_then(|value| value.parse().ok())
+        };
+        let defaults = Self::default();
+        Self::new(
+            var("CHANNEL_MIN_CAPACITY").unwrap_or(defaults.min),
+            var("CHANNEL_MAX_CAPACITY").unwrap_or(defaults.max),
+        )
+    }
+
+    /// The capacity for `events` sent in the busiest recent window to
+    /// `subscribers`.
+    fn capacity(&self, events: u64, subscribers: usize) -> usize {
+        let stalled = events * STALL.as_millis() as u64 / WINDOW.as_millis() as u64;
+        (stalled as usize + subscribers)
+            .next_power_of_two()
+            .clamp(self.min, self.max)
+    }
+}
+
+struct Inner {
+    tx: broadcast::Sender<ChatEvent>,
+    capacity: usize,
+    /// How many times the channel was recreated.
+    generation: u64,
+    /// Receivers subscribed as the channel was last recreated, one for each
+    /// receiver of the previous channel to move to.
+    successors: Vec<broadcast::Receiver<ChatEvent>>,
+    window_started: Instant,
+    /// The events sent in each recent window, the current one last.
+    sent: VecDeque<u64>,
+}
+
+/// A room's channel. Its receivers end once it is dropped.
+pub struct Channel {
+    room: String,
+    sizing: Sizing,
+    inner: Mutex<Inner>,
+}
+
+impl Channel {
+    pub fn new(room: &str, sizing: Sizing) -> Arc<Self> {
+        Arc::new(Self {
+            room: room.to_owned(),
+            sizing,
+            inner: Mutex::new(Inner {
+                tx: broadcast::channel(sizing.min).0,
+                capacity: sizing.min,
+                generation: 0,
+                successors: Vec::new(),
+                window_started: Instant::now(),
+                sent: VecDeque::from([0]),
+            }),
+        })
+    }
+
+    /// Sends `event` to every receiver, first resizing the channel if it is
+    /// quiet. Returns whether there was any.
+    pub fn send(&self, event: ChatEvent) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        let windows = (inner.window_started.elapsed().as_millis() / WINDOW.as_millis()) as u32;
+        for _ in 0..windows.min(WINDOWS as u32) {
+            inner.sent.push_back(0);
+            if inner.sent.len() > WINDOWS {
+                inner.sent.pop_front();
+            }
+        }
+        inner.window_started += WINDOW * windows;
+        *inner.sent.back_mut().unwrap() += 1;
+        self.resize(&mut inner);
+        inner.tx.send(event).is_ok()
+    }
+
+    /// Recreates the channel with the capacity it should have, if every
+    /// receiver has everything sent and has moved on from the last time.
+    fn resize(&self, inner: &mut Inner) {
+        if !inner.successors.is_empty() || !inner.tx.is_empty() {
+            return;
+        }
+        let subscribers = inner.tx.receiver_count();
+        let busiest = inner.sent.iter().copied().max().unwrap_or(0);
+        let capacity = self.sizing.capacity(busiest, subscribers);
+        if capacity == inner.capacity
+            || (capacity < inner.capacity && capacity * 4 > inner.capacity)
+        {
+            return;
+        }
+        debug!(
+            "Resizing the channel of {} from {} to {} events",
+            self.room, inner.capacity, capacity
+        );
+        let tx = broadcast::channel(capacity).0;
+        inner.successors = (0..subscribers).map(|_| tx.subscribe()).collect();
+        // Closes the previous channel, so that its receivers move.
+        inner.tx = tx;
+        inner.capacity = capacity;
+        inner.generation += 1;
+    }
+
+    pub fn subscribe(self: &Arc<Self>) -> Receiver {
+        let inner = self.inner.lock().unwrap();
+        Receiver {
+            rx: inner.tx.subscribe(),
+            channel: Arc::downgrade(self),
+            generation: inner.generation,
+            capacity: inner.capacity,
+        }
+    }
+
+    /// The events the furthest behind receiver has yet to receive.
+    pub fn lag(&self) -> usize {
+        self.inner.lock().unwrap().tx.len()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.inner.lock().unwrap().capacity
+    }
+}
+
+/// A receiver of a room's channel, which follows it when recreated.
+pub struct Receiver {
+    rx: broadcast::Receiver<ChatEvent>,
+    channel: Weak<Channel>,
+    generation: u64,
+    capacity: usize,
+}
+
+impl Receiver {
+    /// The next event, as [`broadcast::Receiver::recv`] receives it.
+    pub async fn recv(&mut self) -> Result<ChatEvent, RecvError> {
+        loop {
+            match self.rx.recv().await {
+                Err(RecvError::Closed) if self.follow() => {}
+                next => return next,
+            }
+        }
+    }
+
+    /// Moves to the channel that replaced the one closed, returning whether
+    /// there is one.
+    fn follow(&mut self) -> bool {
+        let Some(channel) = self.channel.upgrade() else {
+            return false;
+        };
+        let mut inner = channel.inner.lock().unwrap();
+        if inner.generation == self.generation {
+            return false;
+        }
+        // Not recreated again before every receiver moved, so this one has
+        // a successor waiting.
+        self.rx = inner
+            .successors
+            .pop()
+            .unwrap_or_else(|| inner.tx.subscribe());
+        self.generation = inner.generation;
+        self.capacity = inner.capacity;
+        true
+    }
+
+    /// The events waiting for this receiver.
+    pub fn lag(&self) -> usize {
+        self.rx.len()
+    }
+
+    /// The capacity of the channel, as of the last event received.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+}
+
+impl Drop for Receiver {
+    /// Gives up the successor waiting for this receiver, if any.
+    fn drop(&mut self) {
+        let Some(channel) = self.channel.upgrade() else {
+            return;
+        };
+        let mut inner = channel.inner.lock().unwrap();
+        if inner.generation != self.generation {
+            inner.successors.pop();
+        }
+    }
+}
@@ -0,0 +1,534 @@
+//! Cluster mode: the rooms are spread over several nodes by consistent
+//! hashing, each room living on one node only. A WebSocket client joining a
+//! room of another node is proxied to it over an internal connection, so no
+//! node sees the messages of rooms it does not own. HTTP requests for such a
+//! room, its incoming webhooks' included, are [redirected](route) there, and
+//! the other transports' joins refused with its URL.
+//!
+//! The nodes find each other and notice failures by [gossip](crate::gossip),
+//! starting from seeds: `CLUSTER_NODES`, comma-separated `name=url` pairs
+//! of some or all nodes, e.g. `a=http://10.0.0.1:3000,b=http://10.0.0.2:3000`.
+//! `CLUSTER_NODE` names this one and `CLUSTER_URL` is where the others reach
+//! it, if the seeds do not say. `CLUSTER_SECRET` is what nodes present to each
+//! other.
+//!
+//! The session frame of a WebSocket client carries an `affinity` token,
+//! standing for the node owning its room, which load balancers and clients
+//! resolve to the node's URL with `GET /cluster/affinity/:token`, so that
+//! reconnects land on that node.
+
+use axum::extract::ws::{CloseFrame, Message, WebSocket};
+use axum::extract::{OriginalUri, Path, Request, State};
+use axum::http::header::LOCATION;
+use axum::http::{HeaderMap, StatusCode, Uri};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use futures::stream::{SplitSink, SplitStream};
+use futures::{SinkExt, StreamExt};
+use hmac::{Hmac, Mac};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use subtle::ConstantTimeEq;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::http::HeaderValue;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::CloseFrame as ProxiedCloseFrame;
+use tokio_tungstenite::tungstenite::Message as Proxied;
+
+use crate::connections::Tracked;
+use crate::owners::bearer;
+use crate::room_names::RoomName;
+use crate::{ApiResponse, AppState};
+
+/// Points of each node on the ring, which even out their shares of rooms.
+const POINTS_PER_NODE: u32 = 128;
+/// The address of the client an internal connection is for.
+const CLIENT_IP: &str = "x-cluster-client-ip";
+/// The id of the client's connection, which the internal one keeps.
+pub const CONNECTION_ID: &str = "x-cluster-connection-id";
+/// How long a node whose heartbeat stays the same is still taken for alive.
+const FAIL_AFTER: Duration = Duration::from_secs(5);
+/// How long a failed node is remembered, so that it is not gossiped back.
+const FORGET_AFTER: Duration = Duration::from_secs(60);
+
+#[derive(Clone)]
+pub struct ClusterConfig {
+    /// The name of this node.
+    pub node: String,
+    /// The base URL of this node, if not among the seeds.
+    pub url: Option<String>,
+    /// The name and base URL of some nodes, e.g. `http://10.0.0.1:3000`.
+    pub nodes: Vec<(String, String)>,
+    pub secret: String,
+}
+
+impl ClusterConfig {
+    pub fn from_env() -> Option<Self> {
+        let var = |name: &str| std::env::var(name).ok().filter(|val| !val.is_empty());
+        let nodes = var("CLUSTER_NODES")?
+            .split(',')
+            .filter_map(|node| {
+                let (name, url) = node.trim().split_once('=')?;
+                Some((name.trim().to_owned(), url.trim().to_owned()))
+            })
+            .collect();
+        let (Some(node), Some(secret)) = (var("CLUSTER_NODE"), var("CLUSTER_SECRET")) else {
+            error!("Cluster mode needs CLUSTER_NODE and CLUSTER_SECRET");
+            return None;
+        };
+        Some(Self {
+            node,
+            url: var("CLUSTER_URL"),
+            nodes,
+            secret,
+        })
+    }
+}
+
+/// What a node knows of another, as gossiped.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Entry {
+    pub url: String,
+    /// Counts up while the node is alive.
+    pub heartbeat: u64,
+}
+
+struct Member {
+    entry: Entry,
+    /// When the heartbeat last went up.
+    seen: Instant,
+    /// Whether it is one of the seeds, which are never forgotten.
+    seed: bool,
+}
+
+struct Members {
+    table: HashMap<String, Member>,
+    /// The nodes taken for alive, this one included.
+    alive: BTreeSet<String>,
+    /// Each point on the ring and the node it belongs to.
+    ring: BTreeMap<u64, String>,
+}
+
+impl Members {
+    fn rebuild(&mut self) {
+        self.ring = self
+            .alive
+            .iter()
+            .flat_map(|name| {
+                (0..POINTS_PER_NODE)
+                    .map(move |point| (hash(&format!("{}#{}", name, point)), name.clone()))
+            })
+            .collect();
+    }
+}
+
+pub struct Cluster {
+    node: String,
+    secret: String,
+    members: Mutex<Members>,
+}
+
+fn hash(key: &str) -> u64 {
+    let digest = Sha256::digest(key.as_bytes());
+    u64::from_be_bytes(digest[..8].try_into().unwrap())
+}
+
+impl Cluster {
+    /// Starts out with this node alone alive, the seeds to be confirmed by
+    /// gossip.
+    ///
+    /// # Panics
+    ///
+    /// If the URL of this node is unknown.
+    pub fn new(config: ClusterConfig) -> Self {
+        let now = Instant::now();
+        let url = config.url.or_else(|| {
+            config
+                .nodes
+                .iter()
+                .find(|(name, _)| *name == config.node)
+                .map(|(_, url)| url.clone())
+        });
+        let Some(url) = url else {
+            panic!("Neither CLUSTER_NODES nor CLUSTER_URL has the URL of this node");
+        };
+        let seeds = config.nodes.into_iter().chain([(config.node.clone(), url)]);
+        let table = seeds
+            .map(|(name, url)| {
+                let entry = Entry {
+                    url: url.trim_end_matches('/').to_owned(),
+                    heartbeat: 0,
+                };
+                let member = Member {
+                    entry,
+                    seen: now,
+                    seed: true,
+                };
+                (name, member)
+            })
+            .collect();
+        let mut members = Members {
+            table,
+            alive: BTreeSet::from([config.node.clone()]),
+            ring: BTreeMap::new(),
+        };
+        members.rebuild();
+        Self {
+            node: config.node,
+            secret: config.secret,
+            members: Mutex::new(members),
+        }
+    }
+
+    pub fn secret(&self) -> &str {
+        &self.secret
+    }
+
+    /// Whether the request is from a node, presenting the secret.
+    pub fn is_node(&self, headers: &HeaderMap) -> bool {
+        bearer(headers)
+            .is_some_and(|token| bool::from(token.as_bytes().ct_eq(self.secret.as_bytes())))
+    }
+
+    /// The affinity token of `node`, which only the nodes can tell apart
+    /// from any other, keyed as it is with the secret.
+    fn affinity_of(&self, node: &str) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(self.secret.as_bytes())
+            .expect("HMAC accepts keys of any length");
+        mac.update(b"affinity:");
+        mac.update(node.as_bytes());
+        hex::encode(&mac.finalize().into_bytes()[..16])
+    }
+
+    /// The affinity token of this node, for the clients of its rooms.
+    pub fn affinity(&self) -> String {
+        self.affinity_of(&self.node)
+    }
+
+    /// The name and base URL of the node `token` stands for, if alive.
+    pub fn resolve(&self, token: &str) -> Option<(String, String)> {
+        let members = self.members.lock().unwrap();
+        let name = members
+            .alive
+            .iter()
+            .find(|name| bool::from(self.affinity_of(name).as_bytes().ct_eq(token.as_bytes())))?;
+        let url = members.table.get(name)?.entry.url.clone();
+        Some((name.clone(), url))
+    }
+
+    /// The name and base URL of the node owning `room`, unless it is this
+    /// one. Rooms are told apart by `base_path`, e.g. across tenants.
+    pub fn owner(&self, base_path: &str, room: &str) -> Option<(String, String)> {
+        let point = hash(&format!("{}/{}", base_path, room));
+        let members = self.members.lock().unwrap();
+        let (_, name) = members
+            .ring
+            .range(point..)
+            .next()
+            .or_else(|| members.ring.iter().next())?;
+        if *name == self.node {
+            return None;
+        }
+        let url = members.table.get(name)?.entry.url.clone();
+        Some((name.clone(), url))
+    }
+
+    /// Everything this node knows, its heartbeat counted up first.
+    pub fn beat(&self) -> HashMap<String, Entry> {
+        let mut members = self.members.lock().unwrap();
+        if let Some(member) = members.table.get_mut(&self.node) {
+            member.entry.heartbeat += 1;
+            member.seen = Instant::now();
+        }
+        members
+            .table
+            .iter()
+            .map(|(name, member)| (name.clone(), member.entry.clone()))
+            .collect()
+    }
+
+    /// Everything this node knows.
+    pub fn digest(&self) -> HashMap<String, Entry> {
+        let members = self.members.lock().unwrap();
+        members
+            .table
+            .iter()
+            .map(|(name, member)| (name.clone(), member.entry.clone()))
+            .collect()
+    }
+
+    /// Learns of newer heartbeats from another node's `digest`.
+    pub fn merge(&self, digest: HashMap<String, Entry>) {
+        let now = Instant::now();
+        let mut members = self.members.lock().unwrap();
+        for (name, entry) in digest {
+            if name == self.node {
+                continue;
+            }
+            match members.table.get_mut(&name) {
+                Some(member) if entry.heartbeat > member.entry.heartbeat => {
+                    member.entry = entry;
+                    member.seen = now;
+                }
+                Some(_) => {}
+                None => {
+                    let member = Member {
+                        entry,
+                        seen: now,
+                        seed: false,
+                    };
+                    members.table.insert(name, member);
+                }
+            }
+        }
+    }
+
+    /// Takes the nodes whose heartbeats went up lately for alive, forgetting
+    /// the other nodes after a while.
+    pub fn sweep(&self) {
+        let now = Instant::now();
+        let mut members = self.members.lock().unwrap();
+        let node = &self.node;
+        members.table.retain(|name, member| {
+            name == node || member.seed || now.duration_since(member.seen) < FORGET_AFTER
+        });
+        let alive = members
+            .table
+            .iter()
+            .filter(|(name, member)| {
+                // A seed only counts once it has been heard of.
+                *name == node
+                    || (member.entry.heartbeat > 0 && now.duration_since(member.seen) < FAIL_AFTER)
+            })
+            .map(|(name, _)| name.clone())
+            .collect::<BTreeSet<_>>();
+        if alive == members.alive {
+            return;
+        }
+        info!(
+            "Cluster nodes alive: {}",
+            alive.iter().cloned().collect::<Vec<_>>().join(", ")
+        );
+        members.alive = alive;
+        members.rebuild();
+    }
+
+    /// The base URLs of up to `count` other nodes, picked at random.
+    pub fn peers(&self, count: usize) -> Vec<String> {
+        use rand::seq::IteratorRandom;
+        let members = self.members.lock().unwrap();
+        members
+            .table
+            .iter()
+            .filter(|(name, _)| **name != self.node)
+            .map(|(_, member)| member.entry.url.clone())
+            .choose_multiple(&mut rand::thread_rng(), count)
+    }
+
+    /// The address of the client, if the request is another node's proxied
+    /// connection for it, which is never proxied again.
+    pub fn internal(&self, headers: &HeaderMap) -> Option<Option<IpAddr>> {
+        self.is_node(headers).then(|| {
+            headers
+                .get(CLIENT_IP)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|ip| ip.parse().ok())
+        })
+    }
+}
+
+/// The name and base URL of the node owning `room`, in cluster mode and
+/// unless it is this one.
+pub fn elsewhere(state: &AppState, room: &str) -> Option<(String, String)> {
+    state.cluster.as_ref()?.owner(&state.base_path, room)
+}
+
+/// A `307` to `uri` on the node at `url`, which serves the room.
+pub fn redirect(url: &str, uri: &Uri) -> Response {
+    let path = uri.path_and_query().map_or("/", |path| path.as_str());
+    let location = format!("{}{}", url, path);
+    (
+        StatusCode::TEMPORARY_REDIRECT,
+        [(LOCATION, location)],
+        Json(json!({ "status": "This room is served by another node.", "url": url })),
+    )
+        .into_response()
+}
+
+/// Redirects requests for a room of another node to the same URL there,
+/// layered on the routes of rooms, `/rooms/:name/...`, and of incoming
+/// webhooks, `/hooks/:token/...`, which post into their room.
+pub async fn route(
+    State(state): State<Arc<AppState>>,
+    OriginalUri(uri): OriginalUri,
+    params: Option<Path<HashMap<String, String>>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if state.cluster.is_none() {
+        return next.run(request).await;
+    }
+    let param = |name: &str| params.as_ref().and_then(|Path(params)| params.get(name));
+    let path = request.uri().path();
+    let room = if path.starts_with("/rooms/") {
+        param("name")
+            .and_then(|name| RoomName::new(name).ok())
+            .map(String::from)
+    } else if path.starts_with("/hooks/") {
+        param("token").and_then(|token| {
+            let webhooks = state.webhooks.lock().unwrap();
+            webhooks.get(token).map(|hook| hook.room.clone())
+        })
+    } else {
+        None
+    };
+    match room.and_then(|room| elsewhere(&state, &room)) {
+        Some((_, url)) => redirect(&url, &uri),
+        None => next.run(request).await,
+    }
+}
+
+/// `GET /cluster/affinity/:token`
+pub async fn resolve_affinity(
+    Path(token): Path<String>,
+    State(state): State<Arc<AppState>>,
+) -> ApiResponse {
+    let node = state
+        .cluster
+        .as_ref()
+        .and_then(|cluster| cluster.resolve(&token));
+    match node {
+        Some((node, url)) => (
+            StatusCode::OK,
+            Json(json!({ "status": "Success!", "node": node, "url": url })),
+        ),
+        None => (
+            StatusCode::NOT_FOUND,
+            Json(json!({ "status": "No node alive has that affinity token." })),
+        ),
+    }
+}
+
+fn to_proxied(message: Message) -> Proxied {
+    match message {
+        Message::Text(text) => Proxied::Text(text),
+        Message::Binary(bytes) => Proxied::Binary(bytes),
+        Message::Ping(bytes) => Proxied::Ping(bytes),
+        Message::Pong(bytes) => Proxied::Pong(bytes),
+        Message::Close(frame) => Proxied::Close(frame.map(|frame| ProxiedCloseFrame {
+            code: CloseCode::from(frame.code),
+            reason: frame.reason,
+        })),
+    }
+}
+
+fn from_proxied(message: Proxied) -> Option<Message> {
+    Some(match message {
+        Proxied::Text(text) => Message::Text(text),
+        Proxied::Binary(bytes) => Message::Binary(bytes),
+        Proxied::Ping(bytes) => Message::Ping(bytes),
+        Proxied::Pong(bytes) => Message::Pong(bytes),
+        Proxied::Close(frame) => Message::Close(frame.map(|frame| CloseFrame {
+            code: frame.code.into(),
+            reason: frame.reason,
+        })),
+        Proxied::Frame(_) => return None,
+    })
+}
+
+/// Hands the client's `connection` over to `owner`, the name and URL of the
+/// node owning the room, replaying the connect `payload`, and relays frames
+/// both ways until either side closes.
+pub async fn proxy(
+    state: &AppState,
+    (node, url): (String, String),
+    (mut sender, mut receiver): (SplitSink<WebSocket, Message>, SplitStream<WebSocket>),
+    payload: String,
+    locale: &str,
+    ip: Option<IpAddr>,
+    connection: &Tracked,
+) {
+    let id = &connection.id;
+    let Some(cluster) = &state.cluster else {
+        return;
+    };
+    let url = match url.split_once("://") {
+        Some(("https", rest)) => format!("wss://{}", rest),
+        Some((_, rest)) => format!("ws://{}", rest),
+        None => format!("ws://{}", url),
+    };
+    let mut request = match format!("{}{}/ws", url, state.base_path).into_client_request() {
+        Ok(request) => request,
+        Err(err) => {
+            error!("[{}] Invalid URL of node {}: {}", id, node, err);
+            let _ = sender.send(Message::Close(None)).await;
+            return;
+        }
+    };
+    let headers = request.headers_mut();
+    if let Ok(value) = HeaderValue::from_str(&format!("Bearer {}", cluster.secret)) {
+        headers.insert("authorization", value);
+    }
+    if let Ok(value) = HeaderValue::from_str(locale) {
+        headers.insert("accept-language", value);
+    }
+    if let Some(ip) = ip.and_then(|ip| HeaderValue::from_str(&ip.to_string()).ok()) {
+        headers.insert(CLIENT_IP, ip);
+    }
+    if let Ok(value) = HeaderValue::from_str(id) {
+        headers.insert(CONNECTION_ID, value);
+    }
+    let upstream = match tokio_tungstenite::connect_async(request).await {
+        Ok((upstream, _)) => upstream,
+        Err(err) => {
+            error!("[{}] Failed to reach node {}: {}", id, node, err);
+            let _ = sender
+                .send(Message::Close(Some(CloseFrame {
+                    code: axum::extract::ws::close_code::AGAIN,
+                    reason: "Room unavailable.".into(),
+                })))
+                .await;
+            return;
+        }
+    };
+    info!("[{}] Proxying the connection to node {}", id, node);
+    let (mut upstream_sender, mut upstream_receiver) = upstream.split();
+    if upstream_sender.send(Proxied::Text(payload)).await.is_err() {
+        return;
+    }
+    let to_node = async {
+        while let Some(Ok(message)) = receiver.next().await {
+            connection.received();
+            let close = matches!(message, Message::Close(_));
+            if upstream_sender.send(to_proxied(message)).await.is_err() || close {
+                break;
+            }
+        }
+        let _ = upstream_sender.close().await;
+    };
+    let to_client = async {
+        while let Some(Ok(message)) = upstream_receiver.next().await {
+            let Some(message) = from_proxied(message) else {
+                continue;
+            };
+            let close = matches!(message, Message::Close(_));
+            if sender.send(message).await.is_err() || close {
+                break;
+            }
+            connection.sent();
+        }
+        let _ = sender.close().await;
+    };
+    // Whichever side goes first ends the other.
+    tokio::select! {
+        _ = to_node => {}
+        _ = to_client => {}
+    }
+}
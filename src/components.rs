@@ -0,0 +1,201 @@
+//! Buttons and select menus that bots and incoming webhooks attach to their
+//! messages, for approval flows and quick polls without parsing free text.
+//!
+//! A member picks one with an `interact` frame naming the message and the
+//! component. The pick is published as an [`Interaction`], which the room's
+//! bots receive through [`Bot::on_interaction`](crate::bots::Bot) and
+//! outgoing webhooks subscribed to `interaction` as a delivery.
+
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+use crate::events::RoomEvent;
+use crate::{rooms, AppState};
+
+/// Components of a message at most.
+const MAX_COMPONENTS: usize = 5;
+const MAX_OPTIONS: usize = 25;
+const MAX_ID_LEN: usize = 64;
+const MAX_LABEL_LEN: usize = 80;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Style {
+    #[default]
+    Default,
+    Primary,
+    Danger,
+}
+
+impl Style {
+    fn is_default(&self) -> bool {
+        *self == Style::Default
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct SelectOption {
+    pub value: String,
+    pub label: String,
+}
+
+/// A component of a message, `id` telling it apart from the others of the
+/// message.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Component {
+    Button {
+        id: String,
+        label: String,
+        #[serde(default, skip_serializing_if = "Style::is_default")]
+        style: Style,
+    },
+    Select {
+        id: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        placeholder: Option<String>,
+        options: Vec<SelectOption>,
+    },
+}
+
+impl Component {
+    pub fn id(&self) -> &str {
+        match self {
+            Component::Button { id, .. } | Component::Select { id, .. } => id,
+        }
+    }
+}
+
+/// Checks components given by a bot or webhook, or says what is wrong with
+/// them.
+pub fn validate(components: &[Component]) -> Result<(), String> {
+    if components.len() > MAX_COMPONENTS {
+        return Err(format!("At most {} components.", MAX_COMPONENTS));
+    }
+    let label = |label: &str| (1..=MAX_LABEL_LEN).contains(&label.chars().count());
+    for (i, component) in components.iter().enumerate() {
+        let id = component.id();
+        if id.is_empty() || id.len() > MAX_ID_LEN || id.contains(char::is_whitespace) {
+            return Err(format!(
+                "Component ids are 1 to {} characters without spaces.",
+                MAX_ID_LEN
+            ));
+        }
+        if components[..i].iter().any(|other| other.id() == id) {
+            return Err(format!("Component id {} is not unique.", id));
+        }
+        match component {
+            Component::Button { label: text, .. } if !label(text) => {
+                return Err(format!("Labels are 1 to {} characters.", MAX_LABEL_LEN));
+            }
+            Component::Select { options, .. }
+                if options.is_empty() || options.len() > MAX_OPTIONS =>
+            {
+                return Err(format!("Select menus have 1 to {} options.", MAX_OPTIONS));
+            }
+            Component::Select { options, .. }
+                if options
+                    .iter()
+                    .any(|option| option.value.is_empty() || !label(&option.label)) =>
+            {
+                return Err(format!(
+                    "Options have a value and a label of 1 to {} characters.",
+                    MAX_LABEL_LEN
+                ));
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Appends `components` of message `id` to its plain text frame, e.g.
+/// `[7 approve: Approve]` and `[7 env: staging | prod]`, which is what an
+/// `interact` frame names.
+pub fn render(id: u64, components: &[Component]) -> String {
+    let mut text = String::new();
+    for component in components {
+        match component {
+            Component::Button {
+                id: name, label, ..
+            } => text.push_str(&format!(" [{} {}: {}]", id, name, label)),
+            Component::Select {
+                id: name, options, ..
+            } => {
+                let values = options
+                    .iter()
+                    .map(|option| option.value.as_str())
+                    .collect::<Vec<_>>();
+                text.push_str(&format!(" [{} {}: {}]", id, name, values.join(" | ")))
+            }
+        }
+    }
+    text
+}
+
+/// A member's pick of a component of message `message` by `author`.
+#[derive(Clone, Debug, Serialize)]
+pub struct Interaction {
+    pub room: String,
+    pub message: u64,
+    pub author: String,
+    pub username: String,
+    pub component: String,
+    /// The option picked in a select menu.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value: Option<String>,
+}
+
+/// Handles `username` picking `component` of `message`, with the `value` of
+/// the option for select menus. Picks of components the message does not
+/// have, or of options the menu does not offer, are ignored.
+pub fn interact(
+    state: &Arc<AppState>,
+    room: &str,
+    username: &str,
+    message: u64,
+    component: &str,
+    value: Option<String>,
+) {
+    if rooms::limited(state, room, username) {
+        return;
+    }
+    let (author, value) = {
+        let rooms = state.rooms.lock().unwrap();
+        let Some(room_state) = rooms.get(room) else {
+            return;
+        };
+        let history = room_state.history.lock().unwrap();
+        let Some(stored) = history.iter().find(|stored| stored.id == message) else {
+            return;
+        };
+        let value = match stored
+            .components
+            .iter()
+            .find(|found| found.id() == component)
+        {
+            Some(Component::Button { .. }) => None,
+            Some(Component::Select { options, .. }) => {
+                let Some(value) =
+                    value.filter(|value| options.iter().any(|option| &option.value == value))
+                else {
+                    return;
+                };
+                Some(value)
+            }
+            None => return,
+        };
+        (stored.from.clone(), value)
+    };
+    rooms::publish(
+        state,
+        RoomEvent::Interacted(Interaction {
+            room: room.to_owned(),
+            message,
+            author,
+            username: username.to_owned(),
+            component: component.to_owned(),
+            value,
+        }),
+    );
+}
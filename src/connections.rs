@@ -0,0 +1,191 @@
+//! Every connection of each member, whichever the transport, for events
+//! addressed to the member rather than to one room.
+//!
+//! A member has at most one connection per room, so a connection is known
+//! by its room.
+//!
+//! WebSocket connections are also tracked from the upgrade on by an id of
+//! their own, which their log lines and error frames carry, and which
+//! `GET /admin/connections/:id` tells the state of.
+
+use axum::extract::{Path, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::Json;
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use serde::Serialize;
+use serde_json::json;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+
+use crate::events::{unix_timestamp, ChatEvent};
+use crate::owners::{admin_forbidden, is_admin};
+use crate::{ApiResponse, AppState};
+
+type Direct = mpsc::UnboundedSender<ChatEvent>;
+type Live = Arc<Mutex<HashMap<String, Arc<Mutex<Status>>>>>;
+
+#[derive(Default)]
+pub struct Connections {
+    users: Mutex<HashMap<String, Vec<(String, Direct)>>>,
+    /// The state of every WebSocket connection, by id.
+    live: Live,
+}
+
+/// What a WebSocket connection is up to.
+#[derive(Clone, Serialize)]
+pub struct Status {
+    pub ip: Option<IpAddr>,
+    /// When the connection was upgraded, in Unix seconds.
+    pub opened_at: u64,
+    pub locale: String,
+    /// Set once the connection joined.
+    pub username: Option<String>,
+    pub room: Option<String>,
+    /// The cluster node the connection is relayed to, if another.
+    pub node: Option<String>,
+    pub frames_received: u64,
+    pub frames_sent: u64,
+    /// In Unix seconds.
+    pub last_received_at: Option<u64>,
+    pub last_sent_at: Option<u64>,
+    /// The room's events waiting for the connection, and those it skipped
+    /// for falling too far behind, as of the last it received.
+    pub lag: usize,
+    pub skipped: u64,
+}
+
+/// A WebSocket connection, tracked until dropped.
+pub struct Tracked {
+    pub id: String,
+    status: Arc<Mutex<Status>>,
+    live: Live,
+}
+
+impl Tracked {
+    pub fn update(&self, update: impl FnOnce(&mut Status)) {
+        update(&mut self.status.lock().unwrap());
+    }
+
+    /// Counts a frame from the client.
+    pub fn received(&self) {
+        self.update(|status| {
+            status.frames_received += 1;
+            status.last_received_at = Some(unix_timestamp());
+        });
+    }
+
+    /// Counts a frame to the client.
+    pub fn sent(&self) {
+        self.update(|status| {
+            status.frames_sent += 1;
+            status.last_sent_at = Some(unix_timestamp());
+        });
+    }
+}
+
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        self.live.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// A new connection id, unless another node relaying the connection
+/// already gave it one.
+pub fn generate_id() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(16)
+        .map(char::from)
+        .collect()
+}
+
+impl Connections {
+    /// Tracks the WebSocket connection `id` from `ip`.
+    pub fn track(&self, id: String, ip: Option<IpAddr>, locale: &str) -> Tracked {
+        let status = Arc::new(Mutex::new(Status {
+            ip,
+            opened_at: unix_timestamp(),
+            locale: locale.to_owned(),
+            username: None,
+            room: None,
+            node: None,
+            frames_received: 0,
+            frames_sent: 0,
+            last_received_at: None,
+            last_sent_at: None,
+            lag: 0,
+            skipped: 0,
+        }));
+        self.live.lock().unwrap().insert(id.clone(), status.clone());
+        Tracked {
+            id,
+            status,
+            live: self.live.clone(),
+        }
+    }
+
+    pub fn add(&self, username: &str, room: &str, direct: Direct) {
+        let mut users = self.users.lock().unwrap();
+        users
+            .entry(username.to_owned())
+            .or_default()
+            .push((room.to_owned(), direct));
+    }
+
+    pub fn remove(&self, username: &str, room: &str) {
+        let mut users = self.users.lock().unwrap();
+        if let Some(connections) = users.get_mut(username) {
+            connections.retain(|(of_room, _)| of_room != room);
+            if connections.is_empty() {
+                users.remove(username);
+            }
+        }
+    }
+
+    pub fn count(&self, username: &str) -> usize {
+        let users = self.users.lock().unwrap();
+        users.get(username).map_or(0, Vec::len)
+    }
+
+    /// Sends `event` to every connection of `username` but the one in
+    /// `except`, returning how many it reached.
+    pub fn send(&self, username: &str, event: &ChatEvent, except: Option<&str>) -> usize {
+        let users = self.users.lock().unwrap();
+        let Some(connections) = users.get(username) else {
+            return 0;
+        };
+        connections
+            .iter()
+            .filter(|(room, _)| Some(room.as_str()) != except)
+            .filter(|(_, direct)| direct.send(event.clone()).is_ok())
+            .count()
+    }
+}
+
+/// `GET /admin/connections/:id`
+pub async fn get_connection(
+    Path(id): Path<String>,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> ApiResponse {
+    if !is_admin(&state, &headers) {
+        return admin_forbidden();
+    }
+    let status = state.connections.live.lock().unwrap().get(&id).cloned();
+    match status {
+        Some(status) => {
+            let status = status.lock().unwrap().clone();
+            (
+                StatusCode::OK,
+                Json(json!({ "status": "Success!", "id": id, "connection": status })),
+            )
+        }
+        None => (
+            StatusCode::NOT_FOUND,
+            Json(json!({ "status": "Connection not found." })),
+        ),
+    }
+}
@@ -0,0 +1,109 @@
+//! Rooms that exist from startup on and stay when their last member leaves,
+//! such as a lobby, and the welcome payload of `GET /directory`.
+//!
+//! `DEFAULT_ROOMS` declares them as comma-separated names, each optionally
+//! followed by `=` and a topic, e.g. `lobby=Say hi,help=Ask anything`. They
+//! are the directory's featured rooms.
+
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::Json;
+use log::warn;
+use serde::Serialize;
+use serde_json::json;
+use std::sync::Arc;
+
+use crate::room_names::RoomName;
+use crate::{directory, rooms, ApiResponse, AppState};
+
+/// Trending rooms in the directory payload.
+const DIRECTORY_TRENDING: usize = 5;
+
+#[derive(Clone, Debug, Serialize)]
+pub struct DefaultRoom {
+    pub name: RoomName,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub topic: Option<String>,
+}
+
+impl DefaultRoom {
+    /// `None`, with a warning, for an invalid name.
+    pub fn new(name: &str, topic: Option<&str>) -> Option<Self> {
+        match RoomName::new(name) {
+            Ok(name) => Some(Self {
+                name,
+                topic: topic
+                    .map(str::trim)
+                    .filter(|topic| !topic.is_empty())
+                    .map(str::to_owned),
+            }),
+            Err(err) => {
+                warn!("Skipping default room {:?}: {}", name, err);
+                None
+            }
+        }
+    }
+}
+
+/// Reads `DEFAULT_ROOMS`.
+pub fn from_env() -> Vec<DefaultRoom> {
+    let Ok(value) = std::env::var("DEFAULT_ROOMS") else {
+        return Vec::new();
+    };
+    value
+        .split(',')
+        .filter(|room| !room.trim().is_empty())
+        .filter_map(|room| match room.split_once('=') {
+            Some((name, topic)) => DefaultRoom::new(name, Some(topic)),
+            None => DefaultRoom::new(room, None),
+        })
+        .collect()
+}
+
+pub fn is_default(state: &AppState, room: &str) -> bool {
+    state
+        .default_rooms
+        .iter()
+        .any(|default| default.name.as_str() == room)
+}
+
+/// The topic of `room`, if it is a default room with one.
+pub fn topic(state: &AppState, room: &str) -> Option<String> {
+    state
+        .default_rooms
+        .iter()
+        .find(|default| default.name.as_str() == room)
+        .and_then(|default| default.topic.clone())
+}
+
+/// Creates the default rooms with their stored history and tags.
+pub async fn create(state: Arc<AppState>) {
+    for room in &state.default_rooms {
+        let _ = rooms::with_room(&state, &room.name, None, |_| ()).await;
+    }
+}
+
+/// `GET /directory`, what a client's home screen shows: the server's message
+/// of the day, the featured rooms and the trending ones.
+pub async fn get_directory(State(state): State<Arc<AppState>>) -> ApiResponse {
+    let position = |room: &str| {
+        state
+            .default_rooms
+            .iter()
+            .position(|default| default.name.as_str() == room)
+    };
+    let mut featured = directory::summaries(&state)
+        .into_iter()
+        .filter(|room| position(&room.name).is_some())
+        .collect::<Vec<_>>();
+    featured.sort_by_key(|room| position(&room.name));
+    (
+        StatusCode::OK,
+        Json(json!({
+            "status": "Success!",
+            "motd": state.motd.server(),
+            "featured": featured,
+            "trending": directory::trending(&state, DIRECTORY_TRENDING),
+        })),
+    )
+}
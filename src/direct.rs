@@ -0,0 +1,36 @@
+//! Direct messages between members, sent with a `direct` WebSocket frame.
+//!
+//! A direct message reaches every connection of its recipient, in any room.
+//! Recipients who are not connected get a notification instead, and the
+//! message waits in their [`inbox`](crate::inbox).
+
+use std::sync::Arc;
+
+use crate::events::ChatEvent;
+use crate::notifications::{self, Kind, Notification};
+use crate::{inbox, transforms, AppState};
+
+const MAX_LEN: usize = 2000;
+
+/// Sends `text` from `from` in `room` to `to`.
+pub async fn send(state: &Arc<AppState>, room: &str, from: &str, to: &str, text: &str) {
+    let text = transforms::strip_unsafe(text);
+    let text = text.trim();
+    if to == from || text.is_empty() || text.chars().count() > MAX_LEN {
+        return;
+    }
+    let event = ChatEvent::direct(from, text);
+    if state.connections.send(to, &event, None) == 0 {
+        inbox::keep(state, room, from, to, text).await;
+        notifications::notify(
+            state,
+            to,
+            Notification {
+                kind: Kind::Direct,
+                room: room.to_owned(),
+                from: from.to_owned(),
+                text: text.to_owned(),
+            },
+        );
+    }
+}
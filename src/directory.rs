@@ -0,0 +1,336 @@
+//! The room directory, `GET /rooms` and `GET /rooms/search`.
+//!
+//! Lists the active rooms a page at a time, sorted by name, member count or
+//! latest message, optionally only those whose name starts with a prefix or
+//! with certain [tags](crate::tags), finds rooms by the words of their names
+//! and their tags, best matches first, and ranks the rooms busiest over the
+//! last [`TRENDING_WINDOW`].
+
+use axum::extract::{Query, State};
+use axum::http::StatusCode;
+use axum::Json;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::{HashSet, VecDeque};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::{default_rooms, ApiResponse, AppState};
+
+const DEFAULT_LIMIT: usize = 50;
+const MAX_LIMIT: usize = 200;
+const MAX_QUERY_LEN: usize = 100;
+/// How far back trending rooms are ranked by.
+pub const TRENDING_WINDOW: Duration = Duration::from_secs(10 * 60);
+const TRENDING_LIMIT: usize = 10;
+
+/// When and by whom a room's messages of the last [`TRENDING_WINDOW`] were
+/// sent, oldest first.
+#[derive(Default)]
+pub struct Activity {
+    messages: VecDeque<(Instant, String)>,
+}
+
+impl Activity {
+    pub fn record(&mut self, from: &str) {
+        let now = Instant::now();
+        self.prune(now);
+        self.messages.push_back((now, from.to_owned()));
+    }
+
+    fn prune(&mut self, now: Instant) {
+        while let Some((at, _)) = self.messages.front() {
+            if now.duration_since(*at) < TRENDING_WINDOW {
+                break;
+            }
+            self.messages.pop_front();
+        }
+    }
+
+    /// Messages per minute over the window, and the members who sent them.
+    fn rates(&mut self) -> (f64, usize) {
+        self.prune(Instant::now());
+        let minutes = TRENDING_WINDOW.as_secs_f64() / 60.0;
+        let speakers = self
+            .messages
+            .iter()
+            .map(|(_, from)| from)
+            .collect::<HashSet<_>>();
+        (self.messages.len() as f64 / minutes, speakers.len())
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Sort {
+    #[default]
+    Name,
+    /// Most members first.
+    Users,
+    /// Latest message first.
+    Activity,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Order {
+    Asc,
+    Desc,
+}
+
+#[derive(Deserialize)]
+pub struct RoomsQuery {
+    #[serde(default)]
+    sort: Sort,
+    /// The sort's own order by default.
+    order: Option<Order>,
+    limit: Option<usize>,
+    #[serde(default)]
+    offset: usize,
+    /// Only rooms whose name starts with it, ignoring case.
+    prefix: Option<String>,
+    /// Comma-separated, only rooms with all of them.
+    tags: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct RoomSummary {
+    pub name: String,
+    /// Of default rooms.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub topic: Option<String>,
+    pub users: usize,
+    pub tags: Vec<String>,
+    /// When the latest message of the room's history was sent, in Unix seconds.
+    pub last_activity: Option<u64>,
+}
+
+#[derive(Serialize)]
+pub struct RoomPage {
+    pub status: &'static str,
+    pub rooms: Vec<RoomSummary>,
+    /// Rooms matching the filter, on every page.
+    pub total: usize,
+    /// `offset` of the next page, `None` on the last.
+    pub next_offset: Option<usize>,
+}
+
+/// The active rooms, with their member count and latest activity.
+pub fn summaries(state: &AppState) -> Vec<RoomSummary> {
+    let rooms = state.rooms.lock().unwrap();
+    rooms
+        .iter()
+        .map(|(name, room)| RoomSummary {
+            name: name.clone(),
+            topic: default_rooms::topic(state, name),
+            users: room.users.lock().unwrap().len(),
+            tags: room.tags.lock().unwrap().clone(),
+            last_activity: room
+                .history
+                .lock()
+                .unwrap()
+                .back()
+                .map(|message| message.timestamp),
+        })
+        .collect()
+}
+
+/// `GET /rooms?sort=&order=&limit=&offset=&prefix=`
+pub async fn get_rooms(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<RoomsQuery>,
+) -> Json<RoomPage> {
+    let mut rooms = summaries(&state);
+    if let Some(prefix) = query.prefix.as_deref().map(str::to_lowercase) {
+        rooms.retain(|room| room.name.to_lowercase().starts_with(&prefix));
+    }
+    if let Some(tags) = query.tags.as_deref().map(str::to_lowercase) {
+        let tags = tags
+            .split(',')
+            .map(str::trim)
+            .filter(|tag| !tag.is_empty())
+            .collect::<Vec<_>>();
+        rooms.retain(|room| {
+            tags.iter()
+                .all(|tag| room.tags.iter().any(|own| own == tag))
+        });
+    }
+    // Ties are broken by name, so that pages do not overlap.
+    rooms.sort_by(|a, b| match query.sort {
+        Sort::Name => a.name.cmp(&b.name),
+        Sort::Users => b.users.cmp(&a.users).then_with(|| a.name.cmp(&b.name)),
+        Sort::Activity => b
+            .last_activity
+            .cmp(&a.last_activity)
+            .then_with(|| a.name.cmp(&b.name)),
+    });
+    let reversed = matches!(
+        (query.sort, query.order),
+        (Sort::Name, Some(Order::Desc)) | (Sort::Users | Sort::Activity, Some(Order::Asc))
+    );
+    if reversed {
+        rooms.reverse();
+    }
+
+    let total = rooms.len();
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+    let rooms = rooms
+        .into_iter()
+        .skip(query.offset)
+        .take(limit)
+        .collect::<Vec<_>>();
+    let end = query.offset.saturating_add(rooms.len());
+    Json(RoomPage {
+        status: if total == 0 {
+            "No rooms found yet!"
+        } else {
+            "Success!"
+        },
+        rooms,
+        total,
+        next_offset: (end < total).then_some(end),
+    })
+}
+
+#[derive(Deserialize)]
+pub struct SearchQuery {
+    #[serde(default)]
+    q: String,
+    limit: Option<usize>,
+}
+
+/// How well `term` matches `text`, both lowercase: as all of it, its start,
+/// the start of one of its words or anywhere in it.
+fn term_score(text: &str, term: &str) -> Option<u32> {
+    if text == term {
+        Some(8)
+    } else if text.starts_with(term) {
+        Some(4)
+    } else if text
+        .split(|c: char| !c.is_alphanumeric())
+        .any(|word| word.starts_with(term))
+    {
+        Some(2)
+    } else if text.contains(term) {
+        Some(1)
+    } else {
+        None
+    }
+}
+
+/// How well `room` matches every term of the query, `None` if one is missing.
+/// A term counts for the best of the name, the tags and, at half the score,
+/// the topic.
+fn score(room: &RoomSummary, terms: &[String]) -> Option<u32> {
+    let name = room.name.to_lowercase();
+    let topic = room.topic.as_deref().map(str::to_lowercase);
+    terms
+        .iter()
+        .map(|term| {
+            let tag = room.tags.iter().filter_map(|tag| term_score(tag, term));
+            let topic = topic
+                .as_deref()
+                .and_then(|topic| term_score(topic, term))
+                .map(|score| score / 2);
+            tag.chain(term_score(&name, term)).chain(topic).max()
+        })
+        .sum()
+}
+
+/// `GET /rooms/search?q=&limit=`, the rooms matching every word of `q` in
+/// their name, tags or topic, best matches first and busier rooms first among
+/// equal ones.
+pub async fn search(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<SearchQuery>,
+) -> ApiResponse {
+    let q = query.q.trim();
+    if q.is_empty() || q.chars().count() > MAX_QUERY_LEN {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({
+                "status": format!("Query must be 1 to {} characters.", MAX_QUERY_LEN),
+            })),
+        );
+    }
+    let terms = q
+        .to_lowercase()
+        .split_whitespace()
+        .map(str::to_owned)
+        .collect::<Vec<_>>();
+    let mut results = summaries(&state)
+        .into_iter()
+        .filter_map(|room| Some((score(&room, &terms)?, room)))
+        .collect::<Vec<_>>();
+    results.sort_by(|(a_score, a), (b_score, b)| {
+        b_score
+            .cmp(a_score)
+            .then_with(|| b.users.cmp(&a.users))
+            .then_with(|| a.name.cmp(&b.name))
+    });
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+    let rooms = results
+        .into_iter()
+        .take(limit)
+        .map(|(_, room)| room)
+        .collect::<Vec<_>>();
+    (
+        StatusCode::OK,
+        Json(json!({ "status": "Success!", "rooms": rooms })),
+    )
+}
+
+#[derive(Serialize)]
+pub struct TrendingRoom {
+    pub name: String,
+    pub users: usize,
+    pub tags: Vec<String>,
+    pub messages_per_minute: f64,
+    /// Members who sent messages.
+    pub speakers: usize,
+}
+
+/// The rooms with messages over the last [`TRENDING_WINDOW`], busiest first.
+pub fn trending(state: &AppState, limit: usize) -> Vec<TrendingRoom> {
+    let mut rooms = {
+        let rooms = state.rooms.lock().unwrap();
+        rooms
+            .iter()
+            .filter_map(|(name, room)| {
+                let (messages_per_minute, speakers) = room.activity.lock().unwrap().rates();
+                (speakers > 0).then(|| TrendingRoom {
+                    name: name.clone(),
+                    users: room.users.lock().unwrap().len(),
+                    tags: room.tags.lock().unwrap().clone(),
+                    messages_per_minute,
+                    speakers,
+                })
+            })
+            .collect::<Vec<_>>()
+    };
+    rooms.sort_by(|a, b| {
+        b.messages_per_minute
+            .total_cmp(&a.messages_per_minute)
+            .then_with(|| b.speakers.cmp(&a.speakers))
+            .then_with(|| a.name.cmp(&b.name))
+    });
+    rooms.truncate(limit);
+    rooms
+}
+
+#[derive(Deserialize)]
+pub struct TrendingQuery {
+    limit: Option<usize>,
+}
+
+/// `GET /rooms/trending?limit=`
+pub async fn get_trending(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<TrendingQuery>,
+) -> ApiResponse {
+    let limit = query.limit.unwrap_or(TRENDING_LIMIT).clamp(1, MAX_LIMIT);
+    (
+        StatusCode::OK,
+        Json(json!({ "status": "Success!", "rooms": trending(&state, limit) })),
+    )
+}
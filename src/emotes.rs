@@ -0,0 +1,218 @@
+//! Custom emotes: small images a room's owner registers under a name.
+//!
+//! The images live in the attachment store. The `emotes` transform stage
+//! turns `:name:` in messages into `<:name:id>` references, which clients
+//! render with the image behind `/attachments/<id>`.
+
+use axum::extract::{Multipart, Path, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::Json;
+use log::error;
+use serde::Serialize;
+use serde_json::json;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+
+use crate::attachments::{self, Attachment};
+use crate::owners::{forbidden, generate_token, is_owner};
+use crate::room_names::RoomName;
+use crate::sentry;
+use crate::transforms::{Transform, TransformContext};
+use crate::{ApiResponse, AppState};
+
+pub const MAX_BYTES: usize = 256 << 10;
+/// Emotes are at most this many pixels wide and high.
+const MAX_SIZE: u32 = 128;
+const MAX_PER_ROOM: usize = 100;
+const MAX_NAME_LEN: usize = 32;
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Emote {
+    pub name: String,
+    /// Attachment id of the image.
+    pub id: String,
+    pub url: String,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Emotes by name, per room. Shared between the state and the transform.
+pub type Registry = Arc<Mutex<HashMap<String, BTreeMap<String, Emote>>>>;
+
+/// Expands registered `:name:` codes to `<:name:id>`.
+pub struct Emotes(pub Registry);
+
+impl Transform for Emotes {
+    fn name(&self) -> &str {
+        "emotes"
+    }
+
+    fn apply(&self, ctx: &TransformContext, text: String) -> Option<String> {
+        let registry = self.0.lock().unwrap();
+        let Some(emotes) = registry.get(&ctx.room).filter(|emotes| !emotes.is_empty()) else {
+            return Some(text);
+        };
+        let mut expanded = String::with_capacity(text.len());
+        let mut rest = text.as_str();
+        while let Some(start) = rest.find(':') {
+            expanded.push_str(&rest[..start]);
+            let after = &rest[start + 1..];
+            let emote = after
+                .find(':')
+                .and_then(|end| Some((emotes.get(&after[..end])?, end)));
+            match emote {
+                Some((emote, end)) => {
+                    expanded.push_str(&format!("<:{}:{}>", emote.name, emote.id));
+                    rest = &after[end + 1..];
+                }
+                None => {
+                    expanded.push(':');
+                    rest = after;
+                }
+            }
+        }
+        expanded.push_str(rest);
+        Some(expanded)
+    }
+}
+
+fn error(status: StatusCode, message: &str) -> ApiResponse {
+    (status, Json(json!({ "status": message })))
+}
+
+fn is_name(name: &str) -> bool {
+    (2..=MAX_NAME_LEN).contains(&name.len())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+}
+
+/// `GET /rooms/:name/emotes`
+pub async fn list_emotes(
+    Path(room): Path<RoomName>,
+    State(state): State<Arc<AppState>>,
+) -> ApiResponse {
+    let registry = state.emotes.lock().unwrap();
+    let emotes = registry
+        .get(room.as_str())
+        .map(|emotes| emotes.values().cloned().collect::<Vec<_>>())
+        .unwrap_or_default();
+    (
+        StatusCode::OK,
+        Json(json!({ "status": "Success!", "emotes": emotes })),
+    )
+}
+
+/// `PUT /rooms/:name/emotes/:emote`, takes the image as a multipart `file`
+/// field and replaces any emote of the same name.
+pub async fn put_emote(
+    Path((room, name)): Path<(RoomName, String)>,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    mut multipart: Multipart,
+) -> ApiResponse {
+    if !is_owner(&state, &room, &headers) {
+        return forbidden();
+    }
+    let Some(store) = &state.attachments else {
+        return error(StatusCode::NOT_FOUND, "Attachments are not enabled.");
+    };
+    if !is_name(&name) {
+        return error(
+            StatusCode::BAD_REQUEST,
+            &format!(
+                "Emote names are 2 to {} lowercase letters, digits or underscores.",
+                MAX_NAME_LEN
+            ),
+        );
+    }
+    {
+        let registry = state.emotes.lock().unwrap();
+        let emotes = registry.get(room.as_str());
+        if emotes.is_some_and(|emotes| emotes.len() >= MAX_PER_ROOM && !emotes.contains_key(&name))
+        {
+            return error(
+                StatusCode::BAD_REQUEST,
+                &format!("Rooms are limited to {} emotes.", MAX_PER_ROOM),
+            );
+        }
+    }
+    let (_, bytes) = match attachments::read_file(&mut multipart, MAX_BYTES).await {
+        Ok(file) => file,
+        Err(response) => return response,
+    };
+    let content_type = match attachments::detect(&bytes) {
+        Some(content_type) if content_type.starts_with("image/") => content_type,
+        _ => return error(StatusCode::UNSUPPORTED_MEDIA_TYPE, "Emotes must be images."),
+    };
+    let Some((width, height)) = attachments::dimensions(&bytes).await else {
+        return error(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "Could not decode image.",
+        );
+    };
+    if width > MAX_SIZE || height > MAX_SIZE {
+        return error(
+            StatusCode::BAD_REQUEST,
+            &format!("Emotes are limited to {0}×{0} pixels.", MAX_SIZE),
+        );
+    }
+
+    let id = generate_token();
+    let attachment = Attachment {
+        url: format!("{}/attachments/{}", state.base_path, id),
+        id,
+        room: room.to_string(),
+        name: name.clone(),
+        content_type: content_type.to_owned(),
+        size: bytes.len(),
+        width: Some(width),
+        height: Some(height),
+        thumbnail: None,
+        duration: None,
+        quarantined: None,
+    };
+    if let Err(err) = store.save(&attachment, bytes).await {
+        error!("Failed to store emote {}: {}", attachment.id, err);
+        let err = format!("Failed to store emote {}: {}", attachment.id, err);
+        sentry::report_error("emotes", err, Some(&room), None);
+        return error(StatusCode::INTERNAL_SERVER_ERROR, "Failed to store emote.");
+    }
+    let emote = Emote {
+        name: name.clone(),
+        id: attachment.id,
+        url: attachment.url,
+        width,
+        height,
+    };
+    state
+        .emotes
+        .lock()
+        .unwrap()
+        .entry(room.into())
+        .or_default()
+        .insert(name, emote.clone());
+    (
+        StatusCode::OK,
+        Json(json!({ "status": "Success!", "emote": emote })),
+    )
+}
+
+/// `DELETE /rooms/:name/emotes/:emote`
+pub async fn delete_emote(
+    Path((room, name)): Path<(RoomName, String)>,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> ApiResponse {
+    if !is_owner(&state, &room, &headers) {
+        return forbidden();
+    }
+    let mut registry = state.emotes.lock().unwrap();
+    match registry
+        .get_mut(room.as_str())
+        .and_then(|emotes| emotes.remove(&name))
+    {
+        Some(_) => (StatusCode::OK, Json(json!({ "status": "Success!" }))),
+        None => error(StatusCode::NOT_FOUND, "Emote not found."),
+    }
+}
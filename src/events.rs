@@ -0,0 +1,513 @@
+use log::warn;
+use serde::{Deserialize, Serialize};
+use serde_json::error::Category;
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast;
+
+use crate::attachments::Attachment;
+use crate::components::{self, Component, Interaction};
+use crate::gifs::Gif;
+use crate::notifications;
+use crate::parts::Part;
+use crate::polls::Poll;
+use crate::presence;
+use crate::previews::Preview;
+use crate::voice::Signal;
+
+/// Seconds since the Unix epoch.
+pub fn unix_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
+}
+
+/// Everything that travels through a room's broadcast channel or a user's
+/// direct channel. Transports decide how to render it, `Display` gives the
+/// plain text frames WebSocket clients receive.
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum ChatEvent {
+    Message {
+        /// Increases with every message of the room, also in its history.
+        id: u64,
+        from: String,
+        text: String,
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        attachments: Vec<Attachment>,
+        /// Previews of the links in `text`, when enabled.
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        previews: Vec<Preview>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        gif: Option<Gif>,
+        #[serde(skip_serializing_if = "Format::is_plain")]
+        format: Format,
+        /// The prose, code blocks and spoilers of `text`, when it has more than prose.
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        parts: Vec<Part>,
+        /// Unix time in seconds at which the message is deleted.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        expires_at: Option<u64>,
+        /// Whether a bot or incoming webhook posted the message, `app`.
+        #[serde(skip_serializing_if = "std::ops::Not::not")]
+        is_bot: bool,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        app: Option<App>,
+        /// Buttons and select menus of a bot or webhook, see
+        /// [components](crate::components).
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        components: Vec<Component>,
+    },
+    /// Tombstone of a message removed from the history.
+    Deleted { id: u64 },
+    /// The new `text` of message `id`, `partial` while a reply streams in,
+    /// as the [assistant](crate::assistant)'s does, and its new
+    /// `components` if they changed, e.g. none once an approval is given.
+    Edited {
+        id: u64,
+        from: String,
+        text: String,
+        #[serde(skip_serializing_if = "std::ops::Not::not")]
+        partial: bool,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        components: Option<Vec<Component>>,
+    },
+    /// `text` of `from` translated into `language` for the recipient alone,
+    /// message `id` of the room unless they gave the text themselves.
+    Translated {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        id: Option<u64>,
+        from: String,
+        text: String,
+        language: String,
+    },
+    /// A notice from the operators to every room.
+    Announcement { text: String },
+    /// The message of the day, sent to a member right after joining.
+    Motd { text: String },
+    Joined {
+        username: String,
+        /// The room's own wording, the built-in one when `None`.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        text: Option<String>,
+    },
+    Left {
+        username: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        text: Option<String>,
+    },
+    Direct {
+        from: String,
+        text: String,
+        /// Set for messages kept while the recipient was offline, for them
+        /// to acknowledge.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        sent_at: Option<u64>,
+        #[serde(skip_serializing_if = "std::ops::Not::not")]
+        offline: bool,
+    },
+    /// Voice chat signaling from `from`, sent only to its addressee.
+    Signal {
+        from: String,
+        #[serde(flatten)]
+        signal: Signal,
+    },
+    /// The room's voice chat participants, sent to them on every change.
+    Voice {
+        participants: Vec<String>,
+        /// The recipient's token for `GET /rtc/credentials`, when TURN is set up.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        token: Option<String>,
+    },
+    /// A poll of the room after it was created, voted in or closed.
+    Poll { poll: Poll },
+    /// Confirms a `schedule` frame to its sender.
+    Scheduled { id: String, send_at: u64 },
+    /// Confirms a `schedule_cancel` frame to its sender.
+    Unscheduled { id: String },
+    /// A message of `room` containing `keyword`, sent to who watches for it.
+    Highlight {
+        room: String,
+        id: u64,
+        from: String,
+        text: String,
+        keyword: String,
+    },
+    /// The recipient read `room` up to message `id` on another connection.
+    Read { room: String, id: u64 },
+    /// The recipient dismissed the notifications of `room` on another
+    /// connection.
+    Dismissed { room: String },
+    /// A member of the room switched do not disturb on or off.
+    Presence {
+        username: String,
+        status: presence::Status,
+    },
+    /// The room was [archived](crate::archive), the last event its members
+    /// receive.
+    Archived,
+    /// The room moved to another [cluster](crate::cluster) node, where its
+    /// members are to reconnect. The last event they receive here.
+    Moved,
+    /// The recipient is `lag` events behind the room, having just skipped
+    /// `skipped`, see [slow consumers](crate::slow_consumers).
+    Slow { lag: usize, skipped: u64 },
+    /// The recipient's message was refused, being over their [rate
+    /// limit](crate::rate_limits) until `retry_after` seconds from now.
+    Limited { retry_after: u64 },
+}
+
+impl ChatEvent {
+    /// Whether a member that fell behind should still receive the event,
+    /// unlike notices of joins, leaves and presence changes, and edits that
+    /// a later one replaces.
+    pub fn is_essential(&self) -> bool {
+        !matches!(
+            self,
+            ChatEvent::Joined { .. }
+                | ChatEvent::Left { .. }
+                | ChatEvent::Presence { .. }
+                | ChatEvent::Edited { partial: true, .. }
+        )
+    }
+
+    /// The id of the message, if the event is one.
+    pub fn message_id(&self) -> Option<u64> {
+        match self {
+            ChatEvent::Message { id, .. } => Some(*id),
+            _ => None,
+        }
+    }
+
+    /// A plain text message.
+    pub fn message(id: u64, from: impl Into<String>, text: impl Into<String>) -> Self {
+        ChatEvent::Message {
+            id,
+            from: from.into(),
+            text: text.into(),
+            attachments: Vec::new(),
+            previews: Vec::new(),
+            gif: None,
+            format: Format::Plain,
+            parts: Vec::new(),
+            expires_at: None,
+            is_bot: false,
+            app: None,
+            components: Vec::new(),
+        }
+    }
+
+    /// A plain text message posted by a bot or integration, `app`.
+    pub fn bot_message(
+        id: u64,
+        from: impl Into<String>,
+        text: impl Into<String>,
+        app: App,
+    ) -> Self {
+        let mut message = Self::message(id, from, text);
+        message.set_app(app);
+        message
+    }
+
+    /// Marks the message as posted by `app`.
+    pub fn set_app(&mut self, app: App) {
+        if let ChatEvent::Message {
+            is_bot, app: found, ..
+        } = self
+        {
+            *is_bot = true;
+            *found = Some(app);
+        }
+    }
+
+    /// A direct message delivered as it is sent.
+    pub fn direct(from: impl Into<String>, text: impl Into<String>) -> Self {
+        ChatEvent::Direct {
+            from: from.into(),
+            text: text.into(),
+            id: None,
+            sent_at: None,
+            offline: false,
+        }
+    }
+}
+
+/// The bot, plugin or incoming webhook behind a message, for clients to
+/// render its messages apart and let members filter them.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct App {
+    pub name: String,
+    /// URL of the image clients show beside its messages.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub avatar: Option<String>,
+}
+
+impl App {
+    pub fn new(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            avatar: None,
+        }
+    }
+}
+
+/// How clients should render a message's text.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    #[default]
+    Plain,
+    /// The subset described in the [`markdown`](crate::markdown) module,
+    /// normalized by the server.
+    Markdown,
+}
+
+impl Format {
+    pub fn is_plain(&self) -> bool {
+        *self == Format::Plain
+    }
+}
+
+impl fmt::Display for ChatEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChatEvent::Message {
+                id,
+                from,
+                text,
+                attachments,
+                gif,
+                components,
+                ..
+            } => {
+                write!(f, "{}: {}", from, text)?;
+                let links = attachments
+                    .iter()
+                    .map(|attachment| (attachment.name.as_str(), attachment.url.as_str()))
+                    .chain(gif.iter().map(|gif| ("GIF", gif.url.as_str())));
+                for (i, (name, url)) in links.enumerate() {
+                    let separator = if i == 0 && text.is_empty() { "" } else { " " };
+                    write!(f, "{}[{}: {}]", separator, name, url)?;
+                }
+                write!(f, "{}", components::render(*id, components))
+            }
+            ChatEvent::Joined {
+                text: Some(text), ..
+            }
+            | ChatEvent::Left {
+                text: Some(text), ..
+            } => write!(f, "{}", text),
+            ChatEvent::Joined { username, .. } => write!(f, "{} joined the chat!", username),
+            ChatEvent::Left { username, .. } => write!(f, "{} left the chat!", username),
+            ChatEvent::Direct {
+                from,
+                text,
+                offline: false,
+                ..
+            } => write!(f, "[DM] {}: {}", from, text),
+            ChatEvent::Poll { poll } => write!(f, "{}", poll),
+            ChatEvent::Announcement { text } => write!(f, "[Announcement] {}", text),
+            ChatEvent::Motd { text } => write!(f, "[MOTD] {}", text),
+            // Meant for the client rather than its user, so they stay JSON.
+            ChatEvent::Deleted { .. }
+            | ChatEvent::Edited { .. }
+            | ChatEvent::Translated { .. }
+            | ChatEvent::Signal { .. }
+            | ChatEvent::Voice { .. }
+            | ChatEvent::Scheduled { .. }
+            | ChatEvent::Unscheduled { .. }
+            | ChatEvent::Highlight { .. }
+            | ChatEvent::Presence { .. }
+            | ChatEvent::Read { .. }
+            | ChatEvent::Dismissed { .. }
+            | ChatEvent::Archived
+            | ChatEvent::Moved
+            | ChatEvent::Slow { .. }
+            | ChatEvent::Limited { .. }
+            | ChatEvent::Direct { offline: true, .. } => {
+                let json = serde_json::to_string(self).map_err(|_| fmt::Error)?;
+                write!(f, "{}", json)
+            }
+        }
+    }
+}
+
+/// A JSON frame sent by a WebSocket client after joining, chat text included
+/// as a `message`.
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientFrame {
+    Message(Draft),
+    VoiceJoin,
+    VoiceLeave,
+    /// Voice chat signaling for another participant.
+    Signal {
+        to: String,
+        #[serde(flatten)]
+        signal: Signal,
+    },
+    PollCreate {
+        question: String,
+        options: Vec<String>,
+    },
+    PollVote {
+        poll: u64,
+        /// Index into the poll's options.
+        option: usize,
+    },
+    PollClose {
+        poll: u64,
+    },
+    /// Picks `component` of message `message`, with the `value` of an
+    /// option for select menus.
+    Interact {
+        message: u64,
+        component: String,
+        #[serde(default)]
+        value: Option<String>,
+    },
+    /// Posts the message at `send_at`, in Unix seconds.
+    Schedule {
+        send_at: u64,
+        #[serde(flatten)]
+        message: Draft,
+    },
+    ScheduleCancel {
+        id: String,
+    },
+    /// A direct message to another member.
+    Direct {
+        to: String,
+        text: String,
+    },
+    /// What of the room the member is notified of while offline.
+    Notifications {
+        level: notifications::Level,
+    },
+    /// Marks a room read up to message `id`, the current one by default.
+    Read {
+        #[serde(default)]
+        room: Option<String>,
+        id: u64,
+    },
+    /// Dismisses the notifications of a room, the current one by default.
+    Dismiss {
+        #[serde(default)]
+        room: Option<String>,
+    },
+    /// Acknowledges a direct message received while offline.
+    DirectAck {
+        id: String,
+    },
+    /// Replaces the keywords the member watches the room for.
+    Highlights {
+        keywords: Vec<String>,
+    },
+    /// Switches do not disturb on or off.
+    Presence {
+        status: presence::Status,
+    },
+}
+
+impl ClientFrame {
+    /// Parses a frame, or says what is wrong with it.
+    pub fn parse(frame: &str) -> Result<Self, String> {
+        serde_json::from_str(frame).map_err(|err| match err.classify() {
+            // Without the position, frames being a single line.
+            Category::Data => {
+                let reason = err.to_string();
+                match reason.rsplit_once(" at line ") {
+                    Some((reason, _)) => reason.to_owned(),
+                    None => reason,
+                }
+            }
+            _ => "frames are JSON objects with a `type`".to_owned(),
+        })
+    }
+}
+
+/// A message as a member asked to post it, before bot commands, transforms
+/// and hooks have had their say.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Draft {
+    #[serde(default)]
+    pub text: String,
+    /// Ids returned by `POST /rooms/:name/attachments`.
+    #[serde(default)]
+    pub attachments: Vec<String>,
+    /// Id of a result of `GET /gifs/search`.
+    #[serde(default)]
+    pub gif: Option<String>,
+    #[serde(default)]
+    pub format: Format,
+    /// Seconds after which the message is deleted again.
+    #[serde(default)]
+    pub expires_in: Option<u64>,
+}
+
+impl Draft {
+    /// A draft with only text, as sent by the plain text transports.
+    pub fn text(text: impl Into<String>) -> Self {
+        Self {
+            text: text.into(),
+            ..Self::default()
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.text.is_empty() && self.attachments.is_empty() && self.gif.is_none()
+    }
+}
+
+/// Server-wide notifications published on the event bus after the room
+/// broadcast, for subsystems that react to chat activity.
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum RoomEvent {
+    MessageSent {
+        room: String,
+        from: String,
+        text: String,
+    },
+    UserJoined {
+        room: String,
+        username: String,
+    },
+    UserLeft {
+        room: String,
+        username: String,
+    },
+    RoomCreated {
+        room: String,
+    },
+    RoomDeleted {
+        room: String,
+    },
+    /// A member picked a component of a message.
+    Interacted(Interaction),
+}
+
+/// Capacity of the event bus, a subscriber further behind skips events.
+pub const BUS_CAPACITY: usize = 1024;
+
+/// Waits for the next bus event, `None` once the bus is gone.
+pub async fn next(
+    events: &mut broadcast::Receiver<RoomEvent>,
+    subscriber: &str,
+) -> Option<RoomEvent> {
+    loop {
+        match events.recv().await {
+            Ok(event) => return Some(event),
+            Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                warn!(
+                    "{} fell behind the event bus, skipped {} events",
+                    subscriber, skipped
+                );
+            }
+            Err(broadcast::error::RecvError::Closed) => return None,
+        }
+    }
+}
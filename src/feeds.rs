@@ -0,0 +1,602 @@
+//! RSS and Atom feeds room owners subscribe their rooms to.
+//!
+//! A background task polls each feed at its own interval, 15 minutes by
+//! default, and posts its new entries to the room as `feeds`, one Markdown
+//! message each with a preview of the entry's link. The entries a feed has
+//! when subscribed count as seen, so the room gets only what comes after.
+//! Feeds are polled while their room is active. With `FEEDS_FILE` set the
+//! subscriptions and the entries seen are kept in that JSON file and survive
+//! restarts, so that nothing is posted twice.
+
+use axum::extract::{Path, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::Json;
+use log::{debug, info, warn};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::events::{unix_timestamp, App, ChatEvent, Format};
+use crate::json_file::JsonFile;
+use crate::owners::{forbidden, generate_token, is_owner};
+use crate::previews::{self, Preview};
+use crate::room_names::RoomName;
+use crate::{markdown, tasks, ApiResponse, AppState};
+
+/// Who entries are posted as.
+const NAME: &str = "feeds";
+const DEFAULT_INTERVAL: u64 = 15 * 60;
+const MIN_INTERVAL: u64 = 60;
+const MAX_INTERVAL: u64 = 24 * 60 * 60;
+const MAX_FEEDS: usize = 10;
+const TICK: Duration = Duration::from_secs(10);
+const TIMEOUT: Duration = Duration::from_secs(10);
+/// Feeds are read up to this many bytes.
+const MAX_BYTES: usize = 2 << 20;
+/// Entries posted per poll, older new ones are skipped.
+const MAX_POSTS: usize = 5;
+/// Entry ids remembered per feed.
+const MAX_SEEN: usize = 500;
+const MAX_TITLE_LEN: usize = 200;
+const MAX_SUMMARY_LEN: usize = 300;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Feed {
+    pub id: String,
+    pub url: String,
+    /// Seconds between two polls.
+    pub interval: u64,
+    /// The feed's own title, as of its last poll.
+    #[serde(default)]
+    pub title: Option<String>,
+    /// Unix time in seconds of the next poll.
+    pub next_poll: u64,
+    /// Why the last poll failed, if it did.
+    #[serde(default)]
+    pub error: Option<String>,
+    /// The ids of the entries posted or skipped, the latest last.
+    #[serde(default)]
+    seen: VecDeque<String>,
+}
+
+impl Feed {
+    fn see(&mut self, id: String) {
+        self.seen.push_back(id);
+        if self.seen.len() > MAX_SEEN {
+            self.seen.pop_front();
+        }
+    }
+}
+
+/// The rooms' feeds, written through to the feeds file if there is one.
+pub struct Feeds {
+    rooms: Mutex<HashMap<String, Vec<Feed>>>,
+    file: Option<JsonFile>,
+    client: reqwest::Client,
+}
+
+impl Feeds {
+    /// Loads the feeds from `file`, starting without any if it does not
+    /// exist yet.
+    pub fn open(file: Option<PathBuf>) -> Self {
+        let file = file.map(|path| JsonFile::new(path, "feeds"));
+        let rooms = file.as_ref().map(JsonFile::load).unwrap_or_default();
+        Self {
+            rooms: Mutex::new(rooms),
+            file,
+            client: previews::public_client(TIMEOUT),
+        }
+    }
+
+    fn save(&self, rooms: &HashMap<String, Vec<Feed>>) {
+        if let Some(file) = &self.file {
+            file.save(rooms);
+        }
+    }
+
+    /// Removes the feeds of `room` and returns them, to hand them over.
+    pub fn take_room(&self, room: &str) -> Vec<Feed> {
+        let mut rooms = self.rooms.lock().unwrap();
+        let Some(feeds) = rooms.remove(room) else {
+            return Vec::new();
+        };
+        self.save(&rooms);
+        feeds
+    }
+
+    /// Adds the `feeds` of `room` handed over.
+    pub fn restore_room(&self, room: &str, feeds: Vec<Feed>) {
+        if feeds.is_empty() {
+            return;
+        }
+        let mut rooms = self.rooms.lock().unwrap();
+        rooms.entry(room.to_owned()).or_default().extend(feeds);
+        self.save(&rooms);
+    }
+
+    /// The rooms with feeds due.
+    fn due_rooms(&self) -> Vec<String> {
+        let now = unix_timestamp();
+        let rooms = self.rooms.lock().unwrap();
+        rooms
+            .iter()
+            .filter(|(_, feeds)| feeds.iter().any(|feed| feed.next_poll <= now))
+            .map(|(room, _)| room.clone())
+            .collect()
+    }
+
+    /// The feeds due, of the rooms passing `active`, scheduling their next
+    /// polls.
+    fn take_due(&self, active: impl Fn(&str) -> bool) -> Vec<(String, Feed)> {
+        let now = unix_timestamp();
+        let mut rooms = self.rooms.lock().unwrap();
+        let mut due = Vec::new();
+        for (room, feeds) in rooms.iter_mut().filter(|(room, _)| active(room)) {
+            for feed in feeds.iter_mut().filter(|feed| feed.next_poll <= now) {
+                feed.next_poll = now + feed.interval;
+                due.push((room.clone(), feed.clone()));
+            }
+        }
+        due
+    }
+
+    /// Applies `update` to the feed `id` of `room`, returning whether it is
+    /// still subscribed.
+    fn update(&self, room: &str, id: &str, update: impl FnOnce(&mut Feed)) -> bool {
+        let mut rooms = self.rooms.lock().unwrap();
+        let Some(feed) = rooms
+            .get_mut(room)
+            .and_then(|feeds| feeds.iter_mut().find(|feed| feed.id == id))
+        else {
+            return false;
+        };
+        update(feed);
+        self.save(&rooms);
+        true
+    }
+
+    /// Fetches and parses the feed at `url`.
+    /// Fetches the feed at `url`. Failures to reach it are told apart from
+    /// others in the log alone, so as not to map the network for owners.
+    async fn fetch(&self, url: &str) -> Result<Parsed, String> {
+        let unreachable = |err: reqwest::Error| {
+            debug!("Failed to fetch feed {}: {}", url, err);
+            "unreachable".to_owned()
+        };
+        let mut response = self.client.get(url).send().await.map_err(unreachable)?;
+        if !response.status().is_success() {
+            return Err(format!("answered {}", response.status()));
+        }
+        let mut body = Vec::new();
+        while let Some(chunk) = response.chunk().await.map_err(unreachable)? {
+            body.extend_from_slice(&chunk);
+            if body.len() > MAX_BYTES {
+                return Err("too large".to_owned());
+            }
+        }
+        parse(&String::from_utf8_lossy(&body)).ok_or_else(|| "not an RSS or Atom feed".to_owned())
+    }
+}
+
+/// A feed as fetched, its entries in the feed's order, usually the newest
+/// first.
+struct Parsed {
+    title: Option<String>,
+    entries: Vec<Entry>,
+}
+
+struct Entry {
+    id: String,
+    title: Option<String>,
+    link: Option<String>,
+    summary: Option<String>,
+}
+
+/// Reads an RSS or Atom document, or `None` if it is neither.
+fn parse(xml: &str) -> Option<Parsed> {
+    let item = if xml.contains("<rss") || xml.contains("<rdf:RDF") {
+        "item"
+    } else if xml.contains("<feed") {
+        "entry"
+    } else {
+        return None;
+    };
+    let items = elements(xml, item);
+    let head = match items.first() {
+        Some((start, _)) => &xml[..*start],
+        None => xml,
+    };
+    let entries = items
+        .into_iter()
+        .filter_map(|(_, item)| {
+            let title = element(item, "title").and_then(|title| text(title, MAX_TITLE_LEN));
+            let summary = ["description", "summary", "content"]
+                .into_iter()
+                .find_map(|name| element(item, name).and_then(|raw| text(raw, MAX_SUMMARY_LEN)));
+            let link = link(item);
+            let id = ["guid", "id"]
+                .into_iter()
+                .find_map(|name| element(item, name).and_then(|id| text(id, usize::MAX)))
+                .or_else(|| link.clone())
+                .or_else(|| title.clone())?;
+            Some(Entry {
+                id,
+                title,
+                link,
+                summary,
+            })
+        })
+        .collect();
+    Some(Parsed {
+        title: element(head, "title").and_then(|title| text(title, MAX_TITLE_LEN)),
+        entries,
+    })
+}
+
+/// The position and content of every `<name>` element of `xml`.
+fn elements<'a>(xml: &'a str, name: &str) -> Vec<(usize, &'a str)> {
+    let (open, close) = (format!("<{}", name), format!("</{}>", name));
+    let mut found = Vec::new();
+    let mut at = 0;
+    while let Some(start) = xml[at..].find(&open).map(|start| at + start) {
+        let after = &xml[start + open.len()..];
+        at = start + open.len();
+        // Not another element sharing the name's start, e.g. `<items>`.
+        if !after.starts_with(|c: char| c == '>' || c == '/' || c.is_whitespace()) {
+            continue;
+        }
+        let Some(tag_end) = after.find('>') else {
+            break;
+        };
+        if after[..tag_end].ends_with('/') {
+            found.push((start, ""));
+            continue;
+        }
+        let content = &after[tag_end + 1..];
+        let Some(end) = content.find(&close) else {
+            break;
+        };
+        found.push((start, &content[..end]));
+        at = start + open.len() + tag_end + 1 + end + close.len();
+    }
+    found
+}
+
+fn element<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+    elements(xml, name)
+        .into_iter()
+        .map(|(_, content)| content)
+        .next()
+}
+
+/// The link of an entry, RSS's `<link>` text or Atom's alternate
+/// `<link href>`.
+fn link(item: &str) -> Option<String> {
+    let mut at = 0;
+    while let Some(start) = item[at..].find("<link").map(|start| at + start) {
+        let after = &item[start + "<link".len()..];
+        at = start + "<link".len();
+        let end = after.find('>')?;
+        if !after.starts_with(|c: char| c == '>' || c == '/' || c.is_whitespace()) {
+            continue;
+        }
+        let attributes = previews::attributes(after[..end].trim_end_matches('/'));
+        match attributes.get("href") {
+            Some(href) if attributes.get("rel").is_none_or(|rel| rel == "alternate") => {
+                return Some(previews::decode(href).trim().to_owned());
+            }
+            Some(_) => continue,
+            None => {}
+        }
+        let content = &after[end + 1..];
+        let text = text(&content[..content.find("</link>")?], usize::MAX);
+        if text.is_some() {
+            return text;
+        }
+    }
+    None
+}
+
+/// The plain text of an element's content, HTML removed and cut to `max`
+/// characters, or `None` if there is none.
+fn text(raw: &str, max: usize) -> Option<String> {
+    let raw = raw.trim();
+    let raw = raw
+        .strip_prefix("<![CDATA[")
+        .and_then(|raw| raw.strip_suffix("]]>"))
+        .unwrap_or(raw);
+    // Descriptions are HTML, often escaped once more.
+    let text = strip_tags(&previews::decode(&strip_tags(raw)));
+    let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    if text.is_empty() {
+        return None;
+    }
+    if text.chars().count() > max {
+        let cut = text.chars().take(max - 1).collect::<String>();
+        return Some(format!("{}…", cut.trim_end()));
+    }
+    Some(text)
+}
+
+fn strip_tags(html: &str) -> String {
+    let mut text = String::with_capacity(html.len());
+    let mut rest = html;
+    while let Some(start) = rest.find('<') {
+        text.push_str(&rest[..start]);
+        match rest[start..].find('>') {
+            Some(end) => {
+                text.push(' ');
+                rest = &rest[start + end + 1..];
+            }
+            None => {
+                text.push_str(&rest[start..]);
+                rest = "";
+            }
+        }
+    }
+    text.push_str(rest);
+    text
+}
+
+/// Posts `entry` of the feed titled `feed` to `room`.
+async fn post(state: &AppState, room: &str, feed: &str, entry: Entry) {
+    let title = entry.title.as_deref().unwrap_or("New entry");
+    // A link Markdown can take, with no closing paren ending it early.
+    let link = entry
+        .link
+        .map(|link| link.replace(')', "%29"))
+        .filter(|link| link.starts_with("http://") || link.starts_with("https://"));
+    let text = match &link {
+        Some(link) => format!(
+            "**{}**: [{}]({})",
+            markdown::escape(feed),
+            markdown::escape(title),
+            link
+        ),
+        None => format!(
+            "**{}**: {}",
+            markdown::escape(feed),
+            markdown::escape(title)
+        ),
+    };
+    let mut found = Vec::new();
+    if let Some(link) = &link {
+        if let Some(previews) = &state.previews {
+            found = previews.for_text(link).await;
+        }
+        // The feed tells enough of an entry without fetching its page.
+        if found.is_empty() {
+            found.push(Preview {
+                url: link.clone(),
+                title: entry.title.clone(),
+                description: entry.summary.clone(),
+                image: None,
+                site_name: Some(feed.to_owned()),
+            });
+        }
+    }
+    let rooms = state.rooms.lock().unwrap();
+    let Some(room) = rooms.get(room) else {
+        return;
+    };
+    let mut message = ChatEvent::message(room.next_id(), NAME, markdown::normalize(&text));
+    if let ChatEvent::Message {
+        format, previews, ..
+    } = &mut message
+    {
+        *format = Format::Markdown;
+        *previews = found;
+    }
+    message.set_app(App::new(NAME));
+    room.broadcast(message);
+}
+
+/// Fetches `feed` of `room` and posts what is new in it.
+async fn poll(state: &AppState, room: &str, feed: Feed) {
+    let parsed = match state.feeds.fetch(&feed.url).await {
+        Ok(parsed) => parsed,
+        Err(err) => {
+            warn!("Failed to poll feed {} of {}: {}", feed.url, room, err);
+            state
+                .feeds
+                .update(room, &feed.id, |feed| feed.error = Some(err));
+            return;
+        }
+    };
+    let new = parsed
+        .entries
+        .into_iter()
+        .filter(|entry| !feed.seen.contains(&entry.id))
+        .collect::<Vec<_>>();
+    let subscribed = state.feeds.update(room, &feed.id, |feed| {
+        feed.error = None;
+        feed.title = parsed.title.clone().or(feed.title.take());
+        for entry in new.iter().rev() {
+            feed.see(entry.id.clone());
+        }
+    });
+    if !subscribed || new.is_empty() {
+        return;
+    }
+    info!(
+        "Posting {} entries of feed {} to {}",
+        new.len(),
+        feed.url,
+        room
+    );
+    let title = parsed.title.or(feed.title).unwrap_or(feed.url);
+    // The oldest first, of the latest few.
+    for entry in new.into_iter().take(MAX_POSTS).rev() {
+        post(state, room, &title, entry).await;
+    }
+}
+
+/// Polls the feeds that are due.
+pub async fn poller(state: Arc<AppState>) {
+    let mut interval = tokio::time::interval(TICK);
+    loop {
+        interval.tick().await;
+        // Looked up apart, so that the feeds lock is never held with the
+        // rooms lock.
+        let due = state.feeds.due_rooms();
+        let active = {
+            let rooms = state.rooms.lock().unwrap();
+            due.into_iter()
+                .filter(|room| rooms.contains_key(room))
+                .collect::<HashSet<_>>()
+        };
+        for (room, feed) in state.feeds.take_due(|room| active.contains(room)) {
+            let state = state.clone();
+            tasks::spawn("feed", async move {
+                poll(&state, &room, feed).await;
+            });
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct Subscribe {
+    url: String,
+    /// Seconds between two polls.
+    #[serde(default)]
+    interval: Option<u64>,
+}
+
+/// `POST /rooms/:name/feeds`, subscribes the room to a feed, which is
+/// fetched once right away.
+pub async fn subscribe(
+    Path(room): Path<RoomName>,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(body): Json<Subscribe>,
+) -> ApiResponse {
+    if !is_owner(&state, &room, &headers) {
+        return forbidden();
+    }
+    let bad_request = |status: String| (StatusCode::BAD_REQUEST, Json(json!({ "status": status })));
+    if !Url::parse(&body.url).is_ok_and(|url| previews::allowed(&url)) {
+        return bad_request("Feed url must be a public http(s) URL.".to_owned());
+    }
+    let interval = body.interval.unwrap_or(DEFAULT_INTERVAL);
+    if !(MIN_INTERVAL..=MAX_INTERVAL).contains(&interval) {
+        return bad_request(format!(
+            "Intervals are {} to {} seconds.",
+            MIN_INTERVAL, MAX_INTERVAL
+        ));
+    }
+    let full = |state: &AppState| {
+        let rooms = state.feeds.rooms.lock().unwrap();
+        rooms
+            .get(room.as_str())
+            .is_some_and(|feeds| feeds.len() >= MAX_FEEDS)
+    };
+    if full(&state) {
+        return bad_request(format!("At most {} feeds per room.", MAX_FEEDS));
+    }
+    let parsed = match state.feeds.fetch(&body.url).await {
+        Ok(parsed) => parsed,
+        Err(_) => return bad_request("Cannot read the feed.".to_owned()),
+    };
+    let mut feed = Feed {
+        id: generate_token(),
+        url: body.url,
+        interval,
+        title: parsed.title,
+        next_poll: unix_timestamp() + interval,
+        error: None,
+        seen: VecDeque::new(),
+    };
+    for entry in parsed.entries.into_iter().rev() {
+        feed.see(entry.id);
+    }
+    let mut rooms = state.feeds.rooms.lock().unwrap();
+    let feeds = rooms.entry(room.to_string()).or_default();
+    // Checked again, as others may have subscribed meanwhile.
+    if feeds.len() >= MAX_FEEDS {
+        return bad_request(format!("At most {} feeds per room.", MAX_FEEDS));
+    }
+    let (id, title) = (feed.id.clone(), feed.title.clone());
+    feeds.push(feed);
+    state.feeds.save(&rooms);
+    (
+        StatusCode::OK,
+        Json(json!({ "status": "Success!", "id": id, "title": title })),
+    )
+}
+
+#[derive(Serialize)]
+struct Listed<'a> {
+    id: &'a str,
+    url: &'a str,
+    interval: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    title: Option<&'a str>,
+    next_poll: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<&'a str>,
+}
+
+/// `GET /rooms/:name/feeds`
+pub async fn list_feeds(
+    Path(room): Path<RoomName>,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> ApiResponse {
+    if !is_owner(&state, &room, &headers) {
+        return forbidden();
+    }
+    let rooms = state.feeds.rooms.lock().unwrap();
+    let feeds = rooms
+        .get(room.as_str())
+        .into_iter()
+        .flatten()
+        .map(|feed| Listed {
+            id: &feed.id,
+            url: &feed.url,
+            interval: feed.interval,
+            title: feed.title.as_deref(),
+            next_poll: feed.next_poll,
+            error: feed.error.as_deref(),
+        })
+        .collect::<Vec<_>>();
+    (
+        StatusCode::OK,
+        Json(json!({ "status": "Success!", "feeds": feeds })),
+    )
+}
+
+/// `DELETE /rooms/:name/feeds/:id`
+pub async fn unsubscribe(
+    Path((room, id)): Path<(RoomName, String)>,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> ApiResponse {
+    if !is_owner(&state, &room, &headers) {
+        return forbidden();
+    }
+    let mut rooms = state.feeds.rooms.lock().unwrap();
+    let Some(feeds) = rooms.get_mut(room.as_str()) else {
+        return not_found();
+    };
+    let before = feeds.len();
+    feeds.retain(|feed| feed.id != id);
+    if feeds.len() == before {
+        return not_found();
+    }
+    if feeds.is_empty() {
+        rooms.remove(room.as_str());
+    }
+    state.feeds.save(&rooms);
+    (StatusCode::OK, Json(json!({ "status": "Success!" })))
+}
+
+fn not_found() -> ApiResponse {
+    (
+        StatusCode::NOT_FOUND,
+        Json(json!({ "status": "Feed not found." })),
+    )
+}
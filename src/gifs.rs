@@ -0,0 +1,229 @@
+//! GIF search proxied to Giphy or Tenor, so the API key stays on the server.
+//!
+//! Enabled by `GIPHY_API_KEY` or `TENOR_API_KEY`. Members send the `id` of a
+//! search result with their message, and the server sends the GIF's URLs
+//! along, so clients never talk to the provider with a key of their own.
+
+use axum::extract::{Query, State};
+use axum::http::StatusCode;
+use axum::Json;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::{ApiResponse, AppState};
+
+const DEFAULT_LIMIT: usize = 20;
+const MAX_LIMIT: usize = 50;
+const MAX_QUERY_LEN: usize = 100;
+/// Search results remembered so that sending one needs no further request.
+const KNOWN_LEN: usize = 1000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GifProvider {
+    Giphy,
+    Tenor,
+}
+
+#[derive(Clone)]
+pub struct GifConfig {
+    pub provider: GifProvider,
+    pub api_key: String,
+}
+
+impl GifConfig {
+    pub fn from_env() -> Option<Self> {
+        let var = |name: &str| std::env::var(name).ok().filter(|val| !val.is_empty());
+        let (provider, api_key) = match var("GIPHY_API_KEY") {
+            Some(key) => (GifProvider::Giphy, key),
+            None => (GifProvider::Tenor, var("TENOR_API_KEY")?),
+        };
+        Some(Self { provider, api_key })
+    }
+}
+
+/// A GIF as clients display it.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Gif {
+    pub id: String,
+    pub title: String,
+    pub url: String,
+    pub width: u32,
+    pub height: u32,
+    /// A smaller rendition for pickers and previews.
+    pub preview_url: String,
+}
+
+pub struct Gifs {
+    config: GifConfig,
+    client: reqwest::Client,
+    known: Mutex<HashMap<String, Gif>>,
+}
+
+impl Gifs {
+    pub fn new(config: GifConfig) -> Self {
+        Self {
+            config,
+            client: reqwest::Client::builder()
+                .timeout(Duration::from_secs(5))
+                .build()
+                .unwrap(),
+            known: Mutex::new(HashMap::new()),
+        }
+    }
+
+    async fn request(&self, path: &str, query: &[(&str, &str)]) -> Option<Value> {
+        let (base, key) = match self.config.provider {
+            GifProvider::Giphy => ("https://api.giphy.com/v1/gifs", "api_key"),
+            GifProvider::Tenor => ("https://tenor.googleapis.com/v2", "key"),
+        };
+        let mut query = query.to_vec();
+        query.push((key, &self.config.api_key));
+        if self.config.provider == GifProvider::Tenor {
+            query.push(("media_filter", "gif,tinygif"));
+        }
+        let response = self
+            .client
+            .get(format!("{}/{}", base, path))
+            .query(&query)
+            .send()
+            .await;
+        match response {
+            Ok(response) if response.status().is_success() => response.json().await.ok(),
+            Ok(response) => {
+                warn!("GIF provider answered {} for {}", response.status(), path);
+                None
+            }
+            Err(err) => {
+                warn!("GIF provider request failed: {}", err);
+                None
+            }
+        }
+    }
+
+    /// Reads the GIFs out of a provider response, remembering them.
+    fn collect(&self, response: &Value) -> Vec<Gif> {
+        let results = match self.config.provider {
+            GifProvider::Giphy => match &response["data"] {
+                Value::Array(results) => results.iter().collect(),
+                result => vec![result],
+            },
+            GifProvider::Tenor => response["results"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .collect(),
+        };
+        let gifs = results
+            .into_iter()
+            .filter_map(|result| match self.config.provider {
+                GifProvider::Giphy => giphy(result),
+                GifProvider::Tenor => tenor(result),
+            })
+            .collect::<Vec<_>>();
+        let mut known = self.known.lock().unwrap();
+        if known.len() + gifs.len() > KNOWN_LEN {
+            known.clear();
+        }
+        for gif in &gifs {
+            known.insert(gif.id.clone(), gif.clone());
+        }
+        gifs
+    }
+
+    pub async fn search(&self, query: &str, limit: usize) -> Option<Vec<Gif>> {
+        let limit = limit.to_string();
+        let response = self
+            .request("search", &[("q", query), ("limit", &limit)])
+            .await?;
+        Some(self.collect(&response))
+    }
+
+    /// The GIF with `id`, asking the provider when it was not a recent result.
+    pub async fn get(&self, id: &str) -> Option<Gif> {
+        if id.is_empty() || !id.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return None;
+        }
+        if let Some(gif) = self.known.lock().unwrap().get(id) {
+            return Some(gif.clone());
+        }
+        let response = match self.config.provider {
+            GifProvider::Giphy => self.request(id, &[]).await?,
+            GifProvider::Tenor => self.request("posts", &[("ids", id)]).await?,
+        };
+        self.collect(&response).into_iter().find(|gif| gif.id == id)
+    }
+}
+
+fn giphy(result: &Value) -> Option<Gif> {
+    let original = &result["images"]["original"];
+    let dimension = |name: &str| original[name].as_str()?.parse().ok();
+    let preview = &result["images"]["fixed_width_small"];
+    Some(Gif {
+        id: result["id"].as_str()?.to_owned(),
+        title: result["title"].as_str().unwrap_or_default().to_owned(),
+        url: original["url"].as_str()?.to_owned(),
+        width: dimension("width")?,
+        height: dimension("height")?,
+        preview_url: preview["url"]
+            .as_str()
+            .or(original["url"].as_str())?
+            .to_owned(),
+    })
+}
+
+fn tenor(result: &Value) -> Option<Gif> {
+    let gif = &result["media_formats"]["gif"];
+    let dimension = |index: usize| gif["dims"][index].as_u64()?.try_into().ok();
+    let preview = &result["media_formats"]["tinygif"];
+    Some(Gif {
+        id: result["id"].as_str()?.to_owned(),
+        title: result["content_description"]
+            .as_str()
+            .unwrap_or_default()
+            .to_owned(),
+        url: gif["url"].as_str()?.to_owned(),
+        width: dimension(0)?,
+        height: dimension(1)?,
+        preview_url: preview["url"].as_str().or(gif["url"].as_str())?.to_owned(),
+    })
+}
+
+#[derive(Deserialize)]
+pub struct SearchQuery {
+    #[serde(default)]
+    q: String,
+    limit: Option<usize>,
+}
+
+fn error(status: StatusCode, message: &str) -> ApiResponse {
+    (status, Json(json!({ "status": message })))
+}
+
+/// `GET /gifs/search?q=&limit=`
+pub async fn search(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<SearchQuery>,
+) -> ApiResponse {
+    let Some(gifs) = &state.gifs else {
+        return error(StatusCode::NOT_FOUND, "GIF search is not enabled.");
+    };
+    let q = query.q.trim();
+    if q.is_empty() || q.chars().count() > MAX_QUERY_LEN {
+        return error(
+            StatusCode::BAD_REQUEST,
+            &format!("Query must be 1 to {} characters.", MAX_QUERY_LEN),
+        );
+    }
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+    match gifs.search(q, limit).await {
+        Some(results) => (
+            StatusCode::OK,
+            Json(json!({ "status": "Success!", "results": results })),
+        ),
+        None => error(StatusCode::BAD_GATEWAY, "GIF search failed."),
+    }
+}
@@ -0,0 +1,269 @@
+//! Gossip between [cluster](crate::cluster) nodes, how they find each other,
+//! notice failures and hand rooms over.
+//!
+//! Every second, each node counts up its heartbeat and sends what it knows,
+//! the URL and latest heartbeat of every node, to a few others picked at
+//! random, `POST /cluster/gossip`, which answer with what they know. A node
+//! whose heartbeat has not gone up for five seconds is taken for failed, and
+//! the rooms are spread over the nodes alive.
+//!
+//! A node that no longer owns some of its rooms hands each over to the new
+//! owner, `PUT /cluster/rooms/:name` with the room's
+//! [snapshot](crate::snapshot) and what else is set up for it, and ends its
+//! members' connections with a `moved` event for them to reconnect. A room
+//! the owner does not take stays, to be handed over again a second later. The rooms
+//! of a failed node start over where their members reconnect, with the
+//! history in the storage.
+//!
+//! All of it goes over the nodes' HTTP API, held to the cluster secret, so
+//! that nodes need no other port or credentials than the ones they serve the
+//! clients and each other's proxied connections with.
+
+use axum::extract::{Path, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::Json;
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::cluster::{Cluster, Entry};
+use crate::events::{ChatEvent, RoomEvent};
+use crate::feeds::Feed;
+use crate::outgoing_webhooks::OutgoingWebhook;
+use crate::recurring::Recurring;
+use crate::room_names::RoomName;
+use crate::rooms;
+use crate::scheduled::ScheduledMessage;
+use crate::slash_commands::SlashCommand;
+use crate::snapshot::{self, Snapshot};
+use crate::webhooks::IncomingWebhook;
+use crate::{ApiResponse, AppState};
+
+const INTERVAL: Duration = Duration::from_secs(1);
+/// Nodes gossiped to each round.
+const FANOUT: usize = 3;
+
+#[derive(Serialize, Deserialize)]
+pub struct Gossip {
+    members: HashMap<String, Entry>,
+}
+
+/// What is set up for a room besides its snapshot, which leaves it out for
+/// the secrets in it.
+#[derive(Default, Serialize, Deserialize)]
+struct Setup {
+    #[serde(default)]
+    owner_key: Option<String>,
+    /// Incoming webhooks, by token.
+    #[serde(default)]
+    webhooks: HashMap<String, IncomingWebhook>,
+    /// Outgoing webhooks, by id.
+    #[serde(default)]
+    outgoing_webhooks: HashMap<String, OutgoingWebhook>,
+    #[serde(default)]
+    slash_commands: BTreeMap<String, SlashCommand>,
+    #[serde(default)]
+    feeds: Vec<Feed>,
+    #[serde(default)]
+    recurring: Vec<Recurring>,
+    #[serde(default)]
+    scheduled: Vec<ScheduledMessage>,
+}
+
+impl Setup {
+    /// Removes the setup of `room` from this node and returns it.
+    fn take(state: &AppState, room: &str) -> Self {
+        let owner_key = state.owners.lock().unwrap().remove(room);
+        let webhooks = {
+            let mut webhooks = state.webhooks.lock().unwrap();
+            let (taken, kept) = webhooks.drain().partition(|(_, hook)| hook.room == room);
+            *webhooks = kept;
+            taken
+        };
+        let outgoing_webhooks = {
+            let mut webhooks = state.outgoing_webhooks.lock().unwrap();
+            let (taken, kept) = webhooks.drain().partition(|(_, hook)| hook.room == room);
+            *webhooks = kept;
+            taken
+        };
+        Self {
+            owner_key,
+            webhooks,
+            outgoing_webhooks,
+            slash_commands: state.slash_commands.take_room(room),
+            feeds: state.feeds.take_room(room),
+            recurring: state.recurring.take_room(room),
+            scheduled: state.schedule.take_room(room),
+        }
+    }
+
+    /// Adds the setup of `room` to this node, which took the room over or
+    /// failed to hand it over.
+    fn restore(self, state: &AppState, room: &str) {
+        if let Some(key) = self.owner_key {
+            state.owners.lock().unwrap().insert(room.to_owned(), key);
+        }
+        state.webhooks.lock().unwrap().extend(self.webhooks);
+        state
+            .outgoing_webhooks
+            .lock()
+            .unwrap()
+            .extend(self.outgoing_webhooks);
+        state.slash_commands.restore_room(room, self.slash_commands);
+        state.feeds.restore_room(room, self.feeds);
+        state.recurring.restore_room(room, self.recurring);
+        state.schedule.restore(self.scheduled);
+    }
+}
+
+/// A room handed over by the node that owned it.
+#[derive(Serialize, Deserialize)]
+pub struct Handover {
+    snapshot: Snapshot,
+    #[serde(default)]
+    setup: Setup,
+}
+
+/// The cluster, if the request is from one of its nodes.
+fn cluster<'a>(state: &'a AppState, headers: &HeaderMap) -> Result<&'a Cluster, ApiResponse> {
+    match &state.cluster {
+        Some(cluster) if cluster.is_node(headers) => Ok(cluster),
+        _ => Err((
+            StatusCode::FORBIDDEN,
+            Json(json!({ "status": "Not a node of the cluster." })),
+        )),
+    }
+}
+
+/// `POST /cluster/gossip`
+pub async fn receive(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(gossip): Json<Gossip>,
+) -> ApiResponse {
+    let cluster = match cluster(&state, &headers) {
+        Ok(cluster) => cluster,
+        Err(response) => return response,
+    };
+    cluster.merge(gossip.members);
+    (
+        StatusCode::OK,
+        Json(json!({ "status": "Success!", "members": cluster.digest() })),
+    )
+}
+
+/// `PUT /cluster/rooms/:name`, a room handed over by the node that owned it.
+pub async fn take_over(
+    Path(room): Path<RoomName>,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(handover): Json<Handover>,
+) -> ApiResponse {
+    if let Err(response) = cluster(&state, &headers) {
+        return response;
+    }
+    handover.setup.restore(&state, &room);
+    snapshot::restore(&state, &room, handover.snapshot).await
+}
+
+/// Gossips every [`INTERVAL`] and hands over the rooms another node owns.
+pub async fn gossiper(state: Arc<AppState>) {
+    let Some(cluster) = &state.cluster else {
+        return;
+    };
+    let client = reqwest::Client::builder()
+        .timeout(INTERVAL)
+        .build()
+        .expect("the TLS backend is available");
+    let mut ticks = tokio::time::interval(INTERVAL);
+    loop {
+        ticks.tick().await;
+        let gossip = Gossip {
+            members: cluster.beat(),
+        };
+        let sent = cluster.peers(FANOUT).into_iter().map(|url| {
+            let request = client
+                .post(format!("{}{}/cluster/gossip", url, state.base_path))
+                .bearer_auth(cluster.secret())
+                .json(&gossip);
+            async move {
+                request
+                    .send()
+                    .await?
+                    .error_for_status()?
+                    .json::<Gossip>()
+                    .await
+            }
+        });
+        // Failed nodes do not answer, which the heartbeats tell.
+        for reply in futures::future::join_all(sent).await.into_iter().flatten() {
+            cluster.merge(reply.members);
+        }
+        // Also retries the rooms the new owners did not take.
+        cluster.sweep();
+        hand_over(&state, cluster, &client).await;
+    }
+}
+
+/// Hands each room another node now owns over to it.
+async fn hand_over(state: &AppState, cluster: &Cluster, client: &reqwest::Client) {
+    let moving = {
+        let rooms = state.rooms.lock().unwrap();
+        rooms
+            .keys()
+            .filter_map(|room| {
+                let owner = cluster.owner(&state.base_path, room)?;
+                Some((room.clone(), owner))
+            })
+            .collect::<Vec<_>>()
+    };
+    for (room, (node, url)) in moving {
+        let Some(room_state) = state.rooms.lock().unwrap().remove(&room) else {
+            continue;
+        };
+        let handover = Handover {
+            snapshot: snapshot::of(state, &room, &room_state),
+            setup: Setup::take(state, &room),
+        };
+        // Before the members reconnect, so that the history is there for them.
+        let mut target =
+            match reqwest::Url::parse(&format!("{}{}/cluster/rooms", url, state.base_path)) {
+                Ok(target) => target,
+                Err(err) => {
+                    error!("Invalid URL of node {}: {}", node, err);
+                    continue;
+                }
+            };
+        if let Ok(mut segments) = target.path_segments_mut() {
+            segments.push(&room);
+        }
+        let handed = client
+            .put(target)
+            .bearer_auth(cluster.secret())
+            .json(&handover)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+        match handed {
+            Ok(_) => info!("Handed room {} over to node {}", room, node),
+            Err(err) => {
+                // Kept, members and all, to be handed over again next time.
+                warn!("Node {} did not take room {} over: {}", node, room, err);
+                handover.setup.restore(state, &room);
+                state.rooms.lock().unwrap().insert(room, room_state);
+                continue;
+            }
+        }
+        {
+            let users = room_state.users.lock().unwrap();
+            for username in users.keys() {
+                state.connections.remove(username, &room);
+            }
+        }
+        room_state.broadcast(ChatEvent::Moved);
+        rooms::publish(state, RoomEvent::RoomDeleted { room });
+    }
+}
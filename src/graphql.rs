@@ -0,0 +1,279 @@
+//! GraphQL API: `POST /graphql` for queries, `GET /graphql` serves GraphiQL
+//! and `/graphql/ws` carries subscriptions over graphql-ws.
+
+use async_graphql::http::{
+    GraphiQLSource, WebSocket as GraphQLWebSocket, WebSocketProtocols as Protocols, WsMessage,
+};
+use async_graphql::{
+    Context, Data, EmptyMutation, ErrorExtensions, Object, Schema, SimpleObject, Subscription,
+};
+use axum::extract::ws::{Message, WebSocketUpgrade};
+use axum::extract::State;
+use axum::http::header::SEC_WEBSOCKET_PROTOCOL;
+use axum::http::HeaderMap;
+use axum::response::{Html, IntoResponse, Response};
+use axum::Json;
+use futures::{SinkExt, Stream, StreamExt};
+use std::sync::Arc;
+
+use crate::events::ChatEvent;
+use crate::rooms::{JoinError, StoredMessage};
+use crate::{backpressure, cluster, AppState};
+
+pub type ChatSchema = Schema<QueryRoot, EmptyMutation, SubscriptionRoot>;
+
+pub fn schema() -> ChatSchema {
+    Schema::build(QueryRoot, EmptyMutation, SubscriptionRoot).finish()
+}
+
+#[derive(SimpleObject)]
+struct Room {
+    name: String,
+    users: Vec<String>,
+    user_count: usize,
+}
+
+#[derive(SimpleObject)]
+struct HistoryMessage {
+    id: u64,
+    from: String,
+    text: String,
+    timestamp: u64,
+}
+
+impl From<StoredMessage> for HistoryMessage {
+    fn from(message: StoredMessage) -> Self {
+        Self {
+            id: message.id,
+            from: message.from,
+            text: message.text,
+            timestamp: message.timestamp,
+        }
+    }
+}
+
+#[derive(SimpleObject)]
+struct RoomEvent {
+    /// One of `message`, `joined`, `left`.
+    kind: String,
+    username: String,
+    text: Option<String>,
+}
+
+/// Fails for a room of another [cluster](crate::cluster) node, with its
+/// `url` in the error's extensions.
+fn served_here(state: &AppState, room: &str) -> async_graphql::Result<()> {
+    match cluster::elsewhere(state, room) {
+        Some((_, url)) => {
+            let err = JoinError::Elsewhere(url.clone());
+            Err(err.extend_with(|_, extensions| extensions.set("url", url)))
+        }
+        None => Ok(()),
+    }
+}
+
+pub struct QueryRoot;
+
+#[Object]
+impl QueryRoot {
+    async fn rooms(&self, ctx: &Context<'_>) -> Vec<Room> {
+        let state = ctx.data_unchecked::<Arc<AppState>>();
+        let rooms = state.rooms.lock().unwrap();
+        rooms
+            .iter()
+            .map(|(name, room)| {
+                let users = room
+                    .users
+                    .lock()
+                    .unwrap()
+                    .keys()
+                    .cloned()
+                    .collect::<Vec<_>>();
+                Room {
+                    name: name.clone(),
+                    user_count: users.len(),
+                    users,
+                }
+            })
+            .collect()
+    }
+
+    async fn room(&self, ctx: &Context<'_>, name: String) -> async_graphql::Result<Option<Room>> {
+        let state = ctx.data_unchecked::<Arc<AppState>>();
+        served_here(state, &name)?;
+        let rooms = state.rooms.lock().unwrap();
+        Ok(rooms.get(&name).map(|room| {
+            let users = room
+                .users
+                .lock()
+                .unwrap()
+                .keys()
+                .cloned()
+                .collect::<Vec<_>>();
+            Room {
+                name,
+                user_count: users.len(),
+                users,
+            }
+        }))
+    }
+
+    /// Most recent messages of a room, oldest first.
+    async fn history(
+        &self,
+        ctx: &Context<'_>,
+        room: String,
+        #[graphql(default = 50)] limit: usize,
+    ) -> async_graphql::Result<Vec<HistoryMessage>> {
+        let state = ctx.data_unchecked::<Arc<AppState>>();
+        served_here(state, &room)?;
+        let rooms = state.rooms.lock().unwrap();
+        let Some(room) = rooms.get(&room) else {
+            return Ok(Vec::new());
+        };
+        let history = room.history.lock().unwrap();
+        let skip = history.len().saturating_sub(limit);
+        Ok(history.iter().skip(skip).cloned().map(Into::into).collect())
+    }
+}
+
+pub struct SubscriptionRoot;
+
+#[Subscription]
+impl SubscriptionRoot {
+    /// Live events of a room, ends when the room closes.
+    async fn messages(
+        &self,
+        ctx: &Context<'_>,
+        room: String,
+    ) -> async_graphql::Result<impl Stream<Item = RoomEvent>> {
+        let state = ctx.data_unchecked::<Arc<AppState>>();
+        served_here(state, &room)?;
+        let rx = state
+            .rooms
+            .lock()
+            .unwrap()
+            .get(&room)
+            .map(|room| room.tx.subscribe())
+            .ok_or("Room not found.")?;
+
+        let subscriber = backpressure::slow_consumers(state, &room).subscriber(&room);
+        Ok(futures::stream::unfold(
+            (rx, subscriber),
+            |(mut rx, mut subscriber)| async move {
+                loop {
+                    let event = match subscriber.recv(&mut rx).await {
+                        Ok(ChatEvent::Message { from, text, .. }) => RoomEvent {
+                            kind: "message".to_owned(),
+                            username: from,
+                            text: Some(text),
+                        },
+                        Ok(ChatEvent::Joined { username, .. }) => RoomEvent {
+                            kind: "joined".to_owned(),
+                            username,
+                            text: None,
+                        },
+                        Ok(ChatEvent::Left { username, .. }) => RoomEvent {
+                            kind: "left".to_owned(),
+                            username,
+                            text: None,
+                        },
+                        Ok(
+                            ChatEvent::Direct { .. }
+                            | ChatEvent::Deleted { .. }
+                            | ChatEvent::Edited { .. }
+                            | ChatEvent::Translated { .. }
+                            | ChatEvent::Announcement { .. }
+                            | ChatEvent::Motd { .. }
+                            | ChatEvent::Signal { .. }
+                            | ChatEvent::Voice { .. }
+                            | ChatEvent::Poll { .. }
+                            | ChatEvent::Scheduled { .. }
+                            | ChatEvent::Unscheduled { .. }
+                            | ChatEvent::Highlight { .. }
+                            | ChatEvent::Presence { .. }
+                            | ChatEvent::Read { .. }
+                            | ChatEvent::Dismissed { .. }
+                            | ChatEvent::Archived
+                            | ChatEvent::Moved
+                            | ChatEvent::Slow { .. }
+                            | ChatEvent::Limited { .. },
+                        ) => continue,
+                        Err(_) => return None,
+                    };
+                    return Some((event, (rx, subscriber)));
+                }
+            },
+        ))
+    }
+}
+
+pub async fn graphiql() -> Html<String> {
+    Html(
+        GraphiQLSource::build()
+            .endpoint("/graphql")
+            .subscription_endpoint("/graphql/ws")
+            .finish(),
+    )
+}
+
+pub async fn execute(
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<async_graphql::Request>,
+) -> Json<async_graphql::Response> {
+    Json(state.graphql.execute(request.data(state.clone())).await)
+}
+
+/// `GET /graphql/ws`, subscriptions over graphql-transport-ws or the legacy
+/// subscriptions-transport-ws protocol, picked from the client's offer.
+pub async fn subscriptions(
+    ws: WebSocketUpgrade,
+    headers: HeaderMap,
+    State(state): State<Arc<AppState>>,
+) -> Response {
+    let protocol = headers
+        .get(SEC_WEBSOCKET_PROTOCOL)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| {
+            value
+                .split(',')
+                .find_map(|protocol| protocol.trim().parse::<Protocols>().ok())
+        })
+        .unwrap_or(Protocols::GraphQLWS);
+
+    ws.protocols([protocol.sec_websocket_protocol()])
+        .on_upgrade(move |socket| async move {
+            let (mut sink, stream) = socket.split();
+            let incoming = stream
+                .take_while(|message| futures::future::ready(message.is_ok()))
+                .filter_map(|message| async move {
+                    match message {
+                        Ok(Message::Text(text)) => Some(text.into_bytes()),
+                        Ok(Message::Binary(bytes)) => Some(bytes),
+                        _ => None,
+                    }
+                });
+
+            let mut data = Data::default();
+            data.insert(state.clone());
+            let mut outgoing = GraphQLWebSocket::new(state.graphql.clone(), incoming, protocol)
+                .connection_data(data)
+                .boxed();
+
+            while let Some(message) = outgoing.next().await {
+                let message = match message {
+                    WsMessage::Text(text) => Message::Text(text),
+                    WsMessage::Close(code, reason) => {
+                        Message::Close(Some(axum::extract::ws::CloseFrame {
+                            code,
+                            reason: reason.into(),
+                        }))
+                    }
+                };
+                if sink.send(message).await.is_err() {
+                    break;
+                }
+            }
+        })
+        .into_response()
+}
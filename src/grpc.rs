@@ -0,0 +1,302 @@
+//! gRPC API on `GRPC_PORT` for backend services, see `proto/chatr.proto`.
+
+use futures::{Stream, StreamExt};
+use log::{error, info};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+use tonic::{Request, Response, Status, Streaming};
+
+use crate::channels::{self, Channel};
+use crate::events::{ChatEvent, Draft};
+use crate::owners::generate_token;
+use crate::room_names::RoomName;
+use crate::rooms::JoinError;
+use crate::slow_consumers::Ended;
+use crate::{backpressure, rooms, tasks, AppState};
+
+mod generated {
+    include!(concat!(env!("OUT_DIR"), "/chatr.Chat.rs"));
+}
+
+use generated::chat_server::{Chat, ChatServer};
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct JoinRequest {
+    #[prost(string, tag = "1")]
+    pub username: String,
+    #[prost(string, tag = "2")]
+    pub room: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct SendRequest {
+    #[prost(string, tag = "1")]
+    pub session: String,
+    #[prost(string, tag = "2")]
+    pub text: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct SendResponse {}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct StreamRequest {
+    #[prost(string, tag = "1")]
+    pub username: String,
+    #[prost(string, tag = "2")]
+    pub room: String,
+    #[prost(string, tag = "3")]
+    pub text: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Event {
+    #[prost(string, tag = "1")]
+    pub kind: String,
+    #[prost(string, tag = "2")]
+    pub username: String,
+    #[prost(string, tag = "3")]
+    pub text: String,
+    #[prost(string, tag = "4")]
+    pub session: String,
+}
+
+impl From<ChatEvent> for Event {
+    fn from(event: ChatEvent) -> Self {
+        let (kind, username, text) = match event {
+            ChatEvent::Message { from, text, .. } => ("message", from, text),
+            ChatEvent::Joined { username, text } => ("joined", username, text.unwrap_or_default()),
+            ChatEvent::Left { username, text } => ("left", username, text.unwrap_or_default()),
+            ChatEvent::Direct { from, text, .. } => ("direct", from, text),
+            ChatEvent::Deleted { id } => ("deleted", String::new(), id.to_string()),
+            event @ ChatEvent::Edited { .. } => ("edited", String::new(), event.to_string()),
+            event @ ChatEvent::Translated { .. } => {
+                ("translated", String::new(), event.to_string())
+            }
+            ChatEvent::Announcement { text } => ("announcement", String::new(), text),
+            ChatEvent::Motd { text } => ("motd", String::new(), text),
+            ChatEvent::Archived => ("archived", String::new(), String::new()),
+            ChatEvent::Moved => ("moved", String::new(), String::new()),
+            event @ ChatEvent::Slow { .. } => ("slow", String::new(), event.to_string()),
+            event @ ChatEvent::Limited { .. } => ("limited", String::new(), event.to_string()),
+            event @ (ChatEvent::Signal { .. } | ChatEvent::Voice { .. }) => {
+                ("voice", String::new(), event.to_string())
+            }
+            event @ (ChatEvent::Scheduled { .. } | ChatEvent::Unscheduled { .. }) => {
+                ("schedule", String::new(), event.to_string())
+            }
+            event @ ChatEvent::Highlight { .. } => ("highlight", String::new(), event.to_string()),
+            event @ ChatEvent::Presence { .. } => ("presence", String::new(), event.to_string()),
+            event @ (ChatEvent::Read { .. } | ChatEvent::Dismissed { .. }) => {
+                ("read", String::new(), event.to_string())
+            }
+            ChatEvent::Poll { poll } => (
+                "poll",
+                poll.creator.clone(),
+                serde_json::to_string(&poll).unwrap_or_default(),
+            ),
+        };
+        Event {
+            kind: kind.to_owned(),
+            username,
+            text,
+            session: String::new(),
+        }
+    }
+}
+
+/// Room membership held by a `Join` stream, addressed by `SendMessage`.
+pub struct GrpcSession {
+    room: String,
+    username: String,
+}
+
+pub type Sessions = Mutex<HashMap<String, GrpcSession>>;
+
+type EventStream = Pin<Box<dyn Stream<Item = Result<Event, Status>> + Send>>;
+
+struct ChatService {
+    state: Arc<AppState>,
+}
+
+pub async fn serve(addr: SocketAddr, state: Arc<AppState>) {
+    info!("gRPC API on {}", addr);
+    let service = ChatServer::new(ChatService { state });
+    if let Err(err) = tonic::transport::Server::builder()
+        .add_service(service)
+        .serve(addr)
+        .await
+    {
+        error!("gRPC server failed: {}", err);
+    }
+}
+
+fn event_stream(rx: mpsc::Receiver<Result<Event, Status>>) -> EventStream {
+    Box::pin(futures::stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|event| (event, rx))
+    }))
+}
+
+/// Reserves the username, announces the join and returns what the member
+/// task needs to relay the room.
+async fn join_room(
+    state: &Arc<AppState>,
+    room: &RoomName,
+    username: &str,
+) -> Result<
+    (
+        Arc<Channel>,
+        channels::Receiver,
+        mpsc::UnboundedReceiver<ChatEvent>,
+    ),
+    Status,
+> {
+    let (direct_tx, direct_rx) = mpsc::unbounded_channel();
+    let rooms::Membership { tx, rx, .. } = rooms::reserve(state, room, username, None, direct_tx)
+        .await
+        .map_err(|err| match err {
+            JoinError::UsernameTaken => Status::already_exists(err.to_string()),
+            JoinError::InvalidUsername => Status::invalid_argument(err.to_string()),
+            JoinError::Forbidden | JoinError::Rejected(_) => {
+                Status::permission_denied(err.to_string())
+            }
+            JoinError::Archived | JoinError::Elsewhere(_) => {
+                Status::failed_precondition(err.to_string())
+            }
+            JoinError::RoomLimit | JoinError::CreationLimit => {
+                Status::resource_exhausted(err.to_string())
+            }
+        })?;
+    rooms::announce_join(state, room, &tx, username).await;
+    Ok((tx, rx, direct_rx))
+}
+
+#[tonic::async_trait]
+impl Chat for ChatService {
+    type JoinStream = EventStream;
+    type StreamStream = EventStream;
+
+    async fn join(&self, request: Request<JoinRequest>) -> Result<Response<EventStream>, Status> {
+        let JoinRequest { username, room } = request.into_inner();
+        let room = RoomName::new(&room).map_err(|err| Status::invalid_argument(err.to_string()))?;
+        let (tx, mut rx, mut direct_rx) = join_room(&self.state, &room, &username).await?;
+
+        let session = generate_token();
+        self.state.grpc_sessions.lock().unwrap().insert(
+            session.clone(),
+            GrpcSession {
+                room: room.to_string(),
+                username: username.clone(),
+            },
+        );
+
+        let (out, out_rx) = mpsc::channel(64);
+        let _ = out
+            .send(Ok(Event {
+                kind: "session".to_owned(),
+                username: username.clone(),
+                text: String::new(),
+                session: session.clone(),
+            }))
+            .await;
+
+        let state = self.state.clone();
+        let mut subscriber = backpressure::slow_consumers(&state, &room).subscriber(&room);
+        tasks::spawn("grpc-join", async move {
+            loop {
+                let event = tokio::select! {
+                    _ = out.closed() => break,
+                    next = subscriber.recv(&mut rx) => match next {
+                        Ok(event) => event,
+                        Err(Ended::TooSlow) => {
+                            let _ = out.send(Err(Status::resource_exhausted("Too slow."))).await;
+                            break;
+                        }
+                        Err(Ended::Closed) => break,
+                    },
+                    Some(event) = direct_rx.recv() => event,
+                };
+                let delivered = event.message_id();
+                if out.send(Ok(event.into())).await.is_err() {
+                    break;
+                }
+                state.metrics.delivered(&room, delivered);
+            }
+            state.grpc_sessions.lock().unwrap().remove(&session);
+            rooms::leave(&state, &room, &tx, &username).await;
+        });
+
+        Ok(Response::new(event_stream(out_rx)))
+    }
+
+    async fn send_message(
+        &self,
+        request: Request<SendRequest>,
+    ) -> Result<Response<SendResponse>, Status> {
+        let SendRequest { session, text } = request.into_inner();
+        let (room, username) = match self.state.grpc_sessions.lock().unwrap().get(&session) {
+            Some(session) => (session.room.clone(), session.username.clone()),
+            None => return Err(Status::not_found("Session expired.")),
+        };
+        if text.is_empty() {
+            return Err(Status::invalid_argument("text must not be empty"));
+        }
+        rooms::post_message(&self.state, &room, &username, Draft::text(text)).await;
+        Ok(Response::new(SendResponse {}))
+    }
+
+    async fn stream(
+        &self,
+        request: Request<Streaming<StreamRequest>>,
+    ) -> Result<Response<EventStream>, Status> {
+        let mut inbound = request.into_inner();
+        let first = inbound
+            .message()
+            .await?
+            .ok_or_else(|| Status::invalid_argument("expected a join request"))?;
+        let room =
+            RoomName::new(&first.room).map_err(|err| Status::invalid_argument(err.to_string()))?;
+        let username = first.username;
+        let (tx, mut rx, mut direct_rx) = join_room(&self.state, &room, &username).await?;
+
+        let (out, out_rx) = mpsc::channel(64);
+        let state = self.state.clone();
+        let mut subscriber = backpressure::slow_consumers(&state, &room).subscriber(&room);
+        tasks::spawn("grpc-stream", async move {
+            loop {
+                let event = tokio::select! {
+                    _ = out.closed() => break,
+                    request = inbound.next() => match request {
+                        Some(Ok(request)) => {
+                            if !request.text.is_empty() {
+                                rooms::post_message(&state, &room, &username, Draft::text(request.text)).await;
+                            }
+                            continue;
+                        }
+                        _ => break,
+                    },
+                    next = subscriber.recv(&mut rx) => match next {
+                        Ok(event) => event,
+                        Err(Ended::TooSlow) => {
+                            let _ = out.send(Err(Status::resource_exhausted("Too slow."))).await;
+                            break;
+                        }
+                        Err(Ended::Closed) => break,
+                    },
+                    Some(event) = direct_rx.recv() => event,
+                };
+                let delivered = event.message_id();
+                if out.send(Ok(event.into())).await.is_err() {
+                    break;
+                }
+                state.metrics.delivered(&room, delivered);
+            }
+            rooms::leave(&state, &room, &tx, &username).await;
+        });
+
+        Ok(Response::new(event_stream(out_rx)))
+    }
+}
@@ -0,0 +1,202 @@
+//! A memory budget for the rooms' in-memory histories together,
+//! `HISTORY_MAX_BYTES`, 256 MiB by default.
+//!
+//! Every room keeps up to [`HISTORY_LEN`] recent messages, however long they
+//! are. Once the histories of all rooms take more than the budget, the oldest
+//! messages of the least recently used rooms, those whose latest message or
+//! join is the longest ago, are evicted from memory until they fit again. A
+//! storage still has them. Evictions are logged and counted in the metrics.
+//!
+//! [`HISTORY_LEN`]: crate::rooms::HISTORY_LEN
+
+use log::{debug, info};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex, Weak};
+
+use crate::rooms::{History, StoredMessage};
+
+pub const DEFAULT_MAX_BYTES: usize = 256 << 20;
+
+/// The memory a message takes in a history, estimated from its JSON.
+pub fn footprint(message: &StoredMessage) -> usize {
+    std::mem::size_of::<StoredMessage>() + serde_json::to_vec(message).map_or(0, |json| json.len())
+}
+
+struct Account {
+    history: Weak<Mutex<VecDeque<StoredMessage>>>,
+    bytes: usize,
+    /// When the room was last used, in ticks of [`Accounts::clock`].
+    used: u64,
+    evicted: u64,
+}
+
+#[derive(Default)]
+struct Accounts {
+    rooms: HashMap<String, Account>,
+    total: usize,
+    clock: u64,
+    /// Whether the last message charged made others be evicted.
+    evicting: bool,
+    evicted_messages: u64,
+    evicted_bytes: u64,
+}
+
+/// The bytes of each room's history, and evictions so far.
+pub struct Usage {
+    pub max_bytes: usize,
+    pub bytes: usize,
+    pub evicted_messages: u64,
+    pub evicted_bytes: u64,
+    /// The bytes and evicted messages of each live room.
+    pub rooms: HashMap<String, (usize, u64)>,
+}
+
+pub struct HistoryBudget {
+    max_bytes: usize,
+    accounts: Mutex<Accounts>,
+}
+
+impl HistoryBudget {
+    pub fn new(max_bytes: usize) -> Self {
+        Self {
+            max_bytes,
+            accounts: Mutex::default(),
+        }
+    }
+
+    pub fn max_bytes_from_env() -> usize {
+        std::env::var("HISTORY_MAX_BYTES")
+            .ok()
+            .and_then(|bytes| bytes.parse().ok())
+            .unwrap_or(DEFAULT_MAX_BYTES)
+    }
+
+    /// Accounts for the history of a room just created, e.g. from storage.
+    /// The history must not be locked.
+    pub fn open(&self, room: &str, history: &History) {
+        let bytes = history.lock().unwrap().iter().map(footprint).sum();
+        let mut accounts = self.accounts.lock().unwrap();
+        let accounts = &mut *accounts;
+        accounts.clock += 1;
+        let account = Account {
+            history: Arc::downgrade(history),
+            bytes,
+            used: accounts.clock,
+            evicted: 0,
+        };
+        if let Some(previous) = accounts.rooms.insert(room.to_owned(), account) {
+            accounts.total -= previous.bytes;
+        }
+        accounts.total += bytes;
+        self.enforce(accounts);
+    }
+
+    /// Accounts for a message of `added` bytes that pushed out `removed`
+    /// bytes of older ones. The room's history must not be locked.
+    pub fn charge(&self, room: &str, added: usize, removed: usize) {
+        let mut accounts = self.accounts.lock().unwrap();
+        let accounts = &mut *accounts;
+        accounts.clock += 1;
+        let clock = accounts.clock;
+        let Some(account) = accounts.rooms.get_mut(room) else {
+            return;
+        };
+        account.used = clock;
+        let before = account.bytes;
+        account.bytes = (account.bytes + added).saturating_sub(removed);
+        accounts.total = accounts.total + account.bytes - before;
+        self.enforce(accounts);
+    }
+
+    /// Marks `room` as used, as a join does.
+    pub fn touch(&self, room: &str) {
+        let mut accounts = self.accounts.lock().unwrap();
+        accounts.clock += 1;
+        let clock = accounts.clock;
+        if let Some(account) = accounts.rooms.get_mut(room) {
+            account.used = clock;
+        }
+    }
+
+    /// Evicts the oldest messages of the least recently used rooms until the
+    /// histories fit the budget. Locks the histories, so none may be locked
+    /// by the caller.
+    fn enforce(&self, accounts: &mut Accounts) {
+        if accounts.total > self.max_bytes {
+            // Rooms gone since still count until now.
+            accounts
+                .rooms
+                .retain(|_, account| account.history.strong_count() > 0);
+            accounts.total = accounts.rooms.values().map(|account| account.bytes).sum();
+        }
+        if accounts.total <= self.max_bytes {
+            accounts.evicting = false;
+            return;
+        }
+        if !accounts.evicting {
+            info!(
+                "Room histories exceed {} bytes, evicting the oldest messages of the least recently used rooms",
+                self.max_bytes
+            );
+            accounts.evicting = true;
+        }
+        let mut rooms = accounts
+            .rooms
+            .iter()
+            .map(|(room, account)| (account.used, room.clone()))
+            .collect::<Vec<_>>();
+        rooms.sort();
+        for (_, room) in rooms {
+            if accounts.total <= self.max_bytes {
+                break;
+            }
+            let account = accounts.rooms.get_mut(&room).unwrap();
+            let Some(history) = account.history.upgrade() else {
+                continue;
+            };
+            let mut history = history.lock().unwrap();
+            // Messages deleted or expired since are only counted again here.
+            let exact = history.iter().map(footprint).sum::<usize>();
+            let mut total = accounts.total - account.bytes + exact;
+            account.bytes = exact;
+            let (mut messages, mut bytes) = (0, 0);
+            while total > self.max_bytes {
+                let Some(message) = history.pop_front() else {
+                    break;
+                };
+                let size = footprint(&message);
+                account.bytes -= size;
+                total -= size;
+                messages += 1;
+                bytes += size;
+            }
+            account.evicted += messages;
+            accounts.total = total;
+            accounts.evicted_messages += messages;
+            accounts.evicted_bytes += bytes as u64;
+            if messages > 0 {
+                debug!(
+                    "Evicted {} messages, {} bytes, of the history of {}",
+                    messages, bytes, room
+                );
+            }
+        }
+    }
+
+    pub fn usage(&self) -> Usage {
+        let accounts = self.accounts.lock().unwrap();
+        let rooms = accounts
+            .rooms
+            .iter()
+            .filter(|(_, account)| account.history.strong_count() > 0)
+            .map(|(room, account)| (room.clone(), (account.bytes, account.evicted)))
+            .collect::<HashMap<_, _>>();
+        Usage {
+            max_bytes: self.max_bytes,
+            bytes: rooms.values().map(|&(bytes, _)| bytes).sum(),
+            evicted_messages: accounts.evicted_messages,
+            evicted_bytes: accounts.evicted_bytes,
+            rooms,
+        }
+    }
+}
@@ -0,0 +1,236 @@
+//! Message catalogs for what the server itself tells WebSocket clients.
+//!
+//! Clients pick a language with the `locale` of their connect payload, else
+//! their `Accept-Language` header is used. Join and leave notices, the
+//! prefixes of direct messages, announcements and the MOTD, and connection
+//! errors are rendered from the catalog of that language; chat passes
+//! through untouched. English, German, French and Spanish are built in.
+//! `LOCALES_DIR` holds more catalogs as `<locale>.json` files mapping keys
+//! to templates, replacing built-in entries of the same key. A catalog's
+//! `motd_text` stands in for the server's MOTD in its language.
+
+use log::{error, info};
+use serde_json::json;
+use std::collections::HashMap;
+use std::path::Path;
+
+use crate::events::ChatEvent;
+use crate::rooms::JoinError;
+use crate::AppState;
+
+/// Used where the client's language has no catalog or no entry.
+pub const DEFAULT_LOCALE: &str = "en";
+
+const BUILTIN: &[(&str, &str)] = &[
+    ("en", include_str!("../locales/en.json")),
+    ("de", include_str!("../locales/de.json")),
+    ("fr", include_str!("../locales/fr.json")),
+    ("es", include_str!("../locales/es.json")),
+];
+
+type Catalog = HashMap<String, String>;
+
+pub struct Catalogs {
+    catalogs: HashMap<String, Catalog>,
+}
+
+/// Lowercases a language tag and uses `-` between its subtags.
+fn normalize(tag: &str) -> String {
+    tag.trim().replace('_', "-").to_ascii_lowercase()
+}
+
+impl Catalogs {
+    /// The built-in catalogs extended with those in `dir`.
+    pub fn load(dir: Option<&Path>) -> Self {
+        let mut catalogs = BUILTIN
+            .iter()
+            .map(|(locale, json)| {
+                let catalog = serde_json::from_str(json).expect("built-in catalogs are valid");
+                ((*locale).to_owned(), catalog)
+            })
+            .collect::<HashMap<String, Catalog>>();
+        let entries = match dir.map(std::fs::read_dir) {
+            Some(Ok(entries)) => entries,
+            Some(Err(err)) => {
+                error!(
+                    "Cannot read catalogs from {}: {}",
+                    dir.unwrap().display(),
+                    err
+                );
+                return Self { catalogs };
+            }
+            None => return Self { catalogs },
+        };
+        for path in entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+        {
+            if path.extension().is_none_or(|extension| extension != "json") {
+                continue;
+            }
+            let Some(locale) = path.file_stem().and_then(|stem| stem.to_str()) else {
+                continue;
+            };
+            let loaded = std::fs::read(&path)
+                .map_err(|err| err.to_string())
+                .and_then(|json| {
+                    serde_json::from_slice::<Catalog>(&json).map_err(|err| err.to_string())
+                });
+            match loaded {
+                Ok(catalog) => {
+                    info!("Loaded catalog {}", path.display());
+                    catalogs
+                        .entry(normalize(locale))
+                        .or_default()
+                        .extend(catalog);
+                }
+                Err(err) => error!("Failed to load catalog {}: {}", path.display(), err),
+            }
+        }
+        Self { catalogs }
+    }
+
+    /// The first of the `requested` languages with a catalog, trying each
+    /// without its region too, e.g. `de` for `de-AT`.
+    pub fn negotiate<'a>(&self, requested: impl IntoIterator<Item = &'a str>) -> String {
+        for tag in requested {
+            let tag = normalize(tag);
+            if self.catalogs.contains_key(&tag) {
+                return tag;
+            }
+            if let Some((language, _)) = tag.split_once('-') {
+                if self.catalogs.contains_key(language) {
+                    return language.to_owned();
+                }
+            }
+        }
+        DEFAULT_LOCALE.to_owned()
+    }
+
+    fn entry(&self, locale: &str, key: &str) -> Option<&str> {
+        self.catalogs
+            .get(locale)
+            .and_then(|catalog| catalog.get(key))
+            .or_else(|| self.catalogs.get(DEFAULT_LOCALE)?.get(key))
+            .map(String::as_str)
+    }
+
+    /// The template `key` of `locale` with its `{placeholders}` filled in
+    /// from `args`, in one pass so that values are never expanded.
+    pub fn format(&self, locale: &str, key: &str, args: &[(&str, &str)]) -> String {
+        let Some(template) = self.entry(locale, key) else {
+            return key.to_owned();
+        };
+        let mut out = String::with_capacity(template.len());
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            out.push_str(&rest[..start]);
+            let after = &rest[start + 1..];
+            let value = after.find('}').and_then(|end| {
+                let value = args.iter().find(|(name, _)| *name == &after[..end])?.1;
+                Some((value, end))
+            });
+            match value {
+                Some((value, end)) => {
+                    out.push_str(value);
+                    rest = &after[end + 1..];
+                }
+                None => {
+                    out.push('{');
+                    rest = after;
+                }
+            }
+        }
+        out.push_str(rest);
+        out
+    }
+}
+
+/// The languages an `Accept-Language` header asks for, in its order.
+pub fn accepted(header: &str) -> Vec<&str> {
+    header
+        .split(',')
+        .filter_map(|range| range.split(';').next())
+        .map(str::trim)
+        .filter(|range| !range.is_empty() && *range != "*")
+        .collect()
+}
+
+/// The frame a WebSocket client speaking `locale` receives for `event`.
+pub fn render(state: &AppState, room: &str, locale: &str, event: &ChatEvent) -> String {
+    let catalogs = &state.catalogs;
+    match event {
+        ChatEvent::Joined {
+            username,
+            text: None,
+        } => catalogs.format(locale, "joined", &[("username", username)]),
+        ChatEvent::Left {
+            username,
+            text: None,
+        } => catalogs.format(locale, "left", &[("username", username)]),
+        ChatEvent::Direct {
+            from,
+            text,
+            offline: false,
+            ..
+        } => catalogs.format(locale, "direct", &[("from", from), ("text", text)]),
+        ChatEvent::Announcement { text } => {
+            catalogs.format(locale, "announcement", &[("text", text)])
+        }
+        ChatEvent::Motd { text } => {
+            let own = state.motd.is_custom(room);
+            let translated = catalogs
+                .catalogs
+                .get(locale)
+                .and_then(|catalog| catalog.get("motd_text"))
+                .filter(|_| !own);
+            let text = translated.unwrap_or(text);
+            catalogs.format(locale, "motd", &[("text", text)])
+        }
+        event => event.to_string(),
+    }
+}
+
+fn error_frame(code: &str, message: &str, connection: &str) -> String {
+    json!({ "type": "error", "code": code, "message": message, "connection": connection })
+        .to_string()
+}
+
+/// An error frame for a WebSocket client, e.g.
+/// `{"type":"error","code":"BINARY_UNSUPPORTED","message":"...","connection":"..."}`
+/// with the message `key` of `locale` and the id of the `connection`.
+pub fn error(state: &AppState, locale: &str, code: &str, key: &str, connection: &str) -> String {
+    error_frame(code, &state.catalogs.format(locale, key, &[]), connection)
+}
+
+/// The `BAD_FRAME` error frame for a frame that is no [`ClientFrame`], with
+/// the `reason` besides the message in `locale`.
+///
+/// [`ClientFrame`]: crate::events::ClientFrame
+pub fn bad_frame(state: &AppState, locale: &str, reason: &str, connection: &str) -> String {
+    let message = state.catalogs.format(locale, "bad_frame", &[]);
+    json!({
+        "type": "error",
+        "code": "BAD_FRAME",
+        "message": message,
+        "reason": reason,
+        "connection": connection,
+    })
+    .to_string()
+}
+
+/// The error frame telling a WebSocket client why its join failed, with
+/// the message in `locale`.
+pub fn join_error(state: &AppState, locale: &str, err: &JoinError, connection: &str) -> String {
+    let message = match err {
+        JoinError::UsernameTaken => state.catalogs.format(locale, "username_taken", &[]),
+        JoinError::InvalidUsername => state.catalogs.format(locale, "invalid_username", &[]),
+        JoinError::Forbidden => state.catalogs.format(locale, "forbidden", &[]),
+        JoinError::Rejected(reason) => reason.clone(),
+        JoinError::Archived => state.catalogs.format(locale, "archived", &[]),
+        JoinError::RoomLimit => state.catalogs.format(locale, "room_limit", &[]),
+        JoinError::CreationLimit => state.catalogs.format(locale, "creation_limit", &[]),
+        JoinError::Elsewhere(url) => state.catalogs.format(locale, "elsewhere", &[("url", url)]),
+    };
+    error_frame(err.code(), &message, connection)
+}
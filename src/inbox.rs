@@ -0,0 +1,165 @@
+//! Direct messages kept for recipients who are not connected anywhere.
+//!
+//! They are delivered, marked `offline`, when the recipient next comes
+//! online, and again on every later connection until acknowledged with a
+//! `direct_ack` frame or `DELETE /users/:name/inbox/:id`. Only WebSocket
+//! connections [coming back](crate::sessions) with the token of one of the
+//! recipient's recent sessions get them, not whoever joins with the name
+//! next. A [`Storage`] keeps them across restarts.
+//!
+//! [`Storage`]: crate::Storage
+
+use axum::extract::{Path, Query, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::Json;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use crate::events::{unix_timestamp, ChatEvent};
+use crate::owners::generate_token;
+use crate::room_names::RoomName;
+use crate::sessions::is_session;
+use crate::{ApiResponse, AppState};
+
+/// Messages kept per recipient, the oldest go first.
+const MAX_KEPT: usize = 100;
+
+#[derive(Clone, Serialize)]
+pub struct StoredDirect {
+    pub id: String,
+    pub from: String,
+    /// The room it was sent from.
+    pub room: String,
+    pub text: String,
+    pub timestamp: u64,
+}
+
+impl From<StoredDirect> for ChatEvent {
+    fn from(message: StoredDirect) -> Self {
+        ChatEvent::Direct {
+            from: message.from,
+            text: message.text,
+            id: Some(message.id),
+            sent_at: Some(message.timestamp),
+            offline: true,
+        }
+    }
+}
+
+/// The waiting messages by recipient, for those loaded from the storage.
+#[derive(Default)]
+pub struct Inbox {
+    messages: Mutex<HashMap<String, Vec<StoredDirect>>>,
+}
+
+/// Loads the messages waiting for `username` unless already loaded. Only
+/// recipients with messages have an entry.
+async fn load(state: &AppState, username: &str) {
+    if state.inbox.messages.lock().unwrap().contains_key(username) {
+        return;
+    }
+    let stored = state.storage.load_directs(username).await;
+    if stored.is_empty() {
+        return;
+    }
+    let mut messages = state.inbox.messages.lock().unwrap();
+    let waiting = messages.entry(username.to_owned()).or_default();
+    for message in stored {
+        if !waiting.iter().any(|kept| kept.id == message.id) {
+            waiting.push(message);
+        }
+    }
+    waiting.sort_by_key(|message| message.timestamp);
+}
+
+/// Keeps `text` from `from` in `room` for `to`.
+pub async fn keep(state: &AppState, room: &str, from: &str, to: &str, text: &str) {
+    load(state, to).await;
+    let message = StoredDirect {
+        id: generate_token(),
+        from: from.to_owned(),
+        room: room.to_owned(),
+        text: text.to_owned(),
+        timestamp: unix_timestamp(),
+    };
+    let dropped = {
+        let mut messages = state.inbox.messages.lock().unwrap();
+        let waiting = messages.entry(to.to_owned()).or_default();
+        waiting.push(message.clone());
+        let excess = waiting.len().saturating_sub(MAX_KEPT);
+        waiting.drain(..excess).collect::<Vec<_>>()
+    };
+    state.storage.save_direct(to, &message).await;
+    for message in dropped {
+        state.storage.delete_direct(to, &message.id).await;
+    }
+}
+
+/// Sends the messages waiting for `username` to their connection when it
+/// is the one that brought them online, which the caller has checked is
+/// theirs.
+pub async fn deliver(state: &AppState, username: &str) {
+    load(state, username).await;
+    let waiting = state
+        .inbox
+        .messages
+        .lock()
+        .unwrap()
+        .get(username)
+        .cloned()
+        .unwrap_or_default();
+    if waiting.is_empty() {
+        return;
+    }
+    if state.connections.count(username) != 1 {
+        return;
+    }
+    for message in waiting {
+        state.connections.send(username, &message.into(), None);
+    }
+}
+
+/// Removes message `id` from the inbox of `username`, false if unknown.
+pub async fn acknowledge(state: &AppState, username: &str, id: &str) -> bool {
+    let removed = {
+        let mut messages = state.inbox.messages.lock().unwrap();
+        let Some(waiting) = messages.get_mut(username) else {
+            return false;
+        };
+        let before = waiting.len();
+        waiting.retain(|message| message.id != id);
+        let removed = waiting.len() < before;
+        if waiting.is_empty() {
+            messages.remove(username);
+        }
+        removed
+    };
+    if removed {
+        state.storage.delete_direct(username, id).await;
+    }
+    removed
+}
+
+#[derive(Deserialize)]
+pub struct AckQuery {
+    room: RoomName,
+}
+
+/// `DELETE /users/:name/inbox/:id?room=`, acknowledges a message.
+pub async fn acknowledge_message(
+    Path((username, id)): Path<(String, String)>,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Query(query): Query<AckQuery>,
+) -> ApiResponse {
+    let (status, message) = if !is_session(&state, &query.room, &username, &headers) {
+        (StatusCode::FORBIDDEN, "Missing or invalid session token.")
+    } else if acknowledge(&state, &username, &id).await {
+        (StatusCode::OK, "Success!")
+    } else {
+        (StatusCode::NOT_FOUND, "No such message.")
+    };
+    (status, Json(json!({ "status": message })))
+}
@@ -0,0 +1,430 @@
+//! Minimal IRC server so irssi/weechat users can join the same rooms.
+//!
+//! Supports NICK, USER, PING, JOIN, PART, PRIVMSG to channels, NAMES and QUIT.
+//! IRC channel `#name` maps onto room `name`, enabled by setting `IRC_PORT`.
+
+use log::{error, info};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+use crate::channels::Channel;
+use crate::events::{ChatEvent, Draft};
+use crate::room_names::RoomName;
+use crate::rooms::JoinError;
+use crate::{backpressure, rooms, tasks, usernames, AppState};
+
+const SERVER: &str = "chatr";
+/// Longest line a client may send, its CR LF included, as in RFC 1459.
+const MAX_LINE: usize = 512;
+
+pub async fn serve(addr: SocketAddr, state: Arc<AppState>) {
+    let listener = match TcpListener::bind(addr).await {
+        Ok(listener) => listener,
+        Err(err) => {
+            error!("Failed to bind IRC listener on {}: {}", addr, err);
+            return;
+        }
+    };
+    info!("IRC gateway on {}", addr);
+
+    loop {
+        match listener.accept().await {
+            Ok((stream, peer)) => {
+                let state = state.clone();
+                tasks::spawn("irc-client", async move {
+                    handle_client(stream, state).await;
+                    info!("IRC client {} disconnected", peer);
+                });
+            }
+            Err(err) => error!("IRC accept failed: {}", err),
+        }
+    }
+}
+
+struct Joined {
+    tx: Arc<Channel>,
+    forward: JoinHandle<()>,
+}
+
+struct Session {
+    state: Arc<AppState>,
+    out: mpsc::UnboundedSender<String>,
+    nick: Option<String>,
+    registered: bool,
+    channels: HashMap<String, Joined>,
+}
+
+async fn handle_client(stream: TcpStream, state: Arc<AppState>) {
+    let (reader, mut writer) = stream.into_split();
+    let (out, mut out_rx) = mpsc::unbounded_channel::<String>();
+
+    let write_task = tasks::spawn("irc-write", async move {
+        while let Some(line) = out_rx.recv().await {
+            if writer.write_all(line.as_bytes()).await.is_err()
+                || writer.write_all(b"\r\n").await.is_err()
+            {
+                break;
+            }
+        }
+    });
+
+    let mut session = Session {
+        state,
+        out,
+        nick: None,
+        registered: false,
+        channels: HashMap::new(),
+    };
+
+    let mut reader = BufReader::new(reader);
+    let mut line = Vec::with_capacity(MAX_LINE);
+    loop {
+        line.clear();
+        let mut limited = (&mut reader).take(MAX_LINE as u64);
+        match limited.read_until(b'\n', &mut line).await {
+            Ok(0) | Err(_) => break,
+            Ok(_) => {}
+        }
+        if line.len() == MAX_LINE && !line.ends_with(b"\n") {
+            let _ = session.out.send("ERROR :Line too long".to_owned());
+            break;
+        }
+        let Ok(line) = std::str::from_utf8(&line) else {
+            break;
+        };
+        if !session.handle_line(line.trim_end()).await {
+            break;
+        }
+    }
+
+    session.part_all().await;
+    drop(session);
+    let _ = write_task.await;
+}
+
+/// `name` as it can stand in a prefix or a NAMES reply, with what IRC gives
+/// meaning to replaced by `_`. Names of members on other transports may
+/// have any of it.
+fn nick_of(name: &str) -> String {
+    let nick = name
+        .chars()
+        .map(|c| {
+            if c.is_whitespace() || c.is_control() || matches!(c, '!' | '@' | ':' | ',') {
+                '_'
+            } else {
+                c
+            }
+        })
+        .collect::<String>();
+    if nick.is_empty() {
+        "_".to_owned()
+    } else {
+        nick
+    }
+}
+
+/// Whether an IRC client may take `nick` as it is.
+fn valid_nick(nick: &str) -> bool {
+    usernames::is_valid(nick) && !nick.starts_with(['#', '&']) && nick_of(nick) == nick
+}
+
+/// The prefix of what `name` does, e.g. `:ann!ann@chatr`.
+fn prefix(name: &str) -> String {
+    let nick = nick_of(name);
+    format!(":{}!{}@{}", nick, nick, SERVER)
+}
+
+/// `command` followed by each line of `text` on a line of its own, so that
+/// line breaks in messages cannot start commands of their own.
+fn lines(command: &str, text: &str) -> String {
+    text.split(['\r', '\n', '\0'])
+        .filter(|line| !line.is_empty())
+        .map(|line| format!("{} :{}", command, line))
+        .collect::<Vec<_>>()
+        .join("\r\n")
+}
+
+/// Splits a line into the command and its parameters, honouring the
+/// trailing `:` parameter.
+fn parse(line: &str) -> Option<(String, Vec<String>)> {
+    let line = match line.strip_prefix(':') {
+        Some(prefixed) => prefixed.split_once(' ')?.1,
+        None => line,
+    };
+    let (head, trailing) = match line.split_once(" :") {
+        Some((head, trailing)) => (head, Some(trailing)),
+        None => (line, None),
+    };
+    let mut parts = head.split_whitespace();
+    let command = parts.next()?.to_uppercase();
+    let mut params = parts.map(str::to_owned).collect::<Vec<_>>();
+    params.extend(trailing.map(str::to_owned));
+    Some((command, params))
+}
+
+impl Session {
+    fn send(&self, line: String) {
+        let _ = self.out.send(line);
+    }
+
+    fn numeric(&self, code: &str, text: &str) {
+        let nick = self.nick.as_deref().unwrap_or("*");
+        self.send(format!(":{} {} {} {}", SERVER, code, nick, text));
+    }
+
+    /// Returns false once the client should be disconnected.
+    async fn handle_line(&mut self, line: &str) -> bool {
+        let Some((command, params)) = parse(line) else {
+            return true;
+        };
+
+        match command.as_str() {
+            "PING" => {
+                let token = params.first().map(String::as_str).unwrap_or(SERVER);
+                self.send(format!(":{} PONG {} :{}", SERVER, SERVER, token));
+            }
+            "NICK" => match params.first() {
+                Some(_) if !self.channels.is_empty() => {
+                    self.numeric("447", ":Cannot change nickname while in channels");
+                }
+                Some(nick) if !valid_nick(nick) => {
+                    self.numeric("432", &format!("{} :Erroneous nickname", nick_of(nick)));
+                }
+                Some(nick) => {
+                    self.nick = Some(nick.clone());
+                    self.welcome();
+                }
+                None => self.numeric("431", ":No nickname given"),
+            },
+            "USER" => {
+                self.registered = true;
+                self.welcome();
+            }
+            "QUIT" => return false,
+            _ if self.nick.is_none() || !self.registered => {
+                self.numeric("451", ":You have not registered");
+            }
+            "JOIN" => {
+                for channel in params.first().into_iter().flat_map(|c| c.split(',')) {
+                    self.join(channel).await;
+                }
+            }
+            "PART" => {
+                for channel in params.first().into_iter().flat_map(|c| c.split(',')) {
+                    self.part(channel).await;
+                }
+            }
+            "NAMES" => {
+                for channel in params.first().into_iter().flat_map(|c| c.split(',')) {
+                    self.names(channel);
+                }
+            }
+            "PRIVMSG" => match (params.first(), params.get(1)) {
+                (Some(target), Some(text)) => self.privmsg(target, text).await,
+                _ => self.numeric("411", ":No recipient given (PRIVMSG)"),
+            },
+            _ => self.numeric("421", &format!("{} :Unknown command", command)),
+        }
+        true
+    }
+
+    fn welcome(&self) {
+        if self.registered && self.nick.is_some() {
+            self.numeric("001", ":Welcome to chatr");
+        }
+    }
+
+    async fn join(&mut self, channel: &str) {
+        let Some(name) = channel
+            .strip_prefix('#')
+            .and_then(|room| RoomName::new(room).ok())
+        else {
+            self.numeric("403", &format!("{} :No such channel", channel));
+            return;
+        };
+        let room = name.as_str();
+        if self.channels.contains_key(room) {
+            return;
+        }
+        let nick = self.nick.clone().unwrap_or_default();
+        let (direct_tx, mut direct_rx) = mpsc::unbounded_channel();
+        let (tx, mut rx) = match rooms::reserve(&self.state, &name, &nick, None, direct_tx).await {
+            Ok(membership) => (membership.tx, membership.rx),
+            Err(JoinError::UsernameTaken) => {
+                self.numeric("433", &format!("{} :Nickname is already in use", nick));
+                return;
+            }
+            Err(JoinError::InvalidUsername) => {
+                self.numeric("432", &format!("{} :Erroneous nickname", nick_of(&nick)));
+                return;
+            }
+            Err(
+                JoinError::Forbidden
+                | JoinError::Rejected(_)
+                | JoinError::Archived
+                | JoinError::RoomLimit
+                | JoinError::CreationLimit
+                | JoinError::Elsewhere(_),
+            ) => {
+                self.numeric("474", &format!("{} :Cannot join channel", channel));
+                return;
+            }
+        };
+
+        let out = self.out.clone();
+        let channel = format!("#{}", room);
+        let own_nick = nick.clone();
+        let state = self.state.clone();
+        let name = room.to_owned();
+        let mut subscriber = backpressure::slow_consumers(&state, room)
+            .without_disconnect()
+            .subscriber(room);
+        let forward = tasks::spawn("irc-forward", async move {
+            loop {
+                let event = tokio::select! {
+                    next = subscriber.recv(&mut rx) => match next {
+                        Ok(event) => event,
+                        Err(_) => break,
+                    },
+                    Some(event) = direct_rx.recv() => event,
+                };
+                let delivered = event.message_id();
+                let line = match event {
+                    ChatEvent::Message { from, .. } if from == own_nick => continue,
+                    ChatEvent::Message {
+                        from,
+                        text,
+                        attachments,
+                        gif,
+                        ..
+                    } => {
+                        let mut text = text;
+                        for attachment in attachments {
+                            text.push_str(&format!(" [{}: {}]", attachment.name, attachment.url));
+                        }
+                        if let Some(gif) = gif {
+                            text.push_str(&format!(" [GIF: {}]", gif.url));
+                        }
+                        lines(&format!("{} PRIVMSG {}", prefix(&from), channel), &text)
+                    }
+                    // IRC cannot edit, so replies that stream in come once done.
+                    ChatEvent::Edited {
+                        from,
+                        text,
+                        partial: false,
+                        ..
+                    } => lines(&format!("{} PRIVMSG {}", prefix(&from), channel), &text),
+                    ChatEvent::Joined { username, .. } if username != own_nick => {
+                        format!("{} JOIN {}", prefix(&username), channel)
+                    }
+                    ChatEvent::Left { username, .. } if username != own_nick => {
+                        format!("{} PART {}", prefix(&username), channel)
+                    }
+                    ChatEvent::Direct { from, text, .. } => {
+                        lines(&format!("{} PRIVMSG {}", prefix(&from), own_nick), &text)
+                    }
+                    ChatEvent::Translated {
+                        from,
+                        text,
+                        language,
+                        ..
+                    } => lines(
+                        &format!(":{} NOTICE {}", SERVER, own_nick),
+                        &format!("[{}] {}: {}", language, nick_of(&from), text),
+                    ),
+                    ChatEvent::Announcement { text } => {
+                        lines(&format!(":{} NOTICE {}", SERVER, channel), &text)
+                    }
+                    ChatEvent::Slow { lag, skipped } => format!(
+                        ":{} NOTICE {} :You are {} events behind, {} skipped",
+                        SERVER, channel, lag, skipped
+                    ),
+                    ChatEvent::Limited { retry_after } => format!(
+                        ":{} NOTICE {} :You are posting too fast, wait {}s",
+                        SERVER, channel, retry_after
+                    ),
+                    ChatEvent::Motd { text } => {
+                        lines(&format!(":{} NOTICE {}", SERVER, channel), &text)
+                    }
+                    _ => continue,
+                };
+                if line.is_empty() {
+                    continue;
+                }
+                if out.send(line).is_err() {
+                    break;
+                }
+                state.metrics.delivered(&name, delivered);
+            }
+        });
+
+        self.send(format!("{} JOIN #{}", prefix(&nick), room));
+        self.channels.insert(
+            room.to_owned(),
+            Joined {
+                tx: tx.clone(),
+                forward,
+            },
+        );
+        self.names(&format!("#{}", room));
+        rooms::announce_join(&self.state, room, &tx, &nick).await;
+    }
+
+    fn names(&self, channel: &str) {
+        let room = channel.trim_start_matches('#');
+        let names = {
+            let rooms = self.state.rooms.lock().unwrap();
+            rooms
+                .get(room)
+                .map(|room| {
+                    room.users
+                        .lock()
+                        .unwrap()
+                        .keys()
+                        .map(|name| nick_of(name))
+                        .collect::<Vec<_>>()
+                        .join(" ")
+                })
+                .unwrap_or_default()
+        };
+        self.numeric("353", &format!("= {} :{}", channel, names));
+        self.numeric("366", &format!("{} :End of /NAMES list", channel));
+    }
+
+    async fn part(&mut self, channel: &str) {
+        let room = channel.trim_start_matches('#');
+        let Some(joined) = self.channels.remove(room) else {
+            self.numeric("442", &format!("{} :You're not on that channel", channel));
+            return;
+        };
+        let nick = self.nick.clone().unwrap_or_default();
+        joined.forward.abort();
+        self.send(format!("{} PART #{}", prefix(&nick), room));
+        rooms::leave(&self.state, room, &joined.tx, &nick).await;
+    }
+
+    async fn privmsg(&self, target: &str, text: &str) {
+        let room = target.trim_start_matches('#');
+        match (target.starts_with('#'), self.channels.get(room)) {
+            (true, Some(_)) => {
+                let nick = self.nick.clone().unwrap_or_default();
+                rooms::post_message(&self.state, room, &nick, Draft::text(text)).await;
+            }
+            (true, None) => self.numeric("404", &format!("{} :Cannot send to channel", target)),
+            (false, _) => self.numeric("401", &format!("{} :No such nick/channel", target)),
+        }
+    }
+
+    async fn part_all(&mut self) {
+        let nick = self.nick.clone().unwrap_or_default();
+        for (room, joined) in std::mem::take(&mut self.channels) {
+            joined.forward.abort();
+            rooms::leave(&self.state, &room, &joined.tx, &nick).await;
+        }
+    }
+}
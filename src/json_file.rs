@@ -0,0 +1,85 @@
+//! The JSON files stores such as the recurring messages are written through
+//! to, replaced whole on every change.
+//!
+//! Stores serialize their state under their own lock, so that versions are
+//! numbered in the order of the changes, and [`JsonFile::save`] writes it on
+//! the blocking pool, so that neither the lock nor an async worker waits on
+//! the disk. A version is only written if no newer one has been.
+
+use log::error;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::sentry;
+
+pub struct JsonFile {
+    path: PathBuf,
+    /// What the file holds, for logs and error reports, e.g. `recurring`.
+    source: &'static str,
+    /// The latest version serialized.
+    version: AtomicU64,
+    /// The latest version written, held while writing.
+    written: Arc<Mutex<u64>>,
+}
+
+impl JsonFile {
+    pub fn new(path: PathBuf, source: &'static str) -> Self {
+        Self {
+            path,
+            source,
+            version: AtomicU64::new(0),
+            written: Arc::default(),
+        }
+    }
+
+    /// Reads the file, starting out empty if it does not exist yet or cannot
+    /// be read.
+    pub fn load<T: DeserializeOwned + Default>(&self) -> T {
+        let path = self.path.display();
+        match std::fs::read(&self.path) {
+            Ok(json) => serde_json::from_slice(&json).unwrap_or_else(|err| {
+                error!("Ignoring unreadable {} file {}: {}", self.source, path, err);
+                T::default()
+            }),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => T::default(),
+            Err(err) => {
+                error!("Failed to read {} file {}: {}", self.source, path, err);
+                T::default()
+            }
+        }
+    }
+
+    /// Serializes `value`, which callers do under the lock guarding it, and
+    /// writes it in the background.
+    pub fn save(&self, value: &impl Serialize) {
+        let json = match serde_json::to_vec(value) {
+            Ok(json) => json,
+            Err(err) => return failed(self.source, &self.path, err),
+        };
+        let version = self.version.fetch_add(1, Ordering::SeqCst) + 1;
+        let (path, source, written) = (self.path.clone(), self.source, self.written.clone());
+        tokio::task::spawn_blocking(move || {
+            let mut written = written.lock().unwrap();
+            if *written > version {
+                return;
+            }
+            // Replaced whole, so a crash mid-write leaves the previous version.
+            let tmp = path.with_extension("tmp");
+            let saved = std::fs::write(&tmp, json).and_then(|()| std::fs::rename(&tmp, &path));
+            *written = version;
+            if let Err(err) = saved {
+                failed(source, &path, err);
+            }
+        });
+    }
+}
+
+fn failed(source: &str, path: &Path, err: impl Display) {
+    error!("Failed to save {} file {}: {}", source, path.display(), err);
+    let err = format!("Failed to save the {} file: {}", source, err);
+    sentry::report_error(source, err, None, None);
+}
@@ -0,0 +1,677 @@
+//! Chat server library, see [`ChatServer::builder`] to embed it in an axum app.
+
+mod announcements;
+mod archive;
+mod assistant;
+mod attachments;
+mod backpressure;
+mod batching;
+mod bots;
+mod channels;
+mod cluster;
+mod components;
+mod connections;
+mod default_rooms;
+mod direct;
+mod directory;
+mod emotes;
+mod events;
+mod feeds;
+mod gifs;
+mod gossip;
+mod graphql;
+mod grpc;
+mod history_budget;
+mod i18n;
+mod inbox;
+mod irc;
+mod json_file;
+mod longpoll;
+mod markdown;
+mod matrix;
+mod metrics;
+mod motd;
+mod mqtt;
+mod notifications;
+mod outgoing_webhooks;
+mod owners;
+mod parts;
+mod plugins;
+mod polls;
+mod presence;
+mod previews;
+mod profiles;
+mod quotas;
+mod rate_limits;
+mod read_state;
+mod recurring;
+mod reminders;
+mod room_names;
+mod room_templates;
+mod rooms;
+mod scheduled;
+mod scripting;
+mod sentry;
+mod server;
+mod sessions;
+mod slash_commands;
+mod slow_consumers;
+mod snapshot;
+mod socketio;
+mod sse;
+mod stats;
+mod system_messages;
+mod systemd;
+mod tags;
+mod tasks;
+mod tenants;
+mod transforms;
+mod translation;
+mod turn;
+mod usernames;
+mod voice;
+mod web_client;
+mod webhooks;
+
+pub use assistant::AssistantConfig;
+pub use attachments::scan::{ClamdScanner, WebhookScanner};
+pub use attachments::{Attachment, AttachmentPolicy, AttachmentStore, Download, Scanner, Verdict};
+pub use cluster::ClusterConfig;
+pub use components::{Component, Interaction, SelectOption, Style};
+pub use events::{App, ChatEvent, Format, RoomEvent};
+pub use gifs::{Gif, GifConfig, GifProvider};
+pub use inbox::StoredDirect;
+pub use notifications::email::{EmailConfig, SmtpSecurity};
+pub use notifications::mobile::{ApnsConfig, FcmConfig};
+pub use notifications::webpush::VapidConfig;
+pub use notifications::{Kind as NotificationKind, Notification, Notifier};
+pub use parts::Part;
+pub use polls::{Poll, PollOption};
+pub use previews::Preview;
+pub use rooms::{AllowAll, Authenticator, Hooks, JoinError, NoStorage, Storage, StoredMessage};
+pub use sentry::{report_error, SentryConfig};
+pub use server::{ChatServer, ChatServerBuilder, ShutdownHandle};
+pub use slow_consumers::Policy as SlowConsumerPolicy;
+pub use systemd::{activated_listener, ActivatedListener};
+pub use tasks::spawn as spawn_task;
+pub use transforms::{Transform, TransformContext};
+pub use translation::{DeepL, LibreTranslate, Translator};
+pub use turn::TurnConfig;
+
+use axum::extract::{ConnectInfo, State};
+use axum::http::header::ACCEPT_LANGUAGE;
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::IntoResponse;
+use axum::{
+    extract::ws::{close_code, CloseFrame, Message, WebSocket, WebSocketUpgrade},
+    Json,
+};
+use events::ClientFrame;
+use futures::{SinkExt, StreamExt};
+use log::{debug, error, info};
+use room_names::RoomName;
+use rooms::RoomState;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use slow_consumers::Ended;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex, OnceLock};
+use tokio::sync::{broadcast, mpsc};
+
+type ApiResponse = (StatusCode, Json<Value>);
+
+struct AppState {
+    rooms: Mutex<HashMap<String, RoomState>>,
+    /// Owner key per room name, handed out by `POST /rooms/:name/claim`.
+    owners: Mutex<HashMap<String, String>>,
+    /// Incoming webhooks keyed by their secret token.
+    webhooks: Mutex<HashMap<String, webhooks::IncomingWebhook>>,
+    /// Outgoing webhooks keyed by id.
+    outgoing_webhooks: Mutex<HashMap<String, outgoing_webhooks::OutgoingWebhook>>,
+    /// Room owners' slash commands bound to URLs.
+    slash_commands: slash_commands::SlashCommands,
+    webhook_deliveries: mpsc::Sender<outgoing_webhooks::Delivery>,
+    bots: Mutex<Vec<bots::Registration>>,
+    matrix: Option<matrix::Bridge>,
+    mqtt: OnceLock<mqtt::MqttBridge>,
+    /// Long-polling sessions keyed by their secret id.
+    poll_sessions: longpoll::Sessions,
+    /// gRPC `Join` streams keyed by session id.
+    grpc_sessions: grpc::Sessions,
+    graphql: graphql::ChatSchema,
+    storage: Arc<dyn Storage>,
+    auth: Arc<dyn Authenticator>,
+    hooks: Vec<Arc<dyn Hooks>>,
+    /// Bearer token for the `/admin` endpoints, which are disabled without one.
+    admin_token: Option<String>,
+    plugins: Option<plugins::PluginHost>,
+    /// Event bus the bridges, webhooks and bots subscribe to.
+    bus: broadcast::Sender<events::RoomEvent>,
+    transforms: transforms::Pipelines,
+    /// Custom emotes of each room, also read by the `emotes` transform.
+    emotes: emotes::Registry,
+    /// Where uploads are kept, attachments are disabled without one.
+    attachments: Option<Arc<dyn attachments::AttachmentStore>>,
+    attachment_policy: attachments::AttachmentPolicy,
+    /// Checks uploads before they are stored, e.g. for malware.
+    attachment_scanner: Option<Arc<dyn attachments::Scanner>>,
+    translator: Option<Arc<dyn translation::Translator>>,
+    attachment_usage: attachments::Usage,
+    /// Fetches link previews for messages, disabled by default.
+    previews: Option<previews::Previews>,
+    /// GIF search, disabled without a provider API key.
+    gifs: Option<gifs::Gifs>,
+    /// What the server says to WebSocket clients, in each language.
+    catalogs: i18n::Catalogs,
+    /// Wording of the join and leave messages, server-wide and per room.
+    system_messages: system_messages::SystemMessages,
+    /// The message of the day, server-wide and per room.
+    motd: motd::Motd,
+    /// The helper bot's rules and canned responses, server-wide and per room.
+    helper: bots::helper::Helper,
+    /// The path the routes are served under, e.g. `/chat`, empty at the root.
+    base_path: String,
+    /// Sent to WebSocket clients in the session frame, e.g. a name and logo.
+    branding: Option<serde_json::Value>,
+    /// Rooms that exist from startup on.
+    default_rooms: Vec<default_rooms::DefaultRoom>,
+    /// Caps on the rooms that exist and that members create.
+    quotas: quotas::Quotas,
+    /// Caps on how fast members, webhooks and bots post.
+    rate_limits: rate_limits::RateLimits,
+    archive: archive::Archive,
+    /// The templates admins create rooms from.
+    room_templates: room_templates::Templates,
+    /// Activity of every room, including inactive ones.
+    stats: stats::Stats,
+    /// Caps the memory the rooms' histories take together.
+    history_budget: Arc<history_budget::HistoryBudget>,
+    /// Which rooms the metrics label, and the latency of messages.
+    metrics: metrics::Metrics,
+    /// What happens to members that fall behind their room.
+    slow_consumers: slow_consumers::SlowConsumers,
+    /// What rooms chose to do instead.
+    backpressure: backpressure::Backpressure,
+    /// How WebSocket clients that asked for batches get them.
+    batching: batching::Batching,
+    /// The capacities rooms' channels are sized within.
+    channel_sizing: channels::Sizing,
+    /// The tags rooms may have, `None` for any.
+    allowed_tags: Option<Vec<String>>,
+    /// Messages waiting for their time to be posted.
+    schedule: scheduled::Schedule,
+    /// The feeds rooms are subscribed to.
+    feeds: feeds::Feeds,
+    /// The messages rooms have posted on a schedule.
+    recurring: recurring::RecurringMessages,
+    /// Every connection of each member.
+    connections: connections::Connections,
+    /// Which WebSocket connection holds each membership.
+    sessions: sessions::Sessions,
+    /// How far members have read each room.
+    read_markers: read_state::ReadMarkers,
+    /// Deliver notifications to members who are offline.
+    notifiers: Vec<Arc<dyn notifications::Notifier>>,
+    notification_preferences: notifications::preferences::Preferences,
+    /// Schedules of the members who opted into digests.
+    digests: notifications::digests::Digests,
+    /// The keywords members watch rooms for.
+    highlights: notifications::highlights::Highlights,
+    /// Direct messages waiting for their recipients.
+    inbox: inbox::Inbox,
+    /// What members tell about themselves, such as their timezone.
+    profiles: profiles::Profiles,
+    /// Who is in do not disturb.
+    presence: presence::Presence,
+    /// Browser push, disabled without a VAPID key.
+    web_push: Option<Arc<notifications::webpush::WebPush>>,
+    /// Push to native apps, disabled without FCM or APNs credentials.
+    mobile_push: Option<Arc<notifications::mobile::MobilePush>>,
+    /// Email digests, disabled without an SMTP server.
+    email: Option<Arc<notifications::email::Email>>,
+    /// Mints TURN credentials for voice chat, disabled without a secret.
+    turn: Option<turn::TurnConfig>,
+    /// The nodes rooms are spread over, if there are several.
+    cluster: Option<cluster::Cluster>,
+}
+
+async fn handler(
+    ws: WebSocketUpgrade,
+    State(state): State<Arc<AppState>>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let accepted = headers
+        .get(ACCEPT_LANGUAGE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+    let locale = state.catalogs.negotiate(i18n::accepted(accepted));
+    // Unknown when embedded without the connect info.
+    let ip = connect_info.map(|ConnectInfo(addr)| addr.ip());
+    // Another node's connection for its client.
+    let internal = state
+        .cluster
+        .as_ref()
+        .and_then(|cluster| cluster.internal(&headers));
+    let proxied = internal.is_some();
+    let ip = internal.unwrap_or(ip);
+    // The id the relaying node gave the connection, if proxied.
+    let id = internal
+        .and_then(|_| headers.get(cluster::CONNECTION_ID))
+        .and_then(|id| id.to_str().ok())
+        .map(str::to_owned)
+        .unwrap_or_else(connections::generate_id);
+    let connection = state.connections.track(id, ip, &locale);
+    ws.on_upgrade(move |socket| handle_socket(socket, state, locale, ip, proxied, connection))
+}
+
+/// Frames in a row that are no [`ClientFrame`] after which a WebSocket
+/// connection is closed.
+const MAX_BAD_FRAMES: u32 = 5;
+
+#[derive(Deserialize)]
+struct Connect {
+    username: String,
+    channel: String,
+    /// Language of the server's notices, e.g. `de` or `pt-BR`.
+    #[serde(default)]
+    locale: Option<String>,
+    /// Token of an earlier session, to take over its membership.
+    #[serde(default)]
+    resume: Option<String>,
+    /// Whether events may come in batches, see the `batching` module.
+    #[serde(default)]
+    batch: bool,
+}
+
+async fn handle_socket(
+    socket: WebSocket,
+    state: Arc<AppState>,
+    locale: String,
+    ip: Option<IpAddr>,
+    proxied: bool,
+    connection: connections::Tracked,
+) {
+    let connection = Arc::new(connection);
+    let id = connection.id.clone();
+    match ip {
+        Some(ip) => debug!("[{}] Connection opened from {}", id, ip),
+        None => debug!("[{}] Connection opened", id),
+    }
+    handle_connection(socket, state, locale, ip, proxied, connection).await;
+    debug!("[{}] Connection closed", id);
+}
+
+async fn handle_connection(
+    socket: WebSocket,
+    state: Arc<AppState>,
+    mut locale: String,
+    ip: Option<IpAddr>,
+    proxied: bool,
+    connection: Arc<connections::Tracked>,
+) {
+    let id = connection.id.clone();
+    let (mut sender, mut receiver) = socket.split();
+    let mut username = String::new();
+    let mut channel = String::new();
+    let mut batch = false;
+    let mut membership = None::<rooms::Membership>;
+    let mut session = None::<sessions::Session>;
+    // Whether the member proved coming back, with the token of a session of
+    // theirs that ended.
+    let mut returning = false;
+    let (direct_tx, mut direct_rx) = mpsc::unbounded_channel::<ChatEvent>();
+
+    // A taken username or an invalid room name can be corrected with another
+    // connect payload, any other failure closes the connection.
+    while let Some(msg) = receiver.next().await {
+        connection.received();
+        let payload = match msg {
+            Ok(Message::Text(payload)) => payload,
+            Ok(Message::Binary(_)) => {
+                let frame = i18n::error(
+                    &state,
+                    &locale,
+                    "BINARY_UNSUPPORTED",
+                    "binary_unsupported",
+                    &id,
+                );
+                let _ = sender.send(Message::Text(frame)).await;
+                connection.sent();
+                continue;
+            }
+            // Pings are answered by the WebSocket layer.
+            Ok(Message::Ping(_) | Message::Pong(_)) => continue,
+            Ok(Message::Close(_)) => return,
+            Err(err) => {
+                info!("[{}] WebSocket error before joining: {}", id, err);
+                return;
+            }
+        };
+        let connect: Connect = match serde_json::from_str(&payload) {
+            Ok(connect) => connect,
+            Err(err) => {
+                error!("[{}] Error {}, payload: {}", id, err, &payload);
+                let failed = state.catalogs.format(&locale, "connect_failed", &[]);
+                let _ = sender.send(Message::Text(failed)).await;
+                return;
+            }
+        };
+        batch = connect.batch;
+        if let Some(requested) = &connect.locale {
+            locale = state.catalogs.negotiate([requested.as_str()]);
+            connection.update(|status| status.locale = locale.clone());
+        }
+        let Ok(room) = RoomName::new(&connect.channel) else {
+            let frame = i18n::error(&state, &locale, "INVALID_ROOM", "invalid_room", &id);
+            let _ = sender.send(Message::Text(frame)).await;
+            connection.sent();
+            continue;
+        };
+        let owner = state
+            .cluster
+            .as_ref()
+            .filter(|_| !proxied)
+            .and_then(|cluster| cluster.owner(&state.base_path, &room));
+        if let Some(owner) = owner {
+            connection.update(|status| {
+                status.username = Some(connect.username.clone());
+                status.room = Some(room.to_string());
+                status.node = Some(owner.0.clone());
+            });
+            let proxied = (sender, receiver);
+            cluster::proxy(&state, owner, proxied, payload, &locale, ip, &connection).await;
+            return;
+        }
+
+        match rooms::reserve(&state, &room, &connect.username, ip, direct_tx.clone()).await {
+            Ok(joined) => {
+                membership = Some(joined);
+                returning = connect
+                    .resume
+                    .as_deref()
+                    .is_some_and(|token| state.sessions.returning(&connect.username, token));
+                username = connect.username;
+                channel = room.into();
+                break;
+            }
+            Err(JoinError::UsernameTaken) if connect.resume.is_some() => {
+                let resume = connect.resume.as_deref().unwrap_or_default();
+                let resumed = state.sessions.take_over(&room, &connect.username, resume);
+                let joined = resumed.as_ref().and_then(|_| {
+                    rooms::take_over(&state, &room, &connect.username, direct_tx.clone())
+                });
+                if joined.is_some() {
+                    membership = joined;
+                    session = resumed;
+                    username = connect.username;
+                    channel = room.into();
+                    break;
+                }
+                let frame = i18n::join_error(&state, &locale, &JoinError::UsernameTaken, &id);
+                let _ = sender.send(Message::Text(frame)).await;
+                connection.sent();
+            }
+            Err(err) => {
+                let frame = i18n::join_error(&state, &locale, &err, &id);
+                let _ = sender.send(Message::Text(frame)).await;
+                connection.sent();
+                let retry = matches!(
+                    err,
+                    JoinError::UsernameTaken
+                        | JoinError::InvalidUsername
+                        | JoinError::RoomLimit
+                        | JoinError::CreationLimit
+                );
+                if !retry {
+                    return;
+                }
+            }
+        }
+    }
+
+    let Some(rooms::Membership {
+        tx,
+        mut rx,
+        roster,
+        history,
+    }) = membership
+    else {
+        return;
+    };
+
+    // The roster and history are what the room was when `rx` subscribed, so
+    // everything after them, the member's own join first, arrives live.
+    let resumed = session.is_some();
+    debug!("[{}] Joined {} as {}", id, channel, username);
+    connection.update(|status| {
+        status.username = Some(username.clone());
+        status.room = Some(channel.clone());
+    });
+    let session = session.unwrap_or_else(|| state.sessions.open(&channel, &username));
+    let mut hello = json!({ "type": "session", "token": session.token, "connection": id });
+    if let Some(branding) = &state.branding {
+        hello["branding"] = branding.clone();
+    }
+    if let Some(cluster) = &state.cluster {
+        hello["affinity"] = cluster.affinity().into();
+    }
+    let frames = [
+        hello,
+        json!({ "type": "roster", "users": roster, "bots": bots::roster(&state, &channel) }),
+        json!({ "type": "history", "messages": history }),
+    ];
+    let mut sent = true;
+    for frame in frames {
+        sent = sent && sender.send(Message::Text(frame.to_string())).await.is_ok();
+        connection.sent();
+    }
+    if !sent {
+        if state.sessions.close(&channel, &username, &session.token) {
+            rooms::leave(&state, &channel, &tx, &username).await;
+        }
+        return;
+    }
+    if !resumed {
+        rooms::announce_join(&state, &channel, &tx, &username).await;
+    }
+    if returning {
+        inbox::deliver(&state, &username).await;
+    }
+
+    // Frames answering this connection only, such as errors, and the close
+    // frame ending it.
+    let (replies, mut replies_rx) = mpsc::unbounded_channel::<Message>();
+
+    let mut recv_messages = {
+        let state = state.clone();
+        let room = channel.clone();
+        let locale = locale.clone();
+        let connection = connection.clone();
+        let subscriber = backpressure::slow_consumers(&state, &channel).subscriber(&channel);
+        let mut batcher = state.batching.batcher(subscriber, batch);
+        tasks::spawn("ws-send", async move {
+            loop {
+                let mut delivered = Vec::new();
+                let frame = tokio::select! {
+                    next = batcher.recv(&mut rx) => match next.as_deref() {
+                        Ok([ChatEvent::Archived]) => {
+                            let _ = sender.send(Message::Text(ChatEvent::Archived.to_string())).await;
+                            let _ = sender
+                                .send(Message::Close(Some(CloseFrame {
+                                    code: close_code::NORMAL,
+                                    reason: "Room archived.".into(),
+                                })))
+                                .await;
+                            break;
+                        }
+                        Ok([ChatEvent::Moved]) => {
+                            let _ = sender.send(Message::Text(ChatEvent::Moved.to_string())).await;
+                            let _ = sender
+                                .send(Message::Close(Some(CloseFrame {
+                                    code: close_code::RESTART,
+                                    reason: "Room moved, reconnect.".into(),
+                                })))
+                                .await;
+                            break;
+                        }
+                        Ok(events) => {
+                            connection.update(|status| {
+                                status.lag = batcher.subscriber.lag;
+                                status.skipped = batcher.subscriber.skipped;
+                            });
+                            delivered.extend(events.iter().filter_map(ChatEvent::message_id));
+                            let mut frames = events
+                                .iter()
+                                .map(|event| i18n::render(&state, &room, &locale, event));
+                            match events {
+                                [_] => Message::Text(frames.next().unwrap()),
+                                _ => Message::Text(json!(frames.collect::<Vec<_>>()).to_string()),
+                            }
+                        }
+                        Err(Ended::TooSlow) => Message::Close(Some(CloseFrame {
+                            code: slow_consumers::TOO_SLOW,
+                            reason: "Too slow.".into(),
+                        })),
+                        Err(Ended::Closed) => break,
+                    },
+                    Some(msg) = direct_rx.recv() => {
+                        Message::Text(i18n::render(&state, &room, &locale, &msg))
+                    }
+                    Some(frame) = replies_rx.recv() => frame,
+                };
+                let closing = matches!(frame, Message::Close(_));
+                if sender.send(frame).await.is_err() || closing {
+                    break;
+                }
+                connection.sent();
+                for id in delivered {
+                    state.metrics.delivered(&room, Some(id));
+                }
+            }
+        })
+    };
+
+    let mut send_messages = {
+        let name = username.clone();
+        let room = channel.clone();
+        let state = state.clone();
+        tasks::spawn("ws-receive", async move {
+            let mut bad_frames = 0;
+            while let Some(msg) = receiver.next().await {
+                connection.received();
+                let text = match msg {
+                    Ok(Message::Text(text)) => text,
+                    Ok(Message::Binary(_)) => {
+                        let _ = replies.send(Message::Text(i18n::error(
+                            &state,
+                            &locale,
+                            "BINARY_UNSUPPORTED",
+                            "binary_unsupported",
+                            &id,
+                        )));
+                        continue;
+                    }
+                    Ok(Message::Ping(_) | Message::Pong(_)) => continue,
+                    Ok(Message::Close(_)) => break,
+                    Err(err) => {
+                        info!(
+                            "[{}] WebSocket error from {} in {}: {}",
+                            id, name, room, err
+                        );
+                        break;
+                    }
+                };
+                let frame = match ClientFrame::parse(&text) {
+                    Ok(frame) => frame,
+                    Err(reason) => {
+                        bad_frames += 1;
+                        let error = i18n::bad_frame(&state, &locale, &reason, &id);
+                        let _ = replies.send(Message::Text(error));
+                        if bad_frames >= MAX_BAD_FRAMES {
+                            info!(
+                                "[{}] Disconnecting {} from {} after bad frames",
+                                id, name, room
+                            );
+                            let _ = replies.send(Message::Close(Some(CloseFrame {
+                                code: close_code::POLICY,
+                                reason: "Too many bad frames.".into(),
+                            })));
+                            // Until the close frame is out, which ends the connection.
+                            replies.closed().await;
+                            break;
+                        }
+                        continue;
+                    }
+                };
+                bad_frames = 0;
+                match frame {
+                    ClientFrame::Message(draft) => {
+                        rooms::post_message(&state, &room, &name, draft).await
+                    }
+                    ClientFrame::VoiceJoin => voice::join(&state, &room, &name),
+                    ClientFrame::VoiceLeave => voice::leave(&state, &room, &name),
+                    ClientFrame::Signal { to, signal } => {
+                        voice::relay(&state, &room, &name, &to, signal)
+                    }
+                    ClientFrame::PollCreate { question, options } => {
+                        polls::create(&state, &room, &name, &question, &options)
+                    }
+                    ClientFrame::PollVote { poll, option } => {
+                        polls::vote(&state, &room, &name, poll, option)
+                    }
+                    ClientFrame::PollClose { poll } => polls::close(&state, &room, &name, poll),
+                    ClientFrame::Interact {
+                        message,
+                        component,
+                        value,
+                    } => components::interact(&state, &room, &name, message, &component, value),
+                    ClientFrame::Schedule { send_at, message } => {
+                        scheduled::schedule(&state, &room, &name, send_at, message)
+                    }
+                    ClientFrame::ScheduleCancel { id } => {
+                        scheduled::cancel(&state, &room, &name, &id)
+                    }
+                    ClientFrame::Direct { to, text } => {
+                        direct::send(&state, &room, &name, &to, &text).await
+                    }
+                    ClientFrame::Read { room: read, id } => {
+                        let read = read.as_deref().unwrap_or(&room);
+                        read_state::mark_read(&state, &name, read, id, Some(&room))
+                    }
+                    ClientFrame::Dismiss { room: dismissed } => {
+                        let dismissed = dismissed.as_deref().unwrap_or(&room);
+                        read_state::dismiss(&state, &name, dismissed, Some(&room))
+                    }
+                    ClientFrame::DirectAck { id } => {
+                        inbox::acknowledge(&state, &name, &id).await;
+                    }
+                    ClientFrame::Presence { status } => presence::set_status(&state, &name, status),
+                    ClientFrame::Highlights { keywords } => {
+                        if notifications::highlights::invalid(&keywords).is_none() {
+                            state.highlights.set(&name, &room, keywords)
+                        }
+                    }
+                    ClientFrame::Notifications { level } => {
+                        state.notification_preferences.set(&name, &room, level)
+                    }
+                }
+            }
+        })
+    };
+
+    tokio::select! {
+        _ = (&mut send_messages) => recv_messages.abort(),
+        _ = (&mut recv_messages) => send_messages.abort(),
+        _ = session.taken_over() => {
+            send_messages.abort();
+            recv_messages.abort();
+        }
+    }
+
+    // Left to the connection that took the membership over, if any.
+    if state.sessions.close(&channel, &username, &session.token) {
+        rooms::leave(&state, &channel, &tx, &username).await;
+    }
+}
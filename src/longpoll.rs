@@ -0,0 +1,271 @@
+//! HTTP long-polling transport for networks that block WebSockets.
+//!
+//! A poll without a session joins the room and returns a session id; later
+//! polls pass the session and the last cursor they saw and wait for newer
+//! events. Sessions that stop polling are expired and leave the room.
+
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::Json;
+use log::info;
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, Notify};
+use tokio::task::JoinHandle;
+
+use crate::channels::Channel;
+use crate::events::{ChatEvent, Draft};
+use crate::owners::generate_token;
+use crate::room_names::RoomName;
+use crate::rooms::JoinError;
+use crate::{backpressure, rooms, tasks, ApiResponse, AppState};
+
+const POLL_TIMEOUT: Duration = Duration::from_secs(25);
+const SESSION_TIMEOUT: Duration = Duration::from_secs(60);
+const MAX_BUFFERED: usize = 500;
+
+pub struct PollSession {
+    room: String,
+    username: String,
+    tx: Arc<Channel>,
+    inbox: Arc<Inbox>,
+    forward: JoinHandle<()>,
+}
+
+/// Events received for a session but not yet acknowledged by a poll cursor.
+struct Inbox {
+    buffer: Mutex<Buffer>,
+    notify: Notify,
+}
+
+struct Buffer {
+    events: VecDeque<(u64, ChatEvent)>,
+    next_seq: u64,
+    last_poll: Instant,
+}
+
+#[derive(Deserialize)]
+pub struct PollRequest {
+    session: Option<String>,
+    username: Option<String>,
+    #[serde(default)]
+    cursor: u64,
+}
+
+#[derive(Deserialize)]
+pub struct SendRequest {
+    session: String,
+    #[serde(flatten)]
+    message: Draft,
+}
+
+#[derive(Deserialize)]
+pub struct LeaveRequest {
+    session: String,
+}
+
+fn error(status: StatusCode, message: &str) -> ApiResponse {
+    (status, Json(json!({ "status": message })))
+}
+
+fn session(state: &AppState, room: &str, id: &str) -> Option<Arc<PollSession>> {
+    state
+        .poll_sessions
+        .lock()
+        .unwrap()
+        .get(id)
+        .filter(|session| session.room == room)
+        .cloned()
+}
+
+/// `POST /rooms/:name/poll`
+pub async fn poll(
+    Path(room): Path<RoomName>,
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<PollRequest>,
+) -> ApiResponse {
+    let Some(id) = request.session else {
+        return start_session(&state, room, request.username.unwrap_or_default()).await;
+    };
+    let Some(session) = session(&state, &room, &id) else {
+        return error(StatusCode::NOT_FOUND, "Session expired.");
+    };
+
+    let deadline = Instant::now() + POLL_TIMEOUT;
+    loop {
+        // Register interest before checking so an event arriving in between still wakes us.
+        let notified = session.inbox.notify.notified();
+        let events = {
+            let mut buffer = session.inbox.buffer.lock().unwrap();
+            buffer.last_poll = Instant::now();
+            while buffer
+                .events
+                .front()
+                .is_some_and(|(seq, _)| *seq <= request.cursor)
+            {
+                buffer.events.pop_front();
+            }
+            buffer
+                .events
+                .iter()
+                .map(|(seq, event)| json!({ "seq": seq, "event": event }))
+                .collect::<Vec<_>>()
+        };
+
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if !events.is_empty() || remaining.is_zero() {
+            let cursor = events
+                .last()
+                .and_then(|event| event["seq"].as_u64())
+                .unwrap_or(request.cursor);
+            return (
+                StatusCode::OK,
+                Json(json!({ "status": "Success!", "cursor": cursor, "events": events })),
+            );
+        }
+        let _ = tokio::time::timeout(remaining, notified).await;
+    }
+}
+
+async fn start_session(state: &Arc<AppState>, room: RoomName, username: String) -> ApiResponse {
+    let (direct_tx, mut direct_rx) = mpsc::unbounded_channel();
+    let (tx, mut rx) = match rooms::reserve(state, &room, &username, None, direct_tx).await {
+        Ok(membership) => (membership.tx, membership.rx),
+        Err(err @ JoinError::UsernameTaken) => {
+            return error(StatusCode::CONFLICT, &err.to_string())
+        }
+        Err(err @ JoinError::InvalidUsername) => {
+            return error(StatusCode::BAD_REQUEST, &err.to_string())
+        }
+        Err(err @ (JoinError::RoomLimit | JoinError::CreationLimit)) => {
+            return error(StatusCode::TOO_MANY_REQUESTS, &err.to_string())
+        }
+        Err(err @ JoinError::Elsewhere(_)) => {
+            return error(StatusCode::MISDIRECTED_REQUEST, &err.to_string())
+        }
+        Err(err) => return error(StatusCode::FORBIDDEN, &err.to_string()),
+    };
+
+    let inbox = Arc::new(Inbox {
+        buffer: Mutex::new(Buffer {
+            events: VecDeque::new(),
+            next_seq: 0,
+            last_poll: Instant::now(),
+        }),
+        notify: Notify::new(),
+    });
+    let forward = {
+        let inbox = inbox.clone();
+        let mut subscriber = backpressure::slow_consumers(state, &room)
+            .without_disconnect()
+            .subscriber(&room);
+        tasks::spawn("longpoll-forward", async move {
+            loop {
+                let event = tokio::select! {
+                    next = subscriber.recv(&mut rx) => match next {
+                        Ok(event) => event,
+                        Err(_) => break,
+                    },
+                    Some(event) = direct_rx.recv() => event,
+                };
+                {
+                    let mut buffer = inbox.buffer.lock().unwrap();
+                    buffer.next_seq += 1;
+                    let seq = buffer.next_seq;
+                    buffer.events.push_back((seq, event));
+                    if buffer.events.len() > MAX_BUFFERED {
+                        buffer.events.pop_front();
+                    }
+                }
+                inbox.notify.notify_waiters();
+            }
+        })
+    };
+
+    let id = generate_token();
+    let session = Arc::new(PollSession {
+        room: room.to_string(),
+        username: username.clone(),
+        tx: tx.clone(),
+        inbox,
+        forward,
+    });
+    state
+        .poll_sessions
+        .lock()
+        .unwrap()
+        .insert(id.clone(), session);
+
+    rooms::announce_join(state, &room, &tx, &username).await;
+    (
+        StatusCode::CREATED,
+        Json(json!({ "status": "Success!", "session": id, "cursor": 0, "events": [] })),
+    )
+}
+
+/// `POST /rooms/:name/messages`
+pub async fn send_message(
+    Path(room): Path<RoomName>,
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<SendRequest>,
+) -> ApiResponse {
+    let Some(session) = session(&state, &room, &request.session) else {
+        return error(StatusCode::NOT_FOUND, "Session expired.");
+    };
+    if request.message.is_empty() {
+        return error(StatusCode::BAD_REQUEST, "Message text must not be empty.");
+    }
+    rooms::post_message(&state, &room, &session.username, request.message).await;
+    (StatusCode::OK, Json(json!({ "status": "Success!" })))
+}
+
+/// `POST /rooms/:name/leave`
+pub async fn leave(
+    Path(room): Path<RoomName>,
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<LeaveRequest>,
+) -> ApiResponse {
+    if session(&state, &room, &request.session).is_none() {
+        return error(StatusCode::NOT_FOUND, "Session expired.");
+    }
+    let session = state.poll_sessions.lock().unwrap().remove(&request.session);
+    if let Some(session) = session {
+        close(&state, &session).await;
+    }
+    (StatusCode::OK, Json(json!({ "status": "Success!" })))
+}
+
+async fn close(state: &AppState, session: &PollSession) {
+    session.forward.abort();
+    rooms::leave(state, &session.room, &session.tx, &session.username).await;
+}
+
+/// Periodically drops sessions that stopped polling.
+pub async fn sweeper(state: Arc<AppState>) {
+    let mut interval = tokio::time::interval(SESSION_TIMEOUT / 4);
+    loop {
+        interval.tick().await;
+        let expired = {
+            let mut sessions = state.poll_sessions.lock().unwrap();
+            let ids = sessions
+                .iter()
+                .filter(|(_, session)| {
+                    session.inbox.buffer.lock().unwrap().last_poll.elapsed() > SESSION_TIMEOUT
+                })
+                .map(|(id, _)| id.clone())
+                .collect::<Vec<_>>();
+            ids.iter()
+                .filter_map(|id| sessions.remove(id))
+                .collect::<Vec<_>>()
+        };
+        for session in expired {
+            info!("Long-poll session of {} expired", session.username);
+            close(&state, &session).await;
+        }
+    }
+}
+
+pub type Sessions = Mutex<HashMap<String, Arc<PollSession>>>;
@@ -0,0 +1,291 @@
+//! The Markdown subset messages may be formatted with.
+//!
+//! Supported are `**bold**`, `*italic*`, `` `code` `` and `[links](url)` to
+//! `http`, `https` and `mailto` URLs. [`normalize`] rewrites a message to a
+//! canonical form: `__bold__` and `_italic_` use asterisks, and every `*`,
+//! `_`, `` ` ``, `[`, `]` or `\` that is not part of one of these constructs
+//! is escaped with a backslash. Clients render exactly the subset and take
+//! everything else, HTML included, as literal text.
+
+/// Characters with a meaning in the subset, escaped where they are literal.
+const SPECIAL: &[char] = &['*', '_', '`', '[', ']', '\\'];
+
+use std::collections::HashMap;
+
+const SCHEMES: &[&str] = &["http://", "https://", "mailto:"];
+
+/// Constructs still allowed at a nesting level, none may contain itself.
+#[derive(Clone, Copy)]
+struct Allowed {
+    bold: bool,
+    italic: bool,
+    link: bool,
+}
+
+/// Rewrites `text` to the canonical form of the subset.
+pub fn normalize(text: &str) -> String {
+    let mut normalized = String::with_capacity(text.len());
+    inline(
+        text,
+        Allowed {
+            bold: true,
+            italic: true,
+            link: true,
+        },
+        &mut normalized,
+    );
+    normalized
+}
+
+/// Escapes `text` so that it reads literally in a formatted message.
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if SPECIAL.contains(&c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// How a search moves on from the start of what is left of the text.
+enum Step {
+    Found,
+    Failed,
+    Skip(usize),
+}
+
+/// What searches in one text found, so that none passes a position another
+/// already has, which would take quadratic time on texts of many unclosed
+/// delimiters or brackets.
+struct Searches<'a> {
+    text: &'a str,
+    /// For each search and offset passed, where the search ended.
+    found: HashMap<(&'a str, usize), Option<usize>>,
+    /// The offset of the `]` closing each `[`, matched in one pass.
+    brackets: Option<HashMap<usize, usize>>,
+}
+
+impl<'a> Searches<'a> {
+    fn new(text: &'a str) -> Self {
+        Self {
+            text,
+            found: HashMap::new(),
+            brackets: None,
+        }
+    }
+
+    /// The offset of `rest`, a suffix of the text.
+    fn offset(&self, rest: &str) -> usize {
+        self.text.len() - rest.len()
+    }
+
+    /// Where `step` first finds what the search `name` looks for, from
+    /// `start` on. A search reaching an offset an earlier one passed takes
+    /// over its result, as its steps from there on are the same.
+    fn find(&mut self, name: &'a str, start: usize, step: impl Fn(&str) -> Step) -> Option<usize> {
+        let mut passed = Vec::new();
+        let mut at = start;
+        let found = loop {
+            if at >= self.text.len() {
+                break None;
+            }
+            if let Some(&found) = self.found.get(&(name, at)) {
+                break found;
+            }
+            passed.push(at);
+            match step(&self.text[at..]) {
+                Step::Found => break Some(at),
+                Step::Failed => break None,
+                Step::Skip(len) => at += len,
+            }
+        };
+        for at in passed {
+            self.found.insert((name, at), found);
+        }
+        found
+    }
+
+    /// The offset of the `]` closing the `[` at `start`, outside escapes.
+    fn bracket(&mut self, start: usize) -> Option<usize> {
+        let text = self.text;
+        let brackets = self.brackets.get_or_insert_with(|| {
+            let mut open = Vec::new();
+            let mut brackets = HashMap::new();
+            let mut escaped = false;
+            for (at, c) in text.char_indices() {
+                match c {
+                    _ if escaped => escaped = false,
+                    '\\' => escaped = true,
+                    '[' => open.push(at),
+                    ']' => {
+                        if let Some(start) = open.pop() {
+                            brackets.insert(start, at);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            brackets
+        });
+        brackets.get(&start).copied()
+    }
+}
+
+fn inline(text: &str, allowed: Allowed, out: &mut String) {
+    let mut searches = Searches::new(text);
+    let mut at = 0;
+    while let Some(c) = text[at..].chars().next() {
+        let rest = &text[at..];
+        let before = text[..at].chars().next_back();
+        if c == '\\' {
+            match rest[1..].chars().next() {
+                Some(next) if next.is_ascii_punctuation() => {
+                    out.push('\\');
+                    out.push(next);
+                    at += 2;
+                }
+                _ => {
+                    out.push_str("\\\\");
+                    at += 1;
+                }
+            }
+            continue;
+        }
+        if c == '`' {
+            if let Some(end) = rest[1..].find('`').filter(|end| *end > 0) {
+                out.push_str(&rest[..end + 2]);
+                at += end + 2;
+                continue;
+            }
+        }
+        if allowed.bold && (rest.starts_with("**") || rest.starts_with("__")) {
+            if let Some(inner) = emphasis(rest, &rest[..2], before, &mut searches) {
+                out.push_str("**");
+                inline(
+                    inner,
+                    Allowed {
+                        bold: false,
+                        ..allowed
+                    },
+                    out,
+                );
+                out.push_str("**");
+                at += inner.len() + 4;
+                continue;
+            }
+        }
+        if allowed.italic && (c == '*' || c == '_') {
+            if let Some(inner) = emphasis(rest, &rest[..1], before, &mut searches) {
+                out.push('*');
+                inline(
+                    inner,
+                    Allowed {
+                        italic: false,
+                        ..allowed
+                    },
+                    out,
+                );
+                out.push('*');
+                at += inner.len() + 2;
+                continue;
+            }
+        }
+        if allowed.link && c == '[' {
+            if let Some((label, url)) = link(rest, &mut searches) {
+                out.push('[');
+                inline(
+                    label,
+                    Allowed {
+                        link: false,
+                        ..allowed
+                    },
+                    out,
+                );
+                out.push_str("](");
+                out.push_str(url);
+                out.push(')');
+                at += label.len() + url.len() + 4;
+                continue;
+            }
+        }
+        if SPECIAL.contains(&c) {
+            out.push('\\');
+        }
+        out.push(c);
+        at += c.len_utf8();
+    }
+}
+
+/// The text between `delimiter` at the start of `text` and its closer. An
+/// opener is followed and a closer preceded by a non-space, and underscores
+/// do not open or close inside words.
+fn emphasis<'a>(
+    text: &'a str,
+    delimiter: &'a str,
+    before: Option<char>,
+    searches: &mut Searches<'a>,
+) -> Option<&'a str> {
+    let underscore = delimiter.starts_with('_');
+    if underscore && before.is_some_and(char::is_alphanumeric) {
+        return None;
+    }
+    let body = &text[delimiter.len()..];
+    if body.starts_with(char::is_whitespace) {
+        return None;
+    }
+    let start = searches.offset(body);
+    let end = closing(start, delimiter, searches)? - start;
+    let inner = &body[..end];
+    let after = body[end + delimiter.len()..].chars().next();
+    // Runs of delimiters alone, such as masked words, stay literal.
+    let marked = searches.find("marked", start, |rest| match rest.chars().next() {
+        Some('*' | '_') => Step::Skip(1),
+        _ => Step::Found,
+    });
+    let invalid = marked.is_none_or(|marked| marked >= start + end)
+        || inner.ends_with(char::is_whitespace)
+        || underscore && after.is_some_and(char::is_alphanumeric);
+    (!invalid).then_some(inner)
+}
+
+/// Offset of the first `delimiter` from `start` on outside escapes and code
+/// spans, where a single delimiter does not match inside a double one.
+fn closing<'a>(start: usize, delimiter: &'a str, searches: &mut Searches<'a>) -> Option<usize> {
+    searches.find(delimiter, start, |rest| {
+        if let Some(escaped) = rest.strip_prefix('\\') {
+            return Step::Skip(1 + escaped.chars().next().map_or(0, char::len_utf8));
+        }
+        if let Some(end) = rest.strip_prefix('`').and_then(|code| code.find('`')) {
+            return Step::Skip(end + 2);
+        }
+        if rest.starts_with(delimiter) {
+            let doubled = delimiter.len() == 1 && rest[1..].starts_with(delimiter);
+            return if doubled { Step::Skip(2) } else { Step::Found };
+        }
+        Step::Skip(rest.chars().next().map_or(1, char::len_utf8))
+    })
+}
+
+/// The label and URL of a `[label](url)` link at the start of `text`.
+fn link<'a>(text: &'a str, searches: &mut Searches<'a>) -> Option<(&'a str, &'a str)> {
+    let start = searches.offset(text);
+    let end = searches.bracket(start)? - start;
+    let label = &text[1..end];
+    let target = text[end + 1..].strip_prefix('(')?;
+    // The URL ends at the first `)`, and is unsafe with any of these before.
+    let target_start = searches.offset(target);
+    let url_end = searches.find(")", target_start, |rest| match rest.chars().next() {
+        Some(')') => Step::Found,
+        Some(c) if c.is_whitespace() || c.is_control() || "<>\"`".contains(c) => Step::Failed,
+        c => Step::Skip(c.map_or(1, char::len_utf8)),
+    })?;
+    let url = &target[..url_end - target_start];
+    let safe = SCHEMES.iter().any(|scheme| {
+        url.len() > scheme.len()
+            && url.is_char_boundary(scheme.len())
+            && url[..scheme.len()].eq_ignore_ascii_case(scheme)
+    });
+    (!label.trim().is_empty() && safe).then_some((label, url))
+}
@@ -0,0 +1,473 @@
+//! Matrix application-service bridge.
+//!
+//! Rooms opt in through `PUT /rooms/:name/matrix` and are then mirrored to a
+//! Matrix room: chat members appear there as `@chatr_<name>` puppets and
+//! Matrix messages and membership changes are posted back into the chat room.
+//! The bridge is enabled when `MATRIX_HOMESERVER_URL`, `MATRIX_SERVER_NAME`,
+//! `MATRIX_AS_TOKEN` and `MATRIX_HS_TOKEN` are all set.
+
+use axum::extract::{Path, Query, State};
+use axum::http::header::AUTHORIZATION;
+use axum::http::{HeaderMap, StatusCode};
+use axum::Json;
+use log::{info, warn};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{broadcast, mpsc};
+
+use crate::events::{self, ChatEvent, RoomEvent};
+use crate::owners::{forbidden, is_owner};
+use crate::room_names::RoomName;
+use crate::{system_messages, tasks, ApiResponse, AppState};
+
+const PUPPET_PREFIX: &str = "chatr_";
+const SEEN_TRANSACTIONS: usize = 1024;
+
+pub struct MatrixConfig {
+    pub homeserver_url: String,
+    pub server_name: String,
+    pub as_token: String,
+    pub hs_token: String,
+}
+
+impl MatrixConfig {
+    pub fn from_env() -> Option<Self> {
+        let var = |name: &str| std::env::var(name).ok().filter(|val| !val.is_empty());
+        Some(Self {
+            homeserver_url: var("MATRIX_HOMESERVER_URL")?
+                .trim_end_matches('/')
+                .to_owned(),
+            server_name: var("MATRIX_SERVER_NAME")?,
+            as_token: var("MATRIX_AS_TOKEN")?,
+            hs_token: var("MATRIX_HS_TOKEN")?,
+        })
+    }
+}
+
+enum Outbound {
+    Message {
+        room_id: String,
+        username: String,
+        text: String,
+    },
+    Join {
+        room_id: String,
+        username: String,
+    },
+    Leave {
+        room_id: String,
+        username: String,
+    },
+}
+
+pub struct Bridge {
+    config: MatrixConfig,
+    /// Chat room name to linked Matrix room id.
+    links: Mutex<HashMap<String, String>>,
+    outbound: mpsc::UnboundedSender<Outbound>,
+    seen_transactions: Mutex<Vec<String>>,
+}
+
+impl Bridge {
+    /// Creates the bridge and spawns the task talking to the homeserver.
+    pub fn start(config: MatrixConfig) -> Self {
+        let (outbound, rx) = mpsc::unbounded_channel();
+        tasks::spawn(
+            "matrix-relay",
+            relay(
+                config.homeserver_url.clone(),
+                config.server_name.clone(),
+                config.as_token.clone(),
+                rx,
+            ),
+        );
+        info!("Matrix bridge enabled for {}", config.server_name);
+        Self {
+            config,
+            links: Mutex::new(HashMap::new()),
+            outbound,
+            seen_transactions: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Mirrors a bus event to the linked Matrix room, if any.
+    fn forward(&self, event: RoomEvent) {
+        let room = match &event {
+            RoomEvent::MessageSent { room, .. }
+            | RoomEvent::UserJoined { room, .. }
+            | RoomEvent::UserLeft { room, .. } => room,
+            RoomEvent::RoomCreated { .. }
+            | RoomEvent::RoomDeleted { .. }
+            | RoomEvent::Interacted(_) => return,
+        };
+        let Some(room_id) = self.links.lock().unwrap().get(room).cloned() else {
+            return;
+        };
+        let outbound = match event {
+            RoomEvent::MessageSent { from, text, .. } => Outbound::Message {
+                room_id,
+                username: from,
+                text,
+            },
+            RoomEvent::UserJoined { username, .. } => Outbound::Join { room_id, username },
+            RoomEvent::UserLeft { username, .. } => Outbound::Leave { room_id, username },
+            RoomEvent::RoomCreated { .. }
+            | RoomEvent::RoomDeleted { .. }
+            | RoomEvent::Interacted(_) => return,
+        };
+        let _ = self.outbound.send(outbound);
+    }
+
+    fn room_for(&self, room_id: &str) -> Option<String> {
+        self.links
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|(_, linked)| *linked == room_id)
+            .map(|(room, _)| room.clone())
+    }
+
+    fn is_puppet(&self, user_id: &str) -> bool {
+        user_id.starts_with(&format!("@{}", PUPPET_PREFIX))
+            && user_id.ends_with(&format!(":{}", self.config.server_name))
+    }
+}
+
+/// Mirrors bus events to Matrix while the bridge is enabled.
+pub async fn subscriber(state: Arc<AppState>, mut events: broadcast::Receiver<RoomEvent>) {
+    while let Some(event) = events::next(&mut events, "Matrix bridge").await {
+        if let Some(bridge) = &state.matrix {
+            bridge.forward(event);
+        }
+    }
+}
+
+/// Encodes a chat username into the Matrix localpart alphabet.
+fn localpart(username: &str) -> String {
+    let mut encoded = String::from(PUPPET_PREFIX);
+    for byte in username.bytes() {
+        match byte {
+            b'a'..=b'z' | b'0'..=b'9' | b'.' | b'_' | b'-' | b'/' => encoded.push(byte as char),
+            _ => encoded.push_str(&format!("={:02x}", byte)),
+        }
+    }
+    encoded
+}
+
+fn encode(segment: &str) -> String {
+    let mut encoded = String::new();
+    for byte in segment.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+struct Homeserver {
+    client: reqwest::Client,
+    url: String,
+    as_token: String,
+}
+
+impl Homeserver {
+    /// Client-server API call made on behalf of a puppet user.
+    async fn call(&self, method: reqwest::Method, path: &str, user_id: &str, body: Value) -> bool {
+        let url = format!(
+            "{}/_matrix/client/v3/{}?user_id={}",
+            self.url,
+            path,
+            encode(user_id)
+        );
+        let result = self
+            .client
+            .request(method, url)
+            .bearer_auth(&self.as_token)
+            .json(&body)
+            .send()
+            .await;
+        match result {
+            Ok(res) if res.status().is_success() => true,
+            Ok(res) => {
+                warn!("Matrix {} for {} answered {}", path, user_id, res.status());
+                false
+            }
+            Err(err) => {
+                warn!("Matrix {} for {} failed: {}", path, user_id, err);
+                false
+            }
+        }
+    }
+}
+
+/// Sends queued events to the homeserver as puppet users, registering and
+/// joining them on first use.
+async fn relay(
+    homeserver_url: String,
+    server_name: String,
+    as_token: String,
+    mut rx: mpsc::UnboundedReceiver<Outbound>,
+) {
+    let homeserver = Homeserver {
+        client: reqwest::Client::builder()
+            .timeout(Duration::from_secs(10))
+            .build()
+            .unwrap(),
+        url: homeserver_url,
+        as_token,
+    };
+    let mut txn_counter = 0u64;
+    let mut registered = HashSet::<String>::new();
+    let mut joined = HashSet::<(String, String)>::new();
+
+    while let Some(outbound) = rx.recv().await {
+        let (room_id, username) = match &outbound {
+            Outbound::Message {
+                room_id, username, ..
+            }
+            | Outbound::Join { room_id, username }
+            | Outbound::Leave { room_id, username } => (room_id.clone(), username.clone()),
+        };
+        let localpart = localpart(&username);
+        let user_id = format!("@{}:{}", localpart, server_name);
+
+        if !registered.contains(&user_id) {
+            // Registering an existing puppet fails with M_USER_IN_USE, which is fine.
+            let body = json!({ "type": "m.login.application_service", "username": localpart });
+            homeserver
+                .call(reqwest::Method::POST, "register", &user_id, body)
+                .await;
+            let path = format!("profile/{}/displayname", encode(&user_id));
+            let body = json!({ "displayname": username });
+            if homeserver
+                .call(reqwest::Method::PUT, &path, &user_id, body)
+                .await
+            {
+                registered.insert(user_id.clone());
+            }
+        }
+
+        let key = (room_id.clone(), user_id.clone());
+        if let Outbound::Leave { .. } = outbound {
+            joined.remove(&key);
+            let path = format!("rooms/{}/leave", encode(&room_id));
+            homeserver
+                .call(reqwest::Method::POST, &path, &user_id, json!({}))
+                .await;
+            continue;
+        }
+
+        if !joined.contains(&key) {
+            let path = format!("rooms/{}/join", encode(&room_id));
+            if homeserver
+                .call(reqwest::Method::POST, &path, &user_id, json!({}))
+                .await
+            {
+                joined.insert(key);
+            }
+        }
+
+        if let Outbound::Message { text, .. } = outbound {
+            txn_counter += 1;
+            let txn_id = format!("chatr{}.{}", std::process::id(), txn_counter);
+            let path = format!("rooms/{}/send/m.room.message/{}", encode(&room_id), txn_id);
+            let body = json!({ "msgtype": "m.text", "body": text });
+            homeserver
+                .call(reqwest::Method::PUT, &path, &user_id, body)
+                .await;
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct LinkRoom {
+    matrix_room_id: String,
+}
+
+/// `PUT /rooms/:name/matrix`, opts a room into the bridge.
+pub async fn link_room(
+    Path(room): Path<RoomName>,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(body): Json<LinkRoom>,
+) -> ApiResponse {
+    if !is_owner(&state, &room, &headers) {
+        return forbidden();
+    }
+    let Some(bridge) = &state.matrix else {
+        return (
+            StatusCode::NOT_FOUND,
+            Json(json!({ "status": "Matrix bridge is not enabled." })),
+        );
+    };
+    if !body.matrix_room_id.starts_with('!') {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "status": "Expected a Matrix room id like !abc:example.org." })),
+        );
+    }
+    bridge
+        .links
+        .lock()
+        .unwrap()
+        .insert(room.into(), body.matrix_room_id);
+    (StatusCode::OK, Json(json!({ "status": "Success!" })))
+}
+
+/// `DELETE /rooms/:name/matrix`, opts a room out of the bridge.
+pub async fn unlink_room(
+    Path(room): Path<RoomName>,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> ApiResponse {
+    if !is_owner(&state, &room, &headers) {
+        return forbidden();
+    }
+    let removed = state
+        .matrix
+        .as_ref()
+        .and_then(|bridge| bridge.links.lock().unwrap().remove(room.as_str()));
+    match removed {
+        Some(_) => (StatusCode::OK, Json(json!({ "status": "Success!" }))),
+        None => (
+            StatusCode::NOT_FOUND,
+            Json(json!({ "status": "Room is not bridged." })),
+        ),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct AccessToken {
+    access_token: Option<String>,
+}
+
+fn authorized(bridge: &Bridge, headers: &HeaderMap, query: &AccessToken) -> bool {
+    let header = headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    header.or(query.access_token.as_deref()) == Some(bridge.config.hs_token.as_str())
+}
+
+fn matrix_error(status: StatusCode, errcode: &str) -> ApiResponse {
+    (status, Json(json!({ "errcode": errcode })))
+}
+
+#[derive(Deserialize)]
+pub struct Transaction {
+    #[serde(default)]
+    events: Vec<Value>,
+}
+
+/// `PUT /_matrix/app/v1/transactions/:txn_id`, events pushed by the homeserver.
+pub async fn transactions(
+    Path(txn_id): Path<String>,
+    Query(query): Query<AccessToken>,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(transaction): Json<Transaction>,
+) -> ApiResponse {
+    let Some(bridge) = &state.matrix else {
+        return matrix_error(StatusCode::NOT_FOUND, "M_UNRECOGNIZED");
+    };
+    if !authorized(bridge, &headers, &query) {
+        return matrix_error(StatusCode::FORBIDDEN, "M_FORBIDDEN");
+    }
+
+    {
+        let mut seen = bridge.seen_transactions.lock().unwrap();
+        if seen.contains(&txn_id) {
+            return (StatusCode::OK, Json(json!({})));
+        }
+        if seen.len() >= SEEN_TRANSACTIONS {
+            seen.remove(0);
+        }
+        seen.push(txn_id);
+    }
+
+    for event in transaction.events {
+        let field = |name: &str| event.get(name).and_then(Value::as_str).unwrap_or_default();
+        let sender = field("sender");
+        if sender.is_empty() || bridge.is_puppet(sender) {
+            continue;
+        }
+        let Some(room) = bridge.room_for(field("room_id")) else {
+            continue;
+        };
+
+        let content = event.get("content").cloned().unwrap_or_default();
+        let content_field = |name: &str| {
+            content
+                .get(name)
+                .and_then(Value::as_str)
+                .unwrap_or_default()
+                .to_owned()
+        };
+        let chat_event = match field("type") {
+            "m.room.message" => ChatEvent::message(0, sender, content_field("body")),
+            "m.room.member" => match content_field("membership").as_str() {
+                "join" => match system_messages::joined(&state, &room, sender) {
+                    Some(joined) => joined,
+                    None => continue,
+                },
+                "leave" | "ban" => match system_messages::left(&state, &room, sender) {
+                    Some(left) => left,
+                    None => continue,
+                },
+                _ => continue,
+            },
+            _ => continue,
+        };
+
+        if let Some(room_state) = state.rooms.lock().unwrap().get(&room) {
+            match chat_event {
+                ChatEvent::Message { from, text, .. } => room_state.send_message(&from, text),
+                event => room_state.broadcast(event),
+            };
+        }
+    }
+
+    (StatusCode::OK, Json(json!({})))
+}
+
+/// `GET /_matrix/app/v1/users/:user_id`, puppets are created lazily so
+/// every id in our namespace exists.
+pub async fn query_user(
+    Path(user_id): Path<String>,
+    Query(query): Query<AccessToken>,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> ApiResponse {
+    let Some(bridge) = &state.matrix else {
+        return matrix_error(StatusCode::NOT_FOUND, "M_UNRECOGNIZED");
+    };
+    if !authorized(bridge, &headers, &query) {
+        return matrix_error(StatusCode::FORBIDDEN, "M_FORBIDDEN");
+    }
+    if bridge.is_puppet(&user_id) {
+        (StatusCode::OK, Json(json!({})))
+    } else {
+        matrix_error(StatusCode::NOT_FOUND, "M_NOT_FOUND")
+    }
+}
+
+/// `GET /_matrix/app/v1/rooms/:alias`, aliases are not provisioned by the bridge.
+pub async fn query_room(
+    Path(_alias): Path<String>,
+    Query(query): Query<AccessToken>,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> ApiResponse {
+    match &state.matrix {
+        Some(bridge) if !authorized(bridge, &headers, &query) => {
+            matrix_error(StatusCode::FORBIDDEN, "M_FORBIDDEN")
+        }
+        _ => matrix_error(StatusCode::NOT_FOUND, "M_NOT_FOUND"),
+    }
+}
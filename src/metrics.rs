@@ -0,0 +1,412 @@
+//! Prometheus metrics, `GET /metrics`, which needs the admin token if the
+//! server has one.
+//!
+//! Besides the server-wide totals, the messages, members and lag of each
+//! room are labelled with its name. So that thousands of short-lived rooms
+//! do not each make series of their own, only the rooms of an allowlist
+//! (`METRICS_ROOMS`, comma-separated) are labelled, or else the
+//! `METRICS_TOP_ROOMS` rooms with the most members, 10 by default. The other
+//! rooms are added up under `room="_other"`.
+//!
+//! The latency of messages, from when the server received them to when it
+//! sent them to each member, is a histogram, overall and of each room. It is
+//! measured for WebSocket, Server-Sent Events, gRPC, IRC and Socket.IO
+//! clients, not for GraphQL subscriptions or long polling, where a message
+//! waits for the next poll.
+//!
+//! The memory of the rooms' histories, and the messages evicted from them to
+//! fit the [budget](crate::history_budget), are counted too.
+
+use axum::extract::State;
+use axum::http::header::CONTENT_TYPE;
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use crate::channels::DEFAULT_MAX_CAPACITY;
+use crate::history_budget::Usage;
+use crate::owners::{admin_forbidden, is_admin};
+use crate::AppState;
+
+const DEFAULT_TOP_ROOMS: usize = 10;
+/// The label of the rooms added up.
+const OTHER: &str = "_other";
+/// Upper bounds of the latency buckets, in seconds.
+const LATENCY_BUCKETS: [f64; 12] = [
+    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0,
+];
+
+/// Which rooms get series of their own.
+#[derive(Clone, Debug)]
+pub struct MetricsConfig {
+    /// Only these rooms, if set.
+    pub rooms: Option<HashSet<String>>,
+    /// Otherwise this many rooms, those with the most members.
+    pub top_rooms: usize,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        Self {
+            rooms: None,
+            top_rooms: DEFAULT_TOP_ROOMS,
+        }
+    }
+}
+
+impl MetricsConfig {
+    pub fn from_env() -> Self {
+        let var = |name| {
+            std::env::var(name)
+                .ok()
+                .filter(|value: &String| !value.is_empty())
+        };
+        let rooms = var("METRICS_ROOMS").map(|rooms| {
+            rooms
+                .split(',')
+                .map(str::trim)
+                .filter(|room| !room.is_empty())
+                .map(str::to_owned)
+                .collect()
+        });
+        Self {
+            rooms,
+            top_rooms: var("METRICS_TOP_ROOMS")
+                .and_then(|count| count.parse().ok())
+                .unwrap_or(DEFAULT_TOP_ROOMS),
+        }
+    }
+}
+
+#[derive(Clone, Default)]
+struct Histogram {
+    /// Observations up to each of [`LATENCY_BUCKETS`].
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, seconds: f64) {
+        for (bound, count) in LATENCY_BUCKETS.iter().zip(&mut self.buckets) {
+            if seconds <= *bound {
+                *count += 1;
+            }
+        }
+        self.sum += seconds;
+        self.count += 1;
+    }
+
+    fn add(&mut self, other: &Histogram) {
+        for (count, other) in self.buckets.iter_mut().zip(other.buckets) {
+            *count += other;
+        }
+        self.sum += other.sum;
+        self.count += other.count;
+    }
+}
+
+#[derive(Default)]
+struct RoomLatency {
+    /// When the latest messages were received, by id, oldest first.
+    received: VecDeque<(u64, Instant)>,
+    histogram: Histogram,
+}
+
+/// The latency of each room's messages.
+pub struct Metrics {
+    config: MetricsConfig,
+    latency: Mutex<HashMap<String, RoomLatency>>,
+}
+
+impl Metrics {
+    pub fn new(config: MetricsConfig) -> Self {
+        Self {
+            config,
+            latency: Mutex::default(),
+        }
+    }
+
+    /// Notes that message `id` of `room` was received `at`, before it is
+    /// broadcast.
+    pub fn received(&self, room: &str, id: u64, at: Instant) {
+        let mut latency = self.latency.lock().unwrap();
+        let room = latency.entry(room.to_owned()).or_default();
+        // Members further behind than the channel skip the older messages.
+        if room.received.len() >= DEFAULT_MAX_CAPACITY {
+            room.received.pop_front();
+        }
+        room.received.push_back((id, at));
+    }
+
+    /// Measures the latency of the message `id` of `room` as it was just
+    /// sent to a member, if the event sent was a message.
+    pub fn delivered(&self, room: &str, id: Option<u64>) {
+        let Some(id) = id else {
+            return;
+        };
+        let mut latency = self.latency.lock().unwrap();
+        let Some(room) = latency.get_mut(room) else {
+            return;
+        };
+        let received = room
+            .received
+            .iter()
+            .rev()
+            .find(|(received, _)| *received == id)
+            .map(|(_, at)| *at);
+        if let Some(at) = received {
+            room.histogram.observe(at.elapsed().as_secs_f64());
+        }
+    }
+}
+
+#[derive(Default)]
+struct Room {
+    messages: u64,
+    users: usize,
+    lag: usize,
+    channel_capacity: usize,
+    latency: Histogram,
+    history_bytes: usize,
+    evicted: u64,
+}
+
+impl Room {
+    fn add(&mut self, other: &Room) {
+        self.messages += other.messages;
+        self.users += other.users;
+        // The lag of the slowest member, not a sum.
+        self.lag = self.lag.max(other.lag);
+        self.channel_capacity += other.channel_capacity;
+        self.latency.add(&other.latency);
+        self.history_bytes += other.history_bytes;
+        self.evicted += other.evicted;
+    }
+}
+
+/// Every room with live members or past messages, keyed by name.
+fn rooms(state: &AppState, history: &Usage) -> BTreeMap<String, Room> {
+    let mut rooms = BTreeMap::<String, Room>::new();
+    for (name, messages) in state.stats.messages() {
+        rooms.entry(name).or_default().messages = messages;
+    }
+    for (name, latency) in state.metrics.latency.lock().unwrap().iter() {
+        rooms.entry(name.clone()).or_default().latency = latency.histogram.clone();
+    }
+    for (name, room_state) in state.rooms.lock().unwrap().iter() {
+        let room = rooms.entry(name.clone()).or_default();
+        room.users = room_state.users.lock().unwrap().len();
+        room.lag = room_state.tx.lag();
+        room.channel_capacity = room_state.tx.capacity();
+    }
+    for (name, &(bytes, evicted)) in &history.rooms {
+        let room = rooms.entry(name.clone()).or_default();
+        room.history_bytes = bytes;
+        room.evicted = evicted;
+    }
+    rooms
+}
+
+/// Splits `rooms` into those labelled and the others added up.
+fn labelled(config: &MetricsConfig, rooms: BTreeMap<String, Room>) -> (Vec<(String, Room)>, Room) {
+    let (mut labelled, others): (Vec<_>, Vec<_>) = match &config.rooms {
+        Some(allowed) => rooms
+            .into_iter()
+            .partition(|(name, _)| allowed.contains(name)),
+        None => {
+            let mut rooms = rooms.into_iter().collect::<Vec<_>>();
+            // Ties are broken by messages then name, so that the set is stable.
+            rooms.sort_by(|(a_name, a), (b_name, b)| {
+                b.users
+                    .cmp(&a.users)
+                    .then_with(|| b.messages.cmp(&a.messages))
+                    .then_with(|| a_name.cmp(b_name))
+            });
+            let others = rooms.split_off(config.top_rooms.min(rooms.len()));
+            (rooms, others)
+        }
+    };
+    labelled.sort_by(|(a, _), (b, _)| a.cmp(b));
+    let mut other = Room::default();
+    for (_, room) in &others {
+        other.add(room);
+    }
+    (labelled, other)
+}
+
+/// Writes the series of `histogram`, with `labels` such as `room="a",`.
+fn write_histogram(out: &mut String, name: &str, labels: &str, histogram: &Histogram) {
+    for (bound, count) in LATENCY_BUCKETS.iter().zip(histogram.buckets) {
+        let _ = writeln!(
+            out,
+            "{}_bucket{{{}le=\"{}\"}} {}",
+            name, labels, bound, count
+        );
+    }
+    let _ = writeln!(
+        out,
+        "{}_bucket{{{}le=\"+Inf\"}} {}",
+        name, labels, histogram.count
+    );
+    let labels = labels.trim_end_matches(',');
+    let labels = if labels.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", labels)
+    };
+    let _ = writeln!(out, "{}_sum{} {}", name, labels, histogram.sum);
+    let _ = writeln!(out, "{}_count{} {}", name, labels, histogram.count);
+}
+
+/// Escapes a label value as the text format needs.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn render(state: &AppState) -> String {
+    let history = state.history_budget.usage();
+    let rooms = rooms(state, &history);
+    let live = state.rooms.lock().unwrap().len();
+    let mut total = Room::default();
+    for room in rooms.values() {
+        total.add(room);
+    }
+    let (labelled, other) = labelled(&state.metrics.config, rooms);
+    let per_room = labelled
+        .iter()
+        .map(|(name, room)| (escape(name), room))
+        .chain([(OTHER.to_owned(), &other)])
+        .collect::<Vec<_>>();
+
+    let mut out = String::new();
+    let mut family = |name: &str, kind: &str, help: &str, value: &dyn Fn(&Room) -> u64| {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} {}", name, kind);
+        for (room, stats) in &per_room {
+            let _ = writeln!(out, "{}{{room=\"{}\"}} {}", name, room, value(stats));
+        }
+    };
+    family(
+        "chatr_room_messages_total",
+        "counter",
+        "Messages sent to the room.",
+        &|room| room.messages,
+    );
+    family(
+        "chatr_room_users",
+        "gauge",
+        "Members in the room.",
+        &|room| room.users as u64,
+    );
+    family(
+        "chatr_room_lag_events",
+        "gauge",
+        "Events the slowest member of the room has yet to receive.",
+        &|room| room.lag as u64,
+    );
+    family(
+        "chatr_room_channel_capacity_events",
+        "gauge",
+        "Events the room's channel holds, as sized to its throughput.",
+        &|room| room.channel_capacity as u64,
+    );
+    family(
+        "chatr_room_history_bytes",
+        "gauge",
+        "Memory the room's history takes, estimated.",
+        &|room| room.history_bytes as u64,
+    );
+    family(
+        "chatr_room_history_evicted_total",
+        "counter",
+        "Messages evicted from the room's history to fit the memory budget.",
+        &|room| room.evicted,
+    );
+    let name = "chatr_room_message_latency_seconds";
+    let _ = writeln!(
+        out,
+        "# HELP {} Time from receiving a message of the room to sending it to a member.",
+        name
+    );
+    let _ = writeln!(out, "# TYPE {} histogram", name);
+    for (room, stats) in &per_room {
+        write_histogram(
+            &mut out,
+            name,
+            &format!("room=\"{}\",", room),
+            &stats.latency,
+        );
+    }
+    let name = "chatr_message_latency_seconds";
+    let _ = writeln!(
+        out,
+        "# HELP {} Time from receiving a message to sending it to a member.",
+        name
+    );
+    let _ = writeln!(out, "# TYPE {} histogram", name);
+    write_histogram(&mut out, name, "", &total.latency);
+    let _ = writeln!(out, "# HELP chatr_rooms Rooms that exist.");
+    let _ = writeln!(out, "# TYPE chatr_rooms gauge");
+    let _ = writeln!(out, "chatr_rooms {}", live);
+    let _ = writeln!(out, "# HELP chatr_users Members across the rooms.");
+    let _ = writeln!(out, "# TYPE chatr_users gauge");
+    let _ = writeln!(out, "chatr_users {}", total.users);
+    let _ = writeln!(
+        out,
+        "# HELP chatr_messages_total Messages sent to any room."
+    );
+    let _ = writeln!(out, "# TYPE chatr_messages_total counter");
+    let _ = writeln!(out, "chatr_messages_total {}", total.messages);
+    let history_totals = [
+        (
+            "chatr_history_bytes",
+            "gauge",
+            "Memory the rooms' histories take, estimated.",
+            history.bytes as u64,
+        ),
+        (
+            "chatr_history_max_bytes",
+            "gauge",
+            "Memory the rooms' histories may take together.",
+            history.max_bytes as u64,
+        ),
+        (
+            "chatr_history_evicted_messages_total",
+            "counter",
+            "Messages evicted from histories to fit the memory budget.",
+            history.evicted_messages,
+        ),
+        (
+            "chatr_history_evicted_bytes_total",
+            "counter",
+            "Memory freed by evicting messages from histories.",
+            history.evicted_bytes,
+        ),
+    ];
+    for (name, kind, help, value) in history_totals {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} {}", name, kind);
+        let _ = writeln!(out, "{} {}", name, value);
+    }
+    out
+}
+
+/// `GET /metrics`
+pub async fn get_metrics(State(state): State<Arc<AppState>>, headers: HeaderMap) -> Response {
+    if state.admin_token.is_some() && !is_admin(&state, &headers) {
+        return admin_forbidden().into_response();
+    }
+    (
+        StatusCode::OK,
+        [(CONTENT_TYPE, "text/plain; version=0.0.4")],
+        render(&state),
+    )
+        .into_response()
+}
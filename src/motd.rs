@@ -0,0 +1,199 @@
+//! The message of the day, sent to each member right after they joined.
+//!
+//! The server's MOTD is the `MOTD` text or the contents of `MOTD_FILE`,
+//! which is polled for changes so edits apply without a restart. Room owners
+//! replace it for their room through `PUT /rooms/:name/motd`.
+
+use axum::extract::{Path, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::Json;
+use log::{info, warn};
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use crate::events::ChatEvent;
+use crate::owners::{forbidden, is_owner};
+use crate::room_names::RoomName;
+use crate::{ApiResponse, AppState};
+
+const MAX_LEN: usize = 2000;
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// The server's MOTD and the rooms' own ones.
+pub struct Motd {
+    file: Option<PathBuf>,
+    /// Used while there is no file to read.
+    fallback: Option<String>,
+    text: Mutex<Option<String>>,
+    modified: Mutex<Option<SystemTime>>,
+    rooms: Mutex<HashMap<String, String>>,
+}
+
+impl Motd {
+    /// Uses `file` when given, falling back to `text`.
+    pub fn new(text: Option<String>, file: Option<PathBuf>) -> Self {
+        let fallback = text.filter(|text| !text.trim().is_empty());
+        let motd = Self {
+            file,
+            text: Mutex::new(fallback.clone()),
+            fallback,
+            modified: Mutex::new(None),
+            rooms: Mutex::new(HashMap::new()),
+        };
+        motd.reload();
+        motd
+    }
+
+    pub fn is_watched(&self) -> bool {
+        self.file.is_some()
+    }
+
+    /// Rereads the file if it changed since it was last read.
+    fn reload(&self) {
+        let Some(file) = &self.file else {
+            return;
+        };
+        let modified = std::fs::metadata(file).and_then(|meta| meta.modified());
+        let modified = match modified {
+            Ok(modified) => modified,
+            // Warns once when the file goes away.
+            Err(err) => {
+                if self.modified.lock().unwrap().take().is_some() {
+                    *self.text.lock().unwrap() = self.fallback.clone();
+                    warn!("Cannot read MOTD from {}: {}", file.display(), err);
+                }
+                return;
+            }
+        };
+        if *self.modified.lock().unwrap() == Some(modified) {
+            return;
+        }
+        match std::fs::read_to_string(file) {
+            Ok(text) => {
+                let text = text.trim();
+                *self.text.lock().unwrap() = (!text.is_empty()).then(|| text.to_owned());
+                *self.modified.lock().unwrap() = Some(modified);
+                info!("Loaded MOTD from {}", file.display());
+            }
+            Err(err) => warn!("Cannot read MOTD from {}: {}", file.display(), err),
+        }
+    }
+
+    /// Whether the owner of `room` replaced the server's MOTD.
+    pub fn is_custom(&self, room: &str) -> bool {
+        self.rooms.lock().unwrap().contains_key(room)
+    }
+
+    /// The MOTD the owner of `room` set, if any.
+    pub fn own(&self, room: &str) -> Option<String> {
+        self.rooms.lock().unwrap().get(room).cloned()
+    }
+
+    /// Replaces the server's MOTD in `room` with `text`, or goes back to it.
+    pub fn set_own(&self, room: &str, text: Option<String>) {
+        let mut rooms = self.rooms.lock().unwrap();
+        match text {
+            Some(text) => rooms.insert(room.to_owned(), text),
+            None => rooms.remove(room),
+        };
+    }
+
+    /// The server's MOTD, if any.
+    pub fn server(&self) -> Option<String> {
+        self.text.lock().unwrap().clone()
+    }
+
+    /// The MOTD members of `room` get, if any.
+    pub fn get(&self, room: &str) -> Option<String> {
+        let own = self.rooms.lock().unwrap().get(room).cloned();
+        own.or_else(|| self.text.lock().unwrap().clone())
+    }
+}
+
+/// Polls `MOTD_FILE` and applies its changes.
+pub async fn watch(state: Arc<AppState>) {
+    let mut interval = tokio::time::interval(POLL_INTERVAL);
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        let state = state.clone();
+        let _ = tokio::task::spawn_blocking(move || state.motd.reload()).await;
+    }
+}
+
+/// Sends the MOTD of `room` to `username`'s own connection.
+pub fn greet(state: &AppState, room: &str, username: &str) {
+    let Some(text) = state.motd.get(room) else {
+        return;
+    };
+    let rooms = state.rooms.lock().unwrap();
+    let users = rooms.get(room).map(|room| room.users.lock().unwrap());
+    if let Some(direct) = users.as_ref().and_then(|users| users.get(username)) {
+        let _ = direct.send(ChatEvent::Motd { text });
+    }
+}
+
+/// `GET /rooms/:name/motd`, the MOTD the room's members get.
+pub async fn get_motd(
+    Path(room): Path<RoomName>,
+    State(state): State<Arc<AppState>>,
+) -> ApiResponse {
+    (
+        StatusCode::OK,
+        Json(json!({
+            "status": "Success!",
+            "text": state.motd.get(&room),
+            "custom": state.motd.is_custom(&room),
+        })),
+    )
+}
+
+#[derive(Deserialize)]
+pub struct SetMotd {
+    text: String,
+}
+
+/// `PUT /rooms/:name/motd`, replaces the server's MOTD in the room.
+pub async fn set_motd(
+    Path(room): Path<RoomName>,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(body): Json<SetMotd>,
+) -> ApiResponse {
+    if !is_owner(&state, &room, &headers) {
+        return forbidden();
+    }
+    let text = body.text.trim();
+    if text.is_empty() || text.chars().count() > MAX_LEN {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({
+                "status": format!("The MOTD is 1 to {} characters.", MAX_LEN),
+            })),
+        );
+    }
+    state
+        .motd
+        .rooms
+        .lock()
+        .unwrap()
+        .insert(room.into(), text.to_owned());
+    (StatusCode::OK, Json(json!({ "status": "Success!" })))
+}
+
+/// `DELETE /rooms/:name/motd`, goes back to the server's MOTD.
+pub async fn reset_motd(
+    Path(room): Path<RoomName>,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> ApiResponse {
+    if !is_owner(&state, &room, &headers) {
+        return forbidden();
+    }
+    state.motd.rooms.lock().unwrap().remove(room.as_str());
+    (StatusCode::OK, Json(json!({ "status": "Success!" })))
+}
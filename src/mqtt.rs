@@ -0,0 +1,144 @@
+//! Optional MQTT bridge piping topics into rooms and room messages onto topics.
+//!
+//! Configured through environment variables:
+//! - `MQTT_URL`: broker as `host:port`, enables the bridge
+//! - `MQTT_SUBSCRIPTIONS`: `filter=room` pairs separated by commas, e.g. `alerts/#=ops`
+//! - `MQTT_PUBLISH`: `room=topic` pairs, messages of `room` are republished on `topic`
+
+use log::{error, info, warn};
+use rumqttc::{AsyncClient, Event, MqttOptions, Packet, QoS};
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast;
+
+use crate::events::{self, App, RoomEvent};
+use crate::{tasks, AppState};
+
+pub struct MqttBridge {
+    client: AsyncClient,
+    /// Room name to topic its messages are republished on.
+    publish: HashMap<String, String>,
+}
+
+fn pairs(var: &str) -> Vec<(String, String)> {
+    std::env::var(var)
+        .unwrap_or_default()
+        .split(',')
+        .filter_map(|pair| pair.split_once('='))
+        .map(|(left, right)| (left.trim().to_owned(), right.trim().to_owned()))
+        .filter(|(left, right)| !left.is_empty() && !right.is_empty())
+        .collect()
+}
+
+/// Matches a concrete topic against a subscription filter with `+` and `#` wildcards.
+fn matches(filter: &str, topic: &str) -> bool {
+    let mut topic_levels = topic.split('/');
+    for level in filter.split('/') {
+        match (level, topic_levels.next()) {
+            ("#", _) => return true,
+            ("+", Some(_)) => {}
+            (level, Some(topic_level)) if level == topic_level => {}
+            _ => return false,
+        }
+    }
+    topic_levels.next().is_none()
+}
+
+impl MqttBridge {
+    /// Connects when `MQTT_URL` is set and spawns the event loop feeding
+    /// subscribed topics into their rooms.
+    pub fn from_env(state: &Arc<AppState>) -> Option<Self> {
+        let url = std::env::var("MQTT_URL")
+            .ok()
+            .filter(|url| !url.is_empty())?;
+        let Some((host, port)) = url
+            .rsplit_once(':')
+            .and_then(|(host, port)| Some((host.to_owned(), port.parse::<u16>().ok()?)))
+        else {
+            error!("MQTT_URL must be host:port, got {}", url);
+            return None;
+        };
+
+        let mut options = MqttOptions::new(format!("chatr-{}", std::process::id()), host, port);
+        options.set_keep_alive(Duration::from_secs(30));
+        let (client, mut eventloop) = AsyncClient::new(options, 64);
+        let subscriptions = pairs("MQTT_SUBSCRIPTIONS");
+
+        let state = Arc::downgrade(state);
+        let subscriber = client.clone();
+        tasks::spawn("mqtt-client", async move {
+            loop {
+                let event = match eventloop.poll().await {
+                    Ok(event) => event,
+                    Err(err) => {
+                        warn!("MQTT connection error: {}", err);
+                        tokio::time::sleep(Duration::from_secs(5)).await;
+                        continue;
+                    }
+                };
+                let Some(state) = state.upgrade() else {
+                    return;
+                };
+
+                match event {
+                    Event::Incoming(Packet::ConnAck(_)) => {
+                        info!("MQTT connected");
+                        for (filter, _) in &subscriptions {
+                            if let Err(err) = subscriber.try_subscribe(filter, QoS::AtMostOnce) {
+                                warn!("MQTT subscribe to {} failed: {}", filter, err);
+                            }
+                        }
+                    }
+                    Event::Incoming(Packet::Publish(publish)) => {
+                        let text = String::from_utf8_lossy(&publish.payload).into_owned();
+                        let rooms = state.rooms.lock().unwrap();
+                        for (_, room) in subscriptions
+                            .iter()
+                            .filter(|(filter, _)| matches(filter, &publish.topic))
+                        {
+                            if let Some(room_state) = rooms.get(room) {
+                                room_state.send_bot_message(
+                                    App::new("mqtt"),
+                                    &publish.topic,
+                                    &*text,
+                                );
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        });
+
+        Some(Self {
+            client,
+            publish: pairs("MQTT_PUBLISH").into_iter().collect(),
+        })
+    }
+
+    fn forward(&self, room: &str, from: &str, text: &str) {
+        let Some(topic) = self.publish.get(room) else {
+            return;
+        };
+        let payload = json!({ "room": room, "from": from, "text": text }).to_string();
+        if let Err(err) = self
+            .client
+            .try_publish(topic, QoS::AtMostOnce, false, payload)
+        {
+            warn!("MQTT publish to {} failed: {}", topic, err);
+        }
+    }
+}
+
+/// Republishes room messages from the bus while the bridge is enabled.
+pub async fn subscriber(state: Arc<AppState>, mut events: broadcast::Receiver<RoomEvent>) {
+    while let Some(event) = events::next(&mut events, "MQTT bridge").await {
+        if let (Some(bridge), RoomEvent::MessageSent { room, from, text }) =
+            (state.mqtt.get(), event)
+        {
+            bridge.forward(&room, &from, &text);
+        }
+    }
+}
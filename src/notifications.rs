@@ -0,0 +1,156 @@
+//! Notifications for members who are not connected when someone mentions
+//! them or sends them a direct message.
+//!
+//! A member counts as offline while no transport has them in any room. The
+//! server then hands the notification to every [`Notifier`], such as the
+//! Web Push one enabled with `VAPID_PRIVATE_KEY`, the mobile one enabled
+//! with FCM or APNs credentials and the email one enabled with `SMTP_HOST`.
+//! Members in do not disturb are not notified. Members choose per room
+//! whether they are notified of every message, only of mentions and
+//! highlights or of nothing, and when to hear of mentions only.
+
+pub mod digests;
+pub mod email;
+pub mod highlights;
+pub mod mobile;
+pub mod preferences;
+pub mod webpush;
+
+use async_trait::async_trait;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashSet};
+use std::sync::Arc;
+
+pub use preferences::Level;
+
+use crate::{presence, tasks, AppState};
+
+/// Longest text a notification carries, longer ones are cut.
+const MAX_TEXT_LEN: usize = 500;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    /// Any message, in rooms the member gets every message of.
+    Message,
+    Mention,
+    /// A message containing a keyword the member watches for.
+    Highlight,
+    Direct,
+    /// Sums up what a member opted into [digests](digests) for.
+    Digest,
+}
+
+/// What a member missed.
+#[derive(Clone, Debug, Serialize)]
+pub struct Notification {
+    #[serde(rename = "type")]
+    pub kind: Kind,
+    pub room: String,
+    pub from: String,
+    pub text: String,
+}
+
+/// Delivers notifications to members outside the chat.
+#[async_trait]
+pub trait Notifier: Send + Sync {
+    async fn notify(&self, username: &str, notification: &Notification);
+
+    /// Delivers what `username` missed since their last digest, by default
+    /// as one notification per room summing it up, since notifiers only
+    /// deliver a room's notifications to whoever registered in that room.
+    async fn digest(&self, username: &str, notifications: &[Notification]) {
+        let mut rooms = BTreeMap::<&str, Vec<Notification>>::new();
+        for notification in notifications {
+            rooms
+                .entry(&notification.room)
+                .or_default()
+                .push(notification.clone());
+        }
+        for notifications in rooms.values() {
+            self.notify(username, &digests::summary(notifications))
+                .await
+        }
+    }
+}
+
+/// Whether `username` is connected to any room.
+pub fn is_online(state: &AppState, username: &str) -> bool {
+    state.connections.count(username) > 0
+}
+
+/// Hands `notification` to the notifiers unless `username` is online, or
+/// holds it for their digest.
+pub fn notify(state: &Arc<AppState>, username: &str, mut notification: Notification) {
+    let quiet = matches!(notification.kind, Kind::Message | Kind::Highlight)
+        && preferences::is_quiet(state, username);
+    if state.notifiers.is_empty()
+        || is_online(state, username)
+        || presence::is_dnd(state, username)
+        || quiet
+    {
+        return;
+    }
+    if let Some((cut, _)) = notification.text.char_indices().nth(MAX_TEXT_LEN) {
+        notification.text.truncate(cut);
+        notification.text.push('…');
+    }
+    if state.digests.hold(username, &notification) {
+        return;
+    }
+    for notifier in state.notifiers.clone() {
+        let username = username.to_owned();
+        let notification = notification.clone();
+        tasks::spawn("notify", async move {
+            notifier.notify(&username, &notification).await
+        });
+    }
+}
+
+/// The names `text` mentions with `@name`, each once.
+fn mentioned(text: &str) -> Vec<&str> {
+    let mut seen = HashSet::new();
+    text.split_whitespace()
+        .filter_map(|word| word.strip_prefix('@'))
+        .map(|name| name.trim_end_matches(|c: char| c.is_ascii_punctuation()))
+        .filter(|name| !name.is_empty() && seen.insert(*name))
+        .collect()
+}
+
+/// Highlights message `id` of `room` for those watching its keywords, and
+/// notifies the offline members it mentions or highlights, unless they muted
+/// the room, and those getting every message of it.
+pub fn message_posted(state: &Arc<AppState>, room: &str, id: u64, from: &str, text: &str) {
+    let highlighted = highlights::highlight(state, room, id, from, text);
+    if state.notifiers.is_empty() {
+        return;
+    }
+    let preferences = &state.notification_preferences;
+    let mut notified = HashSet::new();
+    let mentions = mentioned(text)
+        .into_iter()
+        .map(|username| (username.to_owned(), Kind::Mention));
+    let messages = preferences
+        .with_level(room, Level::All)
+        .into_iter()
+        .map(|username| (username, Kind::Message));
+    let highlights = highlighted
+        .into_iter()
+        .map(|username| (username, Kind::Highlight));
+    for (username, kind) in mentions.chain(messages).chain(highlights) {
+        let muted = kind != Kind::Message && preferences.level(&username, room) == Level::Muted;
+        if username == from || muted || !notified.insert(username.clone()) {
+            continue;
+        }
+        notify(
+            state,
+            &username,
+            Notification {
+                kind,
+                room: room.to_owned(),
+                from: from.to_owned(),
+                text: text.to_owned(),
+            },
+        );
+    }
+}
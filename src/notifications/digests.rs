@@ -0,0 +1,211 @@
+//! Digests for members who rather hear about what they missed once in a
+//! while than about every mention as it happens.
+//!
+//! Members opt in with an interval through `PUT /users/:name/digest`. Their
+//! notifications are then held and handed to the notifiers together when
+//! the interval is up, which most of them sum up in one notification.
+//! Digests due while the member is in do not disturb wait until it ends.
+
+use axum::extract::{Path, Query, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::Json;
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::{BTreeSet, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use super::{Kind, Notification};
+use crate::events::unix_timestamp;
+use crate::room_names::RoomName;
+use crate::sessions::is_session;
+use crate::{presence, tasks, ApiResponse, AppState};
+
+/// Shortest and longest interval, in minutes.
+const MIN_INTERVAL: u64 = 5;
+const MAX_INTERVAL: u64 = 24 * 60;
+/// Notifications held per member, the oldest go first.
+const MAX_HELD: usize = 100;
+
+struct Schedule {
+    /// In seconds.
+    interval: u64,
+    /// Unix time the next digest is due at.
+    due: u64,
+    held: Vec<Notification>,
+}
+
+/// The members who opted in, by username.
+#[derive(Default)]
+pub struct Digests {
+    schedules: Mutex<HashMap<String, Schedule>>,
+}
+
+impl Digests {
+    /// Holds `notification` for the next digest of `username`, false if
+    /// they did not opt in.
+    pub fn hold(&self, username: &str, notification: &Notification) -> bool {
+        let mut schedules = self.schedules.lock().unwrap();
+        let Some(schedule) = schedules.get_mut(username) else {
+            return false;
+        };
+        schedule.held.push(notification.clone());
+        if schedule.held.len() > MAX_HELD {
+            schedule.held.remove(0);
+        }
+        true
+    }
+
+    /// Opts `username` in with `interval` in minutes, or out with `None`.
+    /// Opting out delivers nothing of what is held.
+    fn set(&self, username: &str, interval: Option<u64>) {
+        let mut schedules = self.schedules.lock().unwrap();
+        let Some(interval) = interval else {
+            schedules.remove(username);
+            return;
+        };
+        let interval = interval * 60;
+        let schedule = schedules
+            .entry(username.to_owned())
+            .or_insert_with(|| Schedule {
+                interval,
+                due: 0,
+                held: Vec::new(),
+            });
+        schedule.interval = interval;
+        schedule.due = unix_timestamp() + interval;
+    }
+
+    fn interval(&self, username: &str) -> Option<u64> {
+        let schedules = self.schedules.lock().unwrap();
+        schedules
+            .get(username)
+            .map(|schedule| schedule.interval / 60)
+    }
+}
+
+/// One notification summing up `notifications`.
+pub fn summary(notifications: &[Notification]) -> Notification {
+    let mut rooms = BTreeSet::new();
+    let mut from = BTreeSet::new();
+    let mut counts = [0; 4];
+    for notification in notifications {
+        rooms.insert(notification.room.as_str());
+        from.insert(notification.from.as_str());
+        let index = match notification.kind {
+            Kind::Mention => 0,
+            Kind::Direct => 1,
+            Kind::Highlight => 2,
+            Kind::Message | Kind::Digest => 3,
+        };
+        counts[index] += 1;
+    }
+    let names = [
+        ("mention", "mentions"),
+        ("direct message", "direct messages"),
+        ("highlight", "highlights"),
+        ("message", "messages"),
+    ];
+    let text = counts
+        .iter()
+        .zip(names)
+        .filter(|(count, _)| **count > 0)
+        .map(|(&count, (one, many))| format!("{} {}", count, if count == 1 { one } else { many }))
+        .collect::<Vec<_>>()
+        .join(", ");
+    Notification {
+        kind: Kind::Digest,
+        room: rooms.into_iter().collect::<Vec<_>>().join(", "),
+        from: from.into_iter().collect::<Vec<_>>().join(", "),
+        text,
+    }
+}
+
+/// Hands out the digests that are due, checking every half minute.
+pub async fn sender(state: Arc<AppState>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(30));
+    loop {
+        interval.tick().await;
+        let now = unix_timestamp();
+        let due = {
+            let mut schedules = state.digests.schedules.lock().unwrap();
+            schedules
+                .iter_mut()
+                .filter(|(_, schedule)| schedule.due <= now)
+                .filter(|(username, _)| !presence::is_dnd(&state, username))
+                .filter_map(|(username, schedule)| {
+                    schedule.due = now + schedule.interval;
+                    let held = std::mem::take(&mut schedule.held);
+                    (!held.is_empty()).then(|| (username.clone(), held))
+                })
+                .collect::<Vec<_>>()
+        };
+        for (username, held) in due {
+            for notifier in state.notifiers.clone() {
+                let username = username.clone();
+                let held = held.clone();
+                tasks::spawn(
+                    "digest",
+                    async move { notifier.digest(&username, &held).await },
+                );
+            }
+        }
+    }
+}
+
+fn error(status: StatusCode, message: &str) -> ApiResponse {
+    (status, Json(json!({ "status": message })))
+}
+
+#[derive(Deserialize)]
+pub struct DigestQuery {
+    room: RoomName,
+}
+
+/// `GET /users/:name/digest?room=`
+pub async fn get_digest(
+    Path(username): Path<String>,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Query(query): Query<DigestQuery>,
+) -> ApiResponse {
+    if !is_session(&state, &query.room, &username, &headers) {
+        return error(StatusCode::FORBIDDEN, "Missing or invalid session token.");
+    }
+    let interval = state.digests.interval(&username);
+    (
+        StatusCode::OK,
+        Json(json!({ "status": "Success!", "interval": interval })),
+    )
+}
+
+#[derive(Deserialize)]
+pub struct SetDigest {
+    room: RoomName,
+    /// Minutes between digests, `null` to be notified of everything again.
+    interval: Option<u64>,
+}
+
+/// `PUT /users/:name/digest`
+pub async fn set_digest(
+    Path(username): Path<String>,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(body): Json<SetDigest>,
+) -> ApiResponse {
+    if !is_session(&state, &body.room, &username, &headers) {
+        return error(StatusCode::FORBIDDEN, "Missing or invalid session token.");
+    }
+    if body
+        .interval
+        .is_some_and(|interval| !(MIN_INTERVAL..=MAX_INTERVAL).contains(&interval))
+    {
+        let message = format!(
+            "Digests are every {} to {} minutes.",
+            MIN_INTERVAL, MAX_INTERVAL
+        );
+        return error(StatusCode::BAD_REQUEST, &message);
+    }
+    state.digests.set(&username, body.interval);
+    (StatusCode::OK, Json(json!({ "status": "Success!" })))
+}
@@ -0,0 +1,437 @@
+//! Email notifications over SMTP, enabled with `SMTP_HOST`.
+//!
+//! Members register an address through `POST /notifications/email`, from a
+//! room whose notifications alone the address then gets, as the same name
+//! may be someone else's in other rooms. The address is sent a confirmation
+//! link first and gets nothing until it is followed; a confirmed address
+//! stays until the new one is. What members miss while offline is collected
+//! and sent as one email per `EMAIL_BATCH_SECONDS`, each with a link that
+//! unsubscribes the address.
+
+use async_trait::async_trait;
+use axum::extract::{Query, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::Json;
+use lettre::message::header::{ContentType, HeaderName, HeaderValue};
+use lettre::message::Mailbox;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::transport::smtp::client::{Tls, TlsParameters};
+use lettre::{Address, AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use log::{error, info};
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use subtle::ConstantTimeEq;
+
+use super::{Kind, Notification, Notifier};
+use crate::owners::generate_token;
+use crate::room_names::RoomName;
+use crate::sessions::is_session;
+use crate::{ApiResponse, AppState};
+
+const DEFAULT_BATCH: Duration = Duration::from_secs(5 * 60);
+/// Notifications listed in one email, the rest are only counted.
+const MAX_LISTED: usize = 50;
+/// How long a member waits between two confirmation emails, so that
+/// addresses of others cannot be flooded with them.
+const CONFIRM_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SmtpSecurity {
+    /// Upgrades the connection with `STARTTLS`, port 587 by default.
+    StartTls,
+    /// TLS from the start, port 465 by default.
+    Tls,
+    /// Plain text, for a relay on the same host or network.
+    None,
+}
+
+#[derive(Clone)]
+pub struct EmailConfig {
+    pub host: String,
+    pub port: Option<u16>,
+    pub security: SmtpSecurity,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Sender of the notifications.
+    pub from: Mailbox,
+    /// Where the server is reachable, for the unsubscribe links.
+    pub public_url: String,
+    /// How long notifications are collected before they are sent.
+    pub batch: Duration,
+}
+
+impl EmailConfig {
+    /// Reads `SMTP_HOST`, `SMTP_PORT`, `SMTP_SECURITY` (`starttls`, `tls` or
+    /// `none`), `SMTP_USERNAME`, `SMTP_PASSWORD`, `SMTP_FROM`, `PUBLIC_URL`
+    /// and `EMAIL_BATCH_SECONDS`.
+    pub fn from_env() -> Option<Self> {
+        let var = |name: &str| std::env::var(name).ok().filter(|val| !val.is_empty());
+        let host = var("SMTP_HOST")?;
+        let (Some(from), Some(public_url)) = (var("SMTP_FROM"), var("PUBLIC_URL")) else {
+            error!("Email notifications need SMTP_FROM and PUBLIC_URL");
+            return None;
+        };
+        let from = match from.parse() {
+            Ok(from) => from,
+            Err(err) => {
+                error!("Invalid SMTP_FROM {}: {}", from, err);
+                return None;
+            }
+        };
+        let security = match var("SMTP_SECURITY").as_deref() {
+            Some("tls") => SmtpSecurity::Tls,
+            Some("none") => SmtpSecurity::None,
+            _ => SmtpSecurity::StartTls,
+        };
+        Some(Self {
+            host,
+            port: var("SMTP_PORT").and_then(|port| port.parse().ok()),
+            security,
+            username: var("SMTP_USERNAME"),
+            password: var("SMTP_PASSWORD"),
+            from,
+            public_url: public_url.trim_end_matches('/').to_owned(),
+            batch: var("EMAIL_BATCH_SECONDS")
+                .and_then(|seconds| seconds.parse().ok())
+                .map(Duration::from_secs)
+                .unwrap_or(DEFAULT_BATCH),
+        })
+    }
+
+    fn transport(&self) -> Result<AsyncSmtpTransport<Tokio1Executor>, String> {
+        let tls = match self.security {
+            SmtpSecurity::None => Tls::None,
+            security => {
+                let parameters =
+                    TlsParameters::new(self.host.clone()).map_err(|err| err.to_string())?;
+                match security {
+                    SmtpSecurity::Tls => Tls::Wrapper(parameters),
+                    _ => Tls::Required(parameters),
+                }
+            }
+        };
+        let port = self.port.unwrap_or(match self.security {
+            SmtpSecurity::StartTls => 587,
+            SmtpSecurity::Tls => 465,
+            SmtpSecurity::None => 25,
+        });
+        let mut builder = AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&self.host)
+            .port(port)
+            .tls(tls)
+            .timeout(Some(Duration::from_secs(30)));
+        if let (Some(username), Some(password)) = (&self.username, &self.password) {
+            builder = builder.credentials(Credentials::new(username.clone(), password.clone()));
+        }
+        Ok(builder.build())
+    }
+}
+
+/// Who registered: a member in the room they registered from.
+type Key = (String, String);
+
+fn key(room: &str, username: &str) -> Key {
+    (room.to_owned(), username.to_owned())
+}
+
+/// An address waiting for its confirmation link to be followed.
+struct Unconfirmed {
+    address: Address,
+    /// Secret of the confirmation link.
+    token: String,
+    sent_at: Instant,
+}
+
+struct Recipient {
+    address: Address,
+    /// Secret of the address's unsubscribe link.
+    token: String,
+    pending: Vec<Notification>,
+    /// Notifications beyond [`MAX_LISTED`].
+    more: usize,
+}
+
+pub struct Email {
+    config: EmailConfig,
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    /// Confirmed addresses by room and username.
+    recipients: Mutex<HashMap<Key, Recipient>>,
+    unconfirmed: Mutex<HashMap<Key, Unconfirmed>>,
+}
+
+impl Email {
+    pub fn new(config: EmailConfig) -> Result<Self, String> {
+        Ok(Self {
+            transport: config.transport()?,
+            config,
+            recipients: Mutex::new(HashMap::new()),
+            unconfirmed: Mutex::new(HashMap::new()),
+        })
+    }
+
+    fn confirmation(&self, username: &str, unconfirmed: &Unconfirmed) -> Option<Message> {
+        let confirm = format!(
+            "{}/notifications/email/confirm?token={}",
+            self.config.public_url, unconfirmed.token
+        );
+        let body = format!(
+            "{} asked to be emailed at this address about what they miss in the chat.\n\n\
+             Confirm it: {}\n\nIf this was not you, ignore this email.\n",
+            username, confirm
+        );
+        Message::builder()
+            .from(self.config.from.clone())
+            .to(Mailbox::new(None, unconfirmed.address.clone()))
+            .subject("Confirm your email notifications")
+            .header(ContentType::TEXT_PLAIN)
+            .body(body)
+            .map_err(|err| error!("Failed to build email: {}", err))
+            .ok()
+    }
+
+    fn compose(&self, recipient: &Recipient) -> Option<Message> {
+        let mut body = String::from("Here is what you missed while you were away:\n\n");
+        for notification in &recipient.pending {
+            let what = match notification.kind {
+                Kind::Message => "wrote",
+                Kind::Mention => "mentioned you",
+                Kind::Highlight => "wrote something you watch for",
+                Kind::Direct => "sent you a direct message",
+                Kind::Digest => "sent you",
+            };
+            let _ = writeln!(
+                body,
+                "[{}] {} {}:\n{}\n",
+                notification.room, notification.from, what, notification.text
+            );
+        }
+        if recipient.more > 0 {
+            let _ = writeln!(body, "... and {} more.\n", recipient.more);
+        }
+        let unsubscribe = format!(
+            "{}/notifications/email/unsubscribe?token={}",
+            self.config.public_url, recipient.token
+        );
+        let _ = write!(body, "--\nStop these emails: {}\n", unsubscribe);
+        let count = recipient.pending.len() + recipient.more;
+        let subject = match count {
+            1 => "1 new notification".to_owned(),
+            count => format!("{} new notifications", count),
+        };
+        Message::builder()
+            .from(self.config.from.clone())
+            .to(Mailbox::new(None, recipient.address.clone()))
+            .subject(subject)
+            .header(ContentType::TEXT_PLAIN)
+            .raw_header(HeaderValue::new(
+                HeaderName::new_from_ascii_str("List-Unsubscribe"),
+                format!("<{}>", unsubscribe),
+            ))
+            .raw_header(HeaderValue::new(
+                HeaderName::new_from_ascii_str("List-Unsubscribe-Post"),
+                "List-Unsubscribe=One-Click".to_owned(),
+            ))
+            .body(body)
+            .map_err(|err| error!("Failed to build email: {}", err))
+            .ok()
+    }
+
+    /// Sends the collected notifications, one email per member.
+    async fn flush(&self) {
+        let messages = {
+            let mut recipients = self.recipients.lock().unwrap();
+            recipients
+                .iter_mut()
+                .filter(|(_, recipient)| !recipient.pending.is_empty())
+                .filter_map(|((_, username), recipient)| {
+                    let message = self.compose(recipient);
+                    recipient.pending.clear();
+                    recipient.more = 0;
+                    Some((username.clone(), message?))
+                })
+                .collect::<Vec<_>>()
+        };
+        for (username, message) in messages {
+            match self.transport.send(message).await {
+                Ok(_) => info!("Emailed notifications to {}", username),
+                Err(err) => error!("Failed to email {}: {}", username, err),
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Notifier for Email {
+    async fn notify(&self, username: &str, notification: &Notification) {
+        self.digest(username, std::slice::from_ref(notification))
+            .await
+    }
+
+    /// Lists every notification, emails being digests already.
+    async fn digest(&self, username: &str, notifications: &[Notification]) {
+        let mut recipients = self.recipients.lock().unwrap();
+        for notification in notifications {
+            if let Some(recipient) = recipients.get_mut(&key(&notification.room, username)) {
+                if recipient.pending.len() < MAX_LISTED {
+                    recipient.pending.push(notification.clone());
+                } else {
+                    recipient.more += 1;
+                }
+            }
+        }
+    }
+}
+
+/// Sends the collected notifications every batch interval.
+pub async fn sender(email: Arc<Email>) {
+    let mut interval = tokio::time::interval(email.config.batch);
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        email.flush().await;
+    }
+}
+
+fn error(status: StatusCode, message: &str) -> ApiResponse {
+    (status, Json(json!({ "status": message })))
+}
+
+fn email(state: &AppState) -> Result<&Email, ApiResponse> {
+    state
+        .email
+        .as_deref()
+        .ok_or_else(|| error(StatusCode::NOT_FOUND, "Email notifications are disabled."))
+}
+
+#[derive(Deserialize)]
+pub struct RegisterRequest {
+    username: String,
+    room: RoomName,
+    address: String,
+}
+
+/// `POST /notifications/email`, sends a confirmation link to the address a
+/// member wants to be emailed at about the room.
+pub async fn register(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(body): Json<RegisterRequest>,
+) -> ApiResponse {
+    let email = match email(&state) {
+        Ok(email) => email,
+        Err(response) => return response,
+    };
+    if !is_session(&state, &body.room, &body.username, &headers) {
+        return error(StatusCode::FORBIDDEN, "Missing or invalid session token.");
+    }
+    let Ok(address) = body.address.trim().parse::<Address>() else {
+        return error(StatusCode::BAD_REQUEST, "Invalid email address.");
+    };
+    let unconfirmed = Unconfirmed {
+        address,
+        token: generate_token(),
+        sent_at: Instant::now(),
+    };
+    let Some(message) = email.confirmation(&body.username, &unconfirmed) else {
+        return error(StatusCode::BAD_REQUEST, "Invalid email address.");
+    };
+    {
+        let mut pending = email.unconfirmed.lock().unwrap();
+        pending.retain(|_, unconfirmed| unconfirmed.sent_at.elapsed() < CONFIRM_INTERVAL);
+        let key = key(&body.room, &body.username);
+        if pending.contains_key(&key) {
+            return error(
+                StatusCode::TOO_MANY_REQUESTS,
+                "A confirmation email was sent already, try again later.",
+            );
+        }
+        pending.insert(key, unconfirmed);
+    }
+    if let Err(err) = email.transport.send(message).await {
+        error!("Failed to email {}: {}", body.username, err);
+        let key = key(&body.room, &body.username);
+        email.unconfirmed.lock().unwrap().remove(&key);
+        return error(
+            StatusCode::BAD_GATEWAY,
+            "Failed to send the confirmation email.",
+        );
+    }
+    (
+        StatusCode::ACCEPTED,
+        Json(json!({ "status": "Confirmation email sent." })),
+    )
+}
+
+#[derive(Deserialize)]
+pub struct ConfirmQuery {
+    token: String,
+}
+
+/// `GET /notifications/email/confirm?token=`, the link in the confirmation
+/// email, from then on emailing the address instead of any previous one.
+pub async fn confirm(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<ConfirmQuery>,
+) -> ApiResponse {
+    let email = match email(&state) {
+        Ok(email) => email,
+        Err(response) => return response,
+    };
+    let confirmed = {
+        let mut pending = email.unconfirmed.lock().unwrap();
+        let key = pending
+            .iter()
+            .find(|(_, unconfirmed)| {
+                unconfirmed.sent_at.elapsed() < CONFIRM_INTERVAL
+                    && bool::from(unconfirmed.token.as_bytes().ct_eq(query.token.as_bytes()))
+            })
+            .map(|(key, _)| key.clone());
+        key.and_then(|key| pending.remove_entry(&key))
+    };
+    let Some((key, unconfirmed)) = confirmed else {
+        return error(
+            StatusCode::NOT_FOUND,
+            "Unknown or expired confirmation link.",
+        );
+    };
+    email.recipients.lock().unwrap().insert(
+        key,
+        Recipient {
+            address: unconfirmed.address,
+            token: generate_token(),
+            pending: Vec::new(),
+            more: 0,
+        },
+    );
+    (StatusCode::OK, Json(json!({ "status": "Confirmed." })))
+}
+
+#[derive(Deserialize)]
+pub struct UnsubscribeQuery {
+    token: String,
+}
+
+/// `GET` or `POST /notifications/email/unsubscribe?token=`, the link in
+/// every email.
+pub async fn unsubscribe(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<UnsubscribeQuery>,
+) -> ApiResponse {
+    let email = match email(&state) {
+        Ok(email) => email,
+        Err(response) => return response,
+    };
+    let mut recipients = email.recipients.lock().unwrap();
+    let before = recipients.len();
+    recipients.retain(|_, recipient| {
+        !bool::from(recipient.token.as_bytes().ct_eq(query.token.as_bytes()))
+    });
+    if recipients.len() < before {
+        (StatusCode::OK, Json(json!({ "status": "Unsubscribed." })))
+    } else {
+        error(StatusCode::NOT_FOUND, "Unknown or used unsubscribe link.")
+    }
+}
@@ -0,0 +1,162 @@
+//! Keywords members watch for per room, like an IRC client's highlights.
+//!
+//! A message containing one of them as a whole word, in any case, reaches
+//! the member as a `highlight` event on every connection, and as a
+//! notification while they are offline.
+
+use axum::extract::{Path, Query, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::Json;
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use crate::events::ChatEvent;
+use crate::room_names::RoomName;
+use crate::sessions::is_session;
+use crate::{ApiResponse, AppState};
+
+const MAX_KEYWORDS: usize = 20;
+const MAX_LEN: usize = 50;
+
+/// The keywords members watch for, lowercase, keyed by username and room.
+#[derive(Default)]
+pub struct Highlights {
+    keywords: Mutex<HashMap<(String, String), Vec<String>>>,
+}
+
+/// Whether `keyword` occurs in `text` with no letter or digit around it.
+fn contains_word(text: &str, keyword: &str) -> bool {
+    text.match_indices(keyword).any(|(start, _)| {
+        let before = text[..start].chars().next_back();
+        let after = text[start + keyword.len()..].chars().next();
+        !before.is_some_and(char::is_alphanumeric) && !after.is_some_and(char::is_alphanumeric)
+    })
+}
+
+impl Highlights {
+    pub fn get(&self, username: &str, room: &str) -> Vec<String> {
+        let keywords = self.keywords.lock().unwrap();
+        keywords
+            .get(&(username.to_owned(), room.to_owned()))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Replaces the keywords of `username` in `room`, none removes them.
+    pub fn set(&self, username: &str, room: &str, keywords: Vec<String>) {
+        let mut normalized = Vec::new();
+        for keyword in keywords {
+            let keyword = keyword.trim().to_lowercase();
+            if !keyword.is_empty() && !normalized.contains(&keyword) {
+                normalized.push(keyword);
+            }
+        }
+        let key = (username.to_owned(), room.to_owned());
+        let mut keywords = self.keywords.lock().unwrap();
+        if normalized.is_empty() {
+            keywords.remove(&key);
+        } else {
+            keywords.insert(key, normalized);
+        }
+    }
+
+    /// The members watching `room` for a keyword in `text`, each with the
+    /// first keyword found.
+    fn matching(&self, room: &str, text: &str) -> Vec<(String, String)> {
+        let text = text.to_lowercase();
+        let keywords = self.keywords.lock().unwrap();
+        keywords
+            .iter()
+            .filter(|((_, of_room), _)| of_room == room)
+            .filter_map(|((username, _), keywords)| {
+                let keyword = keywords
+                    .iter()
+                    .find(|keyword| contains_word(&text, keyword))?;
+                Some((username.clone(), keyword.clone()))
+            })
+            .collect()
+    }
+}
+
+/// Sends a `highlight` event for message `id` to the members whose keywords
+/// it contains, returning them for the offline ones to be notified.
+pub fn highlight(state: &AppState, room: &str, id: u64, from: &str, text: &str) -> Vec<String> {
+    let matching = state.highlights.matching(room, text);
+    matching
+        .into_iter()
+        .filter(|(username, _)| username != from)
+        .map(|(username, keyword)| {
+            let event = ChatEvent::Highlight {
+                room: room.to_owned(),
+                id,
+                from: from.to_owned(),
+                text: text.to_owned(),
+                keyword,
+            };
+            state.connections.send(&username, &event, None);
+            username
+        })
+        .collect()
+}
+
+/// Why keywords were refused, if they were.
+pub fn invalid(keywords: &[String]) -> Option<String> {
+    if keywords.len() > MAX_KEYWORDS {
+        return Some(format!("At most {} keywords.", MAX_KEYWORDS));
+    }
+    keywords
+        .iter()
+        .any(|keyword| keyword.trim().chars().count() > MAX_LEN)
+        .then(|| format!("Keywords are at most {} characters.", MAX_LEN))
+}
+
+fn error(status: StatusCode, message: &str) -> ApiResponse {
+    (status, Json(json!({ "status": message })))
+}
+
+#[derive(Deserialize)]
+pub struct HighlightsQuery {
+    username: String,
+}
+
+/// `GET /rooms/:name/highlights?username=`
+pub async fn get_highlights(
+    Path(room): Path<RoomName>,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Query(query): Query<HighlightsQuery>,
+) -> ApiResponse {
+    if !is_session(&state, &room, &query.username, &headers) {
+        return error(StatusCode::FORBIDDEN, "Missing or invalid session token.");
+    }
+    let keywords = state.highlights.get(&query.username, &room);
+    (
+        StatusCode::OK,
+        Json(json!({ "status": "Success!", "keywords": keywords })),
+    )
+}
+
+#[derive(Deserialize)]
+pub struct SetHighlights {
+    username: String,
+    keywords: Vec<String>,
+}
+
+/// `PUT /rooms/:name/highlights`, replaces the member's keywords.
+pub async fn set_highlights(
+    Path(room): Path<RoomName>,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(body): Json<SetHighlights>,
+) -> ApiResponse {
+    if !is_session(&state, &room, &body.username, &headers) {
+        return error(StatusCode::FORBIDDEN, "Missing or invalid session token.");
+    }
+    if let Some(reason) = invalid(&body.keywords) {
+        return error(StatusCode::BAD_REQUEST, &reason);
+    }
+    state.highlights.set(&body.username, &room, body.keywords);
+    (StatusCode::OK, Json(json!({ "status": "Success!" })))
+}
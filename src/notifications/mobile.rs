@@ -0,0 +1,545 @@
+//! Push notifications for native apps, through Firebase Cloud Messaging
+//! (HTTP v1) and the Apple Push Notification service (token-based).
+//!
+//! Apps register their device token through `POST /push/devices`, from a
+//! room whose notifications alone the device then receives, as for Web
+//! Push. Which notifications are sent is decided as for Web Push too; a
+//! token the service reports as unregistered is removed.
+
+use async_trait::async_trait;
+use axum::extract::State;
+use axum::http::{HeaderMap, StatusCode};
+use axum::Json;
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use base64::Engine;
+use log::{error, info, warn};
+use p256::ecdsa::signature::Signer;
+use p256::ecdsa::{Signature, SigningKey};
+use p256::pkcs8::DecodePrivateKey;
+use ring::rand::SystemRandom;
+use ring::signature::{RsaKeyPair, RSA_PKCS1_SHA256};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use super::{Kind, Notification, Notifier};
+use crate::events::unix_timestamp;
+use crate::room_names::RoomName;
+use crate::sessions::is_session;
+use crate::{ApiResponse, AppState};
+
+const FCM_SCOPE: &str = "https://www.googleapis.com/auth/firebase.messaging";
+/// Devices kept per member.
+const MAX_DEVICES: usize = 10;
+/// APNs rejects provider tokens older than an hour.
+const APNS_TOKEN_LIFETIME: u64 = 50 * 60;
+
+/// The DER body of a PEM block.
+fn pem(pem: &str) -> Option<Vec<u8>> {
+    let body = pem
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with("-----"))
+        .collect::<String>();
+    STANDARD.decode(body).ok()
+}
+
+/// A signed JSON Web Token with `header` and `claims`.
+fn jwt(
+    header: Value,
+    claims: Value,
+    sign: impl FnOnce(&[u8]) -> Option<Vec<u8>>,
+) -> Option<String> {
+    let signed = format!(
+        "{}.{}",
+        URL_SAFE_NO_PAD.encode(header.to_string()),
+        URL_SAFE_NO_PAD.encode(claims.to_string())
+    );
+    let signature = sign(signed.as_bytes())?;
+    Some(format!("{}.{}", signed, URL_SAFE_NO_PAD.encode(signature)))
+}
+
+#[derive(Deserialize)]
+struct ServiceAccount {
+    project_id: String,
+    client_email: String,
+    private_key: String,
+    token_uri: String,
+}
+
+/// A Firebase project's service account.
+#[derive(Clone)]
+pub struct FcmConfig {
+    project_id: String,
+    client_email: String,
+    key: Arc<RsaKeyPair>,
+    token_uri: String,
+}
+
+impl FcmConfig {
+    /// Takes the service account key JSON downloaded from the Firebase
+    /// console.
+    pub fn new(service_account: &str) -> Result<Self, String> {
+        let account: ServiceAccount =
+            serde_json::from_str(service_account).map_err(|err| err.to_string())?;
+        let der = pem(&account.private_key).ok_or("Service account key is not PEM")?;
+        let key = RsaKeyPair::from_pkcs8(&der).map_err(|err| err.to_string())?;
+        Ok(Self {
+            project_id: account.project_id,
+            client_email: account.client_email,
+            key: Arc::new(key),
+            token_uri: account.token_uri,
+        })
+    }
+
+    /// Reads the service account key from the file `FCM_SERVICE_ACCOUNT`.
+    pub fn from_env() -> Option<Self> {
+        let var = |name: &str| std::env::var(name).ok().filter(|val| !val.is_empty());
+        let path = var("FCM_SERVICE_ACCOUNT")?;
+        let config = std::fs::read_to_string(&path)
+            .map_err(|err| err.to_string())
+            .and_then(|json| Self::new(&json));
+        match config {
+            Ok(config) => Some(config),
+            Err(err) => {
+                error!("FCM disabled, cannot use {}: {}", path, err);
+                None
+            }
+        }
+    }
+
+    /// The assertion exchanged for an access token.
+    fn assertion(&self) -> Option<String> {
+        let now = unix_timestamp();
+        let claims = json!({
+            "iss": self.client_email,
+            "scope": FCM_SCOPE,
+            "aud": self.token_uri,
+            "iat": now,
+            "exp": now + 60 * 60,
+        });
+        jwt(json!({ "alg": "RS256", "typ": "JWT" }), claims, |signed| {
+            let mut signature = vec![0; self.key.public().modulus_len()];
+            self.key
+                .sign(
+                    &RSA_PKCS1_SHA256,
+                    &SystemRandom::new(),
+                    signed,
+                    &mut signature,
+                )
+                .ok()?;
+            Some(signature)
+        })
+    }
+}
+
+/// An APNs signing key of an Apple developer team.
+#[derive(Clone)]
+pub struct ApnsConfig {
+    key: SigningKey,
+    key_id: String,
+    team_id: String,
+    /// The app's bundle id.
+    topic: String,
+    /// Uses the development environment, for builds run from Xcode.
+    pub sandbox: bool,
+}
+
+impl ApnsConfig {
+    /// Takes the contents of the `.p8` key file with its id.
+    pub fn new(key: &str, key_id: &str, team_id: &str, topic: &str) -> Result<Self, String> {
+        let der = pem(key).ok_or("APNs key is not PEM")?;
+        let key = SigningKey::from_pkcs8_der(&der).map_err(|_| "APNs key is not a P-256 key")?;
+        Ok(Self {
+            key,
+            key_id: key_id.to_owned(),
+            team_id: team_id.to_owned(),
+            topic: topic.to_owned(),
+            sandbox: false,
+        })
+    }
+
+    /// Reads `APNS_KEY_FILE`, `APNS_KEY_ID`, `APNS_TEAM_ID`, `APNS_TOPIC`
+    /// and `APNS_SANDBOX`.
+    pub fn from_env() -> Option<Self> {
+        let var = |name: &str| std::env::var(name).ok().filter(|val| !val.is_empty());
+        let path = var("APNS_KEY_FILE")?;
+        let (Some(key_id), Some(team_id), Some(topic)) =
+            (var("APNS_KEY_ID"), var("APNS_TEAM_ID"), var("APNS_TOPIC"))
+        else {
+            error!("APNs needs APNS_KEY_ID, APNS_TEAM_ID and APNS_TOPIC");
+            return None;
+        };
+        let config = std::fs::read_to_string(&path)
+            .map_err(|err| err.to_string())
+            .and_then(|key| Self::new(&key, &key_id, &team_id, &topic));
+        match config {
+            Ok(mut config) => {
+                config.sandbox = var("APNS_SANDBOX").is_some_and(|value| value != "0");
+                Some(config)
+            }
+            Err(err) => {
+                error!("APNs disabled, cannot use {}: {}", path, err);
+                None
+            }
+        }
+    }
+
+    fn provider_token(&self) -> Option<String> {
+        let header = json!({ "alg": "ES256", "kid": self.key_id });
+        let claims = json!({ "iss": self.team_id, "iat": unix_timestamp() });
+        jwt(header, claims, |signed| {
+            let signature: Signature = self.key.sign(signed);
+            Some(signature.to_bytes().to_vec())
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Platform {
+    Fcm,
+    Apns,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Device {
+    pub platform: Platform,
+    pub token: String,
+}
+
+/// Who registered: a member in the room they registered from, whose
+/// notifications alone reach them, as the same name may be someone else's
+/// in other rooms.
+type Key = (String, String);
+
+fn key(room: &str, username: &str) -> Key {
+    (room.to_owned(), username.to_owned())
+}
+
+/// An access or provider token with the Unix time it is renewed at.
+type Cached = Mutex<Option<(String, u64)>>;
+
+pub struct MobilePush {
+    fcm: Option<FcmConfig>,
+    apns: Option<ApnsConfig>,
+    client: reqwest::Client,
+    fcm_token: Cached,
+    apns_token: Cached,
+    /// Devices of each member by room and username.
+    devices: Mutex<HashMap<Key, Vec<Device>>>,
+}
+
+/// The alert shown for `notification`.
+fn title(notification: &Notification) -> String {
+    match notification.kind {
+        Kind::Mention => format!(
+            "{} mentioned you in {}",
+            notification.from, notification.room
+        ),
+        Kind::Direct => notification.from.clone(),
+        Kind::Message | Kind::Highlight => {
+            format!("{} in {}", notification.from, notification.room)
+        }
+        Kind::Digest => format!("While you were away in {}", notification.room),
+    }
+}
+
+/// What became of a push.
+enum Outcome {
+    Sent,
+    Failed,
+    /// The device no longer has the app or the token changed.
+    Unregistered,
+}
+
+impl MobilePush {
+    pub fn new(fcm: Option<FcmConfig>, apns: Option<ApnsConfig>) -> Self {
+        Self {
+            fcm,
+            apns,
+            client: reqwest::Client::builder()
+                .timeout(Duration::from_secs(10))
+                .build()
+                .expect("static client config"),
+            fcm_token: Mutex::new(None),
+            apns_token: Mutex::new(None),
+            devices: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn supports(&self, platform: Platform) -> bool {
+        match platform {
+            Platform::Fcm => self.fcm.is_some(),
+            Platform::Apns => self.apns.is_some(),
+        }
+    }
+
+    /// Adds `device` for `username` in `room`, dropping the oldest beyond
+    /// [`MAX_DEVICES`].
+    pub fn register(&self, room: &str, username: &str, device: Device) {
+        let mut devices = self.devices.lock().unwrap();
+        let of_user = devices.entry(key(room, username)).or_default();
+        of_user.retain(|known| known.token != device.token);
+        of_user.push(device);
+        if of_user.len() > MAX_DEVICES {
+            of_user.remove(0);
+        }
+    }
+
+    /// Removes the device with `token`, returning whether there was one.
+    pub fn unregister(&self, room: &str, username: &str, token: &str) -> bool {
+        let key = key(room, username);
+        let mut devices = self.devices.lock().unwrap();
+        let Some(of_user) = devices.get_mut(&key) else {
+            return false;
+        };
+        let before = of_user.len();
+        of_user.retain(|known| known.token != token);
+        let removed = of_user.len() < before;
+        if of_user.is_empty() {
+            devices.remove(&key);
+        }
+        removed
+    }
+
+    /// An OAuth access token for FCM, fetched again when about to expire.
+    async fn fcm_access_token(&self, fcm: &FcmConfig) -> Option<String> {
+        if let Some((token, renew_at)) = self.fcm_token.lock().unwrap().clone() {
+            if unix_timestamp() < renew_at {
+                return Some(token);
+            }
+        }
+        let body = serde_urlencoded::to_string([
+            ("grant_type", "urn:ietf:params:oauth:grant-type:jwt-bearer"),
+            ("assertion", &fcm.assertion()?),
+        ])
+        .ok()?;
+        let response = self
+            .client
+            .post(&fcm.token_uri)
+            .header("Content-Type", "application/x-www-form-urlencoded")
+            .body(body)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+        let granted: Value = match response {
+            Ok(response) => response.json().await.ok()?,
+            Err(err) => {
+                warn!("Failed to get an FCM access token: {}", err);
+                return None;
+            }
+        };
+        let token = granted["access_token"].as_str()?.to_owned();
+        let expires_in = granted["expires_in"].as_u64().unwrap_or(3600);
+        let renew_at = unix_timestamp() + expires_in.saturating_sub(60);
+        *self.fcm_token.lock().unwrap() = Some((token.clone(), renew_at));
+        Some(token)
+    }
+
+    fn apns_provider_token(&self, apns: &ApnsConfig) -> Option<String> {
+        let mut cached = self.apns_token.lock().unwrap();
+        if let Some((token, renew_at)) = cached.as_ref() {
+            if unix_timestamp() < *renew_at {
+                return Some(token.clone());
+            }
+        }
+        let token = apns.provider_token()?;
+        *cached = Some((token.clone(), unix_timestamp() + APNS_TOKEN_LIFETIME));
+        Some(token)
+    }
+
+    async fn send_fcm(&self, token: &str, notification: &Notification) -> Outcome {
+        let Some(fcm) = &self.fcm else {
+            return Outcome::Unregistered;
+        };
+        let Some(access_token) = self.fcm_access_token(fcm).await else {
+            return Outcome::Failed;
+        };
+        let message = json!({
+            "message": {
+                "token": token,
+                "notification": { "title": title(notification), "body": notification.text },
+                "data": {
+                    "type": notification.kind,
+                    "room": notification.room,
+                    "from": notification.from,
+                },
+                "android": { "priority": "high" },
+            }
+        });
+        let url = format!(
+            "https://fcm.googleapis.com/v1/projects/{}/messages:send",
+            fcm.project_id
+        );
+        let response = self
+            .client
+            .post(url)
+            .bearer_auth(access_token)
+            .json(&message)
+            .send()
+            .await;
+        match response {
+            Ok(response) if response.status().is_success() => Outcome::Sent,
+            Ok(response) => {
+                let status = response.status();
+                let body = response.text().await.unwrap_or_default();
+                if status == reqwest::StatusCode::NOT_FOUND || body.contains("UNREGISTERED") {
+                    Outcome::Unregistered
+                } else {
+                    warn!("FCM push failed with {}: {}", status, body);
+                    Outcome::Failed
+                }
+            }
+            Err(err) => {
+                warn!("FCM push failed: {}", err);
+                Outcome::Failed
+            }
+        }
+    }
+
+    async fn send_apns(&self, token: &str, notification: &Notification) -> Outcome {
+        let Some(apns) = &self.apns else {
+            return Outcome::Unregistered;
+        };
+        let Some(provider_token) = self.apns_provider_token(apns) else {
+            return Outcome::Failed;
+        };
+        let host = if apns.sandbox {
+            "api.sandbox.push.apple.com"
+        } else {
+            "api.push.apple.com"
+        };
+        let payload = json!({
+            "aps": {
+                "alert": { "title": title(notification), "body": notification.text },
+                "sound": "default",
+            },
+            "type": notification.kind,
+            "room": notification.room,
+            "from": notification.from,
+        });
+        let response = self
+            .client
+            .post(format!("https://{}/3/device/{}", host, token))
+            .header("authorization", format!("bearer {}", provider_token))
+            .header("apns-topic", &apns.topic)
+            .header("apns-push-type", "alert")
+            .header("apns-priority", "10")
+            .json(&payload)
+            .send()
+            .await;
+        match response {
+            Ok(response) if response.status().is_success() => Outcome::Sent,
+            Ok(response) => {
+                let status = response.status();
+                let body = response.text().await.unwrap_or_default();
+                if status == reqwest::StatusCode::GONE || body.contains("BadDeviceToken") {
+                    Outcome::Unregistered
+                } else {
+                    warn!("APNs push failed with {}: {}", status, body);
+                    Outcome::Failed
+                }
+            }
+            Err(err) => {
+                warn!("APNs push failed: {}", err);
+                Outcome::Failed
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Notifier for MobilePush {
+    async fn notify(&self, username: &str, notification: &Notification) {
+        let devices = self
+            .devices
+            .lock()
+            .unwrap()
+            .get(&key(&notification.room, username))
+            .cloned()
+            .unwrap_or_default();
+        for device in devices {
+            let outcome = match device.platform {
+                Platform::Fcm => self.send_fcm(&device.token, notification).await,
+                Platform::Apns => self.send_apns(&device.token, notification).await,
+            };
+            if let Outcome::Unregistered = outcome {
+                info!("Device of {} unregistered", username);
+                self.unregister(&notification.room, username, &device.token);
+            }
+        }
+    }
+}
+
+fn error(status: StatusCode, message: &str) -> ApiResponse {
+    (status, Json(json!({ "status": message })))
+}
+
+fn mobile_push(state: &AppState) -> Result<&MobilePush, ApiResponse> {
+    state
+        .mobile_push
+        .as_deref()
+        .ok_or_else(|| error(StatusCode::NOT_FOUND, "Mobile push is disabled."))
+}
+
+#[derive(Deserialize)]
+pub struct RegisterRequest {
+    username: String,
+    room: RoomName,
+    #[serde(flatten)]
+    device: Device,
+}
+
+/// `POST /push/devices`
+pub async fn register(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(body): Json<RegisterRequest>,
+) -> ApiResponse {
+    let mobile_push = match mobile_push(&state) {
+        Ok(mobile_push) => mobile_push,
+        Err(response) => return response,
+    };
+    if !is_session(&state, &body.room, &body.username, &headers) {
+        return error(StatusCode::FORBIDDEN, "Missing or invalid session token.");
+    }
+    if !mobile_push.supports(body.device.platform) {
+        return error(StatusCode::BAD_REQUEST, "Platform is not configured.");
+    }
+    let token = &body.device.token;
+    if token.is_empty() || token.len() > 4096 {
+        return error(StatusCode::BAD_REQUEST, "Invalid device token.");
+    }
+    mobile_push.register(&body.room, &body.username, body.device);
+    (StatusCode::CREATED, Json(json!({ "status": "Success!" })))
+}
+
+#[derive(Deserialize)]
+pub struct UnregisterRequest {
+    username: String,
+    room: RoomName,
+    token: String,
+}
+
+/// `POST /push/devices/remove`
+pub async fn unregister(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(body): Json<UnregisterRequest>,
+) -> ApiResponse {
+    let mobile_push = match mobile_push(&state) {
+        Ok(mobile_push) => mobile_push,
+        Err(response) => return response,
+    };
+    if !is_session(&state, &body.room, &body.username, &headers) {
+        return error(StatusCode::FORBIDDEN, "Missing or invalid session token.");
+    }
+    if mobile_push.unregister(&body.room, &body.username, &body.token) {
+        (StatusCode::OK, Json(json!({ "status": "Success!" })))
+    } else {
+        error(StatusCode::NOT_FOUND, "Device not found.")
+    }
+}
@@ -0,0 +1,190 @@
+//! How much of each room members want to be notified of, set with a
+//! `notifications` WebSocket frame or `PUT /rooms/:name/notifications`.
+//!
+//! During their quiet hours, a daily window on their clock, members are
+//! only notified of mentions and direct messages whatever the levels.
+
+use axum::extract::{Path, Query, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::Json;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use crate::profiles::Window;
+use crate::room_names::RoomName;
+use crate::sessions::is_session;
+use crate::{ApiResponse, AppState};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Level {
+    /// Every message of the room.
+    All,
+    /// Messages mentioning the member.
+    #[default]
+    Mentions,
+    /// Nothing from the room, direct messages still notify.
+    Muted,
+}
+
+/// The levels members chose, keyed by username and room.
+#[derive(Default)]
+pub struct Preferences {
+    levels: Mutex<HashMap<(String, String), Level>>,
+    quiet_hours: Mutex<HashMap<String, Window>>,
+}
+
+impl Preferences {
+    pub fn level(&self, username: &str, room: &str) -> Level {
+        let levels = self.levels.lock().unwrap();
+        levels
+            .get(&(username.to_owned(), room.to_owned()))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    pub fn set(&self, username: &str, room: &str, level: Level) {
+        let key = (username.to_owned(), room.to_owned());
+        let mut levels = self.levels.lock().unwrap();
+        if level == Level::default() {
+            levels.remove(&key);
+        } else {
+            levels.insert(key, level);
+        }
+    }
+
+    /// The members who chose `level` for `room`.
+    pub fn with_level(&self, room: &str, level: Level) -> Vec<String> {
+        let levels = self.levels.lock().unwrap();
+        levels
+            .iter()
+            .filter(|((_, of_room), chosen)| of_room == room && **chosen == level)
+            .map(|((username, _), _)| username.clone())
+            .collect()
+    }
+}
+
+/// Whether it is during the quiet hours of `username`.
+pub fn is_quiet(state: &AppState, username: &str) -> bool {
+    let quiet_hours = state.notification_preferences.quiet_hours.lock().unwrap();
+    quiet_hours
+        .get(username)
+        .is_some_and(|window| window.covers(state.profiles.local_minutes(username)))
+}
+
+fn forbidden() -> ApiResponse {
+    (
+        StatusCode::FORBIDDEN,
+        Json(json!({ "status": "Missing or invalid session token." })),
+    )
+}
+
+#[derive(Deserialize)]
+pub struct LevelQuery {
+    username: String,
+}
+
+/// `GET /rooms/:name/notifications?username=`
+pub async fn get_level(
+    Path(room): Path<RoomName>,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Query(query): Query<LevelQuery>,
+) -> ApiResponse {
+    if !is_session(&state, &room, &query.username, &headers) {
+        return forbidden();
+    }
+    let level = state.notification_preferences.level(&query.username, &room);
+    (
+        StatusCode::OK,
+        Json(json!({ "status": "Success!", "level": level })),
+    )
+}
+
+#[derive(Deserialize)]
+pub struct SetLevel {
+    username: String,
+    level: Level,
+}
+
+/// `PUT /rooms/:name/notifications`
+pub async fn set_level(
+    Path(room): Path<RoomName>,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(body): Json<SetLevel>,
+) -> ApiResponse {
+    if !is_session(&state, &room, &body.username, &headers) {
+        return forbidden();
+    }
+    state
+        .notification_preferences
+        .set(&body.username, &room, body.level);
+    (StatusCode::OK, Json(json!({ "status": "Success!" })))
+}
+
+#[derive(Deserialize)]
+pub struct QuietHoursQuery {
+    room: RoomName,
+}
+
+/// `GET /users/:name/quiet-hours?room=`
+pub async fn get_quiet_hours(
+    Path(username): Path<String>,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Query(query): Query<QuietHoursQuery>,
+) -> ApiResponse {
+    if !is_session(&state, &query.room, &username, &headers) {
+        return forbidden();
+    }
+    let preferences = &state.notification_preferences;
+    let schedule = preferences
+        .quiet_hours
+        .lock()
+        .unwrap()
+        .get(&username)
+        .cloned();
+    (
+        StatusCode::OK,
+        Json(json!({
+            "status": "Success!",
+            "schedule": schedule,
+            "quiet": is_quiet(&state, &username),
+        })),
+    )
+}
+
+#[derive(Deserialize)]
+pub struct SetQuietHours {
+    room: RoomName,
+    /// The window, none when missing or `null`.
+    #[serde(default)]
+    schedule: Option<Window>,
+}
+
+/// `PUT /users/:name/quiet-hours`
+pub async fn set_quiet_hours(
+    Path(username): Path<String>,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(body): Json<SetQuietHours>,
+) -> ApiResponse {
+    if !is_session(&state, &body.room, &username, &headers) {
+        return forbidden();
+    }
+    let mut quiet_hours = state.notification_preferences.quiet_hours.lock().unwrap();
+    match body.schedule {
+        Some(window) if !window.is_valid() => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(json!({ "status": "The window is two different HH:MM times." })),
+            );
+        }
+        Some(window) => quiet_hours.insert(username, window),
+        None => quiet_hours.remove(&username),
+    };
+    (StatusCode::OK, Json(json!({ "status": "Success!" })))
+}
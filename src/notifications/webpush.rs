@@ -0,0 +1,409 @@
+//! Browser push notifications following RFC 8030, with payloads encrypted
+//! as in RFC 8291 and the server identified by VAPID (RFC 8292).
+//!
+//! Browsers fetch the server's key from `GET /push/key`, subscribe with it
+//! and register the subscription through `POST /push/subscribe`, from a
+//! room whose notifications alone it then receives. Only public endpoints
+//! are pushed to. The push service's answer that a subscription is gone
+//! removes it.
+
+use aes_gcm::aead::{Aead, KeyInit};
+use aes_gcm::{Aes128Gcm, Nonce};
+use async_trait::async_trait;
+use axum::extract::State;
+use axum::http::{HeaderMap, StatusCode};
+use axum::Json;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use hkdf::Hkdf;
+use log::{error, info, warn};
+use p256::ecdh::diffie_hellman;
+use p256::ecdsa::signature::Signer;
+use p256::ecdsa::{Signature, SigningKey, VerifyingKey};
+use p256::elliptic_curve::sec1::ToEncodedPoint;
+use p256::{PublicKey, SecretKey};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use super::{Notification, Notifier};
+use crate::events::unix_timestamp;
+use crate::room_names::RoomName;
+use crate::sessions::is_session;
+use crate::{previews, ApiResponse, AppState};
+
+/// How long push services keep undelivered notifications, in seconds.
+const TTL: u64 = 24 * 60 * 60;
+/// Subscriptions kept per member, e.g. one per browser.
+const MAX_SUBSCRIPTIONS: usize = 10;
+const RECORD_SIZE: u32 = 4096;
+
+/// The application server's VAPID identity.
+#[derive(Clone)]
+pub struct VapidConfig {
+    key: SigningKey,
+    /// Contact for the push services, a `mailto:` or `https:` URL.
+    subject: Option<String>,
+}
+
+impl VapidConfig {
+    /// Takes the base64url encoded P-256 private key, as generated by most
+    /// Web Push libraries.
+    pub fn new(private_key: &str, subject: Option<String>) -> Result<Self, String> {
+        let bytes = decode(private_key).ok_or("VAPID key is not base64url")?;
+        let key = SigningKey::from_slice(&bytes).map_err(|_| "VAPID key is not a P-256 key")?;
+        Ok(Self { key, subject })
+    }
+
+    /// Reads `VAPID_PRIVATE_KEY` and `VAPID_SUBJECT`.
+    pub fn from_env() -> Option<Self> {
+        let var = |name: &str| std::env::var(name).ok().filter(|val| !val.is_empty());
+        let key = var("VAPID_PRIVATE_KEY")?;
+        match Self::new(&key, var("VAPID_SUBJECT")) {
+            Ok(config) => Some(config),
+            Err(err) => {
+                error!("Web Push disabled: {}", err);
+                None
+            }
+        }
+    }
+
+    /// The public key browsers subscribe with, base64url encoded.
+    pub fn public_key(&self) -> String {
+        let point = VerifyingKey::from(&self.key).to_encoded_point(false);
+        URL_SAFE_NO_PAD.encode(point.as_bytes())
+    }
+
+    /// The `Authorization` header for requests to `endpoint`.
+    fn authorization(&self, endpoint: &str) -> Option<String> {
+        let url = reqwest::Url::parse(endpoint).ok()?;
+        let audience = url.origin().ascii_serialization();
+        let mut claims = json!({ "aud": audience, "exp": unix_timestamp() + 12 * 60 * 60 });
+        if let Some(subject) = &self.subject {
+            claims["sub"] = json!(subject);
+        }
+        let header = URL_SAFE_NO_PAD.encode(r#"{"typ":"JWT","alg":"ES256"}"#);
+        let claims = URL_SAFE_NO_PAD.encode(claims.to_string());
+        let signed = format!("{}.{}", header, claims);
+        let signature: Signature = self.key.sign(signed.as_bytes());
+        let token = format!(
+            "{}.{}",
+            signed,
+            URL_SAFE_NO_PAD.encode(signature.to_bytes())
+        );
+        Some(format!("vapid t={}, k={}", token, self.public_key()))
+    }
+}
+
+/// Decodes base64url with or without padding.
+fn decode(value: &str) -> Option<Vec<u8>> {
+    URL_SAFE_NO_PAD
+        .decode(value.trim().trim_end_matches('='))
+        .ok()
+}
+
+/// A browser's `PushSubscription`, as its `toJSON()` gives it.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Subscription {
+    pub endpoint: String,
+    pub keys: SubscriptionKeys,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SubscriptionKeys {
+    pub p256dh: String,
+    pub auth: String,
+}
+
+/// Encrypts `payload` for the browser holding `keys`, giving the body of
+/// an `aes128gcm` encoded push message.
+fn encrypt(keys: &SubscriptionKeys, payload: &[u8]) -> Option<Vec<u8>> {
+    let mut salt = [0; 16];
+    OsRng.fill_bytes(&mut salt);
+    encrypt_with(keys, payload, &SecretKey::random(&mut OsRng), salt)
+}
+
+/// [`encrypt`] with a given ephemeral key and salt, which must not be
+/// used for another message.
+fn encrypt_with(
+    keys: &SubscriptionKeys,
+    payload: &[u8],
+    secret: &SecretKey,
+    salt: [u8; 16],
+) -> Option<Vec<u8>> {
+    let ua_public = decode(&keys.p256dh)?;
+    let auth = decode(&keys.auth)?;
+    let ua_key = PublicKey::from_sec1_bytes(&ua_public).ok()?;
+    let as_public = secret.public_key().to_encoded_point(false);
+    let shared = diffie_hellman(secret.to_nonzero_scalar(), ua_key.as_affine());
+
+    let mut key_info = b"WebPush: info\0".to_vec();
+    key_info.extend_from_slice(&ua_public);
+    key_info.extend_from_slice(as_public.as_bytes());
+    let mut ikm = [0; 32];
+    Hkdf::<Sha256>::new(Some(&auth), shared.raw_secret_bytes())
+        .expand(&key_info, &mut ikm)
+        .ok()?;
+
+    let prk = Hkdf::<Sha256>::new(Some(&salt), &ikm);
+    let (mut cek, mut nonce) = ([0; 16], [0; 12]);
+    prk.expand(b"Content-Encoding: aes128gcm\0", &mut cek)
+        .ok()?;
+    prk.expand(b"Content-Encoding: nonce\0", &mut nonce).ok()?;
+
+    // A single record, ended by the last-record delimiter.
+    let mut plaintext = payload.to_vec();
+    plaintext.push(2);
+    let ciphertext = Aes128Gcm::new_from_slice(&cek)
+        .ok()?
+        .encrypt(Nonce::from_slice(&nonce), plaintext.as_slice())
+        .ok()?;
+
+    let mut body = salt.to_vec();
+    body.extend_from_slice(&RECORD_SIZE.to_be_bytes());
+    body.push(as_public.as_bytes().len() as u8);
+    body.extend_from_slice(as_public.as_bytes());
+    body.extend_from_slice(&ciphertext);
+    Some(body)
+}
+
+/// Who registered: a member in the room they registered from, whose
+/// notifications alone reach them, as the same name may be someone else's
+/// in other rooms.
+type Key = (String, String);
+
+fn key(room: &str, username: &str) -> Key {
+    (room.to_owned(), username.to_owned())
+}
+
+pub struct WebPush {
+    vapid: VapidConfig,
+    client: reqwest::Client,
+    /// Subscriptions of each member by room and username.
+    subscriptions: Mutex<HashMap<Key, Vec<Subscription>>>,
+}
+
+impl WebPush {
+    pub fn new(vapid: VapidConfig) -> Self {
+        Self {
+            vapid,
+            // Endpoints are given by members, so only public ones are reached.
+            client: previews::public_client(Duration::from_secs(10)),
+            subscriptions: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Adds `subscription` for `username` in `room`, replacing one with the
+    /// same endpoint and dropping the oldest beyond [`MAX_SUBSCRIPTIONS`].
+    pub fn subscribe(&self, room: &str, username: &str, subscription: Subscription) {
+        let mut subscriptions = self.subscriptions.lock().unwrap();
+        let of_user = subscriptions.entry(key(room, username)).or_default();
+        of_user.retain(|known| known.endpoint != subscription.endpoint);
+        of_user.push(subscription);
+        if of_user.len() > MAX_SUBSCRIPTIONS {
+            of_user.remove(0);
+        }
+    }
+
+    /// Removes the subscription with `endpoint`, returning whether there
+    /// was one.
+    pub fn unsubscribe(&self, room: &str, username: &str, endpoint: &str) -> bool {
+        let key = key(room, username);
+        let mut subscriptions = self.subscriptions.lock().unwrap();
+        let Some(of_user) = subscriptions.get_mut(&key) else {
+            return false;
+        };
+        let before = of_user.len();
+        of_user.retain(|known| known.endpoint != endpoint);
+        let removed = of_user.len() < before;
+        if of_user.is_empty() {
+            subscriptions.remove(&key);
+        }
+        removed
+    }
+
+    /// Posts `payload` to one subscription, `false` once it is gone.
+    async fn push(&self, subscription: &Subscription, payload: &[u8]) -> bool {
+        let (Some(body), Some(authorization)) = (
+            encrypt(&subscription.keys, payload),
+            self.vapid.authorization(&subscription.endpoint),
+        ) else {
+            warn!(
+                "Dropping unusable push subscription {}",
+                subscription.endpoint
+            );
+            return false;
+        };
+        let response = self
+            .client
+            .post(&subscription.endpoint)
+            .header("Authorization", authorization)
+            .header("Content-Encoding", "aes128gcm")
+            .header("Content-Type", "application/octet-stream")
+            .header("TTL", TTL.to_string())
+            .header("Urgency", "high")
+            .body(body)
+            .send()
+            .await;
+        match response {
+            Ok(response) if response.status().is_success() => true,
+            Ok(response)
+                if matches!(
+                    response.status(),
+                    reqwest::StatusCode::NOT_FOUND | reqwest::StatusCode::GONE
+                ) =>
+            {
+                info!("Push subscription {} expired", subscription.endpoint);
+                false
+            }
+            Ok(response) => {
+                warn!(
+                    "Push to {} failed with {}",
+                    subscription.endpoint,
+                    response.status()
+                );
+                true
+            }
+            Err(err) => {
+                warn!("Push to {} failed: {}", subscription.endpoint, err);
+                true
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Notifier for WebPush {
+    async fn notify(&self, username: &str, notification: &Notification) {
+        let subscriptions = self
+            .subscriptions
+            .lock()
+            .unwrap()
+            .get(&key(&notification.room, username))
+            .cloned()
+            .unwrap_or_default();
+        let payload = serde_json::to_vec(notification).unwrap_or_default();
+        for subscription in subscriptions {
+            if !self.push(&subscription, &payload).await {
+                self.unsubscribe(&notification.room, username, &subscription.endpoint);
+            }
+        }
+    }
+}
+
+fn error(status: StatusCode, message: &str) -> ApiResponse {
+    (status, Json(json!({ "status": message })))
+}
+
+fn web_push(state: &AppState) -> Result<&WebPush, ApiResponse> {
+    state
+        .web_push
+        .as_deref()
+        .ok_or_else(|| error(StatusCode::NOT_FOUND, "Push notifications are disabled."))
+}
+
+/// `GET /push/key`, the `applicationServerKey` browsers subscribe with.
+pub async fn public_key(State(state): State<Arc<AppState>>) -> ApiResponse {
+    match web_push(&state) {
+        Ok(web_push) => (
+            StatusCode::OK,
+            Json(json!({ "status": "Success!", "key": web_push.vapid.public_key() })),
+        ),
+        Err(response) => response,
+    }
+}
+
+#[derive(Deserialize)]
+pub struct SubscribeRequest {
+    username: String,
+    room: RoomName,
+    subscription: Subscription,
+}
+
+/// `POST /push/subscribe`
+pub async fn subscribe(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(body): Json<SubscribeRequest>,
+) -> ApiResponse {
+    let web_push = match web_push(&state) {
+        Ok(web_push) => web_push,
+        Err(response) => return response,
+    };
+    if !is_session(&state, &body.room, &body.username, &headers) {
+        return error(StatusCode::FORBIDDEN, "Missing or invalid session token.");
+    }
+    let endpoint = reqwest::Url::parse(&body.subscription.endpoint);
+    if !endpoint.is_ok_and(|endpoint| endpoint.scheme() == "https" && previews::allowed(&endpoint))
+    {
+        return error(StatusCode::BAD_REQUEST, "Invalid push endpoint.");
+    }
+    web_push.subscribe(&body.room, &body.username, body.subscription);
+    (StatusCode::CREATED, Json(json!({ "status": "Success!" })))
+}
+
+#[derive(Deserialize)]
+pub struct UnsubscribeRequest {
+    username: String,
+    room: RoomName,
+    endpoint: String,
+}
+
+/// `POST /push/unsubscribe`
+pub async fn unsubscribe(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(body): Json<UnsubscribeRequest>,
+) -> ApiResponse {
+    let web_push = match web_push(&state) {
+        Ok(web_push) => web_push,
+        Err(response) => return response,
+    };
+    if !is_session(&state, &body.room, &body.username, &headers) {
+        return error(StatusCode::FORBIDDEN, "Missing or invalid session token.");
+    }
+    if web_push.unsubscribe(&body.room, &body.username, &body.endpoint) {
+        (StatusCode::OK, Json(json!({ "status": "Success!" })))
+    } else {
+        error(StatusCode::NOT_FOUND, "Subscription not found.")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The example of RFC 8291, appendix A.
+    #[test]
+    fn encrypts_as_rfc_8291() {
+        let keys = SubscriptionKeys {
+            p256dh: "BCVxsr7N_eNgVRqvHtD0zTZsEc6-VV-JvLexhqUzORcxaOzi6-AYWXvTBHm4bjyPjs7Vd8pZGH6SRpkNtoIAiw4"
+                .to_owned(),
+            auth: "BTBZMqHH6r4Tts7J_aSIgg".to_owned(),
+        };
+        let secret = decode("yfWPiYE-n46HLnH0KqZOF1fJJU3MYrct3AELtAQ-oRw").unwrap();
+        let secret = SecretKey::from_slice(&secret).unwrap();
+        let salt = decode("DGv6ra1nlYgDCS1FRnbzlw")
+            .unwrap()
+            .try_into()
+            .unwrap();
+        let body = encrypt_with(
+            &keys,
+            b"When I grow up, I want to be a watermelon",
+            &secret,
+            salt,
+        )
+        .unwrap();
+        assert_eq!(
+            URL_SAFE_NO_PAD.encode(body),
+            "DGv6ra1nlYgDCS1FRnbzlwAAEABBBP4z9KsN6nGRTbVYI_c7VJSPQTBtkgcy27mlmlMoZIIgDll6e3vCYLocInmYW\
+             AmS6TlzAC8wEqKK6PBru3jl7A_yl95bQpu6cVPTpK4Mqgkf1CXztLVBSt2Ks3oZwbuwXPXLWyouBWLVWGNWQexSgS\
+             xsj_Qulcy4a-fN"
+        );
+    }
+}
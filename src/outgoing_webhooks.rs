@@ -0,0 +1,356 @@
+use axum::extract::{Path, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::Json;
+use hmac::{Hmac, Mac};
+use log::{error, info, warn};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::Sha256;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{broadcast, mpsc, Semaphore};
+
+use crate::components::Interaction;
+use crate::events::{self, unix_timestamp, RoomEvent};
+use crate::owners::{forbidden, generate_token, is_owner};
+use crate::room_names::RoomName;
+use crate::{previews, sentry, tasks, ApiResponse, AppState};
+
+const MAX_ATTEMPTS: u32 = 5;
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const TIMEOUT: Duration = Duration::from_secs(10);
+/// Deliveries waiting for the dispatcher at most, later ones are dropped.
+pub const QUEUE_LEN: usize = 1000;
+/// Deliveries in flight at once, retries included.
+const MAX_IN_FLIGHT: usize = 32;
+
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EventKind {
+    Message,
+    Join,
+    Leave,
+    Keyword,
+    /// A member picked a [component](crate::components) of a message.
+    Interaction,
+}
+
+/// A URL registered by a room owner that receives signed event POSTs.
+#[derive(Serialize, Deserialize)]
+pub struct OutgoingWebhook {
+    pub room: String,
+    pub url: String,
+    pub secret: String,
+    pub events: Vec<EventKind>,
+    pub keywords: Vec<String>,
+}
+
+/// A single signed POST waiting to be delivered by the dispatcher.
+pub struct Delivery {
+    /// Id of the webhook.
+    id: String,
+    url: String,
+    secret: String,
+    event: EventKind,
+    body: String,
+    /// What the event concerns, for the error report if it is given up on.
+    room: String,
+    username: String,
+}
+
+#[derive(Deserialize)]
+pub struct CreateOutgoingWebhook {
+    url: String,
+    events: Vec<EventKind>,
+    #[serde(default)]
+    keywords: Vec<String>,
+}
+
+pub async fn create_outgoing_webhook(
+    Path(room): Path<RoomName>,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(body): Json<CreateOutgoingWebhook>,
+) -> ApiResponse {
+    if !is_owner(&state, &room, &headers) {
+        return forbidden();
+    }
+    if !Url::parse(&body.url).is_ok_and(|url| previews::allowed(&url)) {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "status": "Webhook url must be a public http(s) URL." })),
+        );
+    }
+    if body.events.is_empty() {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "status": "At least one event is required." })),
+        );
+    }
+
+    let id = generate_token();
+    let secret = generate_token();
+    state.outgoing_webhooks.lock().unwrap().insert(
+        id.clone(),
+        OutgoingWebhook {
+            room: room.into(),
+            url: body.url,
+            secret: secret.clone(),
+            events: body.events,
+            keywords: body
+                .keywords
+                .iter()
+                .map(|keyword| keyword.to_lowercase())
+                .filter(|keyword| !keyword.is_empty())
+                .collect(),
+        },
+    );
+    (
+        StatusCode::CREATED,
+        Json(json!({ "status": "Success!", "id": id, "secret": secret })),
+    )
+}
+
+pub async fn list_outgoing_webhooks(
+    Path(room): Path<RoomName>,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> ApiResponse {
+    if !is_owner(&state, &room, &headers) {
+        return forbidden();
+    }
+    let webhooks = state.outgoing_webhooks.lock().unwrap();
+    let hooks = webhooks
+        .iter()
+        .filter(|(_, hook)| hook.room == room)
+        .map(|(id, hook)| {
+            json!({
+                "id": id,
+                "url": hook.url,
+                "events": hook.events,
+                "keywords": hook.keywords,
+            })
+        })
+        .collect::<Vec<_>>();
+    (
+        StatusCode::OK,
+        Json(json!({ "status": "Success!", "webhooks": hooks })),
+    )
+}
+
+pub async fn delete_outgoing_webhook(
+    Path((room, id)): Path<(RoomName, String)>,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> ApiResponse {
+    if !is_owner(&state, &room, &headers) {
+        return forbidden();
+    }
+    let mut webhooks = state.outgoing_webhooks.lock().unwrap();
+    match webhooks.get(&id) {
+        Some(hook) if hook.room == room => {
+            webhooks.remove(&id);
+            (StatusCode::OK, Json(json!({ "status": "Success!" })))
+        }
+        _ => (
+            StatusCode::NOT_FOUND,
+            Json(json!({ "status": "Webhook not found." })),
+        ),
+    }
+}
+
+/// Turns joins, messages and leaves on the bus into webhook deliveries.
+pub async fn subscriber(state: Arc<AppState>, mut events: broadcast::Receiver<RoomEvent>) {
+    while let Some(event) = events::next(&mut events, "Outgoing webhooks").await {
+        match event {
+            RoomEvent::MessageSent { room, from, text } => {
+                emit(&state, &room, EventKind::Message, &from, Some(&text))
+            }
+            RoomEvent::UserJoined { room, username } => {
+                emit(&state, &room, EventKind::Join, &username, None)
+            }
+            RoomEvent::UserLeft { room, username } => {
+                emit(&state, &room, EventKind::Leave, &username, None)
+            }
+            RoomEvent::Interacted(interaction) => emit_interaction(&state, &interaction),
+            RoomEvent::RoomCreated { .. } | RoomEvent::RoomDeleted { .. } => {}
+        }
+    }
+}
+
+/// Queues a delivery of `interaction` for every webhook of its room
+/// subscribed to interactions.
+fn emit_interaction(state: &AppState, interaction: &Interaction) {
+    let webhooks = state.outgoing_webhooks.lock().unwrap();
+    let body = json!({
+        "event": EventKind::Interaction,
+        "room": interaction.room,
+        "username": interaction.username,
+        "message": interaction.message,
+        "author": interaction.author,
+        "component": interaction.component,
+        "value": interaction.value,
+        "timestamp": unix_timestamp(),
+    })
+    .to_string();
+    for (id, hook) in webhooks.iter().filter(|(_, hook)| {
+        hook.room == interaction.room && hook.events.contains(&EventKind::Interaction)
+    }) {
+        queue(
+            state,
+            Delivery {
+                id: id.clone(),
+                url: hook.url.clone(),
+                secret: hook.secret.clone(),
+                event: EventKind::Interaction,
+                body: body.clone(),
+                room: interaction.room.clone(),
+                username: interaction.username.clone(),
+            },
+        );
+    }
+}
+
+/// Queues a delivery for every webhook of `room` subscribed to `event`.
+/// Message events additionally fire `keyword` deliveries for matching hooks.
+fn emit(state: &AppState, room: &str, event: EventKind, username: &str, text: Option<&str>) {
+    let webhooks = state.outgoing_webhooks.lock().unwrap();
+    let timestamp = unix_timestamp();
+
+    for (id, hook) in webhooks.iter().filter(|(_, hook)| hook.room == room) {
+        let mut payloads = Vec::new();
+        if hook.events.contains(&event) {
+            payloads.push((event, Value::Null));
+        }
+        if let (EventKind::Message, Some(text)) = (event, text) {
+            if hook.events.contains(&EventKind::Keyword) {
+                let lowered = text.to_lowercase();
+                if let Some(keyword) = hook.keywords.iter().find(|k| lowered.contains(*k)) {
+                    payloads.push((EventKind::Keyword, json!(keyword)));
+                }
+            }
+        }
+
+        for (kind, keyword) in payloads {
+            let body = json!({
+                "event": kind,
+                "room": room,
+                "username": username,
+                "text": text,
+                "keyword": keyword,
+                "timestamp": timestamp,
+            })
+            .to_string();
+            queue(
+                state,
+                Delivery {
+                    id: id.clone(),
+                    url: hook.url.clone(),
+                    secret: hook.secret.clone(),
+                    event: kind,
+                    body,
+                    room: room.to_owned(),
+                    username: username.to_owned(),
+                },
+            );
+        }
+    }
+}
+
+/// Hands `delivery` to the dispatcher, dropping it if too many are waiting.
+fn queue(state: &AppState, delivery: Delivery) {
+    if let Err(mpsc::error::TrySendError::Full(delivery)) =
+        state.webhook_deliveries.try_send(delivery)
+    {
+        warn!(
+            "Dropping an event of {} for webhook {}, too many deliveries are waiting",
+            delivery.room, delivery.id
+        );
+    }
+}
+
+/// The `X-Chatr-Signature` of `body` sent at `timestamp`, the Unix time in
+/// seconds of its `X-Chatr-Timestamp`. Both are signed, as `timestamp.body`,
+/// so that receivers can refuse requests replayed later.
+pub fn sign(secret: &str, timestamp: u64, body: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(format!("{}.{}", timestamp, body).as_bytes());
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+/// Background task delivering queued webhook events, each delivery retried
+/// with exponential backoff on its own task so a slow endpoint can't stall
+/// others, up to [`MAX_IN_FLIGHT`] at once.
+pub async fn dispatcher(mut deliveries: mpsc::Receiver<Delivery>) {
+    let client = previews::public_client(TIMEOUT);
+    let in_flight = Arc::new(Semaphore::new(MAX_IN_FLIGHT));
+
+    while let Some(delivery) = deliveries.recv().await {
+        let client = client.clone();
+        let Ok(permit) = in_flight.clone().acquire_owned().await else {
+            return;
+        };
+        tasks::spawn("webhook-delivery", async move {
+            let _permit = permit;
+            let event = serde_json::to_value(delivery.event).unwrap_or_default();
+            let event = event.as_str().unwrap_or_default();
+            let mut backoff = INITIAL_BACKOFF;
+
+            for attempt in 1..=MAX_ATTEMPTS {
+                let timestamp = unix_timestamp();
+                let result = client
+                    .post(&delivery.url)
+                    .header("content-type", "application/json")
+                    .header("x-chatr-event", event)
+                    .header("x-chatr-timestamp", timestamp)
+                    .header(
+                        "x-chatr-signature",
+                        sign(&delivery.secret, timestamp, &delivery.body),
+                    )
+                    .body(delivery.body.clone())
+                    .send()
+                    .await;
+
+                match result {
+                    Ok(res) if res.status().is_success() => {
+                        info!("Delivered {} event to {}", event, delivery.url);
+                        return;
+                    }
+                    Ok(res) => warn!(
+                        "Webhook {} answered {} (attempt {}/{})",
+                        delivery.url,
+                        res.status(),
+                        attempt,
+                        MAX_ATTEMPTS
+                    ),
+                    Err(err) => warn!(
+                        "Webhook {} failed: {} (attempt {}/{})",
+                        delivery.url, err, attempt, MAX_ATTEMPTS
+                    ),
+                }
+
+                if attempt < MAX_ATTEMPTS {
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                }
+            }
+            error!("Giving up on {} event for {}", event, delivery.url);
+            // The host only, the rest of the URL may hold a secret.
+            let host = reqwest::Url::parse(&delivery.url)
+                .ok()
+                .and_then(|url| url.host_str().map(str::to_owned))
+                .unwrap_or_default();
+            sentry::report_error(
+                "outgoing_webhooks",
+                format_args!(
+                    "Gave up on {} event for webhook {} at {} after {} attempts",
+                    event, delivery.id, host, MAX_ATTEMPTS
+                ),
+                Some(&delivery.room),
+                Some(&delivery.username),
+            );
+        });
+    }
+}
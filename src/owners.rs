@@ -0,0 +1,154 @@
+use axum::extract::{ConnectInfo, OriginalUri, Path, State};
+use axum::http::header::AUTHORIZATION;
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use serde::Deserialize;
+use serde_json::json;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use subtle::ConstantTimeEq;
+
+use crate::quotas::Creator;
+use crate::room_names::RoomName;
+use crate::rooms::{self, JoinError};
+use crate::{cluster, usernames, ApiResponse, AppState};
+
+/// Random alphanumeric secret used for owner keys and webhook tokens.
+pub fn generate_token() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(32)
+        .map(char::from)
+        .collect()
+}
+
+pub fn bearer(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+}
+
+/// Returns true when the request carries the owner key of `room`.
+pub fn is_owner(state: &AppState, room: &str, headers: &HeaderMap) -> bool {
+    let owners = state.owners.lock().unwrap();
+    match (owners.get(room), bearer(headers)) {
+        (Some(key), Some(given)) => key.as_bytes().ct_eq(given.as_bytes()).into(),
+        _ => false,
+    }
+}
+
+/// Returns true when the request carries the server's admin token.
+pub fn is_admin(state: &AppState, headers: &HeaderMap) -> bool {
+    match (&state.admin_token, bearer(headers)) {
+        (Some(token), Some(given)) => token.as_bytes().ct_eq(given.as_bytes()).into(),
+        _ => false,
+    }
+}
+
+pub fn admin_forbidden() -> ApiResponse {
+    (
+        StatusCode::FORBIDDEN,
+        Json(json!({ "status": "Missing or invalid admin token." })),
+    )
+}
+
+pub fn forbidden() -> ApiResponse {
+    (
+        StatusCode::FORBIDDEN,
+        Json(json!({ "status": "Missing or invalid owner key." })),
+    )
+}
+
+/// Hands out the owner key of an active room to the first caller.
+pub async fn claim_room(
+    Path(room): Path<RoomName>,
+    State(state): State<Arc<AppState>>,
+) -> ApiResponse {
+    if !state.rooms.lock().unwrap().contains_key(room.as_str()) {
+        return (
+            StatusCode::NOT_FOUND,
+            Json(json!({ "status": "Room not found." })),
+        );
+    }
+
+    let mut owners = state.owners.lock().unwrap();
+    if owners.contains_key(room.as_str()) {
+        return (
+            StatusCode::CONFLICT,
+            Json(json!({ "status": "Room already has an owner." })),
+        );
+    }
+
+    let key = generate_token();
+    owners.insert(room.into(), key.clone());
+    (
+        StatusCode::CREATED,
+        Json(json!({ "status": "Success!", "owner_key": key })),
+    )
+}
+
+#[derive(Deserialize)]
+pub struct CreateRoom {
+    name: RoomName,
+    username: String,
+}
+
+/// `POST /rooms`, creates an empty room within the [quotas](crate::quotas)
+/// of the member and their address, and hands them its owner key.
+pub async fn create_room(
+    State(state): State<Arc<AppState>>,
+    OriginalUri(uri): OriginalUri,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    Json(body): Json<CreateRoom>,
+) -> Response {
+    let status =
+        |code: StatusCode, status: &str| (code, Json(json!({ "status": status }))).into_response();
+    let room = body.name.as_str();
+    if !usernames::is_valid(&body.username) {
+        return status(
+            StatusCode::BAD_REQUEST,
+            &JoinError::InvalidUsername.to_string(),
+        );
+    }
+    if !state.auth.authorize(room, &body.username) {
+        return status(StatusCode::FORBIDDEN, &JoinError::Forbidden.to_string());
+    }
+    let exists = || {
+        state.rooms.lock().unwrap().contains_key(room)
+            || state.owners.lock().unwrap().contains_key(room)
+    };
+    if exists() {
+        return status(StatusCode::CONFLICT, "Room already exists or has an owner.");
+    }
+    let creator = Creator {
+        username: &body.username,
+        ip: connect_info.map(|ConnectInfo(addr)| addr.ip()),
+    };
+    match rooms::with_room(&state, room, Some(creator), |_| ()).await {
+        Ok(()) => {}
+        Err(JoinError::Elsewhere(url)) => return cluster::redirect(&url, &uri),
+        Err(err @ JoinError::Archived) => return status(StatusCode::CONFLICT, &err.to_string()),
+        Err(err @ (JoinError::RoomLimit | JoinError::CreationLimit)) => {
+            return status(StatusCode::TOO_MANY_REQUESTS, &err.to_string())
+        }
+        Err(err) => return status(StatusCode::FORBIDDEN, &err.to_string()),
+    }
+
+    // A member may have joined and claimed the room in the meantime.
+    let mut owners = state.owners.lock().unwrap();
+    if owners.contains_key(room) {
+        return status(StatusCode::CONFLICT, "Room already exists or has an owner.");
+    }
+    let key = generate_token();
+    owners.insert(room.to_owned(), key.clone());
+    (
+        StatusCode::CREATED,
+        Json(json!({ "status": "Success!", "room": room, "owner_key": key })),
+    )
+        .into_response()
+}
@@ -0,0 +1,146 @@
+//! Structured parts of a message's text: prose, fenced code blocks and
+//! `||spoilers||`. A message whose text has code blocks or spoilers carries
+//! its `parts`, the text itself stays readable by clients that ignore them.
+
+use serde::{Deserialize, Serialize};
+
+/// Longest language tag taken from an opening fence.
+const MAX_LANGUAGE_LEN: usize = 20;
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Part {
+    Text {
+        text: String,
+    },
+    /// A fenced code block, kept verbatim.
+    Code {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        language: Option<String>,
+        code: String,
+    },
+    /// Prose clients hide until clicked.
+    Spoiler {
+        text: String,
+    },
+}
+
+impl Part {
+    pub fn is_text(&self) -> bool {
+        matches!(self, Part::Text { .. })
+    }
+
+    pub fn is_code(&self) -> bool {
+        matches!(self, Part::Code { .. })
+    }
+}
+
+fn is_language(tag: &str) -> bool {
+    (1..=MAX_LANGUAGE_LEN).contains(&tag.len())
+        && tag
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "+-#._".contains(c))
+}
+
+/// Appends `text` trimmed, unless nothing is left, merged with prose
+/// right before it.
+fn push_text(parts: &mut Vec<Part>, text: &str) {
+    let text = text.trim();
+    if text.is_empty() {
+        return;
+    }
+    match parts.last_mut() {
+        Some(Part::Text { text: last }) => {
+            last.push(' ');
+            last.push_str(text);
+        }
+        _ => parts.push(Part::Text {
+            text: text.to_owned(),
+        }),
+    }
+}
+
+/// Appends prose, split at its `||` spoiler markers.
+fn push_prose(parts: &mut Vec<Part>, text: &str) {
+    let mut rest = text;
+    while let Some(start) = rest.find("||") {
+        let after = &rest[start + 2..];
+        let Some(end) = after.find("||") else {
+            break;
+        };
+        let hidden = after[..end].trim();
+        if hidden.is_empty() {
+            push_text(parts, &rest[..start + end + 4]);
+        } else {
+            push_text(parts, &rest[..start]);
+            parts.push(Part::Spoiler {
+                text: hidden.to_owned(),
+            });
+        }
+        rest = &after[end + 2..];
+    }
+    push_text(parts, rest);
+}
+
+/// Splits `text` at its ```` ``` ```` fences and spoiler markers. The first
+/// line of a block is its language when it is a single word. Prose around
+/// the blocks and spoilers is trimmed, and dropped when nothing is left.
+pub fn split(text: &str) -> Vec<Part> {
+    let mut parts = Vec::new();
+    let mut rest = text;
+    while let Some(start) = rest.find("```") {
+        let after = &rest[start + 3..];
+        let Some(end) = after.find("```") else {
+            break;
+        };
+        let block = &after[..end];
+        let (language, code) = match block.split_once('\n') {
+            Some((first, code)) if first.trim().is_empty() || is_language(first.trim()) => {
+                let language = Some(first.trim()).filter(|tag| !tag.is_empty());
+                (language, code)
+            }
+            _ => (None, block),
+        };
+        push_prose(&mut parts, &rest[..start]);
+        parts.push(Part::Code {
+            language: language.map(str::to_owned),
+            code: code.strip_suffix('\n').unwrap_or(code).to_owned(),
+        });
+        rest = &after[end + 3..];
+    }
+    push_prose(&mut parts, rest);
+    parts
+}
+
+/// The text of `parts`, with every code block fenced on its own lines and
+/// spoilers set off by spaces.
+pub fn join(parts: &[Part]) -> String {
+    let mut text = String::new();
+    let mut after_code = false;
+    for part in parts {
+        if !text.is_empty() {
+            text.push(if after_code || part.is_code() {
+                '\n'
+            } else {
+                ' '
+            });
+        }
+        after_code = part.is_code();
+        match part {
+            Part::Text { text: prose } => text.push_str(prose),
+            Part::Code { language, code } => {
+                text.push_str("```");
+                text.push_str(language.as_deref().unwrap_or_default());
+                text.push('\n');
+                text.push_str(code);
+                text.push_str("\n```");
+            }
+            Part::Spoiler { text: hidden } => {
+                text.push_str("||");
+                text.push_str(hidden);
+                text.push_str("||");
+            }
+        }
+    }
+    text
+}
@@ -0,0 +1,473 @@
+//! Sandboxed WASM plugins loaded from `PLUGINS_DIR`.
+//!
+//! Every `*.wasm` (or `*.wat`) file in the directory is a plugin named after
+//! the file. Plugins exchange JSON through their own linear memory:
+//!
+//! - `memory` and `chatr_alloc(len: i32) -> i32` must be exported, the host
+//!   writes each input at the returned offset.
+//! - `chatr_init()` runs once after loading.
+//! - `chatr_on_message(ptr, len) -> i64` gets `{room, from, text}` and may
+//!   answer `{"drop": true}` or `{"text": "..."}` to filter the message.
+//! - `chatr_on_command(ptr, len) -> i64` gets `{room, from, command, args}`
+//!   for `/command args`; a non-zero answer marks it handled.
+//! - `chatr_on_join(ptr, len) -> i64` gets `{room, username}`.
+//! - `chatr_on_interaction(ptr, len) -> i64` gets an
+//!   [interaction](crate::components) with a message of the plugin, `{room,
+//!   message, author, username, component, value}`, and may answer an
+//!   `update` with the message's new `text` and `components`.
+//!
+//! Answers are `(ptr << 32) | len` of a JSON object in plugin memory, `0`
+//! meaning no answer; any answer may carry a `reply` said in the room as the
+//! plugin, with `components` to attach to it. The host imports `chatr.log(ptr, len)` for logging.
+//!
+//! Each call is limited in fuel and each instance in memory, defaults can be
+//! overridden per plugin by a `<name>.json` next to the module with `fuel`
+//! and `memory_bytes`. A plugin that traps too often in a row is disabled
+//! until reloaded through the admin API.
+
+use async_trait::async_trait;
+use axum::extract::{Path, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::Json;
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::path::{Path as FsPath, PathBuf};
+use std::sync::{Arc, Mutex};
+use wasmtime::{
+    Caller, Config, Engine, Instance, Linker, Memory, Module, Store, StoreLimits,
+    StoreLimitsBuilder,
+};
+
+use crate::bots::{Bot, BotContext};
+use crate::components::{Component, Interaction};
+use crate::events::ChatEvent;
+use crate::rooms::Hooks;
+use crate::{owners, ApiResponse, AppState};
+
+const DEFAULT_FUEL: u64 = 10_000_000;
+const DEFAULT_MEMORY_BYTES: usize = 16 << 20;
+const MAX_FAILURES: u32 = 5;
+
+#[derive(Clone, Copy, Deserialize)]
+#[serde(default)]
+struct Limits {
+    fuel: u64,
+    memory_bytes: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            fuel: DEFAULT_FUEL,
+            memory_bytes: DEFAULT_MEMORY_BYTES,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct Answer {
+    #[serde(default)]
+    drop: bool,
+    text: Option<String>,
+    reply: Option<String>,
+    /// Buttons and select menus of the reply.
+    #[serde(default)]
+    components: Vec<Component>,
+    /// New text and components of the message an interaction was with.
+    update: Option<Update>,
+}
+
+#[derive(Deserialize)]
+struct Update {
+    text: String,
+    #[serde(default)]
+    components: Vec<Component>,
+}
+
+impl Answer {
+    /// Says the answer's reply in the room as plugin `name`.
+    fn reply(self, ctx: &BotContext, name: &str) {
+        if let Some(reply) = self.reply {
+            ctx.reply_with_as(name, &reply, self.components);
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Status {
+    Running,
+    Disabled,
+}
+
+struct Sandbox {
+    store: Store<StoreLimits>,
+    instance: Instance,
+    memory: Memory,
+}
+
+struct Plugin {
+    name: String,
+    limits: Limits,
+    sandbox: Mutex<Sandbox>,
+    status: Mutex<(Status, u32)>,
+}
+
+impl Plugin {
+    fn load(engine: &Engine, name: &str, path: &FsPath) -> wasmtime::Result<Plugin> {
+        let limits: Limits = std::fs::read_to_string(path.with_extension("json"))
+            .ok()
+            .and_then(|config| serde_json::from_str(&config).ok())
+            .unwrap_or_default();
+        let module = Module::from_file(engine, path)?;
+
+        let mut linker = Linker::new(engine);
+        let plugin_name = name.to_owned();
+        linker.func_wrap(
+            "chatr",
+            "log",
+            move |mut caller: Caller<'_, StoreLimits>, ptr: i32, len: i32| {
+                let Some(memory) = caller
+                    .get_export("memory")
+                    .and_then(|export| export.into_memory())
+                else {
+                    return;
+                };
+                let mut buf = vec![0; len.max(0) as usize];
+                if memory.read(&caller, ptr as usize, &mut buf).is_ok() {
+                    info!("[plugin {}] {}", plugin_name, String::from_utf8_lossy(&buf));
+                }
+            },
+        )?;
+
+        let mut store = Store::new(
+            engine,
+            StoreLimitsBuilder::new()
+                .memory_size(limits.memory_bytes)
+                .instances(1)
+                .build(),
+        );
+        store.limiter(|limits| limits);
+        store.set_fuel(limits.fuel)?;
+        let instance = linker.instantiate(&mut store, &module)?;
+        let memory = instance
+            .get_memory(&mut store, "memory")
+            .ok_or_else(|| wasmtime::Error::msg("plugin does not export its memory"))?;
+        if let Ok(init) = instance.get_typed_func::<(), ()>(&mut store, "chatr_init") {
+            init.call(&mut store, ())?;
+        }
+
+        Ok(Plugin {
+            name: name.to_owned(),
+            limits,
+            sandbox: Mutex::new(Sandbox {
+                store,
+                instance,
+                memory,
+            }),
+            status: Mutex::new((Status::Running, 0)),
+        })
+    }
+
+    /// Calls `export` with `input`, `None` when the plugin is disabled, does
+    /// not implement it or did not answer.
+    fn call(&self, export: &str, input: &Value) -> Option<Answer> {
+        if self.status.lock().unwrap().0 != Status::Running {
+            return None;
+        }
+        let result = {
+            let mut sandbox = self.sandbox.lock().unwrap();
+            self.invoke(&mut sandbox, export, input)
+        };
+
+        let mut status = self.status.lock().unwrap();
+        match result {
+            Ok(answer) => {
+                status.1 = 0;
+                answer
+            }
+            Err(err) => {
+                status.1 += 1;
+                warn!("Plugin {} failed in {}: {}", self.name, export, err);
+                if status.1 >= MAX_FAILURES {
+                    error!("Disabling plugin {} after {} failures", self.name, status.1);
+                    status.0 = Status::Disabled;
+                }
+                None
+            }
+        }
+    }
+
+    fn invoke(
+        &self,
+        sandbox: &mut Sandbox,
+        export: &str,
+        input: &Value,
+    ) -> wasmtime::Result<Option<Answer>> {
+        let Sandbox {
+            store,
+            instance,
+            memory,
+        } = sandbox;
+        let Ok(func) = instance.get_typed_func::<(i32, i32), i64>(&mut *store, export) else {
+            return Ok(None);
+        };
+        store.set_fuel(self.limits.fuel)?;
+
+        let input = input.to_string();
+        let alloc = instance.get_typed_func::<i32, i32>(&mut *store, "chatr_alloc")?;
+        let ptr = alloc.call(&mut *store, input.len() as i32)?;
+        memory.write(&mut *store, ptr as usize, input.as_bytes())?;
+
+        let packed = func.call(&mut *store, (ptr, input.len() as i32))?;
+        if packed == 0 {
+            return Ok(None);
+        }
+        let (ptr, len) = ((packed >> 32) as u32 as usize, packed as u32 as usize);
+        let mut output = vec![0; len];
+        memory.read(&*store, ptr, &mut output)?;
+        Ok(Some(serde_json::from_slice(&output)?))
+    }
+}
+
+/// The loaded plugins, hooked into the message flow as both [`Hooks`] and
+/// a [`Bot`].
+#[derive(Clone)]
+pub struct PluginHost {
+    dir: PathBuf,
+    engine: Engine,
+    plugins: Arc<Mutex<Vec<Arc<Plugin>>>>,
+}
+
+impl PluginHost {
+    pub fn new(dir: impl Into<PathBuf>) -> wasmtime::Result<Self> {
+        let mut config = Config::new();
+        config.consume_fuel(true);
+        let host = Self {
+            dir: dir.into(),
+            engine: Engine::new(&config)?,
+            plugins: Arc::default(),
+        };
+
+        let entries = std::fs::read_dir(&host.dir)?;
+        let mut names = entries
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                let path = entry.path();
+                let extension = path.extension()?.to_str()?;
+                matches!(extension, "wasm" | "wat")
+                    .then(|| path.file_stem()?.to_str().map(str::to_owned))?
+            })
+            .collect::<Vec<_>>();
+        names.sort();
+        names.dedup();
+        for name in names {
+            if let Err(err) = host.reload(&name) {
+                error!("Failed to load plugin {}: {}", name, err);
+            }
+        }
+        Ok(host)
+    }
+
+    fn module_path(&self, name: &str) -> Option<PathBuf> {
+        ["wasm", "wat"]
+            .iter()
+            .map(|extension| self.dir.join(name).with_extension(extension))
+            .find(|path| path.is_file())
+    }
+
+    /// Loads `name` from disk, replacing a loaded plugin of that name.
+    fn reload(&self, name: &str) -> wasmtime::Result<()> {
+        let invalid = name.is_empty() || name.contains(['/', '\\', '.']);
+        let path = (!invalid)
+            .then(|| self.module_path(name))
+            .flatten()
+            .ok_or_else(|| wasmtime::Error::msg("no such plugin module"))?;
+        let plugin = Arc::new(Plugin::load(&self.engine, name, &path)?);
+        info!("Loaded plugin {}", name);
+
+        let mut plugins = self.plugins.lock().unwrap();
+        match plugins.iter_mut().find(|loaded| loaded.name == name) {
+            Some(loaded) => *loaded = plugin,
+            None => plugins.push(plugin),
+        }
+        Ok(())
+    }
+
+    fn unload(&self, name: &str) -> bool {
+        let mut plugins = self.plugins.lock().unwrap();
+        let before = plugins.len();
+        plugins.retain(|plugin| plugin.name != name);
+        plugins.len() != before
+    }
+
+    fn loaded(&self) -> Vec<Arc<Plugin>> {
+        self.plugins.lock().unwrap().clone()
+    }
+
+    /// Runs a possibly slow plugin call off the async runtime.
+    async fn call(plugin: Arc<Plugin>, export: &'static str, input: Value) -> Option<Answer> {
+        tokio::task::spawn_blocking(move || plugin.call(export, &input))
+            .await
+            .ok()
+            .flatten()
+    }
+}
+
+#[async_trait]
+impl Hooks for PluginHost {
+    async fn before_broadcast(&self, room: &str, mut event: ChatEvent) -> Option<ChatEvent> {
+        let ChatEvent::Message { from, text, .. } = &mut event else {
+            return Some(event);
+        };
+        for plugin in self.loaded() {
+            let input = json!({ "room": room, "from": from, "text": text });
+            let Some(answer) = Self::call(plugin, "chatr_on_message", input).await else {
+                continue;
+            };
+            if answer.drop {
+                return None;
+            }
+            if let Some(rewritten) = answer.text {
+                *text = rewritten;
+            }
+        }
+        Some(event)
+    }
+}
+
+#[async_trait]
+impl Bot for PluginHost {
+    fn name(&self) -> &str {
+        "plugins"
+    }
+
+    async fn on_join(&self, ctx: &BotContext, username: &str) {
+        for plugin in self.loaded() {
+            let name = plugin.name.clone();
+            let input = json!({ "room": ctx.room, "username": username });
+            if let Some(answer) = Self::call(plugin, "chatr_on_join", input).await {
+                answer.reply(ctx, &name);
+            }
+        }
+    }
+
+    async fn on_interaction(&self, ctx: &BotContext, interaction: &Interaction) {
+        // Picks go to the plugin whose message it is.
+        let Some(plugin) = self
+            .loaded()
+            .into_iter()
+            .find(|plugin| plugin.name == interaction.author)
+        else {
+            return;
+        };
+        let name = plugin.name.clone();
+        let input = json!(interaction);
+        let Some(mut answer) = Self::call(plugin, "chatr_on_interaction", input).await else {
+            return;
+        };
+        if let Some(update) = answer.update.take() {
+            ctx.update(interaction.message, &update.text, update.components);
+        }
+        answer.reply(ctx, &name);
+    }
+
+    async fn on_command(&self, ctx: &BotContext, from: &str, command: &str, args: &str) -> bool {
+        for plugin in self.loaded() {
+            let name = plugin.name.clone();
+            let input = json!({ "room": ctx.room, "from": from, "command": command, "args": args });
+            let Some(answer) = Self::call(plugin, "chatr_on_command", input).await else {
+                continue;
+            };
+            answer.reply(ctx, &name);
+            return true;
+        }
+        false
+    }
+}
+
+fn host(state: &AppState) -> Result<&PluginHost, ApiResponse> {
+    state.plugins.as_ref().ok_or((
+        StatusCode::NOT_FOUND,
+        Json(json!({ "status": "Plugins are not enabled." })),
+    ))
+}
+
+/// `GET /admin/plugins`
+pub async fn list_plugins(State(state): State<Arc<AppState>>, headers: HeaderMap) -> ApiResponse {
+    if !owners::is_admin(&state, &headers) {
+        return owners::admin_forbidden();
+    }
+    let host = match host(&state) {
+        Ok(host) => host,
+        Err(response) => return response,
+    };
+    let plugins = host
+        .loaded()
+        .iter()
+        .map(|plugin| {
+            let (status, failures) = *plugin.status.lock().unwrap();
+            json!({
+                "name": plugin.name,
+                "status": status,
+                "failures": failures,
+                "fuel": plugin.limits.fuel,
+                "memory_bytes": plugin.limits.memory_bytes,
+            })
+        })
+        .collect::<Vec<_>>();
+    (
+        StatusCode::OK,
+        Json(json!({ "status": "Success!", "plugins": plugins })),
+    )
+}
+
+/// `POST /admin/plugins/:name/reload`, also loads plugins added since startup.
+pub async fn reload_plugin(
+    Path(name): Path<String>,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> ApiResponse {
+    if !owners::is_admin(&state, &headers) {
+        return owners::admin_forbidden();
+    }
+    let host = match host(&state) {
+        Ok(host) => host.clone(),
+        Err(response) => return response,
+    };
+    match tokio::task::spawn_blocking(move || host.reload(&name)).await {
+        Ok(Ok(())) => (StatusCode::OK, Json(json!({ "status": "Success!" }))),
+        Ok(Err(err)) => (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "status": format!("Failed to load plugin: {}", err) })),
+        ),
+        Err(_) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({ "status": "Failed to load plugin." })),
+        ),
+    }
+}
+
+/// `DELETE /admin/plugins/:name`
+pub async fn unload_plugin(
+    Path(name): Path<String>,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> ApiResponse {
+    if !owners::is_admin(&state, &headers) {
+        return owners::admin_forbidden();
+    }
+    let host = match host(&state) {
+        Ok(host) => host,
+        Err(response) => return response,
+    };
+    if host.unload(&name) {
+        (StatusCode::OK, Json(json!({ "status": "Success!" })))
+    } else {
+        (
+            StatusCode::NOT_FOUND,
+            Json(json!({ "status": "Plugin not loaded." })),
+        )
+    }
+}
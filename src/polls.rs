@@ -0,0 +1,192 @@
+//! Polls members create, vote in and close with WebSocket frames.
+//!
+//! The server keeps each room's polls for as long as the room exists and
+//! broadcasts a poll's standing whenever it changes. Votes are anonymous,
+//! only the counts are shared, and a member voting again moves their vote.
+
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::Json;
+use serde::Serialize;
+use serde_json::json;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+use crate::events::ChatEvent;
+use crate::room_names::RoomName;
+use crate::{ApiResponse, AppState};
+
+const MAX_QUESTION_LEN: usize = 300;
+const MAX_OPTION_LEN: usize = 100;
+const MAX_OPTIONS: usize = 10;
+/// Polls a room keeps, the oldest closed one makes way for a new one.
+const MAX_POLLS: usize = 50;
+
+#[derive(Clone, Debug, Serialize)]
+pub struct PollOption {
+    pub text: String,
+    pub votes: usize,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Poll {
+    pub id: u64,
+    pub creator: String,
+    pub question: String,
+    pub options: Vec<PollOption>,
+    pub closed: bool,
+    /// The option each member voted for.
+    #[serde(skip)]
+    ballots: HashMap<String, usize>,
+}
+
+impl fmt::Display for Poll {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "[Poll {}] {} asks: {}",
+            self.id, self.creator, self.question
+        )?;
+        for (i, option) in self.options.iter().enumerate() {
+            write!(f, " {}. {} ({})", i + 1, option.text, option.votes)?;
+        }
+        if self.closed {
+            write!(f, " [closed]")?;
+        }
+        Ok(())
+    }
+}
+
+/// The polls of a room, oldest first.
+#[derive(Default)]
+pub struct Polls {
+    polls: Vec<Poll>,
+    next_id: u64,
+}
+
+/// Runs `change` on the polls of `room` and broadcasts the poll it returns.
+fn update(state: &AppState, room: &str, change: impl FnOnce(&mut Polls) -> Option<Poll>) {
+    let rooms = state.rooms.lock().unwrap();
+    let Some(room) = rooms.get(room) else {
+        return;
+    };
+    let changed = change(&mut room.polls.lock().unwrap());
+    if let Some(poll) = changed {
+        room.tx.send(ChatEvent::Poll { poll });
+    }
+}
+
+/// Opens a poll in `room`, ignored unless it has 2 to 10 distinct options.
+pub fn create(state: &AppState, room: &str, creator: &str, question: &str, options: &[String]) {
+    let question = question.trim();
+    let options = options
+        .iter()
+        .map(|option| option.trim())
+        .collect::<Vec<_>>();
+    let valid = (1..=MAX_QUESTION_LEN).contains(&question.chars().count())
+        && (2..=MAX_OPTIONS).contains(&options.len())
+        && options
+            .iter()
+            .all(|option| (1..=MAX_OPTION_LEN).contains(&option.chars().count()))
+        && options
+            .iter()
+            .enumerate()
+            .all(|(i, option)| !options[..i].contains(option));
+    if !valid {
+        return;
+    }
+    update(state, room, |polls| {
+        if polls.polls.len() >= MAX_POLLS {
+            let oldest = polls.polls.iter().position(|poll| poll.closed)?;
+            polls.polls.remove(oldest);
+        }
+        polls.next_id += 1;
+        let poll = Poll {
+            id: polls.next_id,
+            creator: creator.to_owned(),
+            question: question.to_owned(),
+            options: options
+                .iter()
+                .map(|text| PollOption {
+                    text: (*text).to_owned(),
+                    votes: 0,
+                })
+                .collect(),
+            closed: false,
+            ballots: HashMap::new(),
+        };
+        polls.polls.push(poll.clone());
+        Some(poll)
+    });
+}
+
+/// Counts the vote of `username` for an option of an open poll.
+pub fn vote(state: &AppState, room: &str, username: &str, id: u64, option: usize) {
+    update(state, room, |polls| {
+        let poll = polls.polls.iter_mut().find(|poll| poll.id == id)?;
+        if poll.closed || option >= poll.options.len() {
+            return None;
+        }
+        let previous = poll.ballots.insert(username.to_owned(), option);
+        if previous == Some(option) {
+            return None;
+        }
+        if let Some(previous) = previous {
+            poll.options[previous].votes -= 1;
+        }
+        poll.options[option].votes += 1;
+        Some(poll.clone())
+    });
+}
+
+/// Closes a poll, which only its creator may do.
+pub fn close(state: &AppState, room: &str, username: &str, id: u64) {
+    update(state, room, |polls| {
+        let poll = polls.polls.iter_mut().find(|poll| poll.id == id)?;
+        if poll.closed || poll.creator != username {
+            return None;
+        }
+        poll.closed = true;
+        Some(poll.clone())
+    });
+}
+
+fn not_found(message: &str) -> ApiResponse {
+    (StatusCode::NOT_FOUND, Json(json!({ "status": message })))
+}
+
+/// `GET /rooms/:name/polls`
+pub async fn list_polls(
+    Path(room): Path<RoomName>,
+    State(state): State<Arc<AppState>>,
+) -> ApiResponse {
+    let rooms = state.rooms.lock().unwrap();
+    let Some(room) = rooms.get(room.as_str()) else {
+        return not_found("Room not found.");
+    };
+    let polls = room.polls.lock().unwrap();
+    (
+        StatusCode::OK,
+        Json(json!({ "status": "Success!", "polls": polls.polls })),
+    )
+}
+
+/// `GET /rooms/:name/polls/:id`
+pub async fn get_poll(
+    Path((room, id)): Path<(RoomName, u64)>,
+    State(state): State<Arc<AppState>>,
+) -> ApiResponse {
+    let rooms = state.rooms.lock().unwrap();
+    let Some(room) = rooms.get(room.as_str()) else {
+        return not_found("Room not found.");
+    };
+    let polls = room.polls.lock().unwrap();
+    match polls.polls.iter().find(|poll| poll.id == id) {
+        Some(poll) => (
+            StatusCode::OK,
+            Json(json!({ "status": "Success!", "poll": poll })),
+        ),
+        None => not_found("Poll not found."),
+    }
+}
@@ -0,0 +1,154 @@
+//! Do not disturb. Members in DND still receive everything on their open
+//! connections, but are not notified of what they miss. They switch it on
+//! or off with a `presence` frame, or give it a daily window on their
+//! clock, e.g. from `22:00` to `07:00`. The other members of their rooms see manual
+//! changes as `presence` events.
+
+use axum::extract::{Path, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::Json;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use crate::events::ChatEvent;
+use crate::profiles::Window;
+use crate::room_names::RoomName;
+use crate::sessions::is_session;
+use crate::{ApiResponse, AppState};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    #[default]
+    Online,
+    /// Do not disturb.
+    Dnd,
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+struct Settings {
+    status: Status,
+    schedule: Option<Window>,
+}
+
+/// The members' statuses and windows, kept while they are offline.
+#[derive(Default)]
+pub struct Presence {
+    users: Mutex<HashMap<String, Settings>>,
+}
+
+impl Presence {
+    pub fn status(&self, username: &str) -> Status {
+        let users = self.users.lock().unwrap();
+        users
+            .get(username)
+            .map(|settings| settings.status)
+            .unwrap_or_default()
+    }
+
+    fn update(&self, username: &str, change: impl FnOnce(&mut Settings)) {
+        let mut users = self.users.lock().unwrap();
+        let settings = users.entry(username.to_owned()).or_default();
+        change(settings);
+        if settings.status == Status::Online && settings.schedule.is_none() {
+            users.remove(username);
+        }
+    }
+}
+
+/// Whether `username` is in DND, switched on or by their window.
+pub fn is_dnd(state: &AppState, username: &str) -> bool {
+    let Some(settings) = state.presence.users.lock().unwrap().get(username).cloned() else {
+        return false;
+    };
+    settings.status == Status::Dnd
+        || settings
+            .schedule
+            .is_some_and(|schedule| schedule.covers(state.profiles.local_minutes(username)))
+}
+
+/// Sets the status of `username` and tells the rooms they are in.
+pub fn set_status(state: &AppState, username: &str, status: Status) {
+    if state.presence.status(username) == status {
+        return;
+    }
+    state
+        .presence
+        .update(username, |settings| settings.status = status);
+    let event = ChatEvent::Presence {
+        username: username.to_owned(),
+        status,
+    };
+    let rooms = state.rooms.lock().unwrap();
+    for room in rooms.values() {
+        if room.users.lock().unwrap().contains_key(username) {
+            room.tx.send(event.clone());
+        }
+    }
+}
+
+/// `GET /users/:name/presence`
+pub async fn get_presence(
+    Path(username): Path<String>,
+    State(state): State<Arc<AppState>>,
+) -> ApiResponse {
+    let settings = state
+        .presence
+        .users
+        .lock()
+        .unwrap()
+        .get(&username)
+        .cloned()
+        .unwrap_or_default();
+    (
+        StatusCode::OK,
+        Json(json!({
+            "status": "Success!",
+            "presence": settings.status,
+            "schedule": settings.schedule,
+            "dnd": is_dnd(&state, &username),
+        })),
+    )
+}
+
+#[derive(Deserialize)]
+pub struct SetPresence {
+    room: RoomName,
+    #[serde(default)]
+    presence: Status,
+    /// The daily window, none when missing or `null`.
+    #[serde(default)]
+    schedule: Option<Window>,
+}
+
+/// `PUT /users/:name/presence`, sets the status and the window.
+pub async fn set_presence(
+    Path(username): Path<String>,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(body): Json<SetPresence>,
+) -> ApiResponse {
+    if !is_session(&state, &body.room, &username, &headers) {
+        return (
+            StatusCode::FORBIDDEN,
+            Json(json!({ "status": "Missing or invalid session token." })),
+        );
+    }
+    if body
+        .schedule
+        .as_ref()
+        .is_some_and(|schedule| !schedule.is_valid())
+    {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "status": "The window is two different HH:MM times." })),
+        );
+    }
+    state
+        .presence
+        .update(&username, |settings| settings.schedule = body.schedule);
+    set_status(&state, &username, body.presence);
+    (StatusCode::OK, Json(json!({ "status": "Success!" })))
+}
@@ -0,0 +1,375 @@
+//! Link previews: the title, description and image of pages linked from a
+//! message, fetched by the server and sent along with the message.
+//!
+//! Only public addresses are fetched. Names resolving to loopback, private,
+//! link-local and similar networks are refused for the first request and
+//! every redirect alike, so that chat users cannot probe the server's
+//! network. Other URLs given by users, such as those of slash commands,
+//! webhooks and feeds, are fetched with the same [`public_client`].
+
+use futures::future::join_all;
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use reqwest::header::CONTENT_TYPE;
+use reqwest::{redirect, Url};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Links previewed per message, later ones are ignored.
+const MAX_LINKS: usize = 3;
+const MAX_URL_LEN: usize = 2048;
+const MAX_REDIRECTS: usize = 3;
+const TIMEOUT: Duration = Duration::from_secs(3);
+/// Pages are read up to this many bytes, which covers their `<head>`.
+const MAX_BYTES: usize = 256 << 10;
+/// How long a preview, or the lack of one, is remembered.
+const CACHE_TTL: Duration = Duration::from_secs(60 * 60);
+const CACHE_LEN: usize = 1000;
+const MAX_TITLE_LEN: usize = 200;
+const MAX_DESCRIPTION_LEN: usize = 300;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Preview {
+    pub url: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub image: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub site_name: Option<String>,
+}
+
+type Cache = Mutex<HashMap<String, (Instant, Option<Preview>)>>;
+
+pub struct Previews {
+    client: reqwest::Client,
+    cache: Cache,
+}
+
+/// A client reaching public addresses only, for URLs given by users, with
+/// the redirects it follows checked alike.
+pub fn public_client(timeout: Duration) -> reqwest::Client {
+    let policy = redirect::Policy::custom(|attempt| {
+        if attempt.previous().len() >= MAX_REDIRECTS || !allowed(attempt.url()) {
+            attempt.stop()
+        } else {
+            attempt.follow()
+        }
+    });
+    reqwest::Client::builder()
+        .timeout(timeout)
+        .redirect(policy)
+        .dns_resolver(Arc::new(PublicOnly))
+        .no_proxy()
+        .user_agent(concat!("chatroom-rs/", env!("CARGO_PKG_VERSION")))
+        .build()
+        .unwrap()
+}
+
+impl Previews {
+    pub fn new() -> Self {
+        Self {
+            client: public_client(TIMEOUT),
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Previews of the links in `text`, skipping pages that have none.
+    pub async fn for_text(&self, text: &str) -> Vec<Preview> {
+        join_all(links(text).into_iter().map(|url| self.preview(url)))
+            .await
+            .into_iter()
+            .flatten()
+            .collect()
+    }
+
+    async fn preview(&self, url: Url) -> Option<Preview> {
+        let key = url.to_string();
+        if let Some((fetched, preview)) = self.cache.lock().unwrap().get(&key) {
+            if fetched.elapsed() < CACHE_TTL {
+                return preview.clone();
+            }
+        }
+        let preview = self.fetch(url).await;
+        let mut cache = self.cache.lock().unwrap();
+        if cache.len() >= CACHE_LEN {
+            cache.retain(|_, (fetched, _)| fetched.elapsed() < CACHE_TTL);
+            if cache.len() >= CACHE_LEN {
+                cache.clear();
+            }
+        }
+        cache.insert(key, (Instant::now(), preview.clone()));
+        preview
+    }
+
+    async fn fetch(&self, url: Url) -> Option<Preview> {
+        if !allowed(&url) {
+            return None;
+        }
+        let mut response = self.client.get(url).send().await.ok()?;
+        let is_html = response
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.starts_with("text/html"));
+        if !response.status().is_success() || !is_html {
+            return None;
+        }
+        // Whatever redirects were followed, links in the page are relative to it.
+        let url = response.url().clone();
+        let mut body = Vec::new();
+        while body.len() < MAX_BYTES {
+            match response.chunk().await {
+                Ok(Some(chunk)) => body.extend_from_slice(&chunk),
+                Ok(None) => break,
+                Err(_) if !body.is_empty() => break,
+                Err(_) => return None,
+            }
+        }
+        body.truncate(MAX_BYTES);
+        parse(&url, &String::from_utf8_lossy(&body))
+    }
+}
+
+/// The distinct http(s) links in `text`, in order.
+fn links(text: &str) -> Vec<Url> {
+    let mut links = Vec::new();
+    for word in text.split_whitespace() {
+        let word = word.trim_start_matches(|c| "([{<'\"".contains(c));
+        if !word.starts_with("http://") && !word.starts_with("https://") {
+            continue;
+        }
+        let word = word.trim_end_matches(|c| ".,;:!?)]}>'\"".contains(c));
+        if word.len() > MAX_URL_LEN {
+            continue;
+        }
+        if let Ok(url) = Url::parse(word) {
+            if !links.contains(&url) {
+                links.push(url);
+            }
+        }
+        if links.len() == MAX_LINKS {
+            break;
+        }
+    }
+    links
+}
+
+/// Whether `url` may be fetched. Hostnames are checked once resolved, by
+/// [`PublicOnly`].
+pub fn allowed(url: &Url) -> bool {
+    if !matches!(url.scheme(), "http" | "https") {
+        return false;
+    }
+    let Some(host) = url.host_str() else {
+        return false;
+    };
+    match host.trim_start_matches('[').trim_end_matches(']').parse() {
+        Ok(ip) => is_public(ip),
+        Err(_) => true,
+    }
+}
+
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_v4(ip),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public_v4(ip),
+            None => is_public_v6(ip),
+        },
+    }
+}
+
+fn is_public_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, c, _] = ip.octets();
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || ip.is_multicast()
+        || a == 0
+        // Shared address space for carrier-grade NAT.
+        || (a == 100 && (64..128).contains(&b))
+        || (a == 192 && b == 0 && c == 0)
+        // Benchmarking.
+        || (a == 198 && (18..20).contains(&b))
+        // Reserved.
+        || a >= 240)
+}
+
+fn is_public_v6(ip: Ipv6Addr) -> bool {
+    let segments = ip.segments();
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_multicast()
+        // Unique local.
+        || (segments[0] & 0xfe00) == 0xfc00
+        // Link-local.
+        || (segments[0] & 0xffc0) == 0xfe80
+        // Documentation.
+        || (segments[0] == 0x2001 && segments[1] == 0x0db8)
+        // NAT64, which can reach IPv4 networks of any kind.
+        || (segments[0] == 0x0064 && segments[1] == 0xff9b))
+}
+
+/// Resolves names like the system does, but only to public addresses. Every
+/// connection goes through it, so validated names cannot be rebound later.
+struct PublicOnly;
+
+impl Resolve for PublicOnly {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            let addrs = tokio::net::lookup_host((name.as_str(), 0))
+                .await?
+                .filter(|addr| is_public(addr.ip()))
+                .collect::<Vec<SocketAddr>>();
+            if addrs.is_empty() {
+                return Err(format!("{} has no public address", name.as_str()).into());
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+/// Reads the OpenGraph tags of a page, falling back to its `<title>`.
+fn parse(url: &Url, html: &str) -> Option<Preview> {
+    // Lowercasing ASCII keeps byte offsets, so matches index into `html`.
+    let lower = html.to_ascii_lowercase();
+    let mut meta = HashMap::new();
+    let mut rest = 0;
+    while let Some(start) = lower[rest..].find("<meta") {
+        let start = rest + start + "<meta".len();
+        let Some(end) = lower[start..].find('>') else {
+            break;
+        };
+        let end = start + end;
+        let attributes = attributes(&html[start..end]);
+        let key = attributes
+            .get("property")
+            .or_else(|| attributes.get("name"))
+            .map(|key| key.to_ascii_lowercase());
+        if let (Some(key), Some(content)) = (key, attributes.get("content")) {
+            meta.entry(key).or_insert_with(|| content.clone());
+        }
+        rest = end;
+    }
+    let title_tag = lower.find("<title").and_then(|start| {
+        let start = start + lower[start..].find('>')? + 1;
+        let end = start + lower[start..].find("</title")?;
+        Some(html[start..end].to_owned())
+    });
+
+    let text = |value: Option<String>, max: usize| {
+        let value = decode(&value?)
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" ");
+        let value = value.chars().take(max).collect::<String>();
+        (!value.is_empty()).then_some(value)
+    };
+    let title = text(meta.get("og:title").cloned().or(title_tag), MAX_TITLE_LEN);
+    let description = text(
+        meta.get("og:description")
+            .or_else(|| meta.get("description"))
+            .cloned(),
+        MAX_DESCRIPTION_LEN,
+    );
+    if title.is_none() && description.is_none() {
+        return None;
+    }
+    let image = meta
+        .get("og:image")
+        .and_then(|image| url.join(decode(image).trim()).ok())
+        .filter(|image| matches!(image.scheme(), "http" | "https"))
+        .map(String::from)
+        .filter(|image| image.len() <= MAX_URL_LEN);
+    Some(Preview {
+        url: url.to_string(),
+        title,
+        description,
+        image,
+        site_name: text(meta.get("og:site_name").cloned(), MAX_TITLE_LEN),
+    })
+}
+
+/// The attributes of a tag, given what follows its name.
+pub fn attributes(tag: &str) -> HashMap<String, String> {
+    let mut attributes = HashMap::new();
+    let mut rest = tag.trim_start_matches(|c: char| c.is_whitespace() || c == '/');
+    while !rest.is_empty() {
+        let name_end = rest
+            .find(|c: char| c == '=' || c.is_whitespace() || c == '/')
+            .unwrap_or(rest.len());
+        let name = rest[..name_end].to_ascii_lowercase();
+        rest = rest[name_end..].trim_start();
+        let mut value = String::new();
+        if let Some(after) = rest.strip_prefix('=') {
+            let after = after.trim_start();
+            let (found, remaining) = match after.chars().next() {
+                Some(quote @ ('"' | '\'')) => {
+                    let inner = &after[1..];
+                    let end = inner.find(quote).unwrap_or(inner.len());
+                    (&inner[..end], inner.get(end + 1..).unwrap_or_default())
+                }
+                _ => {
+                    let end = after.find(char::is_whitespace).unwrap_or(after.len());
+                    (&after[..end], &after[end..])
+                }
+            };
+            value = found.to_owned();
+            rest = remaining;
+        }
+        if !name.is_empty() {
+            attributes.entry(name).or_insert(value);
+        }
+        rest = rest.trim_start_matches(|c: char| c.is_whitespace() || c == '/');
+    }
+    attributes
+}
+
+/// Decodes the character references common in titles and descriptions.
+pub fn decode(text: &str) -> String {
+    let mut decoded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        decoded.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let entity = rest[1..].find(';').and_then(|end| {
+            let name = &rest[1..end + 1];
+            let c = match name {
+                "amp" => '&',
+                "lt" => '<',
+                "gt" => '>',
+                "quot" => '"',
+                "apos" => '\'',
+                "nbsp" => ' ',
+                _ => {
+                    let code = match name.strip_prefix("#x").or(name.strip_prefix("#X")) {
+                        Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+                        None => name.strip_prefix('#')?.parse().ok()?,
+                    };
+                    char::from_u32(code)?
+                }
+            };
+            Some((c, end + 2))
+        });
+        match entity {
+            Some((c, len)) => {
+                decoded.push(c);
+                rest = &rest[len..];
+            }
+            None => {
+                decoded.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    decoded.push_str(rest);
+    decoded
+}
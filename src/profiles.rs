@@ -0,0 +1,145 @@
+//! What members tell the server about themselves, for now their timezone.
+//!
+//! Daily windows such as do not disturb and quiet hours follow the
+//! member's timezone, UTC until they set one.
+
+use axum::extract::{Path, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::Json;
+use jiff::tz::TimeZone;
+use jiff::Timestamp;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use crate::room_names::RoomName;
+use crate::sessions::is_session;
+use crate::translation;
+use crate::{ApiResponse, AppState};
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Profile {
+    /// An IANA timezone such as `Europe/Berlin`.
+    #[serde(default)]
+    pub timezone: Option<String>,
+    /// The language messages reach the member translated into, see
+    /// [translation](crate::translation).
+    #[serde(default)]
+    pub translate: Option<String>,
+}
+
+/// The members' profiles by username.
+#[derive(Default)]
+pub struct Profiles {
+    profiles: Mutex<HashMap<String, Profile>>,
+}
+
+impl Profiles {
+    pub fn get(&self, username: &str) -> Profile {
+        let profiles = self.profiles.lock().unwrap();
+        profiles.get(username).cloned().unwrap_or_default()
+    }
+
+    pub fn set(&self, username: &str, profile: Profile) {
+        let mut profiles = self.profiles.lock().unwrap();
+        profiles.insert(username.to_owned(), profile);
+    }
+
+    /// The member's timezone, UTC until they set one.
+    pub fn timezone(&self, username: &str) -> TimeZone {
+        self.get(username)
+            .timezone
+            .and_then(|name| TimeZone::get(&name).ok())
+            .unwrap_or(TimeZone::UTC)
+    }
+
+    /// Minutes since midnight on the member's clock.
+    pub fn local_minutes(&self, username: &str) -> u32 {
+        let now = Timestamp::now().to_zoned(self.timezone(username));
+        now.hour() as u32 * 60 + now.minute() as u32
+    }
+}
+
+/// A daily window, as `HH:MM` on the member's clock. It spans midnight
+/// when `end` is before `start`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Window {
+    pub start: String,
+    pub end: String,
+}
+
+/// Minutes since midnight of an `HH:MM` time.
+fn minutes(time: &str) -> Option<u32> {
+    let (hours, minutes) = time.split_once(':')?;
+    let (hours, minutes) = (hours.parse::<u32>().ok()?, minutes.parse::<u32>().ok()?);
+    (hours < 24 && minutes < 60).then_some(hours * 60 + minutes)
+}
+
+impl Window {
+    pub fn is_valid(&self) -> bool {
+        matches!((minutes(&self.start), minutes(&self.end)), (Some(start), Some(end)) if start != end)
+    }
+
+    /// Whether the window includes `now`, in minutes since midnight.
+    pub fn covers(&self, now: u32) -> bool {
+        let (Some(start), Some(end)) = (minutes(&self.start), minutes(&self.end)) else {
+            return false;
+        };
+        if start < end {
+            (start..end).contains(&now)
+        } else {
+            now >= start || now < end
+        }
+    }
+}
+
+/// `GET /users/:name/profile`
+pub async fn get_profile(
+    Path(username): Path<String>,
+    State(state): State<Arc<AppState>>,
+) -> ApiResponse {
+    let profile = state.profiles.get(&username);
+    (
+        StatusCode::OK,
+        Json(json!({ "status": "Success!", "profile": profile })),
+    )
+}
+
+#[derive(Deserialize)]
+pub struct SetProfile {
+    room: RoomName,
+    #[serde(flatten)]
+    profile: Profile,
+}
+
+/// `PUT /users/:name/profile`
+pub async fn set_profile(
+    Path(username): Path<String>,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(body): Json<SetProfile>,
+) -> ApiResponse {
+    if !is_session(&state, &body.room, &username, &headers) {
+        return (
+            StatusCode::FORBIDDEN,
+            Json(json!({ "status": "Missing or invalid session token." })),
+        );
+    }
+    let timezone = body.profile.timezone.as_deref();
+    if timezone.is_some_and(|name| TimeZone::get(name).is_err()) {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "status": "Unknown timezone." })),
+        );
+    }
+    let translate = body.profile.translate.as_deref();
+    if translate.is_some_and(|code| !translation::is_language(code)) {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "status": "Unknown language." })),
+        );
+    }
+    state.profiles.set(&username, body.profile);
+    (StatusCode::OK, Json(json!({ "status": "Success!" })))
+}
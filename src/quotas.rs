@@ -0,0 +1,94 @@
+//! Limits on the rooms joins and `POST /rooms` create, so that a script
+//! cannot fill the server with them: `MAX_ROOMS` caps the rooms that exist at
+//! once and `ROOM_CREATIONS_PER_HOUR` the rooms each member, and each address
+//! where the transport knows it, creates within an hour. Default rooms, and
+//! rooms admins restore or owners unarchive, are created regardless.
+
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::rooms::JoinError;
+
+const WINDOW: Duration = Duration::from_secs(60 * 60);
+
+/// Who is creating a room by joining it.
+#[derive(Clone, Copy)]
+pub struct Creator<'a> {
+    pub username: &'a str,
+    pub ip: Option<IpAddr>,
+}
+
+/// The limits, none by default.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Limits {
+    pub max_rooms: Option<usize>,
+    pub creations_per_hour: Option<usize>,
+}
+
+impl Limits {
+    pub fn from_env() -> Self {
+        let var = |name| std::env::var(name).ok();
+        Self {
+            max_rooms: var("MAX_ROOMS").and_then(|count| count.parse().ok()),
+            creations_per_hour: var("ROOM_CREATIONS_PER_HOUR").and_then(|count| count.parse().ok()),
+        }
+    }
+}
+
+pub struct Quotas {
+    limits: Limits,
+    /// When each member and address created their rooms of the last hour,
+    /// oldest first.
+    created: Mutex<HashMap<String, VecDeque<Instant>>>,
+}
+
+impl Quotas {
+    pub fn new(limits: Limits) -> Self {
+        Self {
+            limits,
+            created: Mutex::default(),
+        }
+    }
+
+    /// Counts a room `creator` creates while `rooms` exist, or tells which
+    /// limit it is over.
+    pub fn create(&self, rooms: usize, creator: Creator) -> Result<(), JoinError> {
+        if self
+            .limits
+            .max_rooms
+            .is_some_and(|max_rooms| rooms >= max_rooms)
+        {
+            return Err(JoinError::RoomLimit);
+        }
+        let Some(per_hour) = self.limits.creations_per_hour else {
+            return Ok(());
+        };
+        let keys = std::iter::once(format!("user:{}", creator.username))
+            .chain(creator.ip.map(|ip| format!("ip:{}", ip)))
+            .collect::<Vec<_>>();
+        let now = Instant::now();
+        let mut created = self.created.lock().unwrap();
+        created.retain(|_, times| {
+            while times
+                .front()
+                .is_some_and(|at| now.duration_since(*at) >= WINDOW)
+            {
+                times.pop_front();
+            }
+            !times.is_empty()
+        });
+        if keys.iter().any(|key| {
+            created
+                .get(key)
+                .is_some_and(|times| times.len() >= per_hour)
+        }) {
+            return Err(JoinError::CreationLimit);
+        }
+        for key in keys {
+            created.entry(key).or_default().push_back(now);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,129 @@
+//! Limits on how fast messages are posted, in two classes so that members
+//! and integrations are tuned apart: `MESSAGES_PER_MINUTE` and
+//! `MESSAGE_BURST` for members, `BOT_MESSAGES_PER_MINUTE` and
+//! `BOT_MESSAGE_BURST` for incoming webhooks and bots. Each sender may post
+//! a burst at once and then at the rate, a burst of the rate's worth of a
+//! minute where unset. Neither class is limited by default.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Who is posting, and by which key their messages are counted.
+#[derive(Clone, Copy)]
+pub enum Sender<'a> {
+    /// A member, over any transport and in any room.
+    Member(&'a str),
+    /// An incoming webhook, by its token.
+    Webhook(&'a str),
+    /// A bot, by the name it speaks as.
+    Bot(&'a str),
+}
+
+impl Sender<'_> {
+    fn key(&self) -> String {
+        match self {
+            Sender::Member(username) => format!("member:{}", username),
+            Sender::Webhook(token) => format!("webhook:{}", token),
+            Sender::Bot(name) => format!("bot:{}", name),
+        }
+    }
+}
+
+/// The limit of a class.
+#[derive(Clone, Copy, Debug)]
+pub struct Limit {
+    pub per_minute: u32,
+    pub burst: u32,
+}
+
+impl Limit {
+    fn from_env(per_minute: &str, burst: &str) -> Option<Self> {
+        let var = |name| std::env::var(name).ok().and_then(|val| val.parse().ok());
+        let per_minute = var(per_minute).filter(|per_minute| *per_minute > 0)?;
+        Some(Self {
+            per_minute,
+            burst: var(burst).unwrap_or(per_minute).max(1),
+        })
+    }
+
+    /// Messages a sender gets back each second.
+    fn refill(&self) -> f64 {
+        self.per_minute as f64 / 60.0
+    }
+
+    /// The messages of `bucket` by `now`, up to the burst.
+    fn tokens(&self, bucket: &Bucket, now: Instant) -> f64 {
+        let refilled = now.duration_since(bucket.at).as_secs_f64() * self.refill();
+        (bucket.tokens + refilled).min(self.burst as f64)
+    }
+}
+
+/// The limits, none by default.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Limits {
+    pub members: Option<Limit>,
+    pub bots: Option<Limit>,
+}
+
+impl Limits {
+    pub fn from_env() -> Self {
+        Self {
+            members: Limit::from_env("MESSAGES_PER_MINUTE", "MESSAGE_BURST"),
+            bots: Limit::from_env("BOT_MESSAGES_PER_MINUTE", "BOT_MESSAGE_BURST"),
+        }
+    }
+}
+
+/// Messages a sender may still post at once, as of when it was counted.
+struct Bucket {
+    tokens: f64,
+    at: Instant,
+    limit: Limit,
+}
+
+pub struct RateLimits {
+    limits: Limits,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl RateLimits {
+    pub fn new(limits: Limits) -> Self {
+        Self {
+            limits,
+            buckets: Mutex::default(),
+        }
+    }
+
+    fn limit(&self, sender: Sender) -> Option<Limit> {
+        match sender {
+            Sender::Member(_) => self.limits.members,
+            Sender::Webhook(_) | Sender::Bot(_) => self.limits.bots,
+        }
+    }
+
+    /// Counts a message of `sender`, or tells how long until they may post
+    /// again.
+    pub fn check(&self, sender: Sender) -> Result<(), Duration> {
+        let Some(limit) = self.limit(sender) else {
+            return Ok(());
+        };
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        // Senders back at their burst need no bucket.
+        buckets.retain(|_, bucket| bucket.limit.tokens(bucket, now) < bucket.limit.burst as f64);
+        let bucket = buckets.entry(sender.key()).or_insert(Bucket {
+            tokens: limit.burst as f64,
+            at: now,
+            limit,
+        });
+        bucket.tokens = limit.tokens(bucket, now);
+        bucket.at = now;
+        if bucket.tokens < 1.0 {
+            let wait = (1.0 - bucket.tokens) / limit.refill();
+            return Err(Duration::from_secs_f64(wait));
+        }
+        bucket.tokens -= 1.0;
+        Ok(())
+    }
+}
@@ -0,0 +1,133 @@
+//! How far members have read each room, kept in step across their
+//! connections so that unread badges clear on every device.
+//!
+//! A `read` frame moves the member's marker in a room to a message id, a
+//! `dismiss` frame clears the notifications shown for a room. Both reach
+//! the member's other connections as `read` and `dismissed` events.
+
+use axum::extract::{Path, Query, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::Json;
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use crate::events::ChatEvent;
+use crate::room_names::RoomName;
+use crate::sessions::is_session;
+use crate::{ApiResponse, AppState};
+
+/// The id of the last message each member read, keyed by username and room.
+#[derive(Default)]
+pub struct ReadMarkers {
+    markers: Mutex<HashMap<(String, String), u64>>,
+}
+
+impl ReadMarkers {
+    pub fn get(&self, username: &str, room: &str) -> Option<u64> {
+        let markers = self.markers.lock().unwrap();
+        markers
+            .get(&(username.to_owned(), room.to_owned()))
+            .copied()
+    }
+
+    /// Moves the marker forward to `id`, false if it already was further.
+    fn advance(&self, username: &str, room: &str, id: u64) -> bool {
+        let mut markers = self.markers.lock().unwrap();
+        let marker = markers
+            .entry((username.to_owned(), room.to_owned()))
+            .or_default();
+        if *marker >= id {
+            return false;
+        }
+        *marker = id;
+        true
+    }
+}
+
+/// Marks `room` read by `username` up to message `id`, telling their
+/// connections but the one in `from`.
+pub fn mark_read(state: &AppState, username: &str, room: &str, id: u64, from: Option<&str>) {
+    if !state.read_markers.advance(username, room, id) {
+        return;
+    }
+    let event = ChatEvent::Read {
+        room: room.to_owned(),
+        id,
+    };
+    state.connections.send(username, &event, from);
+}
+
+/// Tells the connections of `username` but the one in `from` that the
+/// notifications of `room` were dismissed.
+pub fn dismiss(state: &AppState, username: &str, room: &str, from: Option<&str>) {
+    let event = ChatEvent::Dismissed {
+        room: room.to_owned(),
+    };
+    state.connections.send(username, &event, from);
+}
+
+/// Messages of `room` in its history after the marker of `username`.
+fn unread(state: &AppState, username: &str, room: &str) -> usize {
+    let marker = state.read_markers.get(username, room).unwrap_or(0);
+    let rooms = state.rooms.lock().unwrap();
+    rooms.get(room).map_or(0, |room| {
+        let history = room.history.lock().unwrap();
+        history
+            .iter()
+            .filter(|message| message.id > marker && message.from != username)
+            .count()
+    })
+}
+
+#[derive(Deserialize)]
+pub struct ReadQuery {
+    username: String,
+}
+
+/// `GET /rooms/:name/read?username=`, the marker and the unread count.
+pub async fn get_read(
+    Path(room): Path<RoomName>,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Query(query): Query<ReadQuery>,
+) -> ApiResponse {
+    if !is_session(&state, &room, &query.username, &headers) {
+        return (
+            StatusCode::FORBIDDEN,
+            Json(json!({ "status": "Missing or invalid session token." })),
+        );
+    }
+    (
+        StatusCode::OK,
+        Json(json!({
+            "status": "Success!",
+            "id": state.read_markers.get(&query.username, &room),
+            "unread": unread(&state, &query.username, &room),
+        })),
+    )
+}
+
+#[derive(Deserialize)]
+pub struct SetRead {
+    username: String,
+    id: u64,
+}
+
+/// `PUT /rooms/:name/read`, moves the marker forward.
+pub async fn set_read(
+    Path(room): Path<RoomName>,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(body): Json<SetRead>,
+) -> ApiResponse {
+    if !is_session(&state, &room, &body.username, &headers) {
+        return (
+            StatusCode::FORBIDDEN,
+            Json(json!({ "status": "Missing or invalid session token." })),
+        );
+    }
+    mark_read(&state, &body.username, &room, body.id, None);
+    (StatusCode::OK, Json(json!({ "status": "Success!" })))
+}
@@ -0,0 +1,577 @@
+//! Messages room owners have posted on a schedule, such as the rules every
+//! hour.
+//!
+//! A schedule is a cron expression of five fields, minute, hour, day of the
+//! month, month and day of the week, each `*`, a number, a range such as
+//! `9-17` or a list of those, with an optional `/step`, e.g. `0 9-17 * * 1-5`
+//! for every hour of a working day. Months and days of the week may also be
+//! named by their first three letters, as in `0 9 * JAN-MAR MON`. When both
+//! day fields are restricted, not starting with `*`, a day matching either
+//! will do, as in cron. `@hourly`, `@daily`, `@weekly` and
+//! `@monthly` stand for the usual ones. Schedules run on the clock of their
+//! `timezone`, UTC by default, and post as `announcements` while their room
+//! is active; runs of inactive rooms are skipped. With `RECURRING_FILE` set
+//! they are kept in that JSON file and survive restarts.
+
+use axum::extract::{Path, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::Json;
+use jiff::civil::{Date, DateTime};
+use jiff::tz::TimeZone;
+use jiff::{Timestamp, ToSpan};
+use log::info;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::events::{unix_timestamp, App};
+use crate::json_file::JsonFile;
+use crate::owners::{forbidden, generate_token, is_owner};
+use crate::room_names::RoomName;
+use crate::{notifications, ApiResponse, AppState};
+
+/// Who recurring messages are posted as.
+const NAME: &str = "announcements";
+const MAX_MESSAGES: usize = 10;
+const MAX_LEN: usize = 2000;
+const TICK: Duration = Duration::from_secs(15);
+/// Steps looked ahead for the next run, years of them for rare schedules.
+const MAX_STEPS: usize = 10_000;
+const MONTHS: &[&str] = &[
+    "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+];
+const WEEKDAYS: &[&str] = &["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+/// The values a field of a schedule matches, as bits.
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct Field(u64);
+
+impl Field {
+    /// Parses `field`, whose values from `min` on may also be given by
+    /// `names`.
+    fn parse(field: &str, min: u8, max: u8, names: &[&str]) -> Option<Self> {
+        let value = |value: &str| {
+            value.parse().ok().or_else(|| {
+                let at = names
+                    .iter()
+                    .position(|name| name.eq_ignore_ascii_case(value))?;
+                Some(min + at as u8)
+            })
+        };
+        let mut bits = 0u64;
+        for item in field.split(',') {
+            let (range, step) = match item.split_once('/') {
+                Some((range, step)) => (range, step.parse::<u8>().ok().filter(|step| *step > 0)?),
+                None => (item, 1),
+            };
+            let (start, end) = match range {
+                "*" => (min, max),
+                range => match range.split_once('-') {
+                    Some((start, end)) => (value(start)?, value(end)?),
+                    // `5/15` runs from 5 on.
+                    None if item.contains('/') => (value(range)?, max),
+                    None => {
+                        let value = value(range)?;
+                        (value, value)
+                    }
+                },
+            };
+            if start < min || end > max || start > end {
+                return None;
+            }
+            for value in (start..=end).step_by(step as usize) {
+                bits |= 1 << value;
+            }
+        }
+        Some(Self(bits))
+    }
+
+    fn has(&self, value: i8) -> bool {
+        self.0 & (1 << value) != 0
+    }
+}
+
+/// A parsed schedule.
+#[derive(Clone, Copy)]
+struct Cron {
+    minutes: Field,
+    hours: Field,
+    days: Field,
+    months: Field,
+    weekdays: Field,
+    /// Whether days and weekdays were both restricted, not starting with
+    /// `*`, when either matching will do, as in cron.
+    either_day: bool,
+}
+
+impl Cron {
+    fn parse(schedule: &str) -> Option<Self> {
+        let schedule = match schedule.trim() {
+            "@hourly" => "0 * * * *",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@weekly" => "0 0 * * 0",
+            "@monthly" => "0 0 1 * *",
+            schedule => schedule,
+        };
+        let fields = schedule.split_whitespace().collect::<Vec<_>>();
+        let [minutes, hours, days, months, weekdays] = fields[..] else {
+            return None;
+        };
+        let mut weekday_bits = Field::parse(weekdays, 0, 7, WEEKDAYS)?;
+        // Sunday is both 0 and 7.
+        if weekday_bits.has(7) {
+            weekday_bits.0 |= 1;
+        }
+        Some(Self {
+            minutes: Field::parse(minutes, 0, 59, &[])?,
+            hours: Field::parse(hours, 0, 23, &[])?,
+            days: Field::parse(days, 1, 31, &[])?,
+            months: Field::parse(months, 1, 12, MONTHS)?,
+            weekdays: weekday_bits,
+            either_day: !days.starts_with('*') && !weekdays.starts_with('*'),
+        })
+    }
+
+    fn day_matches(&self, date: Date) -> bool {
+        let day = self.days.has(date.day());
+        let weekday = self.weekdays.has(date.weekday().to_sunday_zero_offset());
+        if self.either_day {
+            day || weekday
+        } else {
+            day && weekday
+        }
+    }
+
+    /// The first time after `after` the schedule runs, on the clock of
+    /// `timezone`.
+    fn next(&self, after: Timestamp, timezone: &TimeZone) -> Option<Timestamp> {
+        let start = after.to_zoned(timezone.clone()).datetime();
+        let mut time = start
+            .date()
+            .at(start.hour(), start.minute(), 0, 0)
+            .checked_add(1.minute())
+            .ok()?;
+        for _ in 0..MAX_STEPS {
+            let date = time.date();
+            if !self.months.has(time.month()) {
+                time = next_day(date.last_of_month())?;
+            } else if !self.day_matches(date) {
+                time = next_day(date)?;
+            } else if !self.hours.has(time.hour()) {
+                time = date.at(time.hour(), 0, 0, 0).checked_add(1.hour()).ok()?;
+            } else if !self.minutes.has(time.minute()) {
+                time = time.checked_add(1.minute()).ok()?;
+            } else {
+                // Times a clock change skips run once it is past them.
+                let timestamp = time.to_zoned(timezone.clone()).ok()?.timestamp();
+                if timestamp > after {
+                    return Some(timestamp);
+                }
+                time = time.checked_add(1.minute()).ok()?;
+            }
+        }
+        None
+    }
+}
+
+fn next_day(date: Date) -> Option<DateTime> {
+    Some(date.tomorrow().ok()?.at(0, 0, 0, 0))
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Recurring {
+    pub id: String,
+    pub text: String,
+    pub schedule: String,
+    pub timezone: String,
+    pub enabled: bool,
+    /// Unix time in seconds of the next run, none while disabled or if the
+    /// schedule never runs again.
+    #[serde(default)]
+    pub next_run: Option<u64>,
+}
+
+impl Recurring {
+    /// Schedules the next run after `after`, in Unix seconds.
+    fn schedule_after(&mut self, after: u64) {
+        let cron = Cron::parse(&self.schedule);
+        let timezone = TimeZone::get(&self.timezone).unwrap_or(TimeZone::UTC);
+        self.next_run = match (self.enabled, cron, Timestamp::from_second(after as i64)) {
+            (true, Some(cron), Ok(after)) => cron
+                .next(after, &timezone)
+                .map(|next| next.as_second() as u64),
+            _ => None,
+        };
+    }
+}
+
+/// The rooms' recurring messages, written through to the recurring file if
+/// there is one.
+pub struct RecurringMessages {
+    rooms: Mutex<HashMap<String, Vec<Recurring>>>,
+    file: Option<JsonFile>,
+}
+
+impl RecurringMessages {
+    /// Loads the messages from `file`, starting without any if it does not
+    /// exist yet. Runs missed while the server was down are skipped.
+    pub fn open(file: Option<PathBuf>) -> Self {
+        let file = file.map(|path| JsonFile::new(path, "recurring"));
+        let mut rooms: HashMap<String, Vec<Recurring>> =
+            file.as_ref().map(JsonFile::load).unwrap_or_default();
+        let now = unix_timestamp();
+        for recurring in rooms.values_mut().flatten() {
+            if recurring.next_run.is_none_or(|next_run| next_run <= now) {
+                recurring.schedule_after(now);
+            }
+        }
+        Self {
+            rooms: Mutex::new(rooms),
+            file,
+        }
+    }
+
+    fn save(&self, rooms: &HashMap<String, Vec<Recurring>>) {
+        if let Some(file) = &self.file {
+            file.save(rooms);
+        }
+    }
+
+    /// Removes the recurring messages of `room` and returns them, to hand
+    /// them over.
+    pub fn take_room(&self, room: &str) -> Vec<Recurring> {
+        let mut rooms = self.rooms.lock().unwrap();
+        let Some(messages) = rooms.remove(room) else {
+            return Vec::new();
+        };
+        self.save(&rooms);
+        messages
+    }
+
+    /// Adds the recurring `messages` of `room` handed over.
+    pub fn restore_room(&self, room: &str, messages: Vec<Recurring>) {
+        if messages.is_empty() {
+            return;
+        }
+        let mut rooms = self.rooms.lock().unwrap();
+        rooms.entry(room.to_owned()).or_default().extend(messages);
+        self.save(&rooms);
+    }
+
+    /// The messages due, scheduling their next runs.
+    fn take_due(&self) -> Vec<(String, String)> {
+        let now = unix_timestamp();
+        let mut rooms = self.rooms.lock().unwrap();
+        let mut due = Vec::new();
+        for (room, messages) in rooms.iter_mut() {
+            for recurring in messages
+                .iter_mut()
+                .filter(|recurring| recurring.next_run.is_some_and(|next_run| next_run <= now))
+            {
+                recurring.schedule_after(now);
+                due.push((room.clone(), recurring.text.clone()));
+            }
+        }
+        if !due.is_empty() {
+            self.save(&rooms);
+        }
+        due
+    }
+}
+
+fn post(state: &Arc<AppState>, room: &str, text: &str) {
+    let id = {
+        let rooms = state.rooms.lock().unwrap();
+        let Some(room_state) = rooms.get(room) else {
+            return;
+        };
+        room_state.send_components(App::new(NAME), NAME, text, Vec::new())
+    };
+    info!("Posted a recurring message to {}", room);
+    // So that mentions in it notify.
+    notifications::message_posted(state, room, id, NAME, text);
+}
+
+/// Posts the recurring messages that are due, skipping those of inactive
+/// rooms.
+pub async fn poster(state: Arc<AppState>) {
+    let mut interval = tokio::time::interval(TICK);
+    loop {
+        interval.tick().await;
+        for (room, text) in state.recurring.take_due() {
+            post(&state, &room, &text);
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct RecurringRequest {
+    text: String,
+    schedule: String,
+    #[serde(default)]
+    timezone: Option<String>,
+    #[serde(default = "enabled")]
+    enabled: bool,
+}
+
+fn enabled() -> bool {
+    true
+}
+
+impl RecurringRequest {
+    /// The message the request describes, or what is wrong with it.
+    fn into_recurring(self, id: String) -> Result<Recurring, String> {
+        let text = self.text.trim();
+        if text.is_empty() || text.chars().count() > MAX_LEN {
+            return Err(format!("Messages are 1 to {} characters.", MAX_LEN));
+        }
+        if Cron::parse(&self.schedule).is_none() {
+            return Err("Schedules are five cron fields, e.g. 0 * * * *.".to_owned());
+        }
+        let timezone = self.timezone.unwrap_or_else(|| "UTC".to_owned());
+        if TimeZone::get(&timezone).is_err() {
+            return Err("Unknown timezone.".to_owned());
+        }
+        let mut recurring = Recurring {
+            id,
+            text: text.to_owned(),
+            schedule: self.schedule.trim().to_owned(),
+            timezone,
+            enabled: self.enabled,
+            next_run: None,
+        };
+        recurring.schedule_after(unix_timestamp());
+        Ok(recurring)
+    }
+}
+
+fn bad_request(status: String) -> ApiResponse {
+    (StatusCode::BAD_REQUEST, Json(json!({ "status": status })))
+}
+
+/// `POST /rooms/:name/recurring`
+pub async fn create_recurring(
+    Path(room): Path<RoomName>,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(body): Json<RecurringRequest>,
+) -> ApiResponse {
+    if !is_owner(&state, &room, &headers) {
+        return forbidden();
+    }
+    let recurring = match body.into_recurring(generate_token()) {
+        Ok(recurring) => recurring,
+        Err(err) => return bad_request(err),
+    };
+    let mut rooms = state.recurring.rooms.lock().unwrap();
+    let messages = rooms.entry(room.to_string()).or_default();
+    if messages.len() >= MAX_MESSAGES {
+        return bad_request(format!(
+            "At most {} recurring messages per room.",
+            MAX_MESSAGES
+        ));
+    }
+    let (id, next_run) = (recurring.id.clone(), recurring.next_run);
+    messages.push(recurring);
+    state.recurring.save(&rooms);
+    (
+        StatusCode::CREATED,
+        Json(json!({ "status": "Success!", "id": id, "next_run": next_run })),
+    )
+}
+
+/// `GET /rooms/:name/recurring`
+pub async fn list_recurring(
+    Path(room): Path<RoomName>,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> ApiResponse {
+    if !is_owner(&state, &room, &headers) {
+        return forbidden();
+    }
+    let rooms = state.recurring.rooms.lock().unwrap();
+    let messages = rooms.get(room.as_str()).cloned().unwrap_or_default();
+    (
+        StatusCode::OK,
+        Json(json!({ "status": "Success!", "recurring": messages })),
+    )
+}
+
+/// `PUT /rooms/:name/recurring/:id`, replaces the message, enabling or
+/// disabling it with `enabled`.
+pub async fn update_recurring(
+    Path((room, id)): Path<(RoomName, String)>,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(body): Json<RecurringRequest>,
+) -> ApiResponse {
+    if !is_owner(&state, &room, &headers) {
+        return forbidden();
+    }
+    let updated = match body.into_recurring(id.clone()) {
+        Ok(updated) => updated,
+        Err(err) => return bad_request(err),
+    };
+    let mut rooms = state.recurring.rooms.lock().unwrap();
+    let Some(recurring) = rooms
+        .get_mut(room.as_str())
+        .and_then(|messages| messages.iter_mut().find(|recurring| recurring.id == id))
+    else {
+        return not_found();
+    };
+    let next_run = updated.next_run;
+    *recurring = updated;
+    state.recurring.save(&rooms);
+    (
+        StatusCode::OK,
+        Json(json!({ "status": "Success!", "next_run": next_run })),
+    )
+}
+
+/// `DELETE /rooms/:name/recurring/:id`
+pub async fn delete_recurring(
+    Path((room, id)): Path<(RoomName, String)>,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> ApiResponse {
+    if !is_owner(&state, &room, &headers) {
+        return forbidden();
+    }
+    let mut rooms = state.recurring.rooms.lock().unwrap();
+    let Some(messages) = rooms.get_mut(room.as_str()) else {
+        return not_found();
+    };
+    let before = messages.len();
+    messages.retain(|recurring| recurring.id != id);
+    if messages.len() == before {
+        return not_found();
+    }
+    if messages.is_empty() {
+        rooms.remove(room.as_str());
+    }
+    state.recurring.save(&rooms);
+    (StatusCode::OK, Json(json!({ "status": "Success!" })))
+}
+
+fn not_found() -> ApiResponse {
+    (
+        StatusCode::NOT_FOUND,
+        Json(json!({ "status": "Recurring message not found." })),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn runs(schedule: &str, timezone: &str, after: &str, count: usize) -> Vec<String> {
+        let cron = Cron::parse(schedule).unwrap();
+        let timezone = TimeZone::get(timezone).unwrap();
+        let mut after: Timestamp = after.parse().unwrap();
+        (0..count)
+            .map(|_| {
+                after = cron.next(after, &timezone).unwrap();
+                after.to_string()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn ranges_and_lists() {
+        let cron = Cron::parse("0 9-11,14 * * *").unwrap();
+        let hours = (0..24)
+            .filter(|hour| cron.hours.has(*hour))
+            .collect::<Vec<_>>();
+        assert_eq!(hours, [9, 10, 11, 14]);
+        assert!(Cron::parse("0 11-9 * * *").is_none());
+        assert!(Cron::parse("0 24 * * *").is_none());
+        assert!(Cron::parse("0 0 0 * *").is_none());
+        assert!(Cron::parse("0 0 * *").is_none());
+    }
+
+    #[test]
+    fn steps() {
+        let cron = Cron::parse("*/20 1-10/4 5/10 * *").unwrap();
+        let minutes = (0..60)
+            .filter(|minute| cron.minutes.has(*minute))
+            .collect::<Vec<_>>();
+        assert_eq!(minutes, [0, 20, 40]);
+        let hours = (0..24)
+            .filter(|hour| cron.hours.has(*hour))
+            .collect::<Vec<_>>();
+        assert_eq!(hours, [1, 5, 9]);
+        let days = (1..32)
+            .filter(|day| cron.days.has(*day))
+            .collect::<Vec<_>>();
+        assert_eq!(days, [5, 15, 25]);
+        assert!(Cron::parse("*/0 * * * *").is_none());
+    }
+
+    #[test]
+    fn names() {
+        let named = Cron::parse("0 9 * jan-Mar,DEC MON-fri").unwrap();
+        let numbered = Cron::parse("0 9 * 1-3,12 1-5").unwrap();
+        assert!(named.months == numbered.months && named.weekdays == numbered.weekdays);
+        assert!(Cron::parse("0 0 * * sun").unwrap().weekdays.has(0));
+        assert!(Cron::parse("0 0 * * 7").unwrap().weekdays.has(0));
+        assert!(Cron::parse("0 0 mon * *").is_none());
+        assert!(Cron::parse("0 0 * * monday").is_none());
+    }
+
+    #[test]
+    fn either_day_when_both_restricted() {
+        // The 13th or any Friday.
+        assert_eq!(
+            runs("0 0 13 * 5", "UTC", "2026-03-01T00:00:00Z", 4),
+            [
+                "2026-03-06T00:00:00Z",
+                "2026-03-13T00:00:00Z",
+                "2026-03-20T00:00:00Z",
+                "2026-03-27T00:00:00Z",
+            ]
+        );
+        // Odd days that are Mondays, a day field starting with `*` is not
+        // restricted.
+        assert_eq!(
+            runs("0 0 */2 * 1", "UTC", "2026-03-01T00:00:00Z", 3),
+            [
+                "2026-03-09T00:00:00Z",
+                "2026-03-23T00:00:00Z",
+                "2026-04-13T00:00:00Z",
+            ]
+        );
+        assert!(!Cron::parse("0 0 * * 1").unwrap().either_day);
+        assert!(!Cron::parse("0 0 1-31/2 * *").unwrap().either_day);
+        assert!(Cron::parse("0 0 1-31/2 * 1").unwrap().either_day);
+    }
+
+    #[test]
+    fn next_across_clock_changes() {
+        // 02:30 does not exist on the day New York springs forward, so that
+        // run happens once the clock is past it.
+        assert_eq!(
+            runs("30 2 * * *", "America/New_York", "2026-03-07T12:00:00Z", 3),
+            [
+                "2026-03-08T07:30:00Z",
+                "2026-03-09T06:30:00Z",
+                "2026-03-10T06:30:00Z",
+            ]
+        );
+        // 01:30 happens twice when it falls back, and runs the first time.
+        assert_eq!(
+            runs("30 1 * * *", "America/New_York", "2026-10-31T12:00:00Z", 2),
+            ["2026-11-01T05:30:00Z", "2026-11-02T06:30:00Z"]
+        );
+        assert_eq!(
+            runs("0 * * * *", "Europe/Paris", "2026-03-29T00:30:00Z", 3),
+            [
+                "2026-03-29T01:00:00Z",
+                "2026-03-29T02:00:00Z",
+                "2026-03-29T03:00:00Z",
+            ]
+        );
+    }
+}
@@ -0,0 +1,339 @@
+//! Reminders set with `/remind`, kept as [scheduled](crate::scheduled)
+//! messages until due.
+//!
+//! `/remind me in 2h stretch` reminds the member alone, in whichever room
+//! they are then or through their inbox, and `/remind room tomorrow 9am
+//! standup` posts to the room, mentioning them. Times are `in` a duration
+//! such as `90m`, `1h30m` or `2 hours`, `at 14:30`, `today 5pm` or
+//! `tomorrow`, at 9am unless given, on the member's clock. `/remind list`
+//! lists their reminders and `/remind cancel 2` cancels the second.
+
+use async_trait::async_trait;
+use jiff::civil::Date;
+use jiff::{Timestamp, Zoned};
+use std::sync::{Arc, Weak};
+
+use crate::bots::{Bot, BotContext};
+use crate::events::{unix_timestamp, App, ChatEvent, Draft};
+use crate::owners::generate_token;
+use crate::scheduled::{ScheduledMessage, Source};
+use crate::{direct, notifications, AppState};
+
+/// Who reminders come from.
+const NAME: &str = "reminders";
+/// How far ahead reminders may be set, as scheduled messages may.
+const MAX_DELAY: i64 = 30 * 24 * 60 * 60;
+const MAX_LEN: usize = 500;
+const USAGE: &str = "Usage: /remind me|room <when> <text>, e.g. /remind me in 2h stretch";
+
+/// Seconds in a unit of time, by the names it goes by.
+fn unit(name: &str) -> Option<i64> {
+    match name {
+        "m" | "min" | "mins" | "minute" | "minutes" => Some(60),
+        "h" | "hr" | "hrs" | "hour" | "hours" => Some(60 * 60),
+        "d" | "day" | "days" => Some(24 * 60 * 60),
+        "w" | "week" | "weeks" => Some(7 * 24 * 60 * 60),
+        _ => None,
+    }
+}
+
+/// Seconds of a duration written in one word, e.g. `2h` or `1h30m`.
+fn compact(word: &str) -> Option<i64> {
+    let mut seconds = 0;
+    let mut rest = word;
+    while !rest.is_empty() {
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let letters = rest[digits..]
+            .find(|c: char| c.is_ascii_digit())
+            .map_or(rest.len(), |end| digits + end);
+        let count = rest[..digits].parse::<i64>().ok()?;
+        seconds += count.checked_mul(unit(&rest[digits..letters])?)?;
+        rest = &rest[letters..];
+    }
+    (seconds > 0).then_some(seconds)
+}
+
+/// Seconds of the duration `words` start with, e.g. `2 hours 30m`, and how
+/// many words it takes.
+fn duration(words: &[String]) -> Option<(i64, usize)> {
+    let mut seconds = 0i64;
+    let mut used = 0;
+    while let Some(word) = words.get(used) {
+        let next = words.get(used + 1).and_then(|next| unit(next));
+        if let Some(compact) = compact(word) {
+            seconds = seconds.saturating_add(compact);
+            used += 1;
+        } else if let (Some(next), Ok(count)) = (next, word.parse::<i64>()) {
+            seconds = seconds.saturating_add(count.saturating_mul(next));
+            used += 2;
+        } else if let (Some(next), "a" | "an") = (next, word.as_str()) {
+            seconds = seconds.saturating_add(next);
+            used += 2;
+        } else if word == "and" && used > 0 {
+            used += 1;
+        } else {
+            break;
+        }
+    }
+    // Not ending in a stray `and`, which belongs to the text.
+    if words
+        .get(used.wrapping_sub(1))
+        .is_some_and(|word| word == "and")
+    {
+        used -= 1;
+    }
+    (seconds > 0).then_some((seconds, used))
+}
+
+/// The hour and minute of `9am`, `9:30 pm` or `14:30` at the start of
+/// `words`, and how many words it takes.
+fn time_of_day(words: &[String]) -> Option<(i8, i8, usize)> {
+    let word = words.first()?;
+    let (clock, pm, used) = if let Some(clock) = word.strip_suffix("am") {
+        (clock, Some(false), 1)
+    } else if let Some(clock) = word.strip_suffix("pm") {
+        (clock, Some(true), 1)
+    } else {
+        match words.get(1).map(String::as_str) {
+            Some("am") => (word.as_str(), Some(false), 2),
+            Some("pm") => (word.as_str(), Some(true), 2),
+            _ => (word.as_str(), None, 1),
+        }
+    };
+    let (hour, minute) = match clock.split_once(':') {
+        Some((hour, minute)) => (hour.parse::<i8>().ok()?, minute.parse::<i8>().ok()?),
+        // A bare number is more likely part of the text.
+        None if pm.is_none() => return None,
+        None => (clock.parse::<i8>().ok()?, 0),
+    };
+    if !(0..60).contains(&minute) {
+        return None;
+    }
+    let hour = match pm {
+        Some(pm) if (1..=12).contains(&hour) => hour % 12 + if pm { 12 } else { 0 },
+        None if (0..24).contains(&hour) => hour,
+        _ => return None,
+    };
+    Some((hour, minute, used))
+}
+
+/// When the words of a `/remind` after its target say, on the clock of
+/// `now`, and how many words that takes.
+fn when(words: &[String], now: &Zoned) -> Option<(Zoned, usize)> {
+    let at = |date: Date, hour: i8, minute: i8| {
+        date.at(hour, minute, 0, 0)
+            .to_zoned(now.time_zone().clone())
+            .ok()
+    };
+    let first = words.first()?.as_str();
+    match first {
+        "in" => {
+            let (seconds, used) = duration(&words[1..])?;
+            let timestamp = Timestamp::from_second(now.timestamp().as_second() + seconds).ok()?;
+            Some((timestamp.to_zoned(now.time_zone().clone()), used + 1))
+        }
+        "today" | "tomorrow" => {
+            let date = match first {
+                "today" => now.date(),
+                _ => now.date().tomorrow().ok()?,
+            };
+            let skip = if words.get(1).is_some_and(|word| word == "at") {
+                2
+            } else {
+                1
+            };
+            match time_of_day(&words[skip.min(words.len())..]) {
+                Some((hour, minute, used)) => Some((at(date, hour, minute)?, skip + used)),
+                None if first == "tomorrow" => Some((at(date, 9, 0)?, 1)),
+                None => None,
+            }
+        }
+        "at" => {
+            let (hour, minute, used) = time_of_day(&words[1..])?;
+            let today = at(now.date(), hour, minute)?;
+            // The next time the clock says so.
+            let time = if today.timestamp() > now.timestamp() {
+                today
+            } else {
+                at(now.date().tomorrow().ok()?, hour, minute)?
+            };
+            Some((time, used + 1))
+        }
+        _ => None,
+    }
+}
+
+/// Whether `message` is one of the reminders of `username`.
+fn reminds(message: &ScheduledMessage, username: &str) -> bool {
+    message.from == username && matches!(message.source, Source::Reminder | Source::RoomReminder)
+}
+
+/// Delivers a reminder that is due.
+pub async fn deliver(state: &Arc<AppState>, reminder: ScheduledMessage) {
+    let text = reminder.message.text;
+    if reminder.source == Source::Reminder {
+        let text = format!("Reminder: {}", text);
+        direct::send(state, &reminder.room, NAME, &reminder.from, &text).await;
+        return;
+    }
+    let text = format!("Reminder for @{}: {}", reminder.from, text);
+    let id = {
+        let rooms = state.rooms.lock().unwrap();
+        let Some(room) = rooms.get(&reminder.room) else {
+            return;
+        };
+        let id = room.next_id();
+        room.broadcast(ChatEvent::bot_message(
+            id,
+            NAME,
+            text.clone(),
+            App::new(NAME),
+        ));
+        id
+    };
+    // So that the mention notifies them should they be away.
+    notifications::message_posted(state, &reminder.room, id, NAME, &text);
+}
+
+pub struct RemindBot {
+    pub state: Weak<AppState>,
+}
+
+impl RemindBot {
+    fn list(&self, state: &AppState, ctx: &BotContext, from: &str) {
+        let reminders = state.schedule.pending(|message| reminds(message, from));
+        if reminders.is_empty() {
+            ctx.dm(from, "You have no reminders.");
+            return;
+        }
+        let timezone = state.profiles.timezone(from);
+        let mut lines = vec!["Your reminders:".to_owned()];
+        for (n, reminder) in reminders.iter().enumerate() {
+            let time = Timestamp::from_second(reminder.send_at as i64)
+                .map(|time| {
+                    time.to_zoned(timezone.clone())
+                        .strftime("%Y-%m-%d %H:%M %Z")
+                        .to_string()
+                })
+                .unwrap_or_default();
+            let target = match reminder.source {
+                Source::RoomReminder => format!(" in {}", reminder.room),
+                _ => String::new(),
+            };
+            lines.push(format!(
+                "{}. {}{}: {}",
+                n + 1,
+                time,
+                target,
+                reminder.message.text
+            ));
+        }
+        ctx.dm(from, &lines.join("\n"));
+    }
+
+    fn cancel(&self, state: &AppState, ctx: &BotContext, from: &str, n: &str) {
+        let reminders = state.schedule.pending(|message| reminds(message, from));
+        let reminder = n
+            .parse::<usize>()
+            .ok()
+            .and_then(|n| reminders.get(n.checked_sub(1)?));
+        let cancelled = reminder.is_some_and(|reminder| {
+            state
+                .schedule
+                .cancel(&reminder.id, |message| reminds(message, from))
+        });
+        if cancelled {
+            ctx.dm(from, "Reminder cancelled.");
+        } else {
+            ctx.dm(from, "No such reminder, see /remind list.");
+        }
+    }
+
+    fn remind(&self, state: &AppState, ctx: &BotContext, from: &str, words: &[&str]) {
+        let source = match words.first() {
+            Some(&"me") => Source::Reminder,
+            Some(&"room") => Source::RoomReminder,
+            _ => {
+                ctx.dm(from, USAGE);
+                return;
+            }
+        };
+        let lowercase = words[1..]
+            .iter()
+            .map(|word| word.to_lowercase())
+            .collect::<Vec<_>>();
+        let now = Timestamp::now().to_zoned(state.profiles.timezone(from));
+        let Some((time, used)) = when(&lowercase, &now) else {
+            ctx.dm(from, USAGE);
+            return;
+        };
+        let text = words[1 + used..].join(" ");
+        if text.is_empty() || text.chars().count() > MAX_LEN {
+            ctx.dm(from, &format!("Reminders are 1 to {} characters.", MAX_LEN));
+            return;
+        }
+        let delay = time.timestamp().as_second() - now.timestamp().as_second();
+        if delay <= 0 {
+            ctx.dm(from, "That time has passed.");
+            return;
+        }
+        if delay > MAX_DELAY {
+            ctx.dm(from, "Reminders can be set up to 30 days ahead.");
+            return;
+        }
+        let reminder = ScheduledMessage {
+            id: generate_token(),
+            room: ctx.room.clone(),
+            from: from.to_owned(),
+            source,
+            send_at: unix_timestamp() + delay as u64,
+            message: Draft::text(text),
+        };
+        if let Err(err) = state.schedule.add(reminder) {
+            ctx.dm(from, err);
+            return;
+        }
+        let time = time.strftime("%Y-%m-%d %H:%M %Z");
+        let reply = match source {
+            Source::RoomReminder => format!("I will remind the room at {}.", time),
+            _ => format!("I will remind you at {}.", time),
+        };
+        ctx.dm(from, &reply);
+    }
+}
+
+#[async_trait]
+impl Bot for RemindBot {
+    fn name(&self) -> &str {
+        NAME
+    }
+
+    fn commands(&self) -> &'static [(&'static str, &'static str)] {
+        &[
+            (
+                "remind me|room <when> <text>",
+                "reminds you or the room, e.g. /remind me in 2h stretch",
+            ),
+            ("remind list", "lists your reminders"),
+            ("remind cancel <n>", "cancels reminder n of the list"),
+        ]
+    }
+
+    async fn on_command(&self, ctx: &BotContext, from: &str, command: &str, args: &str) -> bool {
+        if command != "remind" {
+            return false;
+        }
+        let Some(state) = self.state.upgrade() else {
+            return false;
+        };
+        let words = args.split_whitespace().collect::<Vec<_>>();
+        match words.as_slice() {
+            ["list"] => self.list(&state, ctx, from),
+            ["cancel", n] => self.cancel(&state, ctx, from, n),
+            words => self.remind(&state, ctx, from, words),
+        }
+        true
+    }
+}
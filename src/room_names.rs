@@ -0,0 +1,98 @@
+//! Room names, as accepted by every transport and endpoint.
+//!
+//! A name is trimmed and brought to its NFC form, so that names looking the
+//! same and typed differently reach the same room. It must then have between
+//! 1 and [`MAX_LEN`] characters, none of them control or invisible ones.
+
+use icu_normalizer::ComposingNormalizerBorrowed;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::ops::Deref;
+
+use crate::usernames::is_invisible;
+
+/// Longest room name, in characters.
+pub const MAX_LEN: usize = 64;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum InvalidRoomName {
+    Empty,
+    TooLong,
+    /// A control or invisible character.
+    Character(char),
+}
+
+impl fmt::Display for InvalidRoomName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InvalidRoomName::Empty => write!(f, "Room names cannot be empty."),
+            InvalidRoomName::TooLong => {
+                write!(f, "Room names have at most {} characters.", MAX_LEN)
+            }
+            InvalidRoomName::Character(c) => {
+                write!(f, "Room names cannot contain U+{:04X}.", u32::from(*c))
+            }
+        }
+    }
+}
+
+impl std::error::Error for InvalidRoomName {}
+
+/// A normalized, valid room name.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct RoomName(String);
+
+impl RoomName {
+    pub fn new(name: &str) -> Result<Self, InvalidRoomName> {
+        let name = ComposingNormalizerBorrowed::new_nfc().normalize(name.trim());
+        if name.is_empty() {
+            return Err(InvalidRoomName::Empty);
+        }
+        if name.chars().count() > MAX_LEN {
+            return Err(InvalidRoomName::TooLong);
+        }
+        if let Some(c) = name.chars().find(|c| c.is_control() || is_invisible(*c)) {
+            return Err(InvalidRoomName::Character(c));
+        }
+        Ok(Self(name.into_owned()))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Deref for RoomName {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl TryFrom<String> for RoomName {
+    type Error = InvalidRoomName;
+
+    fn try_from(name: String) -> Result<Self, InvalidRoomName> {
+        Self::new(&name)
+    }
+}
+
+impl From<RoomName> for String {
+    fn from(name: RoomName) -> String {
+        name.0
+    }
+}
+
+impl fmt::Display for RoomName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl PartialEq<RoomName> for String {
+    fn eq(&self, other: &RoomName) -> bool {
+        *self == other.0
+    }
+}
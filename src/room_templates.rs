@@ -0,0 +1,264 @@
+//! Room templates admins define, e.g. for event rooms, and the rooms created
+//! from them over REST or with `/create-from-template <template> <room>`.
+//!
+//! A template has the [settings](crate::snapshot::Settings) of a snapshot and
+//! optionally a rules message, the first message of its rooms. Whoever
+//! creates a room from a template owns it. Templates are kept in memory.
+
+use async_trait::async_trait;
+use axum::extract::{Path, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::Json;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex, Weak};
+
+use crate::bots::{helper, Bot, BotContext};
+use crate::events::RoomEvent;
+use crate::owners::{admin_forbidden, generate_token, is_admin};
+use crate::quotas::Creator;
+use crate::room_names::RoomName;
+use crate::rooms::{self, RoomState, Stored};
+use crate::snapshot::Settings;
+use crate::{archive, tags, ApiResponse, AppState};
+
+/// Longest template name, in characters.
+const MAX_NAME_LEN: usize = 32;
+/// Longest rules message, in characters.
+const MAX_RULES_LEN: usize = 2000;
+/// Who the rules message is from.
+const RULES_FROM: &str = "rules";
+
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct RoomTemplate {
+    #[serde(flatten)]
+    pub settings: Settings,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rules: Option<String>,
+}
+
+/// Templates by name.
+pub type Templates = Mutex<BTreeMap<String, RoomTemplate>>;
+
+/// Creates `room` from `template`, within the quotas of `creator` if given,
+/// and returns the new room's owner key, or the status and reason it cannot
+/// be created.
+pub async fn create(
+    state: &AppState,
+    template: &str,
+    room: &RoomName,
+    creator: Option<Creator<'_>>,
+) -> Result<String, (StatusCode, String)> {
+    let Some(template) = state.room_templates.lock().unwrap().get(template).cloned() else {
+        return Err((StatusCode::NOT_FOUND, "Template not found.".to_owned()));
+    };
+    let Settings {
+        tags,
+        motd,
+        system_messages,
+        transforms,
+        backpressure,
+        helper,
+    } = template.settings;
+    let key = {
+        let mut rooms = state.rooms.lock().unwrap();
+        let mut owners = state.owners.lock().unwrap();
+        if rooms.contains_key(room.as_str()) || owners.contains_key(room.as_str()) {
+            return Err((
+                StatusCode::CONFLICT,
+                "Room already exists or has an owner.".to_owned(),
+            ));
+        }
+        if archive::is_archived(state, room) {
+            return Err((StatusCode::CONFLICT, "Room is archived.".to_owned()));
+        }
+        if let Some(creator) = creator {
+            state
+                .quotas
+                .create(rooms.len(), creator)
+                .map_err(|err| (StatusCode::TOO_MANY_REQUESTS, err.to_string()))?;
+        }
+        let stored = Stored {
+            history: Vec::new(),
+            tags: tags.clone(),
+        };
+        let room_state = RoomState::new(state, room, stored);
+        if let Some(rules) = &template.rules {
+            room_state.send_message(RULES_FROM, rules.as_str());
+        }
+        rooms.insert(room.to_string(), room_state);
+        let key = generate_token();
+        owners.insert(room.to_string(), key.clone());
+        key
+    };
+    state.motd.set_own(room, motd);
+    state.system_messages.set_own(room, system_messages);
+    state.transforms.set_own(room, transforms);
+    state.backpressure.set_own(room, backpressure);
+    state.helper.set_own(room, helper);
+    state.storage.save_tags(room, &tags).await;
+    rooms::publish(
+        state,
+        RoomEvent::RoomCreated {
+            room: room.to_string(),
+        },
+    );
+    Ok(key)
+}
+
+/// `GET /admin/templates`
+pub async fn list_templates(State(state): State<Arc<AppState>>, headers: HeaderMap) -> ApiResponse {
+    if !is_admin(&state, &headers) {
+        return admin_forbidden();
+    }
+    let templates = state.room_templates.lock().unwrap().clone();
+    (
+        StatusCode::OK,
+        Json(json!({ "status": "Success!", "templates": templates })),
+    )
+}
+
+/// `PUT /admin/templates/:name`, defines or replaces a template.
+pub async fn put_template(
+    Path(name): Path<String>,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(mut template): Json<RoomTemplate>,
+) -> ApiResponse {
+    if !is_admin(&state, &headers) {
+        return admin_forbidden();
+    }
+    let bad_request = |status: String| (StatusCode::BAD_REQUEST, Json(json!({ "status": status })));
+    let valid_name = !name.is_empty()
+        && name.chars().count() <= MAX_NAME_LEN
+        && name.chars().all(|c| c.is_alphanumeric() || c == '-');
+    if !valid_name {
+        return bad_request(format!(
+            "Template names are 1 to {} letters, digits or dashes.",
+            MAX_NAME_LEN
+        ));
+    }
+    template.settings.tags = match tags::normalize(&state, &template.settings.tags) {
+        Ok(tags) => tags,
+        Err(status) => return bad_request(status),
+    };
+    if let Some(status) = template
+        .settings
+        .helper
+        .as_ref()
+        .and_then(helper::Config::invalid)
+    {
+        return bad_request(status);
+    }
+    template.rules = template
+        .rules
+        .map(|rules| rules.trim().to_owned())
+        .filter(|rules| !rules.is_empty());
+    if template
+        .rules
+        .as_ref()
+        .is_some_and(|rules| rules.chars().count() > MAX_RULES_LEN)
+    {
+        return bad_request(format!("Rules are at most {} characters.", MAX_RULES_LEN));
+    }
+    state.room_templates.lock().unwrap().insert(name, template);
+    (StatusCode::OK, Json(json!({ "status": "Success!" })))
+}
+
+/// `DELETE /admin/templates/:name`, rooms created from it are left as they are.
+pub async fn delete_template(
+    Path(name): Path<String>,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> ApiResponse {
+    if !is_admin(&state, &headers) {
+        return admin_forbidden();
+    }
+    match state.room_templates.lock().unwrap().remove(&name) {
+        Some(_) => (StatusCode::OK, Json(json!({ "status": "Success!" }))),
+        None => (
+            StatusCode::NOT_FOUND,
+            Json(json!({ "status": "Template not found." })),
+        ),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct CreateRoom {
+    room: RoomName,
+}
+
+/// `POST /admin/templates/:name/rooms`, creates a room from the template.
+pub async fn create_room(
+    Path(template): Path<String>,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(body): Json<CreateRoom>,
+) -> ApiResponse {
+    if !is_admin(&state, &headers) {
+        return admin_forbidden();
+    }
+    match create(&state, &template, &body.room, None).await {
+        Ok(key) => (
+            StatusCode::CREATED,
+            Json(json!({ "status": "Success!", "room": body.room, "owner_key": key })),
+        ),
+        Err((code, status)) => (code, Json(json!({ "status": status }))),
+    }
+}
+
+/// Answers `/create-from-template <template> <room>`, sending the member who
+/// created the room its owner key.
+pub struct TemplateBot {
+    pub state: Weak<AppState>,
+}
+
+#[async_trait]
+impl Bot for TemplateBot {
+    fn name(&self) -> &str {
+        "templates"
+    }
+
+    fn commands(&self) -> &'static [(&'static str, &'static str)] {
+        &[(
+            "create-from-template <template> <room>",
+            "creates a room from a template",
+        )]
+    }
+
+    async fn on_command(&self, ctx: &BotContext, from: &str, command: &str, args: &str) -> bool {
+        if command != "create-from-template" {
+            return false;
+        }
+        let Some(state) = self.state.upgrade() else {
+            return false;
+        };
+        let Some((template, room)) = args.split_once(' ') else {
+            ctx.dm(from, "Usage: /create-from-template <template> <room>");
+            return true;
+        };
+        let room = match RoomName::new(room) {
+            Ok(room) => room,
+            Err(err) => {
+                ctx.dm(from, &err.to_string());
+                return true;
+            }
+        };
+        let creator = Creator {
+            username: from,
+            ip: None,
+        };
+        match create(&state, template, &room, Some(creator)).await {
+            Ok(key) => ctx.dm(
+                from,
+                &format!(
+                    "Created {} from {}, its owner key is {}",
+                    room, template, key
+                ),
+            ),
+            Err((_, reason)) => ctx.dm(from, &reason),
+        };
+        true
+    }
+}
@@ -0,0 +1,893 @@
+//! Room membership and message flow shared by every transport.
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::mpsc;
+
+use crate::attachments::{self, Attachment};
+use crate::channels::{self, Channel};
+use crate::components::Component;
+use crate::events::{unix_timestamp, App, ChatEvent, Draft, Format, RoomEvent};
+use crate::gifs::Gif;
+use crate::history_budget::{self, HistoryBudget};
+use crate::inbox::StoredDirect;
+use crate::parts::{self, Part};
+use crate::previews::Preview;
+use crate::rate_limits::Sender;
+use crate::room_names::RoomName;
+use crate::{
+    archive, backpressure, bots, cluster, default_rooms, directory, markdown, motd, notifications,
+    polls, quotas, stats, system_messages, tasks, transforms, translation, usernames, voice,
+    AppState,
+};
+
+/// Number of recent messages kept per room.
+pub const HISTORY_LEN: usize = 100;
+/// Longest `expires_in` of a message, a week.
+const MAX_EXPIRY: u64 = 7 * 24 * 60 * 60;
+/// Longest message text in characters, longer ones are dropped.
+pub const MAX_MESSAGE_LEN: usize = 4000;
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct StoredMessage {
+    pub id: u64,
+    pub from: String,
+    pub text: String,
+    pub timestamp: u64,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub attachments: Vec<Attachment>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub previews: Vec<Preview>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub gif: Option<Gif>,
+    #[serde(default, skip_serializing_if = "Format::is_plain")]
+    pub format: Format,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub parts: Vec<Part>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<u64>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub is_bot: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub app: Option<App>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub components: Vec<Component>,
+}
+
+pub type History = Arc<Mutex<VecDeque<StoredMessage>>>;
+
+impl StoredMessage {
+    /// The message `event` carries, as the history keeps it.
+    fn of(event: &ChatEvent) -> Option<Self> {
+        let ChatEvent::Message {
+            id,
+            from,
+            text,
+            attachments,
+            previews,
+            gif,
+            format,
+            parts,
+            expires_at,
+            is_bot,
+            app,
+            components,
+        } = event
+        else {
+            return None;
+        };
+        Some(Self {
+            id: *id,
+            from: from.clone(),
+            text: text.clone(),
+            timestamp: unix_timestamp(),
+            attachments: attachments.clone(),
+            previews: previews.clone(),
+            gif: gif.clone(),
+            format: *format,
+            parts: parts.clone(),
+            expires_at: *expires_at,
+            is_bot: *is_bot,
+            app: app.clone(),
+            components: components.clone(),
+        })
+    }
+}
+
+/// What a room is created with from storage.
+pub struct Stored {
+    pub history: Vec<StoredMessage>,
+    pub tags: Vec<String>,
+}
+
+/// A member's place in a room, with the room as it was when they joined.
+pub struct Membership {
+    pub tx: Arc<Channel>,
+    /// Subscribed before anything the member has not seen in `roster` and
+    /// `history` was sent, and after everything they have.
+    pub rx: channels::Receiver,
+    /// The members, the new one included, sorted.
+    pub roster: Vec<String>,
+    /// Recent messages, oldest first.
+    pub history: Vec<StoredMessage>,
+}
+
+/// Persistence for room messages. The in-memory history is always kept;
+/// a storage additionally sees every message and seeds the history when a
+/// room is created again. Failures are left to the storage to handle, and
+/// to report with [`report_error`](crate::report_error).
+#[async_trait]
+pub trait Storage: Send + Sync {
+    async fn save(&self, room: &str, message: &StoredMessage);
+
+    /// Removes an expired message.
+    async fn delete(&self, _room: &str, _id: u64) {}
+
+    /// Up to `limit` most recent messages of `room`, oldest first.
+    async fn load(&self, _room: &str, _limit: usize) -> Vec<StoredMessage> {
+        Vec::new()
+    }
+
+    /// Keeps a direct message for its offline recipient `to`.
+    async fn save_direct(&self, _to: &str, _message: &StoredDirect) {}
+
+    /// Removes a direct message `to` acknowledged.
+    async fn delete_direct(&self, _to: &str, _id: &str) {}
+
+    /// The direct messages waiting for `to`.
+    async fn load_directs(&self, _to: &str) -> Vec<StoredDirect> {
+        Vec::new()
+    }
+
+    /// Keeps the [tags](crate::tags) of `room`, replacing earlier ones.
+    async fn save_tags(&self, _room: &str, _tags: &[String]) {}
+
+    async fn load_tags(&self, _room: &str) -> Vec<String> {
+        Vec::new()
+    }
+
+    /// Every message of `room` sent since `since`, in Unix seconds, for its
+    /// [statistics](crate::stats). `None` leaves them to the count kept in
+    /// memory.
+    async fn load_since(&self, _room: &str, _since: u64) -> Option<Vec<StoredMessage>> {
+        None
+    }
+}
+
+/// Default storage, history lives only as long as the room.
+pub struct NoStorage;
+
+#[async_trait]
+impl Storage for NoStorage {
+    async fn save(&self, _room: &str, _message: &StoredMessage) {}
+}
+
+/// Decides who may join which room, consulted by every transport.
+pub trait Authenticator: Send + Sync {
+    fn authorize(&self, room: &str, username: &str) -> bool;
+}
+
+/// Default authenticator, lets everyone in.
+pub struct AllowAll;
+
+impl Authenticator for AllowAll {
+    fn authorize(&self, _room: &str, _username: &str) -> bool {
+        true
+    }
+}
+
+/// Extension points in the join/message/leave flow shared by all transports.
+/// Hooks run in registration order; every method defaults to a no-op.
+#[async_trait]
+pub trait Hooks: Send + Sync {
+    /// Runs before `username` is added to `room`, an `Err` rejects the join
+    /// with that reason.
+    async fn before_join(&self, _room: &str, _username: &str) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// Runs before a member's join, message or leave is broadcast. Returns the
+    /// event to send, possibly rewritten, or `None` to drop it.
+    async fn before_broadcast(&self, _room: &str, event: ChatEvent) -> Option<ChatEvent> {
+        Some(event)
+    }
+
+    /// Runs after `username` has left `room`.
+    async fn after_disconnect(&self, _room: &str, _username: &str) {}
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum JoinError {
+    UsernameTaken,
+    /// Not a name [that may be taken](crate::usernames::is_valid).
+    InvalidUsername,
+    Forbidden,
+    /// Rejected by a [`Hooks::before_join`] hook.
+    Rejected(String),
+    /// The room is [archived](crate::archive).
+    Archived,
+    /// Creating the room would exceed the server's [room limit](crate::quotas).
+    RoomLimit,
+    /// The member, or their address, created too many rooms of late.
+    CreationLimit,
+    /// The room lives on another [cluster](crate::cluster) node, at this
+    /// base URL.
+    Elsewhere(String),
+}
+
+impl fmt::Display for JoinError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JoinError::UsernameTaken => write!(f, "Username already taken."),
+            JoinError::InvalidUsername => write!(f, "Invalid username."),
+            JoinError::Forbidden => write!(f, "Not allowed to join this room."),
+            JoinError::Rejected(reason) => write!(f, "{}", reason),
+            JoinError::Archived => write!(f, "This room is archived."),
+            JoinError::RoomLimit => {
+                write!(f, "The server has too many rooms, join an existing one.")
+            }
+            JoinError::CreationLimit => write!(f, "Too many new rooms, try again later."),
+            JoinError::Elsewhere(url) => {
+                write!(f, "This room is served by another node, at {}.", url)
+            }
+        }
+    }
+}
+
+impl JoinError {
+    /// What clients match on, the message being meant for their users.
+    pub fn code(&self) -> &'static str {
+        match self {
+            JoinError::UsernameTaken => "USERNAME_TAKEN",
+            JoinError::InvalidUsername => "INVALID_USERNAME",
+            JoinError::Forbidden => "FORBIDDEN",
+            JoinError::Rejected(_) => "REJECTED",
+            JoinError::Archived => "ARCHIVED",
+            JoinError::RoomLimit => "ROOM_LIMIT",
+            JoinError::CreationLimit => "CREATION_LIMIT",
+            JoinError::Elsewhere(_) => "ELSEWHERE",
+        }
+    }
+}
+
+pub struct RoomState {
+    /// Connected users and the channel for events addressed only to them.
+    pub users: Mutex<HashMap<String, mpsc::UnboundedSender<ChatEvent>>>,
+    pub tx: Arc<Channel>,
+    /// Recent messages, oldest first.
+    pub history: History,
+    /// Members in the room's voice chat, in the order they joined it.
+    pub voice: Mutex<Vec<voice::Participant>>,
+    pub polls: Mutex<polls::Polls>,
+    /// Set by the owner to be found in the directory.
+    pub tags: Mutex<Vec<String>>,
+    /// Recent messages, for trending rooms.
+    pub activity: Mutex<directory::Activity>,
+    /// Shared with [`AppState`]'s, so that they outlive the room.
+    stats: Arc<Mutex<stats::RoomStats>>,
+    name: String,
+    budget: Arc<HistoryBudget>,
+    /// Id of the last message.
+    last_id: AtomicU64,
+}
+
+impl RoomState {
+    /// Creates the room with its `stored` history and tags and spawns the
+    /// task recording its messages, which ends once the room's channel is
+    /// gone.
+    pub fn new(state: &AppState, name: &str, stored: Stored) -> Self {
+        let Stored {
+            history: stored,
+            tags,
+        } = stored;
+        let tx = Channel::new(name, state.channel_sizing);
+        let last_id = stored.iter().map(|message| message.id).max().unwrap_or(0);
+        let history = Arc::new(Mutex::new(VecDeque::from(stored)));
+        state.history_budget.open(name, &history);
+        tasks::spawn(
+            "room-record",
+            record(
+                name.to_owned(),
+                tx.subscribe(),
+                Arc::downgrade(&tx),
+                history.clone(),
+                state.storage.clone(),
+            ),
+        );
+        Self {
+            users: Mutex::new(HashMap::new()),
+            tx,
+            history,
+            voice: Mutex::new(Vec::new()),
+            polls: Mutex::default(),
+            tags: Mutex::new(tags),
+            activity: Mutex::default(),
+            stats: state.stats.room(name),
+            name: name.to_owned(),
+            budget: state.history_budget.clone(),
+            last_id: AtomicU64::new(last_id),
+        }
+    }
+
+    /// Adds `username` unless a member's name looks the same, checking and
+    /// inserting under one lock so that only one of two joins can succeed.
+    pub fn join(
+        &self,
+        username: &str,
+        direct: mpsc::UnboundedSender<ChatEvent>,
+    ) -> Option<Membership> {
+        let mut users = self.users.lock().unwrap();
+        let skeletons = usernames::skeletons(username);
+        if users.keys().any(|taken| {
+            let theirs = usernames::skeletons(taken);
+            skeletons
+                .iter()
+                .zip(&theirs)
+                .any(|(ours, theirs)| ours == theirs)
+        }) {
+            return None;
+        }
+        users.insert(username.to_owned(), direct);
+        self.stats.lock().unwrap().members(users.len());
+        let membership = self.membership(&users);
+        self.budget.touch(&self.name);
+        Some(membership)
+    }
+
+    /// Subscribes to the room and takes its roster and history in one step,
+    /// with the members locked as `users`: a join or leave after it comes
+    /// through the subscription, and every message is either in the history
+    /// or received, never both or neither.
+    fn membership(&self, users: &HashMap<String, mpsc::UnboundedSender<ChatEvent>>) -> Membership {
+        let history = self.history.lock().unwrap();
+        let mut roster = users.keys().cloned().collect::<Vec<_>>();
+        roster.sort();
+        Membership {
+            tx: self.tx.clone(),
+            rx: self.tx.subscribe(),
+            roster,
+            history: history.iter().cloned().collect(),
+        }
+    }
+
+    /// Broadcasts `event`, adding it to the history in the same step if it
+    /// is a message. Returns whether anyone received it.
+    pub fn broadcast(&self, event: ChatEvent) -> bool {
+        let mut history = self.history.lock().unwrap();
+        let mut charge = None;
+        if let Some(message) = StoredMessage::of(&event) {
+            self.activity.lock().unwrap().record(&message.from);
+            self.stats
+                .lock()
+                .unwrap()
+                .record(&message.from, message.timestamp);
+            let added = history_budget::footprint(&message);
+            history.push_back(message);
+            let removed = if history.len() > HISTORY_LEN {
+                history
+                    .pop_front()
+                    .as_ref()
+                    .map_or(0, history_budget::footprint)
+            } else {
+                0
+            };
+            charge = Some((added, removed));
+        }
+        let received = self.tx.send(event);
+        // Unlocked first, as the budget may evict from this history too.
+        drop(history);
+        if let Some((added, removed)) = charge {
+            self.budget.charge(&self.name, added, removed);
+        }
+        received
+    }
+
+    /// Id for a new message of the room.
+    pub fn next_id(&self) -> u64 {
+        self.last_id.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    /// Broadcasts a plain text message that skips the transforms and hooks,
+    /// as bridges and bots post.
+    pub fn send_message(&self, from: &str, text: impl Into<String>) -> bool {
+        let text = transforms::strip_unsafe(&text.into());
+        self.broadcast(ChatEvent::message(self.next_id(), from, text))
+    }
+
+    /// Broadcasts a plain text message as [`send_message`](Self::send_message)
+    /// does, marked as posted by `app`.
+    pub fn send_bot_message(&self, app: App, from: &str, text: impl Into<String>) -> bool {
+        let text = transforms::strip_unsafe(&text.into());
+        self.broadcast(ChatEvent::bot_message(self.next_id(), from, text, app))
+    }
+
+    /// Broadcasts a message of `app` with buttons or select menus, as
+    /// [`send_bot_message`](Self::send_bot_message) does, and returns its id.
+    pub fn send_components(
+        &self,
+        app: App,
+        from: &str,
+        text: impl Into<String>,
+        components: Vec<Component>,
+    ) -> u64 {
+        let text = transforms::strip_unsafe(&text.into());
+        let id = self.next_id();
+        let mut message = ChatEvent::bot_message(id, from, text, app);
+        if let ChatEvent::Message {
+            components: found, ..
+        } = &mut message
+        {
+            *found = components;
+        }
+        self.broadcast(message);
+        id
+    }
+
+    /// Replaces the text of message `id`, still in the history, and
+    /// broadcasts the edit in the same step. Returns whether it was there.
+    pub fn edit(&self, id: u64, text: impl Into<String>, partial: bool) -> bool {
+        self.replace(id, text.into(), partial, None)
+    }
+
+    /// Replaces the text and the [components](crate::components) of message
+    /// `id`, as [`edit`](Self::edit) does the text.
+    pub fn update(&self, id: u64, text: impl Into<String>, components: Vec<Component>) -> bool {
+        self.replace(id, text.into(), false, Some(components))
+    }
+
+    fn replace(
+        &self,
+        id: u64,
+        text: String,
+        partial: bool,
+        components: Option<Vec<Component>>,
+    ) -> bool {
+        let text = transforms::strip_unsafe(&text);
+        let mut history = self.history.lock().unwrap();
+        let Some(message) = history.iter_mut().find(|message| message.id == id) else {
+            return false;
+        };
+        let removed = history_budget::footprint(message);
+        message.text = text.clone();
+        if let Some(components) = &components {
+            message.components = components.clone();
+        }
+        let added = history_budget::footprint(message);
+        self.tx.send(ChatEvent::Edited {
+            id,
+            from: message.from.clone(),
+            text,
+            partial,
+            components,
+        });
+        drop(history);
+        self.budget.charge(&self.name, added, removed);
+        true
+    }
+}
+
+/// Deletes the messages of `expiring` that are due and announces their
+/// tombstones.
+async fn expire(
+    room: &str,
+    expiring: &mut BTreeSet<(u64, u64)>,
+    tx: &Weak<Channel>,
+    history: &History,
+    storage: &dyn Storage,
+) {
+    while let Some(&(at, id)) = expiring.first() {
+        if at > unix_timestamp() {
+            break;
+        }
+        expiring.pop_first();
+        history.lock().unwrap().retain(|message| message.id != id);
+        storage.delete(room, id).await;
+        if let Some(tx) = tx.upgrade() {
+            tx.send(ChatEvent::Deleted { id });
+        }
+    }
+}
+
+/// Keeps the history, and the storage, in step with the room's messages.
+/// Holds the channel only weakly, so that it ends with the room.
+async fn record(
+    room: String,
+    mut rx: channels::Receiver,
+    tx: Weak<Channel>,
+    history: History,
+    storage: Arc<dyn Storage>,
+) {
+    // Expiry time and id of ephemeral messages, soonest first.
+    let mut expiring = history
+        .lock()
+        .unwrap()
+        .iter()
+        .filter_map(|message| Some((message.expires_at?, message.id)))
+        .collect::<BTreeSet<_>>();
+    loop {
+        expire(&room, &mut expiring, &tx, &history, storage.as_ref()).await;
+        let wait = expiring
+            .first()
+            .map(|(at, _)| Duration::from_secs(at.saturating_sub(unix_timestamp())));
+        let event = tokio::select! {
+            event = rx.recv() => event,
+            () = tokio::time::sleep(wait.unwrap_or_default()), if wait.is_some() => continue,
+        };
+        // The history got the message along with its broadcast.
+        match event {
+            // The storage keeps the text once done.
+            Ok(ChatEvent::Edited {
+                id, partial: false, ..
+            }) => {
+                let message = history
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .find(|message| message.id == id)
+                    .cloned();
+                if let Some(message) = message {
+                    storage.delete(&room, id).await;
+                    storage.save(&room, &message).await;
+                }
+            }
+            Ok(event) => {
+                let Some(message) = StoredMessage::of(&event) else {
+                    continue;
+                };
+                if let Some(at) = message.expires_at {
+                    expiring.insert((at, message.id));
+                }
+                storage.save(&room, &message).await;
+            }
+            Err(RecvError::Lagged(_)) => {}
+            Err(RecvError::Closed) => break,
+        }
+    }
+}
+
+/// Adds `username` to `room`, creating the room if needed, and returns their
+/// membership. `ip` is where they connect from, if the transport knows.
+pub async fn reserve(
+    state: &AppState,
+    room: &RoomName,
+    username: &str,
+    ip: Option<IpAddr>,
+    direct: mpsc::UnboundedSender<ChatEvent>,
+) -> Result<Membership, JoinError> {
+    let room = room.as_str();
+    if !usernames::is_valid(username) {
+        return Err(JoinError::InvalidUsername);
+    }
+    if !state.auth.authorize(room, username) {
+        return Err(JoinError::Forbidden);
+    }
+    for hook in &state.hooks {
+        hook.before_join(room, username)
+            .await
+            .map_err(JoinError::Rejected)?;
+    }
+    let creator = quotas::Creator { username, ip };
+    with_room(state, room, Some(creator), |room_state| {
+        let membership = room_state
+            .join(username, direct.clone())
+            .ok_or(JoinError::UsernameTaken)?;
+        state.connections.add(username, room, direct.clone());
+        Ok(membership)
+    })
+    .await?
+}
+
+/// Runs `f` on `room` under the rooms lock, creating the room if needed and,
+/// for a `creator`, within their [quotas](crate::quotas). Fails if the room
+/// is archived, which is checked under the same lock, over a quota or owned
+/// by another cluster node.
+pub async fn with_room<T>(
+    state: &AppState,
+    room: &str,
+    creator: Option<quotas::Creator<'_>>,
+    mut f: impl FnMut(&RoomState) -> T,
+) -> Result<T, JoinError> {
+    // A room is only created together with its stored history, so that new
+    // messages continue the stored ids, and tags. They are loaded without the
+    // lock, and the room looked up again once it is back.
+    if let Some((_, url)) = cluster::elsewhere(state, room) {
+        return Err(JoinError::Elsewhere(url));
+    }
+    let mut stored = None;
+    loop {
+        {
+            let mut rooms = state.rooms.lock().unwrap();
+            if archive::is_archived(state, room) {
+                return Err(JoinError::Archived);
+            }
+            let count = rooms.len();
+            let room_state = match rooms.entry(room.to_owned()) {
+                Entry::Occupied(entry) => Some(entry.into_mut()),
+                Entry::Vacant(entry) => match stored.take() {
+                    Some(stored) => {
+                        if let Some(creator) = creator {
+                            state.quotas.create(count, creator)?;
+                        }
+                        publish(
+                            state,
+                            RoomEvent::RoomCreated {
+                                room: room.to_owned(),
+                            },
+                        );
+                        Some(entry.insert(RoomState::new(state, room, stored)))
+                    }
+                    None => None,
+                },
+            };
+            if let Some(room_state) = room_state {
+                return Ok(f(room_state));
+            }
+        }
+        stored = Some(Stored {
+            history: state.storage.load(room, HISTORY_LEN).await,
+            tags: state.storage.load_tags(room).await,
+        });
+    }
+}
+
+/// Moves the membership of `username` in `room` to the connection behind
+/// `direct`, returning the new membership unless the member is gone.
+pub fn take_over(
+    state: &AppState,
+    room: &str,
+    username: &str,
+    direct: mpsc::UnboundedSender<ChatEvent>,
+) -> Option<Membership> {
+    let rooms = state.rooms.lock().unwrap();
+    let room_state = rooms.get(room)?;
+    let mut users = room_state.users.lock().unwrap();
+    *users.get_mut(username)? = direct.clone();
+    state.connections.remove(username, room);
+    state.connections.add(username, room, direct);
+    Some(room_state.membership(&users))
+}
+
+/// Publishes on the event bus, which has no subscribers in a bare server.
+pub fn publish(state: &AppState, event: RoomEvent) {
+    let _ = state.bus.send(event);
+}
+
+/// Passes an event through the `before_broadcast` hooks.
+async fn before_broadcast(state: &AppState, room: &str, event: ChatEvent) -> Option<ChatEvent> {
+    let mut event = event;
+    for hook in &state.hooks {
+        event = hook.before_broadcast(room, event).await?;
+    }
+    Some(event)
+}
+
+/// Announces a reserved user to the room and the event bus.
+pub async fn announce_join(state: &Arc<AppState>, room: &str, tx: &Channel, username: &str) {
+    let rejoined = state.system_messages.rejoined(room, username);
+    if let Some(joined) = system_messages::joined(state, room, username).filter(|_| !rejoined) {
+        if let Some(joined) = before_broadcast(state, room, joined).await {
+            tx.send(joined);
+        }
+    }
+    motd::greet(state, room, username);
+    publish(
+        state,
+        RoomEvent::UserJoined {
+            room: room.to_owned(),
+            username: username.to_owned(),
+        },
+    );
+}
+
+/// Handles a line of chat from a member within their [rate
+/// limit](crate::rate_limits), telling them when they are over it.
+pub async fn post_message(state: &Arc<AppState>, room: &str, from: &str, draft: Draft) {
+    if limited(state, room, from) {
+        return;
+    }
+    post_unlimited(state, room, from, draft).await;
+}
+
+/// Counts a message of `from` against their rate limit, telling them in
+/// `room` if they are over it.
+pub fn limited(state: &AppState, room: &str, from: &str) -> bool {
+    let Err(wait) = state.rate_limits.check(Sender::Member(from)) else {
+        return false;
+    };
+    let rooms = state.rooms.lock().unwrap();
+    if let Some(direct) = rooms
+        .get(room)
+        .and_then(|room| room.users.lock().unwrap().get(from).cloned())
+    {
+        let _ = direct.send(ChatEvent::Limited {
+            retry_after: wait.as_secs() + 1,
+        });
+    }
+    true
+}
+
+/// Handles a line of chat: bot commands first, then the room's transforms
+/// and the hooks before the broadcast. Scheduled messages come this way
+/// when due, the server posting them on the member's behalf.
+pub async fn post_unlimited(state: &Arc<AppState>, room: &str, from: &str, mut draft: Draft) {
+    let received = Instant::now();
+    if draft.text.chars().count() > MAX_MESSAGE_LEN {
+        return;
+    }
+    draft.text = transforms::strip_unsafe(&draft.text);
+    if bots::dispatch_command(state, room, from, &draft.text).await {
+        return;
+    }
+    let attachments = attachments::resolve(state, room, &draft.attachments).await;
+    let gif = match (&state.gifs, &draft.gif) {
+        (Some(gifs), Some(id)) => gifs.get(id).await,
+        _ => None,
+    };
+    let split = parts::split(&draft.text);
+    // Files posted without a caption skip the transforms, which would drop them.
+    let text = if draft.text.is_empty() && (!attachments.is_empty() || gif.is_some()) {
+        String::new()
+    } else if !split.iter().all(Part::is_text) {
+        // Code blocks are kept verbatim, only prose and spoilers are transformed.
+        let transformed = split
+            .into_iter()
+            .map(|part| match part {
+                Part::Text { text } => {
+                    transforms::apply(state, room, from, text).map(|text| Part::Text { text })
+                }
+                Part::Spoiler { text } => {
+                    transforms::apply(state, room, from, text).map(|text| Part::Spoiler { text })
+                }
+                code => Some(code),
+            })
+            .collect::<Option<Vec<_>>>();
+        match transformed {
+            Some(transformed) => parts::join(&transformed),
+            None => return,
+        }
+    } else {
+        match transforms::apply(state, room, from, draft.text) {
+            Some(text) => text,
+            None => return,
+        }
+    };
+    let message = ChatEvent::Message {
+        id: 0,
+        from: from.to_owned(),
+        text,
+        attachments,
+        previews: Vec::new(),
+        gif,
+        format: draft.format,
+        parts: Vec::new(),
+        expires_at: draft
+            .expires_in
+            .filter(|seconds| *seconds > 0)
+            .map(|seconds| unix_timestamp() + seconds.min(MAX_EXPIRY)),
+        is_bot: false,
+        app: None,
+        components: Vec::new(),
+    };
+    let Some(mut message) = before_broadcast(state, room, message).await else {
+        return;
+    };
+    // Split and normalized after the hooks, so that no rewrite leaves
+    // broken markup or parts that disagree with the text.
+    if let ChatEvent::Message {
+        text,
+        format,
+        parts: found,
+        ..
+    } = &mut message
+    {
+        let mut split = parts::split(text);
+        if *format == Format::Markdown {
+            for part in &mut split {
+                if let Part::Text { text } | Part::Spoiler { text } = part {
+                    *text = markdown::normalize(text);
+                }
+            }
+        }
+        if !split.iter().all(Part::is_text) {
+            *text = parts::join(&split);
+            *found = split;
+        } else if *format == Format::Markdown {
+            *text = markdown::normalize(text);
+        }
+    }
+    // Unfurled last, so the links are the ones actually sent.
+    if let (
+        Some(previews),
+        ChatEvent::Message {
+            text,
+            previews: found,
+            ..
+        },
+    ) = (&state.previews, &mut message)
+    {
+        *found = previews.for_text(text).await;
+    }
+    backpressure::wait(state, room).await;
+    // Numbered last, so that ids follow the order of the broadcast.
+    {
+        let rooms = state.rooms.lock().unwrap();
+        let Some(room_state) = rooms.get(room) else {
+            return;
+        };
+        if let ChatEvent::Message { id, .. } = &mut message {
+            *id = room_state.next_id();
+            state.metrics.received(room, *id, received);
+        }
+        room_state.broadcast(message.clone());
+    }
+    if let ChatEvent::Message { id, from, text, .. } = message {
+        notifications::message_posted(state, room, id, &from, &text);
+        translation::message_posted(state, room, id, &from, &text);
+        publish(
+            state,
+            RoomEvent::MessageSent {
+                room: room.to_owned(),
+                from,
+                text,
+            },
+        );
+    }
+}
+
+/// Announces the departure and drops the room once the last member is gone,
+/// unless it is a default room. The member is removed first, so that whoever
+/// joins before the notice has them neither in their roster nor missing the
+/// notice.
+pub async fn leave(state: &AppState, room: &str, tx: &Arc<Channel>, username: &str) {
+    voice::leave(state, room, username);
+    let deleted = {
+        let mut rooms = state.rooms.lock().unwrap();
+        let empty = rooms.get(room).is_some_and(|room_state| {
+            let mut users = room_state.users.lock().unwrap();
+            users.remove(username);
+            state.connections.remove(username, room);
+            users.is_empty()
+        });
+        // Default rooms stay, members or not.
+        let deleted = empty && !default_rooms::is_default(state, room);
+        if deleted {
+            rooms.remove(room);
+        }
+        deleted
+    };
+
+    if let Some(left) = system_messages::left(state, room, username) {
+        if let Some(left) = before_broadcast(state, room, left).await {
+            state
+                .system_messages
+                .announce_leave(room, username, tx, left);
+        }
+    }
+    publish(
+        state,
+        RoomEvent::UserLeft {
+            room: room.to_owned(),
+            username: username.to_owned(),
+        },
+    );
+    if deleted {
+        publish(
+            state,
+            RoomEvent::RoomDeleted {
+                room: room.to_owned(),
+            },
+        );
+    }
+
+    for hook in &state.hooks {
+        hook.after_disconnect(room, username).await;
+    }
+}
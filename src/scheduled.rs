@@ -0,0 +1,333 @@
+//! Messages posted at a later time.
+//!
+//! Members schedule with a `schedule` WebSocket frame and cancel with
+//! `schedule_cancel`, services with the token of an incoming webhook. A
+//! background task posts each message once its `send_at` has passed and the
+//! room is active. With `SCHEDULE_FILE` set the pending messages are kept in
+//! that JSON file and survive restarts.
+
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::Json;
+use log::info;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::events::{unix_timestamp, App, ChatEvent, Draft};
+use crate::json_file::JsonFile;
+use crate::owners::generate_token;
+use crate::{reminders, rooms, ApiResponse, AppState};
+
+/// How far ahead messages may be scheduled.
+const MAX_DELAY: u64 = 30 * 24 * 60 * 60;
+/// Pending messages per member or webhook.
+const MAX_PENDING: usize = 25;
+const TICK: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Source {
+    /// Goes through the same pipeline as the member's live messages.
+    Member,
+    /// Posted as is, like the webhook's direct posts.
+    Webhook,
+    /// A [reminder](crate::reminders) posted to the room, mentioning `from`.
+    RoomReminder,
+    /// A [reminder](crate::reminders) sent to `from` alone, wherever they are.
+    Reminder,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ScheduledMessage {
+    pub id: String,
+    pub room: String,
+    pub from: String,
+    pub source: Source,
+    /// Unix time in seconds.
+    pub send_at: u64,
+    #[serde(flatten)]
+    pub message: Draft,
+}
+
+/// Pending messages, written through to the schedule file if there is one.
+pub struct Schedule {
+    pending: Mutex<Vec<ScheduledMessage>>,
+    file: Option<JsonFile>,
+}
+
+impl Schedule {
+    /// Loads the pending messages from `file`, starting empty if it does not
+    /// exist yet.
+    pub fn open(file: Option<PathBuf>) -> Self {
+        let file = file.map(|path| JsonFile::new(path, "scheduled"));
+        let pending = file.as_ref().map(JsonFile::load).unwrap_or_default();
+        Self {
+            pending: Mutex::new(pending),
+            file,
+        }
+    }
+
+    fn save(&self, pending: &[ScheduledMessage]) {
+        if let Some(file) = &self.file {
+            file.save(&pending);
+        }
+    }
+
+    /// Adds `message`, unless it is not due within [`MAX_DELAY`] or its
+    /// sender already has [`MAX_PENDING`] messages waiting.
+    pub fn add(&self, message: ScheduledMessage) -> Result<(), &'static str> {
+        let now = unix_timestamp();
+        if message.send_at <= now || message.send_at > now + MAX_DELAY {
+            return Err("Messages can be scheduled up to 30 days ahead.");
+        }
+        if message.message.is_empty() {
+            return Err("Message text must not be empty.");
+        }
+        let mut pending = self.pending.lock().unwrap();
+        let queued = pending
+            .iter()
+            .filter(|queued| queued.from == message.from && queued.source == message.source)
+            .count();
+        if queued >= MAX_PENDING {
+            return Err("Too many scheduled messages.");
+        }
+        pending.push(message);
+        self.save(&pending);
+        Ok(())
+    }
+
+    /// Removes the message `id` if `owns` it, returning whether it did.
+    pub fn cancel(&self, id: &str, owns: impl Fn(&ScheduledMessage) -> bool) -> bool {
+        let mut pending = self.pending.lock().unwrap();
+        let Some(at) = pending
+            .iter()
+            .position(|message| message.id == id && owns(message))
+        else {
+            return false;
+        };
+        pending.remove(at);
+        self.save(&pending);
+        true
+    }
+
+    /// The pending messages that `owns`, the soonest first.
+    pub fn pending(&self, owns: impl Fn(&ScheduledMessage) -> bool) -> Vec<ScheduledMessage> {
+        let pending = self.pending.lock().unwrap();
+        let mut owned = pending
+            .iter()
+            .filter(|message| owns(message))
+            .cloned()
+            .collect::<Vec<_>>();
+        owned.sort_by_key(|message| message.send_at);
+        owned
+    }
+
+    /// Removes the pending messages of `room` and returns them, to hand them
+    /// over.
+    pub fn take_room(&self, room: &str) -> Vec<ScheduledMessage> {
+        let mut pending = self.pending.lock().unwrap();
+        let (taken, kept) = pending
+            .drain(..)
+            .partition::<Vec<_>, _>(|message| message.room == room);
+        *pending = kept;
+        if !taken.is_empty() {
+            self.save(&pending);
+        }
+        taken
+    }
+
+    /// Adds the pending `messages` handed over, past the limits of [`add`](Self::add)
+    /// as they were within them when added.
+    pub fn restore(&self, messages: Vec<ScheduledMessage>) {
+        if messages.is_empty() {
+            return;
+        }
+        let mut pending = self.pending.lock().unwrap();
+        pending.extend(messages);
+        self.save(&pending);
+    }
+
+    /// The rooms of the messages due.
+    fn due_rooms(&self) -> HashSet<String> {
+        let now = unix_timestamp();
+        let pending = self.pending.lock().unwrap();
+        pending
+            .iter()
+            .filter(|message| message.send_at <= now)
+            .map(|message| message.room.clone())
+            .collect()
+    }
+
+    /// Takes the messages that are due and can be delivered, as `ready`
+    /// tells.
+    fn take_due(&self, ready: impl Fn(&ScheduledMessage) -> bool) -> Vec<ScheduledMessage> {
+        let now = unix_timestamp();
+        let mut pending = self.pending.lock().unwrap();
+        let (due, waiting) = pending
+            .drain(..)
+            .partition::<Vec<_>, _>(|message| message.send_at <= now && ready(message));
+        *pending = waiting;
+        if !due.is_empty() {
+            self.save(&pending);
+        }
+        due
+    }
+}
+
+/// Tells `username` in `room` about the outcome of a frame.
+fn reply(state: &AppState, room: &str, username: &str, event: ChatEvent) {
+    let rooms = state.rooms.lock().unwrap();
+    let users = rooms.get(room).map(|room| room.users.lock().unwrap());
+    if let Some(direct) = users.as_ref().and_then(|users| users.get(username)) {
+        let _ = direct.send(event);
+    }
+}
+
+/// Handles a `schedule` frame, answering the member with a `scheduled` event.
+pub fn schedule(state: &AppState, room: &str, from: &str, send_at: u64, message: Draft) {
+    let scheduled = ScheduledMessage {
+        id: generate_token(),
+        room: room.to_owned(),
+        from: from.to_owned(),
+        source: Source::Member,
+        send_at,
+        message,
+    };
+    let (id, send_at) = (scheduled.id.clone(), scheduled.send_at);
+    if state.schedule.add(scheduled).is_ok() {
+        reply(state, room, from, ChatEvent::Scheduled { id, send_at });
+    }
+}
+
+/// Handles a `schedule_cancel` frame for one of the member's messages.
+pub fn cancel(state: &AppState, room: &str, from: &str, id: &str) {
+    let owns = |message: &ScheduledMessage| {
+        message.source == Source::Member && message.room == room && message.from == from
+    };
+    if state.schedule.cancel(id, owns) {
+        reply(
+            state,
+            room,
+            from,
+            ChatEvent::Unscheduled { id: id.to_owned() },
+        );
+    }
+}
+
+/// Posts due messages once a second.
+pub async fn scheduler(state: Arc<AppState>) {
+    let mut interval = tokio::time::interval(TICK);
+    loop {
+        interval.tick().await;
+        // Looked up apart, so that the schedule lock is never held with the
+        // rooms lock.
+        let due = state.schedule.due_rooms();
+        let active = {
+            let rooms = state.rooms.lock().unwrap();
+            due.into_iter()
+                .filter(|room| rooms.contains_key(room))
+                .collect::<HashSet<_>>()
+        };
+        // Reminders of a member reach them in any room, or their inbox.
+        let due = state.schedule.take_due(|message| {
+            message.source == Source::Reminder || active.contains(&message.room)
+        });
+        for scheduled in due {
+            info!(
+                "Posting scheduled message {} by {} to {}",
+                scheduled.id, scheduled.from, scheduled.room
+            );
+            match scheduled.source {
+                Source::Member => {
+                    rooms::post_unlimited(
+                        &state,
+                        &scheduled.room,
+                        &scheduled.from,
+                        scheduled.message,
+                    )
+                    .await
+                }
+                Source::Webhook => {
+                    let rooms = state.rooms.lock().unwrap();
+                    if let Some(room) = rooms.get(&scheduled.room) {
+                        room.send_bot_message(
+                            App::new(&scheduled.from),
+                            &scheduled.from,
+                            scheduled.message.text,
+                        );
+                    }
+                }
+                Source::RoomReminder | Source::Reminder => {
+                    reminders::deliver(&state, scheduled).await;
+                }
+            }
+        }
+    }
+}
+
+fn error(status: StatusCode, message: &str) -> ApiResponse {
+    (status, Json(json!({ "status": message })))
+}
+
+/// The room and name of the incoming webhook with `token`.
+fn webhook(state: &AppState, token: &str) -> Option<(String, String)> {
+    let webhooks = state.webhooks.lock().unwrap();
+    webhooks
+        .get(token)
+        .map(|hook| (hook.room.clone(), hook.name.clone()))
+}
+
+#[derive(Deserialize)]
+pub struct ScheduleRequest {
+    text: String,
+    send_at: u64,
+}
+
+/// `POST /hooks/:token/scheduled`, schedules a message as the webhook.
+pub async fn create_scheduled(
+    Path(token): Path<String>,
+    State(state): State<Arc<AppState>>,
+    Json(body): Json<ScheduleRequest>,
+) -> ApiResponse {
+    let Some((room, name)) = webhook(&state, &token) else {
+        return error(StatusCode::NOT_FOUND, "Webhook not found.");
+    };
+    let scheduled = ScheduledMessage {
+        id: generate_token(),
+        room,
+        from: name,
+        source: Source::Webhook,
+        send_at: body.send_at,
+        message: Draft::text(body.text.trim()),
+    };
+    let (id, send_at) = (scheduled.id.clone(), scheduled.send_at);
+    match state.schedule.add(scheduled) {
+        Ok(()) => (
+            StatusCode::OK,
+            Json(json!({ "status": "Success!", "id": id, "send_at": send_at })),
+        ),
+        Err(message) => error(StatusCode::BAD_REQUEST, message),
+    }
+}
+
+/// `DELETE /hooks/:token/scheduled/:id`
+pub async fn cancel_scheduled(
+    Path((token, id)): Path<(String, String)>,
+    State(state): State<Arc<AppState>>,
+) -> ApiResponse {
+    let Some((room, name)) = webhook(&state, &token) else {
+        return error(StatusCode::NOT_FOUND, "Webhook not found.");
+    };
+    let owns = |message: &ScheduledMessage| {
+        message.source == Source::Webhook && message.room == room && message.from == name
+    };
+    if state.schedule.cancel(&id, owns) {
+        (StatusCode::OK, Json(json!({ "status": "Success!" })))
+    } else {
+        error(StatusCode::NOT_FOUND, "Scheduled message not found.")
+    }
+}
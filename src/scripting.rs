@@ -0,0 +1,248 @@
+//! Lua scripts loaded from `SCRIPTS_DIR` for moderation and auto-responses.
+//!
+//! Each `*.lua` file runs in its own interpreter and may define:
+//!
+//! - `on_message(room, from, text)`: return `false` to drop the message, a
+//!   string to replace its text, or nothing to let it through.
+//! - `on_join(room, username)`: called after a user joined.
+//!
+//! Scripts can call `send_to_room(room, text)` to speak as the script. The
+//! directory is polled for changes and scripts are reloaded in place. Only
+//! the string, table, math and utf8 libraries are available, and each call
+//! is cut short after [`CALL_TIMEOUT`].
+
+use async_trait::async_trait;
+use log::{error, info, warn};
+use mlua::{Function, HookTriggers, Lua, LuaOptions, StdLib, Value, Variadic, VmState};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock, Weak};
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::bots::{Bot, BotContext};
+use crate::events::{App, ChatEvent};
+use crate::rooms::Hooks;
+use crate::AppState;
+
+pub const CALL_TIMEOUT: Duration = Duration::from_millis(100);
+const MEMORY_LIMIT: usize = 16 << 20;
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+struct Script {
+    lua: Lua,
+    modified: SystemTime,
+    /// Start of the running call, checked by the instruction hook.
+    started: Arc<Mutex<Instant>>,
+}
+
+#[derive(Clone)]
+pub struct ScriptHost {
+    dir: PathBuf,
+    scripts: Arc<Mutex<HashMap<String, Arc<Script>>>>,
+    state: Arc<OnceLock<Weak<AppState>>>,
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|meta| meta.modified())
+        .ok()
+}
+
+impl ScriptHost {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        let host = Self {
+            dir: dir.into(),
+            scripts: Arc::default(),
+            state: Arc::default(),
+        };
+        host.rescan();
+        host
+    }
+
+    /// Gives `send_to_room` access to the rooms.
+    pub fn attach(&self, state: &Arc<AppState>) {
+        let _ = self.state.set(Arc::downgrade(state));
+    }
+
+    /// Polls the directory and reloads scripts as they change.
+    pub async fn watch(self) {
+        let mut interval = tokio::time::interval(POLL_INTERVAL);
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            let host = self.clone();
+            let _ = tokio::task::spawn_blocking(move || host.rescan()).await;
+        }
+    }
+
+    /// Loads new and changed scripts and forgets deleted ones.
+    fn rescan(&self) {
+        let on_disk = match std::fs::read_dir(&self.dir) {
+            Ok(entries) => entries
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.path())
+                .filter(|path| path.extension().is_some_and(|extension| extension == "lua"))
+                .filter_map(|path| {
+                    let name = path.file_stem()?.to_str()?.to_owned();
+                    Some((name, path))
+                })
+                .collect::<HashMap<_, _>>(),
+            Err(err) => {
+                warn!("Cannot read scripts from {}: {}", self.dir.display(), err);
+                return;
+            }
+        };
+
+        self.scripts.lock().unwrap().retain(|name, _| {
+            let keep = on_disk.contains_key(name);
+            if !keep {
+                info!("Unloaded script {}", name);
+            }
+            keep
+        });
+        for (name, path) in on_disk {
+            let Some(modified) = modified(&path) else {
+                continue;
+            };
+            let current = self
+                .scripts
+                .lock()
+                .unwrap()
+                .get(&name)
+                .map(|script| script.modified);
+            if current == Some(modified) {
+                continue;
+            }
+            match self.load(&name, &path, modified) {
+                Ok(script) => {
+                    info!("Loaded script {}", name);
+                    self.scripts.lock().unwrap().insert(name, Arc::new(script));
+                }
+                Err(err) => error!("Failed to load script {}: {}", name, err),
+            }
+        }
+    }
+
+    fn load(&self, name: &str, path: &Path, modified: SystemTime) -> mlua::Result<Script> {
+        let source = std::fs::read_to_string(path)?;
+        let lua = Lua::new_with(
+            StdLib::STRING | StdLib::TABLE | StdLib::MATH | StdLib::UTF8,
+            LuaOptions::default(),
+        )?;
+        lua.set_memory_limit(MEMORY_LIMIT)?;
+
+        let started = Arc::new(Mutex::new(Instant::now()));
+        let deadline = started.clone();
+        lua.set_hook(
+            HookTriggers::new().every_nth_instruction(1000),
+            move |_, _| {
+                if deadline.lock().unwrap().elapsed() > CALL_TIMEOUT {
+                    return Err(mlua::Error::runtime("script took too long"));
+                }
+                Ok(VmState::Continue)
+            },
+        )?;
+
+        let state = self.state.clone();
+        let script_name = name.to_owned();
+        let send_to_room = lua.create_function(move |_, (room, text): (String, String)| {
+            let Some(state) = state.get().and_then(Weak::upgrade) else {
+                return Ok(false);
+            };
+            let rooms = state.rooms.lock().unwrap();
+            let sent = rooms.get(&room).is_some_and(|room| {
+                room.send_bot_message(App::new(&script_name), &script_name, text)
+            });
+            Ok(sent)
+        })?;
+        lua.globals().set("send_to_room", send_to_room)?;
+
+        *started.lock().unwrap() = Instant::now();
+        lua.load(&source).set_name(name).exec()?;
+        Ok(Script {
+            lua,
+            modified,
+            started,
+        })
+    }
+
+    fn loaded(&self) -> Vec<(String, Arc<Script>)> {
+        let mut scripts = self
+            .scripts
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, script)| (name.clone(), script.clone()))
+            .collect::<Vec<_>>();
+        scripts.sort_by(|a, b| a.0.cmp(&b.0));
+        scripts
+    }
+}
+
+/// Calls the global `function` of a script if it defines one.
+fn call(name: &str, script: &Script, function: &str, args: &[&str]) -> Option<Value> {
+    let callback = script
+        .lua
+        .globals()
+        .get::<Option<Function>>(function)
+        .ok()??;
+    *script.started.lock().unwrap() = Instant::now();
+    let args = args
+        .iter()
+        .map(|arg| arg.to_string())
+        .collect::<Variadic<_>>();
+    match callback.call::<Value>(args) {
+        Ok(value) => Some(value),
+        Err(err) => {
+            warn!("Script {} failed in {}: {}", name, function, err);
+            None
+        }
+    }
+}
+
+#[async_trait]
+impl Hooks for ScriptHost {
+    async fn before_broadcast(&self, room: &str, event: ChatEvent) -> Option<ChatEvent> {
+        if !matches!(event, ChatEvent::Message { .. }) {
+            return Some(event);
+        }
+        let scripts = self.loaded();
+        let room = room.to_owned();
+        tokio::task::spawn_blocking(move || {
+            let mut event = event;
+            let ChatEvent::Message { from, text, .. } = &mut event else {
+                return Some(event);
+            };
+            // Each script sees the text as rewritten by the ones before it.
+            for (name, script) in scripts {
+                match call(&name, &script, "on_message", &[&room, from, text]) {
+                    Some(Value::Boolean(false)) => return None,
+                    Some(Value::String(replaced)) => *text = replaced.to_string_lossy(),
+                    _ => {}
+                }
+            }
+            Some(event)
+        })
+        .await
+        .unwrap_or(None)
+    }
+}
+
+#[async_trait]
+impl Bot for ScriptHost {
+    fn name(&self) -> &str {
+        "scripts"
+    }
+
+    async fn on_join(&self, ctx: &BotContext, username: &str) {
+        let scripts = self.loaded();
+        let room = ctx.room.clone();
+        let username = username.to_owned();
+        let _ = tokio::task::spawn_blocking(move || {
+            for (name, script) in scripts {
+                call(&name, &script, "on_join", &[&room, &username]);
+            }
+        })
+        .await;
+    }
+}
@@ -0,0 +1,85 @@
+//! Error reporting to Sentry, enabled with `SENTRY_DSN`, with
+//! `SENTRY_ENVIRONMENT` and `SENTRY_RELEASE` (the crate version by default).
+//!
+//! Panics are reported, as are outgoing webhook deliveries given up on,
+//! attachments, emotes and scheduled messages failing to be stored, and
+//! whatever [`report_error`] is called with, e.g. by a [`Storage`]. Events
+//! are tagged with the room and username they concern, never with message
+//! text or webhook payloads.
+//!
+//! [`Storage`]: crate::Storage
+
+use ::sentry::protocol::{Event, Level};
+use ::sentry::types::Dsn;
+use ::sentry::ClientOptions;
+use log::error;
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+#[derive(Clone)]
+pub struct SentryConfig {
+    /// E.g. `https://<key>@o0.ingest.sentry.io/<project>`.
+    pub dsn: String,
+    pub environment: Option<String>,
+    pub release: Option<String>,
+}
+
+impl SentryConfig {
+    pub fn from_env() -> Option<Self> {
+        let var = |name: &str| std::env::var(name).ok().filter(|val| !val.is_empty());
+        Some(Self {
+            dsn: var("SENTRY_DSN")?,
+            environment: var("SENTRY_ENVIRONMENT"),
+            release: var("SENTRY_RELEASE"),
+        })
+    }
+}
+
+static STARTED: AtomicBool = AtomicBool::new(false);
+
+/// Starts reporting to Sentry, panics included, returning a task to run
+/// until shutdown: events still queued are sent when it is dropped. Only
+/// the first call of a process does.
+pub fn init(config: SentryConfig) -> Option<impl std::future::Future<Output = ()>> {
+    let dsn = match config.dsn.parse::<Dsn>() {
+        Ok(dsn) => dsn,
+        Err(err) => {
+            error!("Ignoring SENTRY_DSN: {}", err);
+            return None;
+        }
+    };
+    if STARTED.swap(true, Ordering::SeqCst) {
+        return None;
+    }
+    let guard = ::sentry::init(ClientOptions {
+        dsn: Some(dsn),
+        environment: config.environment.map(Into::into),
+        release: Some(
+            config
+                .release
+                .unwrap_or_else(|| env!("CARGO_PKG_VERSION").to_owned())
+                .into(),
+        ),
+        ..Default::default()
+    });
+    Some(async move {
+        let _guard = guard;
+        std::future::pending::<()>().await
+    })
+}
+
+/// Reports that `error` happened in `source`, e.g. a storage, concerning
+/// `room` and `username` if given. The error should not quote message text.
+pub fn report_error(source: &str, error: impl Display, room: Option<&str>, username: Option<&str>) {
+    let mut tags = BTreeMap::new();
+    tags.extend(room.map(|room| ("room".to_owned(), room.to_owned())));
+    tags.extend(username.map(|username| ("username".to_owned(), username.to_owned())));
+    ::sentry::capture_event(Event {
+        message: Some(error.to_string()),
+        level: Level::Error,
+        logger: Some(source.to_owned()),
+        tags,
+        ..Default::default()
+    });
+}
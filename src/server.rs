@@ -0,0 +1,1470 @@
+//! Embeddable server: [`ChatServer::builder`] sets up the shared state and
+//! the optional listeners, [`ChatServer::router`] returns the HTTP routes to
+//! serve directly or merge into an existing axum app.
+
+use axum::extract::{DefaultBodyLimit, Request};
+use axum::http::{HeaderValue, Method};
+use axum::response::IntoResponse;
+use axum::routing::{delete, get, post, put, Route};
+use axum::{middleware, Router};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto;
+use hyper_util::service::TowerToHyperService;
+use log::{error, info};
+use socketioxide::layer::SocketIoLayer;
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::future::{Future, IntoFuture};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+use tokio::net::{TcpListener, UnixListener};
+use tokio::sync::{broadcast, mpsc, watch};
+use tower::{Layer, Service};
+use tower_http::cors::{AllowOrigin, Any, CorsLayer};
+
+use crate::assistant::{AssistantBot, AssistantConfig};
+use crate::attachments::s3::{S3Config, S3Store};
+use crate::attachments::scan::{ClamdScanner, WebhookScanner};
+use crate::attachments::{AttachmentPolicy, AttachmentStore, DiskStore, Scanner};
+use crate::batching::Batching;
+use crate::bots::helper::{self, Helper, HelperBot};
+use crate::cluster::{self, Cluster, ClusterConfig};
+use crate::default_rooms::DefaultRoom;
+use crate::events::RoomEvent;
+use crate::gifs::GifConfig;
+use crate::history_budget::{self, HistoryBudget};
+use crate::metrics::MetricsConfig;
+use crate::notifications::email::{self, Email, EmailConfig};
+use crate::notifications::mobile::{self, ApnsConfig, FcmConfig, MobilePush};
+use crate::notifications::webpush::{self, VapidConfig, WebPush};
+use crate::notifications::Notifier;
+use crate::notifications::{digests, highlights, preferences};
+use crate::quotas::{Limits, Quotas};
+use crate::rooms::{AllowAll, Authenticator, Hooks, NoStorage, Storage};
+use crate::sentry::{self, SentryConfig};
+use crate::slow_consumers::{Policy, SlowConsumers};
+use crate::system_messages::{Suppression, Templates};
+use crate::transforms::{builtin, Transform};
+use crate::translation::{self, Translator};
+use crate::turn::TurnConfig;
+use crate::{
+    announcements, archive, attachments, backpressure, bots, channels, connections, default_rooms,
+    directory, emotes, events, feeds, gifs, gossip, graphql, grpc, handler, i18n, inbox, irc,
+    longpoll, matrix, metrics, motd, mqtt, outgoing_webhooks, owners, plugins, polls, presence,
+    previews, profiles, rate_limits, read_state, recurring, reminders, room_templates, scheduled,
+    scripting, slash_commands, snapshot, socketio, sse, stats, system_messages, systemd, tags,
+    tasks, tenants, transforms, turn, voice, web_client, webhooks, AppState,
+};
+
+/// A tower layer applied to the REST router, kept type-erased.
+type RouterLayer = Arc<dyn Fn(Router) -> Router + Send + Sync>;
+
+#[derive(Clone)]
+pub struct ChatServerBuilder {
+    storage: Arc<dyn Storage>,
+    auth: Arc<dyn Authenticator>,
+    hooks: Vec<Arc<dyn Hooks>>,
+    layers: Vec<RouterLayer>,
+    irc_port: Option<u16>,
+    grpc_port: Option<u16>,
+    socketio: bool,
+    bridges: bool,
+    admin_token: Option<String>,
+    plugins_dir: Option<PathBuf>,
+    scripts_dir: Option<PathBuf>,
+    schedule_file: Option<PathBuf>,
+    feeds_file: Option<PathBuf>,
+    attachment_usage_file: Option<PathBuf>,
+    recurring_file: Option<PathBuf>,
+    motd: Option<String>,
+    motd_file: Option<PathBuf>,
+    /// The helper bot's rules for every room, if the bot is on.
+    helper_bot: Option<Option<String>>,
+    dice_bot: bool,
+    locales_dir: Option<PathBuf>,
+    system_messages: Templates,
+    notice_suppression: Suppression,
+    allowed_tags: Option<Vec<String>>,
+    default_rooms: Vec<DefaultRoom>,
+    room_limits: Limits,
+    rate_limits: rate_limits::Limits,
+    metrics: MetricsConfig,
+    history_max_bytes: usize,
+    slow_consumers: SlowConsumers,
+    batching: Batching,
+    channel_sizing: channels::Sizing,
+    transforms: HashMap<String, Arc<dyn Transform>>,
+    default_transforms: Vec<String>,
+    emotes: emotes::Registry,
+    attachments: Option<Arc<dyn AttachmentStore>>,
+    attachment_policy: AttachmentPolicy,
+    attachment_scanner: Option<Arc<dyn Scanner>>,
+    translator: Option<Arc<dyn Translator>>,
+    link_previews: bool,
+    gifs: Option<GifConfig>,
+    turn: Option<TurnConfig>,
+    assistant: Option<AssistantConfig>,
+    cluster: Option<ClusterConfig>,
+    sentry: Option<SentryConfig>,
+    vapid: Option<VapidConfig>,
+    fcm: Option<FcmConfig>,
+    apns: Option<ApnsConfig>,
+    email: Option<EmailConfig>,
+    notifiers: Vec<Arc<dyn Notifier>>,
+    tenants: Vec<(String, tenants::Configure)>,
+    hosts: Vec<String>,
+    cors_origins: Option<Vec<HeaderValue>>,
+    branding: Option<serde_json::Value>,
+    web_client: bool,
+    base_path: String,
+    /// Whether this is a tenant, whose routes the main server mounts.
+    nested: bool,
+}
+
+impl ChatServerBuilder {
+    /// Reads the environment variables of the optional features listed in
+    /// the README and enables the Matrix and MQTT bridges configured there.
+    pub fn from_env(mut self) -> Self {
+        let port = |var| {
+            std::env::var(var)
+                .ok()
+                .map(|port: String| port.parse().unwrap())
+        };
+        self.irc_port = port("IRC_PORT");
+        self.grpc_port = port("GRPC_PORT");
+        self.socketio =
+            std::env::var("SOCKETIO").is_ok_and(|value| !value.is_empty() && value != "0");
+        self.link_previews =
+            std::env::var("LINK_PREVIEWS").is_ok_and(|value| !value.is_empty() && value != "0");
+        self.gifs = GifConfig::from_env();
+        self.sentry = SentryConfig::from_env();
+        self.turn = TurnConfig::from_env();
+        self.assistant = AssistantConfig::from_env();
+        self.cluster = ClusterConfig::from_env();
+        self.vapid = VapidConfig::from_env();
+        self.fcm = FcmConfig::from_env();
+        self.apns = ApnsConfig::from_env();
+        self.email = EmailConfig::from_env();
+        self.bridges = true;
+        self.admin_token = std::env::var("ADMIN_TOKEN")
+            .ok()
+            .filter(|token| !token.is_empty());
+        self.plugins_dir = std::env::var_os("PLUGINS_DIR").map(PathBuf::from);
+        self.scripts_dir = std::env::var_os("SCRIPTS_DIR").map(PathBuf::from);
+        self.schedule_file = std::env::var_os("SCHEDULE_FILE").map(PathBuf::from);
+        self.feeds_file = std::env::var_os("FEEDS_FILE").map(PathBuf::from);
+        self.recurring_file = std::env::var_os("RECURRING_FILE").map(PathBuf::from);
+        self.motd = std::env::var("MOTD").ok().filter(|text| !text.is_empty());
+        self.motd_file = std::env::var_os("MOTD_FILE").map(PathBuf::from);
+        if std::env::var("HELPER_BOT").is_ok_and(|enabled| enabled == "1") {
+            self.helper_bot = Some(std::env::var("HELPER_RULES").ok());
+        }
+        self.dice_bot = std::env::var("DICE_BOT").is_ok_and(|enabled| enabled == "1");
+        self.locales_dir = std::env::var_os("LOCALES_DIR").map(PathBuf::from);
+        // Set but empty, these suppress the messages.
+        self.system_messages = Templates {
+            join: std::env::var("JOIN_MESSAGE").ok(),
+            leave: std::env::var("LEAVE_MESSAGE").ok(),
+        };
+        self.notice_suppression = Suppression::from_env();
+        self.allowed_tags = tags::allowed_from_env();
+        self.default_rooms = default_rooms::from_env();
+        self.room_limits = Limits::from_env();
+        self.rate_limits = rate_limits::Limits::from_env();
+        self.metrics = MetricsConfig::from_env();
+        self.history_max_bytes = HistoryBudget::max_bytes_from_env();
+        self.slow_consumers = SlowConsumers::from_env();
+        self.batching = Batching::from_env();
+        self.channel_sizing = channels::Sizing::from_env();
+        self.tenants = tenants::from_env();
+        if let Some(origins) = std::env::var("CORS_ORIGINS")
+            .ok()
+            .filter(|value| !value.is_empty())
+        {
+            self = self.cors_origins(origins.split(','));
+        }
+        self.branding = tenants::branding_from_env("BRANDING");
+        self.web_client = std::env::var("WEB_CLIENT").map_or(true, |value| value != "0");
+        if let Ok(path) = std::env::var("BASE_PATH") {
+            self = self.base_path(path);
+        }
+        let attachments_dir = std::env::var_os("ATTACHMENTS_DIR").map(PathBuf::from);
+        self.attachments = match S3Config::from_env() {
+            Some(config) => Some(Arc::new(S3Store::new(config))),
+            None => attachments_dir
+                .clone()
+                .map(|dir| Arc::new(DiskStore::new(dir)) as Arc<dyn AttachmentStore>),
+        };
+        // Next to the uploads unless kept elsewhere, e.g. with those in S3.
+        self.attachment_usage_file = std::env::var_os("ATTACHMENT_USAGE_FILE")
+            .map(PathBuf::from)
+            .or_else(|| attachments_dir.map(|dir| dir.join("usage.json")));
+        self.attachment_policy = AttachmentPolicy::from_env();
+        if let Ok(addr) = std::env::var("CLAMD_ADDR") {
+            self.attachment_scanner = Some(Arc::new(ClamdScanner::new(addr)));
+        } else if let Ok(url) = std::env::var("SCAN_WEBHOOK_URL") {
+            self.attachment_scanner = Some(Arc::new(WebhookScanner::new(url)));
+        }
+        self.translator = translation::from_env();
+        let list = |var| {
+            std::env::var(var).ok().map(|value: String| {
+                value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|item| !item.is_empty())
+                    .map(str::to_owned)
+                    .collect::<Vec<_>>()
+            })
+        };
+        if let Some(names) = list("TRANSFORMS") {
+            self.default_transforms = names;
+        }
+        if let Some(words) = list("PROFANITY_WORDS") {
+            self = self.transform(builtin::Profanity::new(words));
+        }
+        self
+    }
+
+    pub fn storage(mut self, storage: impl Storage + 'static) -> Self {
+        self.storage = Arc::new(storage);
+        self
+    }
+
+    pub fn authenticator(mut self, auth: impl Authenticator + 'static) -> Self {
+        self.auth = Arc::new(auth);
+        self
+    }
+
+    /// Adds hooks to the join/message/leave flow, run after the ones added earlier.
+    pub fn hook(mut self, hooks: impl Hooks + 'static) -> Self {
+        self.hooks.push(Arc::new(hooks));
+        self
+    }
+
+    /// Wraps the routes of [`ChatServer::router`] in a tower layer, e.g. for
+    /// auth, tracing or rate limiting. Layers added later run first.
+    pub fn layer<L>(mut self, layer: L) -> Self
+    where
+        L: Layer<Route> + Clone + Send + Sync + 'static,
+        L::Service: Service<Request> + Clone + Send + 'static,
+        <L::Service as Service<Request>>::Response: IntoResponse + 'static,
+        <L::Service as Service<Request>>::Error: Into<Infallible> + 'static,
+        <L::Service as Service<Request>>::Future: Send + 'static,
+    {
+        self.layers
+            .push(Arc::new(move |router: Router| router.layer(layer.clone())));
+        self
+    }
+
+    /// Enables the `/admin` endpoints for requests bearing `token`.
+    pub fn admin_token(mut self, token: impl Into<String>) -> Self {
+        self.admin_token = Some(token.into());
+        self
+    }
+
+    /// Hosts the tenant `name` under `/tenants/<name>/`, with the settings of
+    /// this server as `configure` overrides them, see the `tenants` module.
+    ///
+    /// # Panics
+    ///
+    /// If `name` is not lowercase letters, digits and dashes.
+    pub fn tenant(
+        mut self,
+        name: impl Into<String>,
+        configure: impl Fn(ChatServerBuilder) -> ChatServerBuilder + Send + Sync + 'static,
+    ) -> Self {
+        let name = name.into();
+        assert!(tenants::is_valid(&name), "invalid tenant name {:?}", name);
+        self.tenants.retain(|(tenant, _)| *tenant != name);
+        self.tenants.push((name, Arc::new(configure)));
+        self
+    }
+
+    /// Serves this tenant, besides under `/tenants/<name>/`, to requests for
+    /// `host`, e.g. `chat.example.com`. Only meaningful for tenants.
+    pub fn host(mut self, host: impl Into<String>) -> Self {
+        self.hosts.push(host.into().to_ascii_lowercase());
+        self
+    }
+
+    /// Allows cross-origin requests from `origins` only, instead of from any
+    /// origin, when served with [`ChatServer::serve`].
+    pub fn cors_origins<I>(mut self, origins: I) -> Self
+    where
+        I: IntoIterator,
+        I::Item: AsRef<str>,
+    {
+        let origins = origins
+            .into_iter()
+            .filter_map(|origin| {
+                let origin = origin.as_ref().trim();
+                if origin.is_empty() {
+                    return None;
+                }
+                let value = HeaderValue::from_str(origin);
+                if value.is_err() {
+                    error!("Ignoring the invalid CORS origin {:?}", origin);
+                }
+                value.ok()
+            })
+            .collect();
+        self.cors_origins = Some(origins);
+        self
+    }
+
+    /// Metadata such as a name, logo and colors, sent to WebSocket clients
+    /// in the session frame.
+    pub fn branding(mut self, branding: serde_json::Value) -> Self {
+        self.branding = Some(branding);
+        self
+    }
+
+    /// Serves every route under `path`, e.g. `/chat` behind a proxy routing
+    /// by path, and generates URLs with it.
+    pub fn base_path(mut self, path: impl AsRef<str>) -> Self {
+        let path = path.as_ref().trim_matches('/');
+        self.base_path = if path.is_empty() {
+            String::new()
+        } else {
+            format!("/{}", path)
+        };
+        self
+    }
+
+    /// Serves the bundled web client at `/app`.
+    pub fn web_client(mut self, enabled: bool) -> Self {
+        self.web_client = enabled;
+        self
+    }
+
+    /// Loads WASM plugins from `dir`, see the `plugins` module for the ABI.
+    pub fn plugins_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.plugins_dir = Some(dir.into());
+        self
+    }
+
+    /// Keeps scheduled messages in the JSON file at `path`, so that they
+    /// survive restarts.
+    pub fn schedule_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.schedule_file = Some(path.into());
+        self
+    }
+
+    /// Keeps the rooms' feeds and the entries seen in the JSON file at
+    /// `path`, so that no entry is posted twice across restarts.
+    pub fn feeds_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.feeds_file = Some(path.into());
+        self
+    }
+
+    /// Keeps the bytes each member uploaded in the JSON file at `path`, so
+    /// that attachment quotas survive restarts.
+    pub fn attachment_usage_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.attachment_usage_file = Some(path.into());
+        self
+    }
+
+    /// Keeps the rooms' recurring messages in the JSON file at `path`, so
+    /// that they survive restarts.
+    pub fn recurring_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.recurring_file = Some(path.into());
+        self
+    }
+
+    /// Sends `text` as the message of the day to every member joining a
+    /// room whose owner did not set their own.
+    pub fn motd(mut self, text: impl Into<String>) -> Self {
+        self.motd = Some(text.into());
+        self
+    }
+
+    /// Takes the message of the day from the file at `path`, rereading it
+    /// when it changes. Overrides [`motd`](Self::motd).
+    pub fn motd_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.motd_file = Some(path.into());
+        self
+    }
+
+    /// Runs the helper bot, which greets members with their room's rules,
+    /// `rules` in rooms without their own, and answers `/help`.
+    pub fn helper_bot(mut self, rules: Option<String>) -> Self {
+        self.helper_bot = Some(rules);
+        self
+    }
+
+    /// Runs the dice bot, which answers `/roll 2d6` in every room.
+    pub fn dice_bot(mut self) -> Self {
+        self.dice_bot = true;
+        self
+    }
+
+    /// Loads message catalogs from the `<locale>.json` files in `dir`, see
+    /// the `i18n` module for the keys.
+    pub fn locales_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.locales_dir = Some(dir.into());
+        self
+    }
+
+    /// Words the message announcing a member's arrival, with `{username}`
+    /// and `{room}` replaced. An empty template suppresses the message.
+    pub fn join_message(mut self, template: impl Into<String>) -> Self {
+        self.system_messages.join = Some(template.into());
+        self
+    }
+
+    /// Words the message announcing a member's departure, see
+    /// [`join_message`](Self::join_message).
+    pub fn leave_message(mut self, template: impl Into<String>) -> Self {
+        self.system_messages.leave = Some(template.into());
+        self
+    }
+
+    /// Holds leave notices back for `grace`, dropping them and the notice of
+    /// the member's return when they rejoin within it.
+    pub fn notice_grace(mut self, grace: Duration) -> Self {
+        self.notice_suppression.grace = Some(grace);
+        self
+    }
+
+    /// Announces no joins and leaves in rooms with more than `count` members.
+    pub fn notice_max_members(mut self, count: usize) -> Self {
+        self.notice_suppression.max_members = Some(count);
+        self
+    }
+
+    /// Creates `name` at startup and keeps it when its last member leaves,
+    /// with `topic` in the directory. Invalid names are skipped.
+    pub fn default_room(mut self, name: &str, topic: Option<&str>) -> Self {
+        self.default_rooms.extend(DefaultRoom::new(name, topic));
+        self
+    }
+
+    /// Refuses joins that would create a room while `count` rooms exist.
+    pub fn max_rooms(mut self, count: usize) -> Self {
+        self.room_limits.max_rooms = Some(count);
+        self
+    }
+
+    /// Refuses joins that would create a room once the member, or their
+    /// address, created `count` rooms within the hour.
+    pub fn room_creations_per_hour(mut self, count: usize) -> Self {
+        self.room_limits.creations_per_hour = Some(count);
+        self
+    }
+
+    /// Lets each member post `burst` messages at once, then `per_minute`.
+    pub fn message_rate(mut self, per_minute: u32, burst: u32) -> Self {
+        self.rate_limits.members = Some(rate_limits::Limit {
+            per_minute,
+            burst: burst.max(1),
+        });
+        self
+    }
+
+    /// Lets each incoming webhook and bot post `burst` messages at once,
+    /// then `per_minute`, whatever members may.
+    pub fn bot_message_rate(mut self, per_minute: u32, burst: u32) -> Self {
+        self.rate_limits.bots = Some(rate_limits::Limit {
+            per_minute,
+            burst: burst.max(1),
+        });
+        self
+    }
+
+    /// Only labels the metrics of these rooms, adding the others up, instead
+    /// of those of the rooms with the most members.
+    pub fn metrics_rooms<S: Into<String>>(mut self, rooms: impl IntoIterator<Item = S>) -> Self {
+        self.metrics.rooms = Some(rooms.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// Labels the metrics of the `count` rooms with the most members, 10 by
+    /// default, adding the others up.
+    pub fn metrics_top_rooms(mut self, count: usize) -> Self {
+        self.metrics.top_rooms = count;
+        self
+    }
+
+    /// Caps the memory the rooms' in-memory histories take together, 256 MiB
+    /// by default, evicting the oldest messages of the least recently used
+    /// rooms beyond it.
+    pub fn history_max_bytes(mut self, bytes: usize) -> Self {
+        self.history_max_bytes = bytes;
+        self
+    }
+
+    /// Applies `policy` to members `lag` events or more behind their room,
+    /// or half the room's channel if less, by default warning those half the
+    /// channel behind.
+    pub fn slow_consumers(mut self, policy: Policy, lag: usize) -> Self {
+        self.slow_consumers = SlowConsumers {
+            policy,
+            lag: lag.max(1),
+        };
+        self
+    }
+
+    /// Sizes each room's channel within `min` and `max` events, 16 and 1024
+    /// by default, from its recent throughput and subscribers. The same for
+    /// both gives every room that capacity.
+    pub fn channel_capacity(mut self, min: usize, max: usize) -> Self {
+        self.channel_sizing = channels::Sizing::new(min, max);
+        self
+    }
+
+    /// Sends WebSocket clients that ask for batches up to `max_events`
+    /// events per frame, waiting up to `window` after the first for more.
+    pub fn batching(mut self, window: Duration, max_events: usize) -> Self {
+        self.batching = Batching {
+            window,
+            max_events: max_events.max(1),
+        };
+        self
+    }
+
+    /// Only accepts these room tags instead of free-form ones.
+    pub fn room_tags<S: Into<String>>(mut self, tags: impl IntoIterator<Item = S>) -> Self {
+        let tags = tags.into_iter().map(|tag| tag.into().to_lowercase());
+        self.allowed_tags = Some(tags.collect());
+        self
+    }
+
+    /// Loads Lua scripts from `dir` and reloads them when they change, see
+    /// the `scripting` module for the API.
+    pub fn scripts_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.scripts_dir = Some(dir.into());
+        self
+    }
+
+    /// Enables `POST /rooms/:name/attachments`, keeping uploads in `dir`.
+    pub fn attachments_dir(self, dir: impl Into<PathBuf>) -> Self {
+        self.attachment_store(DiskStore::new(dir))
+    }
+
+    /// Enables attachments kept in a custom store, e.g. a database.
+    pub fn attachment_store(mut self, store: impl AttachmentStore + 'static) -> Self {
+        self.attachments = Some(Arc::new(store));
+        self
+    }
+
+    /// Attaches previews of linked pages to messages, fetched from public
+    /// addresses only.
+    pub fn link_previews(mut self, enabled: bool) -> Self {
+        self.link_previews = enabled;
+        self
+    }
+
+    /// Reports panics and failures to Sentry, see the `sentry` module.
+    pub fn sentry(mut self, config: SentryConfig) -> Self {
+        self.sentry = Some(config);
+        self
+    }
+
+    /// Enables `GET /gifs/search` and GIFs in messages.
+    pub fn gif_search(mut self, config: GifConfig) -> Self {
+        self.gifs = Some(config);
+        self
+    }
+
+    /// Enables `GET /rtc/credentials` for voice chat participants, minting
+    /// credentials for the TURN server sharing the config's secret.
+    pub fn turn(mut self, config: TurnConfig) -> Self {
+        self.turn = Some(config);
+        self
+    }
+
+    /// Answers `/ask` and mentions of the assistant in every room through
+    /// the chat completions endpoint of `config`, see the `assistant` module.
+    pub fn assistant(mut self, config: AssistantConfig) -> Self {
+        self.assistant = Some(config);
+        self
+    }
+
+    /// Spreads the rooms over the nodes of `config`, see the `cluster` module.
+    pub fn cluster(mut self, config: ClusterConfig) -> Self {
+        self.cluster = Some(config);
+        self
+    }
+
+    /// Enables browser push notifications for mentions and direct messages
+    /// received while offline, see `POST /push/subscribe`.
+    pub fn web_push(mut self, vapid: VapidConfig) -> Self {
+        self.vapid = Some(vapid);
+        self
+    }
+
+    /// Sends Android and other Firebase apps push notifications, see
+    /// `POST /push/devices`.
+    pub fn fcm(mut self, config: FcmConfig) -> Self {
+        self.fcm = Some(config);
+        self
+    }
+
+    /// Sends iOS and macOS apps push notifications, see `POST /push/devices`.
+    pub fn apns(mut self, config: ApnsConfig) -> Self {
+        self.apns = Some(config);
+        self
+    }
+
+    /// Emails members a digest of the mentions and direct messages they
+    /// received while offline, see `POST /notifications/email`.
+    pub fn email(mut self, config: EmailConfig) -> Self {
+        self.email = Some(config);
+        self
+    }
+
+    /// Adds a channel notifying members of mentions and direct messages
+    /// received while they are offline.
+    pub fn notifier(mut self, notifier: impl Notifier + 'static) -> Self {
+        self.notifiers.push(Arc::new(notifier));
+        self
+    }
+
+    /// Limits the types, sizes and per-member totals of uploads.
+    pub fn attachment_policy(mut self, policy: AttachmentPolicy) -> Self {
+        self.attachment_policy = policy;
+        self
+    }
+
+    /// Scans uploads before they are stored, quarantining flagged files.
+    pub fn attachment_scanner(mut self, scanner: impl Scanner + 'static) -> Self {
+        self.attachment_scanner = Some(Arc::new(scanner));
+        self
+    }
+
+    /// Translates messages for members with `/translate`, and for those who
+    /// turned on translation into their language.
+    pub fn translator(mut self, translator: impl Translator + 'static) -> Self {
+        self.translator = Some(Arc::new(translator));
+        self
+    }
+
+    /// Registers a message transform under its name, replacing any stage of
+    /// the same name. Rooms opt into it or it joins the default pipeline.
+    pub fn transform(mut self, transform: impl Transform + 'static) -> Self {
+        self.transforms
+            .insert(transform.name().to_owned(), Arc::new(transform));
+        self
+    }
+
+    /// Stages run by rooms whose owner did not choose their own, none by default.
+    pub fn default_transforms<S: Into<String>>(
+        mut self,
+        names: impl IntoIterator<Item = S>,
+    ) -> Self {
+        self.default_transforms = names.into_iter().map(Into::into).collect();
+        self
+    }
+
+    pub fn irc_port(mut self, port: u16) -> Self {
+        self.irc_port = Some(port);
+        self
+    }
+
+    pub fn grpc_port(mut self, port: u16) -> Self {
+        self.grpc_port = Some(port);
+        self
+    }
+
+    pub fn socketio(mut self, enabled: bool) -> Self {
+        self.socketio = enabled;
+        self
+    }
+
+    /// Creates the state and spawns the background tasks and extra
+    /// listeners, so it must be called from within a Tokio runtime.
+    pub fn build(self) -> ChatServer {
+        self.build_with(ShutdownHandle(Arc::new(watch::channel(false).0)))
+    }
+
+    /// What a tenant starts from: this server's settings without its own
+    /// rooms, listeners and admin token, and storage kept under the tenant's
+    /// prefix.
+    fn for_tenant(&self, name: &str) -> Self {
+        let mut builder = self.clone();
+        builder.tenants = Vec::new();
+        builder.hosts = Vec::new();
+        builder.base_path = format!("{}/tenants/{}", self.base_path, name);
+        builder.nested = true;
+        builder.layers = Vec::new();
+        builder.irc_port = None;
+        builder.grpc_port = None;
+        builder.socketio = false;
+        builder.bridges = false;
+        builder.admin_token = None;
+        // Reported to the main server's.
+        builder.sentry = None;
+        builder.plugins_dir = None;
+        builder.scripts_dir = None;
+        builder.storage = Arc::new(tenants::TenantStorage::new(name, self.storage.clone()));
+        builder.schedule_file = self.schedule_file.as_ref().map(|path| {
+            let mut path = path.clone().into_os_string();
+            path.push(format!(".{}", name));
+            PathBuf::from(path)
+        });
+        builder.feeds_file = self.feeds_file.as_ref().map(|path| {
+            let mut path = path.clone().into_os_string();
+            path.push(format!(".{}", name));
+            PathBuf::from(path)
+        });
+        builder.attachment_usage_file = self.attachment_usage_file.as_ref().map(|path| {
+            let mut path = path.clone().into_os_string();
+            path.push(format!(".{}", name));
+            PathBuf::from(path)
+        });
+        builder.recurring_file = self.recurring_file.as_ref().map(|path| {
+            let mut path = path.clone().into_os_string();
+            path.push(format!(".{}", name));
+            PathBuf::from(path)
+        });
+        builder.emotes = emotes::Registry::default();
+        builder.transforms.insert(
+            "emotes".to_owned(),
+            Arc::new(emotes::Emotes(builder.emotes.clone())),
+        );
+        builder
+    }
+
+    fn build_with(mut self, shutdown: ShutdownHandle) -> ChatServer {
+        let tenants = std::mem::take(&mut self.tenants)
+            .into_iter()
+            .map(|(name, configure)| {
+                let tenant = configure(self.for_tenant(&name)).build_with(shutdown.clone());
+                info!("Hosting tenant {} under /tenants/{}", name, name);
+                (name, tenant)
+            })
+            .collect();
+        let plugins = self
+            .plugins_dir
+            .and_then(|dir| match plugins::PluginHost::new(&dir) {
+                Ok(host) => Some(host),
+                Err(err) => {
+                    error!("Failed to start plugins from {}: {}", dir.display(), err);
+                    None
+                }
+            });
+        if let Some(host) = &plugins {
+            self.hooks.push(Arc::new(host.clone()));
+        }
+        let scripts = self.scripts_dir.map(scripting::ScriptHost::new);
+        if let Some(host) = &scripts {
+            self.hooks.push(Arc::new(host.clone()));
+        }
+
+        self.default_transforms.retain(|name| {
+            let known = self.transforms.contains_key(name);
+            if !known {
+                error!("Unknown transform {} in the default pipeline", name);
+            }
+            known
+        });
+
+        let web_push = self.vapid.map(|vapid| Arc::new(WebPush::new(vapid)));
+        if let Some(web_push) = &web_push {
+            self.notifiers.push(web_push.clone());
+        }
+        let mobile_push = (self.fcm.is_some() || self.apns.is_some())
+            .then(|| Arc::new(MobilePush::new(self.fcm, self.apns)));
+        if let Some(mobile_push) = &mobile_push {
+            self.notifiers.push(mobile_push.clone());
+        }
+        // The unsubscribe links point under the base path.
+        if let Some(config) = &mut self.email {
+            config.public_url.push_str(&self.base_path);
+        }
+        let email = self.email.and_then(|config| match Email::new(config) {
+            Ok(email) => Some(Arc::new(email)),
+            Err(err) => {
+                error!("Email notifications disabled: {}", err);
+                None
+            }
+        });
+        if let Some(email) = &email {
+            self.notifiers.push(email.clone());
+        }
+
+        let (webhook_deliveries, deliveries) = mpsc::channel(outgoing_webhooks::QUEUE_LEN);
+        let state = Arc::new(AppState {
+            rooms: Mutex::new(HashMap::new()),
+            owners: Mutex::new(HashMap::new()),
+            webhooks: Mutex::new(HashMap::new()),
+            outgoing_webhooks: Mutex::new(HashMap::new()),
+            slash_commands: slash_commands::SlashCommands::default(),
+            webhook_deliveries,
+            bots: Mutex::new(Vec::new()),
+            matrix: self
+                .bridges
+                .then(matrix::MatrixConfig::from_env)
+                .flatten()
+                .map(matrix::Bridge::start),
+            mqtt: OnceLock::new(),
+            poll_sessions: Mutex::new(HashMap::new()),
+            grpc_sessions: Mutex::new(HashMap::new()),
+            graphql: graphql::schema(),
+            storage: self.storage,
+            auth: self.auth,
+            hooks: self.hooks,
+            admin_token: self.admin_token,
+            plugins,
+            bus: broadcast::channel(events::BUS_CAPACITY).0,
+            transforms: transforms::Pipelines::new(self.transforms, self.default_transforms),
+            emotes: self.emotes,
+            attachments: self.attachments,
+            attachment_policy: self.attachment_policy,
+            attachment_scanner: self.attachment_scanner,
+            translator: self.translator.clone(),
+            attachment_usage: attachments::Usage::open(self.attachment_usage_file),
+            previews: self.link_previews.then(previews::Previews::new),
+            gifs: self.gifs.map(gifs::Gifs::new),
+            catalogs: i18n::Catalogs::load(self.locales_dir.as_deref()),
+            system_messages: system_messages::SystemMessages::new(
+                self.system_messages,
+                self.notice_suppression,
+            ),
+            motd: motd::Motd::new(self.motd, self.motd_file),
+            helper: Helper::new(self.helper_bot.clone().flatten()),
+            base_path: self.base_path.clone(),
+            branding: self.branding,
+            allowed_tags: self.allowed_tags,
+            default_rooms: self.default_rooms,
+            quotas: Quotas::new(self.room_limits),
+            rate_limits: rate_limits::RateLimits::new(self.rate_limits),
+            archive: Mutex::default(),
+            room_templates: Mutex::default(),
+            stats: stats::Stats::default(),
+            history_budget: Arc::new(HistoryBudget::new(self.history_max_bytes)),
+            metrics: metrics::Metrics::new(self.metrics),
+            slow_consumers: self.slow_consumers,
+            backpressure: backpressure::Backpressure::default(),
+            batching: self.batching,
+            channel_sizing: self.channel_sizing,
+            schedule: scheduled::Schedule::open(self.schedule_file),
+            feeds: feeds::Feeds::open(self.feeds_file),
+            recurring: recurring::RecurringMessages::open(self.recurring_file),
+            turn: self.turn,
+            cluster: self.cluster.map(Cluster::new),
+            notifiers: self.notifiers,
+            connections: Default::default(),
+            sessions: Default::default(),
+            read_markers: Default::default(),
+            notification_preferences: Default::default(),
+            digests: Default::default(),
+            highlights: Default::default(),
+            inbox: Default::default(),
+            presence: Default::default(),
+            profiles: Default::default(),
+            web_push,
+            mobile_push,
+            email,
+        });
+
+        if let Some(reporter) = self.sentry.and_then(sentry::init) {
+            shutdown.spawn("sentry", reporter);
+        }
+        shutdown.spawn(
+            "webhook-dispatcher",
+            outgoing_webhooks::dispatcher(deliveries),
+        );
+        shutdown.spawn(
+            "webhook-subscriber",
+            outgoing_webhooks::subscriber(state.clone(), state.bus.subscribe()),
+        );
+        shutdown.spawn(
+            "bots",
+            bots::subscriber(state.clone(), state.bus.subscribe()),
+        );
+        shutdown.spawn("longpoll-sweeper", longpoll::sweeper(state.clone()));
+        shutdown.spawn("scheduler", scheduled::scheduler(state.clone()));
+        shutdown.spawn("feeds", feeds::poller(state.clone()));
+        shutdown.spawn("recurring", recurring::poster(state.clone()));
+        shutdown.spawn("default-rooms", default_rooms::create(state.clone()));
+        if state.cluster.is_some() {
+            shutdown.spawn("gossip", gossip::gossiper(state.clone()));
+        }
+        if !state.notifiers.is_empty() {
+            shutdown.spawn("digests", digests::sender(state.clone()));
+        }
+        if let Some(email) = &state.email {
+            shutdown.spawn("email", email::sender(email.clone()));
+        }
+        if state.motd.is_watched() {
+            shutdown.spawn("motd-watch", motd::watch(state.clone()));
+        }
+        if state.matrix.is_some() {
+            shutdown.spawn(
+                "matrix",
+                matrix::subscriber(state.clone(), state.bus.subscribe()),
+            );
+        }
+        if self.bridges {
+            if let Some(bridge) = mqtt::MqttBridge::from_env(&state) {
+                let _ = state.mqtt.set(bridge);
+                shutdown.spawn(
+                    "mqtt",
+                    mqtt::subscriber(state.clone(), state.bus.subscribe()),
+                );
+            }
+        }
+        if self.dice_bot {
+            bots::register_bot(&state, None, bots::dice::DiceBot);
+        }
+        let templates = room_templates::TemplateBot {
+            state: Arc::downgrade(&state),
+        };
+        bots::register_bot(&state, None, templates);
+        let slash_commands = slash_commands::SlashCommandBot {
+            state: Arc::downgrade(&state),
+        };
+        bots::register_bot(&state, None, slash_commands);
+        let reminders = reminders::RemindBot {
+            state: Arc::downgrade(&state),
+        };
+        bots::register_bot(&state, None, reminders);
+        if self.helper_bot.is_some() {
+            let helper = HelperBot {
+                state: Arc::downgrade(&state),
+            };
+            bots::register_bot(&state, None, helper);
+        }
+        if state.translator.is_some() {
+            let translate = translation::TranslateBot {
+                state: Arc::downgrade(&state),
+            };
+            bots::register_bot(&state, None, translate);
+        }
+        if let Some(config) = self.assistant.clone() {
+            let assistant = AssistantBot::new(config, Arc::downgrade(&state));
+            bots::register_bot(&state, None, assistant);
+        }
+        if let Some(host) = &state.plugins {
+            bots::register_bot(&state, None, host.clone());
+        }
+        if let Some(host) = scripts {
+            host.attach(&state);
+            bots::register_bot(&state, None, host.clone());
+            shutdown.spawn("scripts-watch", host.watch());
+        }
+
+        if let Some(port) = self.irc_port {
+            let addr = SocketAddr::from(([0, 0, 0, 0], port));
+            shutdown.spawn("irc", irc::serve(addr, state.clone()));
+        }
+        if let Some(port) = self.grpc_port {
+            let addr = SocketAddr::from(([0, 0, 0, 0], port));
+            shutdown.spawn("grpc", grpc::serve(addr, state.clone()));
+        }
+
+        let socketio = self.socketio.then(|| socketio::layer(&state));
+        ChatServer {
+            state,
+            layers: self.layers,
+            socketio,
+            shutdown,
+            tenants,
+            hosts: self.hosts,
+            web_client: self.web_client,
+            base_path: if self.nested {
+                String::new()
+            } else {
+                self.base_path
+            },
+            cors: match self.cors_origins {
+                Some(origins) => CorsLayer::new().allow_origin(AllowOrigin::list(origins)),
+                None => CorsLayer::new().allow_origin(Any),
+            }
+            .allow_methods(vec![Method::GET, Method::POST, Method::PUT, Method::DELETE])
+            .allow_headers(Any),
+        }
+    }
+}
+
+/// Stops the HTTP server gracefully along with the background tasks and
+/// the IRC and gRPC listeners.
+#[derive(Clone)]
+pub struct ShutdownHandle(Arc<watch::Sender<bool>>);
+
+impl ShutdownHandle {
+    pub fn shutdown(&self) {
+        self.0.send_replace(true);
+    }
+
+    /// Resolves once [`shutdown`](Self::shutdown) has been called.
+    pub async fn wait(&self) {
+        let _ = self.0.subscribe().wait_for(|stopped| *stopped).await;
+    }
+
+    fn spawn(&self, name: &'static str, task: impl Future<Output = ()> + Send + 'static) {
+        let shutdown = self.clone();
+        tasks::spawn(name, async move {
+            tokio::select! {
+                _ = task => {}
+                _ = shutdown.wait() => {}
+            }
+        });
+    }
+}
+
+pub struct ChatServer {
+    state: Arc<AppState>,
+    layers: Vec<RouterLayer>,
+    socketio: Option<SocketIoLayer>,
+    shutdown: ShutdownHandle,
+    tenants: Vec<(String, ChatServer)>,
+    hosts: Vec<String>,
+    web_client: bool,
+    /// Where [`router`](ChatServer::router) mounts the routes, empty for
+    /// tenants.
+    base_path: String,
+    cors: CorsLayer,
+}
+
+impl ChatServer {
+    pub fn builder() -> ChatServerBuilder {
+        let emotes = emotes::Registry::default();
+        let builtins: [Arc<dyn Transform>; 5] = [
+            Arc::new(builtin::Sanitize),
+            Arc::new(builtin::Profanity::default()),
+            Arc::new(builtin::Emoji),
+            Arc::new(builtin::Mentions),
+            Arc::new(emotes::Emotes(emotes.clone())),
+        ];
+        ChatServerBuilder {
+            storage: Arc::new(NoStorage),
+            auth: Arc::new(AllowAll),
+            hooks: Vec::new(),
+            layers: Vec::new(),
+            irc_port: None,
+            grpc_port: None,
+            socketio: false,
+            bridges: false,
+            admin_token: None,
+            plugins_dir: None,
+            scripts_dir: None,
+            schedule_file: None,
+            feeds_file: None,
+            attachment_usage_file: None,
+            recurring_file: None,
+            motd: None,
+            motd_file: None,
+            helper_bot: None,
+            dice_bot: false,
+            locales_dir: None,
+            system_messages: Templates::default(),
+            notice_suppression: Suppression::default(),
+            allowed_tags: None,
+            default_rooms: Vec::new(),
+            room_limits: Limits::default(),
+            rate_limits: rate_limits::Limits::default(),
+            metrics: MetricsConfig::default(),
+            history_max_bytes: history_budget::DEFAULT_MAX_BYTES,
+            slow_consumers: SlowConsumers::default(),
+            batching: Batching::default(),
+            channel_sizing: channels::Sizing::default(),
+            transforms: builtins
+                .into_iter()
+                .map(|transform| (transform.name().to_owned(), transform))
+                .collect(),
+            default_transforms: Vec::new(),
+            emotes,
+            attachments: None,
+            attachment_policy: AttachmentPolicy::default(),
+            attachment_scanner: None,
+            translator: None,
+            link_previews: false,
+            gifs: None,
+            turn: None,
+            assistant: None,
+            cluster: None,
+            sentry: None,
+            vapid: None,
+            fcm: None,
+            apns: None,
+            email: None,
+            notifiers: Vec::new(),
+            tenants: Vec::new(),
+            hosts: Vec::new(),
+            cors_origins: None,
+            branding: None,
+            web_client: false,
+            base_path: String::new(),
+            nested: false,
+        }
+    }
+
+    /// All HTTP and WebSocket routes, those of tenants for their hosts
+    /// included. Merge it into your own `Router`; the Socket.IO endpoint is a
+    /// layer, see [`socketio_layer`](Self::socketio_layer).
+    pub fn router(&self) -> Router {
+        self.route_hosts(self.mounted(self.routes()), ChatServer::routes)
+    }
+
+    /// `app` under the base path.
+    fn mounted(&self, app: Router) -> Router {
+        if self.base_path.is_empty() {
+            app
+        } else {
+            Router::new().nest(&self.base_path, app)
+        }
+    }
+
+    /// Sends the requests for a tenant's hosts to what `tenant_app` makes of
+    /// the tenant, and the others to `app`.
+    fn route_hosts(&self, app: Router, tenant_app: impl Fn(&ChatServer) -> Router) -> Router {
+        let hosts = self
+            .tenants
+            .iter()
+            .flat_map(|(_, tenant)| {
+                // At the root and where its URLs point, under the main server's.
+                let app = tenant_app(tenant);
+                let app = app.clone().nest(&tenant.state.base_path, app);
+                tenant
+                    .hosts
+                    .iter()
+                    .map(move |host| (host.clone(), app.clone()))
+            })
+            .collect::<HashMap<_, _>>();
+        if hosts.is_empty() {
+            return app;
+        }
+        tenants::route_hosts(app, Arc::new(hosts))
+    }
+
+    fn routes(&self) -> Router {
+        let router = Router::new()
+            .route("/ws", get(handler))
+            .route(
+                "/rooms",
+                get(directory::get_rooms).post(owners::create_room),
+            )
+            .route("/rooms/search", get(directory::search))
+            .route("/rooms/trending", get(directory::get_trending))
+            .route("/directory", get(default_rooms::get_directory))
+            .route("/rooms/:name/claim", post(owners::claim_room))
+            .route("/rooms/:name/events", get(sse::room_events))
+            .route("/rooms/:name/poll", post(longpoll::poll))
+            .route("/rooms/:name/messages", post(longpoll::send_message))
+            .route("/rooms/:name/leave", post(longpoll::leave))
+            .route(
+                "/rooms/:name/hooks",
+                get(webhooks::list_webhooks).post(webhooks::create_webhook),
+            )
+            .route(
+                "/rooms/:name/hooks/:token",
+                delete(webhooks::revoke_webhook),
+            )
+            .route("/hooks/:token", post(webhooks::post_webhook))
+            .route("/hooks/:token/scheduled", post(scheduled::create_scheduled))
+            .route(
+                "/hooks/:token/scheduled/:id",
+                delete(scheduled::cancel_scheduled),
+            )
+            .route(
+                "/rooms/:name/outgoing-hooks",
+                get(outgoing_webhooks::list_outgoing_webhooks)
+                    .post(outgoing_webhooks::create_outgoing_webhook),
+            )
+            .route(
+                "/rooms/:name/outgoing-hooks/:id",
+                delete(outgoing_webhooks::delete_outgoing_webhook),
+            )
+            .route(
+                "/rooms/:name/feeds",
+                get(feeds::list_feeds).post(feeds::subscribe),
+            )
+            .route("/rooms/:name/feeds/:id", delete(feeds::unsubscribe))
+            .route(
+                "/rooms/:name/recurring",
+                get(recurring::list_recurring).post(recurring::create_recurring),
+            )
+            .route(
+                "/rooms/:name/recurring/:id",
+                put(recurring::update_recurring).delete(recurring::delete_recurring),
+            )
+            .route("/rooms/:name/commands", get(slash_commands::list_commands))
+            .route(
+                "/rooms/:name/commands/:command",
+                put(slash_commands::set_command).delete(slash_commands::delete_command),
+            )
+            .route(
+                "/rooms/:name/matrix",
+                put(matrix::link_room).delete(matrix::unlink_room),
+            )
+            .route(
+                "/_matrix/app/v1/transactions/:txn_id",
+                put(matrix::transactions),
+            )
+            .route(
+                "/rooms/:name/transforms",
+                get(transforms::get_transforms)
+                    .put(transforms::set_transforms)
+                    .delete(transforms::reset_transforms),
+            )
+            .route(
+                "/rooms/:name/attachments",
+                post(attachments::upload).layer(DefaultBodyLimit::max(
+                    self.state.attachment_policy.max_bytes + (64 << 10),
+                )),
+            )
+            .route("/attachments/:id", get(attachments::download))
+            .route("/rooms/:name/emotes", get(emotes::list_emotes))
+            .route(
+                "/rooms/:name/emotes/:emote",
+                put(emotes::put_emote)
+                    .delete(emotes::delete_emote)
+                    .layer(DefaultBodyLimit::max(emotes::MAX_BYTES + (64 << 10))),
+            )
+            .route("/gifs/search", get(gifs::search))
+            .route("/rooms/:name/voice", get(voice::roster))
+            .route(
+                "/rooms/:name/motd",
+                get(motd::get_motd)
+                    .put(motd::set_motd)
+                    .delete(motd::reset_motd),
+            )
+            .route(
+                "/rooms/:name/system-messages",
+                get(system_messages::get_system_messages)
+                    .put(system_messages::set_system_messages)
+                    .delete(system_messages::reset_system_messages),
+            )
+            .route(
+                "/rooms/:name/backpressure",
+                get(backpressure::get_backpressure)
+                    .put(backpressure::set_backpressure)
+                    .delete(backpressure::reset_backpressure),
+            )
+            .route(
+                "/rooms/:name/helper",
+                get(helper::get_helper)
+                    .put(helper::set_helper)
+                    .delete(helper::reset_helper),
+            )
+            .route("/rooms/:name/tags", get(tags::get_tags).put(tags::set_tags))
+            .route("/rooms/:name/stats", get(stats::get_stats))
+            .route("/metrics", get(metrics::get_metrics))
+            .route("/admin/connections/:id", get(connections::get_connection))
+            .route("/admin/tasks", get(tasks::list_tasks))
+            .route(
+                "/rooms/:name/archive",
+                get(archive::get_archive)
+                    .post(archive::archive_room)
+                    .delete(archive::unarchive_room),
+            )
+            .route("/rooms/:name/polls", get(polls::list_polls))
+            .route("/rooms/:name/polls/:id", get(polls::get_poll))
+            .route("/rtc/credentials", get(turn::credentials))
+            .route("/push/key", get(webpush::public_key))
+            .route("/push/subscribe", post(webpush::subscribe))
+            .route("/push/unsubscribe", post(webpush::unsubscribe))
+            .route("/push/devices", post(mobile::register))
+            .route("/push/devices/remove", post(mobile::unregister))
+            .route(
+                "/rooms/:name/notifications",
+                get(preferences::get_level).put(preferences::set_level),
+            )
+            .route(
+                "/rooms/:name/read",
+                get(read_state::get_read).put(read_state::set_read),
+            )
+            .route(
+                "/rooms/:name/highlights",
+                get(highlights::get_highlights).put(highlights::set_highlights),
+            )
+            .route("/users/:name/inbox/:id", delete(inbox::acknowledge_message))
+            .route(
+                "/users/:name/digest",
+                get(digests::get_digest).put(digests::set_digest),
+            )
+            .route(
+                "/users/:name/profile",
+                get(profiles::get_profile).put(profiles::set_profile),
+            )
+            .route(
+                "/users/:name/quiet-hours",
+                get(preferences::get_quiet_hours).put(preferences::set_quiet_hours),
+            )
+            .route(
+                "/users/:name/presence",
+                get(presence::get_presence).put(presence::set_presence),
+            )
+            .route("/notifications/email", post(email::register))
+            .route("/notifications/email/confirm", get(email::confirm))
+            .route(
+                "/notifications/email/unsubscribe",
+                get(email::unsubscribe).post(email::unsubscribe),
+            )
+            .route("/graphql", get(graphql::graphiql).post(graphql::execute))
+            .route("/graphql/ws", get(graphql::subscriptions))
+            .route("/_matrix/app/v1/users/:user_id", get(matrix::query_user))
+            .route("/_matrix/app/v1/rooms/:alias", get(matrix::query_room))
+            .route(
+                "/admin/announcements",
+                post(announcements::post_announcement),
+            )
+            .route(
+                "/admin/rooms/:name/snapshot",
+                get(snapshot::take_snapshot).put(snapshot::restore_snapshot),
+            )
+            .route("/admin/templates", get(room_templates::list_templates))
+            .route(
+                "/admin/templates/:name",
+                put(room_templates::put_template).delete(room_templates::delete_template),
+            )
+            .route(
+                "/admin/templates/:name/rooms",
+                post(room_templates::create_room),
+            )
+            .route("/cluster/gossip", post(gossip::receive))
+            .route("/cluster/rooms/:name", put(gossip::take_over))
+            .route("/cluster/affinity/:token", get(cluster::resolve_affinity))
+            .route("/admin/plugins", get(plugins::list_plugins))
+            .route("/admin/plugins/:name", delete(plugins::unload_plugin))
+            .route("/admin/plugins/:name/reload", post(plugins::reload_plugin))
+            .route_layer(middleware::from_fn_with_state(
+                self.state.clone(),
+                cluster::route,
+            ))
+            .with_state(self.state.clone());
+        let router = if self.web_client {
+            router
+                .route("/app", get(web_client::index))
+                .route("/app/", get(web_client::index))
+                .route("/app/*path", get(web_client::asset))
+        } else {
+            router
+        };
+        let router = self.tenants.iter().fold(router, |router, (name, tenant)| {
+            router.nest(&format!("/tenants/{}", name), tenant.routes())
+        });
+        self.layers
+            .iter()
+            .fold(router, |router, layer| layer(router))
+    }
+
+    /// The Socket.IO layer when enabled. It answers `/socket.io` itself, so
+    /// apply it to the outermost router after merging.
+    pub fn socketio_layer(&self) -> Option<SocketIoLayer> {
+        self.socketio.clone()
+    }
+
+    /// Subscribes to joins, leaves, messages and room lifecycle events of
+    /// every transport. A receiver lagging far behind skips events.
+    pub fn subscribe(&self) -> broadcast::Receiver<RoomEvent> {
+        self.state.bus.subscribe()
+    }
+
+    /// Sends a notice to every room, or with `direct` to every member's
+    /// connection, e.g. before shutting down. Returns how many were reached.
+    pub fn announce(&self, text: &str, direct: bool) -> usize {
+        announcements::announce(&self.state, text, direct)
+    }
+
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// What [`serve`](Self::serve) and [`serve_unix`](Self::serve_unix) serve.
+    fn app(&self) -> Router {
+        let app = |server: &ChatServer| {
+            Router::new()
+                .route("/", get(|| async { "Hello World!" }))
+                .merge(server.routes())
+        };
+        // Socket.IO answers its path itself, so it sees the base path.
+        let main = match self.socketio_layer() {
+            Some(layer) => self.mounted(app(self)).layer(layer),
+            None => self.mounted(app(self)),
+        };
+        // Each host with its own CORS policy, so they are routed outermost.
+        self.route_hosts(main.layer(self.cors.clone()), |tenant| {
+            app(tenant).layer(tenant.cors.clone())
+        })
+    }
+
+    /// Serves the routes until shut down, allowing cross-origin requests from
+    /// any origin unless [`cors_origins`](ChatServerBuilder::cors_origins) says otherwise.
+    pub async fn serve(self, listener: TcpListener) -> std::io::Result<()> {
+        self.serve_all(vec![listener]).await
+    }
+
+    /// Like [`serve`](Self::serve) but on each of `listeners`, e.g. a public
+    /// and an internal address, all with the same routes and state. Stops
+    /// serving on all of them if one fails.
+    pub async fn serve_all(self, listeners: Vec<TcpListener>) -> std::io::Result<()> {
+        let app = self.app();
+        for listener in &listeners {
+            info!("Hosted on {}", listener.local_addr()?);
+        }
+        self.ready();
+        let served = futures::future::try_join_all(listeners.into_iter().map(|listener| {
+            let shutdown = self.shutdown.clone();
+            axum::serve(
+                listener,
+                app.clone()
+                    .into_make_service_with_connect_info::<SocketAddr>(),
+            )
+            .with_graceful_shutdown(async move { shutdown.wait().await })
+            .into_future()
+        }))
+        .await;
+        systemd::notify("STOPPING=1");
+        self.shutdown.shutdown();
+        served.map(|_| ())
+    }
+
+    /// Tells systemd, when run by it, that the server accepts connections,
+    /// and keeps its watchdog fed until shut down.
+    fn ready(&self) {
+        systemd::notify("READY=1");
+        if let Some(interval) = systemd::watchdog_interval() {
+            self.shutdown
+                .spawn("systemd-watchdog", systemd::watchdog(interval));
+        }
+    }
+
+    /// Like [`serve`](Self::serve) but on a Unix socket, e.g. behind a proxy
+    /// on the same machine. Clients have no address there, so room creation
+    /// quotas count members only.
+    pub async fn serve_unix(self, listener: UnixListener) -> std::io::Result<()> {
+        let app = self.app();
+        if let Some(path) = listener.local_addr()?.as_pathname() {
+            info!("Hosted on {}", path.display());
+        }
+        self.ready();
+        loop {
+            let stream = tokio::select! {
+                accepted = listener.accept() => match accepted {
+                    Ok((stream, _)) => stream,
+                    Err(err) => {
+                        error!("Failed to accept a connection: {}", err);
+                        continue;
+                    }
+                },
+                _ = self.shutdown.wait() => break,
+            };
+            let service = TowerToHyperService::new(app.clone());
+            let shutdown = self.shutdown.clone();
+            tasks::spawn("http-connection", async move {
+                let builder = auto::Builder::new(TokioExecutor::new());
+                let connection =
+                    builder.serve_connection_with_upgrades(TokioIo::new(stream), service);
+                tokio::pin!(connection);
+                let result = tokio::select! {
+                    result = connection.as_mut() => result,
+                    _ = shutdown.wait() => {
+                        connection.as_mut().graceful_shutdown();
+                        connection.await
+                    }
+                };
+                if let Err(err) = result {
+                    info!("Connection on the Unix socket failed: {}", err);
+                }
+            });
+        }
+        systemd::notify("STOPPING=1");
+        self.shutdown.shutdown();
+        Ok(())
+    }
+}
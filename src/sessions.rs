@@ -0,0 +1,167 @@
+//! Resumable WebSocket sessions.
+//!
+//! Every WebSocket join is answered with `{"type":"session","token":...}`,
+//! along with the id of the `connection`, the server's `branding` if it has
+//! any and, in cluster mode, the `affinity` token of the node owning the
+//! room.
+//! A connection that finds its username taken in a room can present that
+//! token as `resume` in its connect payload: the connection holding the
+//! membership, typically one whose network dropped before the server
+//! noticed, is then closed and the membership moves to the new one without
+//! leave and join notices.
+//!
+//! The token also proves the name to REST endpoints acting for a member,
+//! such as push subscriptions and profiles, sent along as
+//! `Authorization: Bearer <token>` with the room it was issued in. The
+//! tokens of a member's last sessions are remembered for a while after they
+//! end, so that a `resume` token given when the name is free shows the
+//! member coming back rather than someone else taking the name, which is
+//! what their [inbox](crate::inbox) is delivered on.
+
+use axum::http::HeaderMap;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use subtle::ConstantTimeEq;
+use tokio::sync::Notify;
+
+use crate::owners::{bearer, generate_token};
+use crate::AppState;
+
+/// Ended sessions remembered per member, the latest last.
+const MAX_ENDED: usize = 4;
+/// How long the token of an ended session shows its member coming back.
+const ENDED_TTL: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+/// How often the tokens of sessions ended too long ago are forgotten.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+fn same(a: &str, b: &str) -> bool {
+    a.as_bytes().ct_eq(b.as_bytes()).into()
+}
+
+/// One connection's hold on a membership.
+pub struct Session {
+    pub token: String,
+    taken_over: Arc<Notify>,
+}
+
+impl Session {
+    fn new() -> Self {
+        Self {
+            token: generate_token(),
+            taken_over: Arc::new(Notify::new()),
+        }
+    }
+
+    fn handle(&self) -> Self {
+        Self {
+            token: self.token.clone(),
+            taken_over: self.taken_over.clone(),
+        }
+    }
+
+    /// Completes once another connection has taken the membership over.
+    pub async fn taken_over(&self) {
+        self.taken_over.notified().await
+    }
+}
+
+/// The tokens of the members' ended sessions, by username.
+#[derive(Default)]
+struct Ended {
+    tokens: HashMap<String, VecDeque<(String, Instant)>>,
+    pruned: Option<Instant>,
+}
+
+#[derive(Default)]
+pub struct Sessions {
+    /// By room and username.
+    sessions: Mutex<HashMap<(String, String), Session>>,
+    ended: Mutex<Ended>,
+}
+
+impl Sessions {
+    /// Starts the session of a connection that just joined `room`.
+    pub fn open(&self, room: &str, username: &str) -> Session {
+        let session = Session::new();
+        let handle = session.handle();
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.insert((room.to_owned(), username.to_owned()), session);
+        handle
+    }
+
+    /// Hands the membership to a new connection presenting `token`, with a
+    /// fresh token, and tells the connection holding it to close.
+    pub fn take_over(&self, room: &str, username: &str, token: &str) -> Option<Session> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let key = (room.to_owned(), username.to_owned());
+        let session = sessions
+            .get_mut(&key)
+            .filter(|session| same(&session.token, token))?;
+        session.taken_over.notify_one();
+        *session = Session::new();
+        Some(session.handle())
+    }
+
+    /// Whether `token` is that of the session of `username` in `room`.
+    pub fn verify(&self, room: &str, username: &str, token: &str) -> bool {
+        let sessions = self.sessions.lock().unwrap();
+        sessions
+            .get(&(room.to_owned(), username.to_owned()))
+            .is_some_and(|session| same(&session.token, token))
+    }
+
+    /// Ends the session holding `token`. `false` if it was taken over, in
+    /// which case the membership is no longer the connection's to leave.
+    pub fn close(&self, room: &str, username: &str, token: &str) -> bool {
+        let mut sessions = self.sessions.lock().unwrap();
+        let key = (room.to_owned(), username.to_owned());
+        if sessions
+            .get(&key)
+            .is_some_and(|session| same(&session.token, token))
+        {
+            sessions.remove(&key);
+            drop(sessions);
+            self.end(username, token);
+            true
+        } else {
+            false
+        }
+    }
+
+    fn end(&self, username: &str, token: &str) {
+        let now = Instant::now();
+        let mut ended = self.ended.lock().unwrap();
+        if ended
+            .pruned
+            .is_none_or(|pruned| now.duration_since(pruned) >= PRUNE_INTERVAL)
+        {
+            ended.tokens.retain(|_, tokens| {
+                tokens.retain(|(_, at)| now.duration_since(*at) < ENDED_TTL);
+                !tokens.is_empty()
+            });
+            ended.pruned = Some(now);
+        }
+        let tokens = ended.tokens.entry(username.to_owned()).or_default();
+        tokens.push_back((token.to_owned(), now));
+        if tokens.len() > MAX_ENDED {
+            tokens.pop_front();
+        }
+    }
+
+    /// Whether `token` is that of a recently ended session of `username`.
+    pub fn returning(&self, username: &str, token: &str) -> bool {
+        let ended = self.ended.lock().unwrap();
+        ended.tokens.get(username).is_some_and(|tokens| {
+            tokens
+                .iter()
+                .any(|(ended, at)| at.elapsed() < ENDED_TTL && same(ended, token))
+        })
+    }
+}
+
+/// Whether the request carries the session token of `username` in `room`,
+/// which endpoints acting for a member take as proof that the name is theirs.
+pub fn is_session(state: &AppState, room: &str, username: &str, headers: &HeaderMap) -> bool {
+    bearer(headers).is_some_and(|token| state.sessions.verify(room, username, token))
+}
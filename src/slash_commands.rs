@@ -0,0 +1,315 @@
+//! Slash commands room owners bind to their own URLs, as Slack's are: a
+//! member's `/weather paris` POSTs the command to its URL, signed like an
+//! [outgoing webhook](crate::outgoing_webhooks), and the response is posted
+//! back to the room as the command, e.g. `weather`.
+//!
+//! The request body is JSON:
+//! `{"command": "/weather", "text": "paris", "room": "...", "username": "...", "timestamp": ...}`.
+//! The response is plain text, or JSON `{"text": "..."}` with an optional
+//! `"response_type": "ephemeral"` to answer the member alone. An empty
+//! response posts nothing.
+
+use async_trait::async_trait;
+use axum::extract::{Path, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::Json;
+use log::warn;
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex, OnceLock, Weak};
+use std::time::Duration;
+
+use crate::bots::{Bot, BotContext};
+use crate::events::unix_timestamp;
+use crate::outgoing_webhooks::sign;
+use crate::owners::{forbidden, generate_token, is_owner};
+use crate::room_names::RoomName;
+use crate::{previews, tasks, ApiResponse, AppState};
+
+/// How long a command's URL has to respond.
+const TIMEOUT: Duration = Duration::from_secs(3);
+const MAX_COMMANDS: usize = 50;
+const MAX_NAME_LEN: usize = 32;
+const MAX_DESCRIPTION_LEN: usize = 200;
+/// Longest response posted, in characters, the rest is cut.
+const MAX_RESPONSE_LEN: usize = 4000;
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SlashCommand {
+    pub url: String,
+    pub secret: String,
+    pub description: Option<String>,
+}
+
+/// The commands of each room, by name without the `/`.
+#[derive(Default)]
+pub struct SlashCommands {
+    rooms: Mutex<HashMap<String, BTreeMap<String, SlashCommand>>>,
+}
+
+impl SlashCommands {
+    pub fn get(&self, room: &str, command: &str) -> Option<SlashCommand> {
+        let rooms = self.rooms.lock().unwrap();
+        rooms.get(room)?.get(command).cloned()
+    }
+
+    /// The names and descriptions of the commands of `room`, sorted.
+    pub fn list(&self, room: &str) -> Vec<(String, Option<String>)> {
+        let rooms = self.rooms.lock().unwrap();
+        rooms.get(room).map_or_else(Vec::new, |commands| {
+            commands
+                .iter()
+                .map(|(name, command)| (name.clone(), command.description.clone()))
+                .collect()
+        })
+    }
+
+    /// Removes the commands of `room` and returns them, to hand them over.
+    pub fn take_room(&self, room: &str) -> BTreeMap<String, SlashCommand> {
+        self.rooms.lock().unwrap().remove(room).unwrap_or_default()
+    }
+
+    /// Adds the `commands` of `room` handed over.
+    pub fn restore_room(&self, room: &str, commands: BTreeMap<String, SlashCommand>) {
+        if commands.is_empty() {
+            return;
+        }
+        let mut rooms = self.rooms.lock().unwrap();
+        rooms.entry(room.to_owned()).or_default().extend(commands);
+    }
+}
+
+#[derive(Deserialize)]
+struct Response {
+    text: String,
+    #[serde(default)]
+    response_type: Option<String>,
+}
+
+fn client() -> &'static reqwest::Client {
+    static CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+    CLIENT.get_or_init(|| previews::public_client(TIMEOUT))
+}
+
+/// POSTs `/command args` of `from` to the command's URL and returns the
+/// response, and whether it is for them alone.
+async fn run(
+    room: &str,
+    name: &str,
+    command: &SlashCommand,
+    from: &str,
+    args: &str,
+) -> Result<Option<(String, bool)>, String> {
+    let timestamp = unix_timestamp();
+    let body = json!({
+        "command": format!("/{}", name),
+        "text": args,
+        "room": room,
+        "username": from,
+        "timestamp": timestamp,
+    })
+    .to_string();
+    let res = client()
+        .post(&command.url)
+        .header("content-type", "application/json")
+        .header("x-chatr-event", "command")
+        .header("x-chatr-timestamp", timestamp)
+        .header("x-chatr-signature", sign(&command.secret, timestamp, &body))
+        .body(body)
+        .send()
+        .await
+        .map_err(|err| err.to_string())?;
+    if !res.status().is_success() {
+        return Err(format!("answered {}", res.status()));
+    }
+    let json = res
+        .headers()
+        .get("content-type")
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("application/json"));
+    let body = res.text().await.map_err(|err| err.to_string())?;
+    let (text, ephemeral) = if json {
+        let response = serde_json::from_str::<Response>(&body).map_err(|err| err.to_string())?;
+        let ephemeral = response.response_type.as_deref() == Some("ephemeral");
+        (response.text, ephemeral)
+    } else {
+        (body, false)
+    };
+    let text = text.trim();
+    if text.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some((
+        text.chars().take(MAX_RESPONSE_LEN).collect(),
+        ephemeral,
+    )))
+}
+
+/// Runs the commands of the room, each on a task of its own so that the
+/// member's message is not held up by the URL.
+pub struct SlashCommandBot {
+    pub state: Weak<AppState>,
+}
+
+#[async_trait]
+impl Bot for SlashCommandBot {
+    fn name(&self) -> &str {
+        "commands"
+    }
+
+    async fn on_command(&self, ctx: &BotContext, from: &str, command: &str, args: &str) -> bool {
+        let Some(state) = self.state.upgrade() else {
+            return false;
+        };
+        let Some(slash_command) = state.slash_commands.get(&ctx.room, command) else {
+            return false;
+        };
+        let (ctx, name, from, args) = (
+            ctx.clone(),
+            command.to_owned(),
+            from.to_owned(),
+            args.to_owned(),
+        );
+        tasks::spawn("slash-command", async move {
+            match run(&ctx.room, &name, &slash_command, &from, &args).await {
+                Ok(Some((text, true))) => {
+                    ctx.dm_as(&name, &from, &text);
+                }
+                Ok(Some((text, false))) => ctx.reply_as(&name, &text),
+                Ok(None) => {}
+                Err(err) => {
+                    warn!("Command /{} of {} failed: {}", name, ctx.room, err);
+                    ctx.dm_as(&name, &from, &format!("/{} failed, try again later.", name));
+                }
+            }
+        });
+        true
+    }
+}
+
+#[derive(Deserialize)]
+pub struct SetSlashCommand {
+    url: String,
+    #[serde(default)]
+    description: Option<String>,
+}
+
+/// `PUT /rooms/:name/commands/:command`, binds `/command` to a URL and
+/// returns the secret its requests are signed with.
+pub async fn set_command(
+    Path((room, name)): Path<(RoomName, String)>,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(body): Json<SetSlashCommand>,
+) -> ApiResponse {
+    if !is_owner(&state, &room, &headers) {
+        return forbidden();
+    }
+    let bad_request = |status: String| (StatusCode::BAD_REQUEST, Json(json!({ "status": status })));
+    let valid_name = !name.is_empty()
+        && name.len() <= MAX_NAME_LEN
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-');
+    if !valid_name {
+        return bad_request(format!(
+            "Commands are 1 to {} lowercase letters, digits or dashes.",
+            MAX_NAME_LEN
+        ));
+    }
+    if !Url::parse(&body.url).is_ok_and(|url| previews::allowed(&url)) {
+        return bad_request("Command url must be a public http(s) URL.".to_owned());
+    }
+    let description = body
+        .description
+        .map(|description| description.trim().to_owned())
+        .filter(|description| !description.is_empty());
+    if description
+        .as_ref()
+        .is_some_and(|description| description.chars().count() > MAX_DESCRIPTION_LEN)
+    {
+        return bad_request(format!(
+            "Descriptions are at most {} characters.",
+            MAX_DESCRIPTION_LEN
+        ));
+    }
+    let mut rooms = state.slash_commands.rooms.lock().unwrap();
+    let commands = rooms.entry(room.into()).or_default();
+    if commands.len() >= MAX_COMMANDS && !commands.contains_key(&name) {
+        return bad_request(format!("At most {} commands per room.", MAX_COMMANDS));
+    }
+    let secret = generate_token();
+    commands.insert(
+        name,
+        SlashCommand {
+            url: body.url,
+            secret: secret.clone(),
+            description,
+        },
+    );
+    (
+        StatusCode::OK,
+        Json(json!({ "status": "Success!", "secret": secret })),
+    )
+}
+
+#[derive(Serialize)]
+struct Listed<'a> {
+    command: String,
+    url: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    description: Option<&'a str>,
+}
+
+/// `GET /rooms/:name/commands`
+pub async fn list_commands(
+    Path(room): Path<RoomName>,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> ApiResponse {
+    if !is_owner(&state, &room, &headers) {
+        return forbidden();
+    }
+    let rooms = state.slash_commands.rooms.lock().unwrap();
+    let commands = rooms
+        .get(room.as_str())
+        .into_iter()
+        .flatten()
+        .map(|(name, command)| Listed {
+            command: format!("/{}", name),
+            url: &command.url,
+            description: command.description.as_deref(),
+        })
+        .collect::<Vec<_>>();
+    (
+        StatusCode::OK,
+        Json(json!({ "status": "Success!", "commands": commands })),
+    )
+}
+
+/// `DELETE /rooms/:name/commands/:command`
+pub async fn delete_command(
+    Path((room, name)): Path<(RoomName, String)>,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> ApiResponse {
+    if !is_owner(&state, &room, &headers) {
+        return forbidden();
+    }
+    let mut rooms = state.slash_commands.rooms.lock().unwrap();
+    let removed = rooms
+        .get_mut(room.as_str())
+        .and_then(|commands| commands.remove(&name));
+    if rooms.get(room.as_str()).is_some_and(BTreeMap::is_empty) {
+        rooms.remove(room.as_str());
+    }
+    match removed {
+        Some(_) => (StatusCode::OK, Json(json!({ "status": "Success!" }))),
+        None => (
+            StatusCode::NOT_FOUND,
+            Json(json!({ "status": "Command not found." })),
+        ),
+    }
+}
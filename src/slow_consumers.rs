@@ -0,0 +1,182 @@
+//! Members whose connections fall behind their room's broadcast, as a slow
+//! network or client does. A member further behind than the channel holds
+//! skips the oldest events.
+//!
+//! Once a member's lag, the events waiting for it, reaches `SLOW_CONSUMER_LAG`
+//! or half the capacity of the room's [channel], whichever is less, the
+//! `SLOW_CONSUMERS` policy applies:
+//!
+//! - `warn`, the default, sends the member a `slow` event with its `lag`,
+//!   once until it catches up, and another with the events it `skipped`
+//!   whenever it skips some.
+//! - `shed` also drops the events the member can do without, join and leave
+//!   notices and presence changes, while it is behind.
+//! - `disconnect` ends the member's subscription, for WebSocket clients with
+//!   close code 4008, for gRPC ones with `RESOURCE_EXHAUSTED`. IRC, Socket.IO
+//!   and long polling members, whose connections may hold other rooms, are
+//!   shed instead.
+//!
+//! [channel]: crate::channels
+
+use log::{info, warn};
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::channels::Receiver;
+use crate::events::ChatEvent;
+
+/// The WebSocket close code for members that fell too far behind.
+pub const TOO_SLOW: u16 = 4008;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Policy {
+    #[default]
+    Warn,
+    Shed,
+    Disconnect,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct SlowConsumers {
+    pub policy: Policy,
+    /// The lag, in events, from which a member is slow, at most half the
+    /// channel's capacity.
+    pub lag: usize,
+}
+
+impl Default for SlowConsumers {
+    fn default() -> Self {
+        Self {
+            policy: Policy::default(),
+            lag: usize::MAX,
+        }
+    }
+}
+
+impl SlowConsumers {
+    pub fn from_env() -> Self {
+        let mut config = Self::default();
+        match std::env::var("SLOW_CONSUMERS").as_deref() {
+            Ok("warn") | Err(_) => {}
+            Ok("shed") => config.policy = Policy::Shed,
+            Ok("disconnect") => config.policy = Policy::Disconnect,
+            Ok(other) => warn!(
+                "Ignoring SLOW_CONSUMERS={}: not warn, shed or disconnect",
+                other
+            ),
+        }
+        let lag = std::env::var("SLOW_CONSUMER_LAG").ok();
+        if let Some(lag) = lag.and_then(|lag| lag.parse::<usize>().ok()) {
+            config.lag = lag.max(1);
+        }
+        config
+    }
+
+    /// The lag from which a member of a channel of `capacity` is slow.
+    pub fn lag_within(&self, capacity: usize) -> usize {
+        self.lag.min(capacity / 2).max(1)
+    }
+
+    /// The policy for transports that cannot end a single membership, which
+    /// shed rather than disconnect.
+    pub fn without_disconnect(self) -> Self {
+        Self {
+            policy: match self.policy {
+                Policy::Disconnect => Policy::Shed,
+                policy => policy,
+            },
+            ..self
+        }
+    }
+
+    /// Watches a subscriber of `room` from the start.
+    pub fn subscriber(&self, room: &str) -> Subscriber {
+        Subscriber {
+            config: *self,
+            room: room.to_owned(),
+            behind: false,
+            pending: None,
+            lag: 0,
+            skipped: 0,
+        }
+    }
+}
+
+/// Why a subscriber receives no more events.
+pub enum Ended {
+    /// The room's channel closed.
+    Closed,
+    /// The subscriber fell behind and is to be disconnected.
+    TooSlow,
+}
+
+/// One subscriber of a room's broadcast.
+pub struct Subscriber {
+    config: SlowConsumers,
+    room: String,
+    /// Whether it was warned and has not caught up since.
+    behind: bool,
+    /// The event received as the warning was sent, to deliver after it.
+    pending: Option<ChatEvent>,
+    /// The events waiting for it as of the last one it received.
+    pub lag: usize,
+    /// The events it skipped altogether.
+    pub skipped: u64,
+}
+
+impl Subscriber {
+    /// The next event to deliver from `rx`, after applying the policy. Safe
+    /// to cancel, as in a `select!`.
+    pub async fn recv(&mut self, rx: &mut Receiver) -> Result<ChatEvent, Ended> {
+        if let Some(event) = self.pending.take() {
+            return Ok(event);
+        }
+        loop {
+            let event = match rx.recv().await {
+                Ok(event) => event,
+                Err(RecvError::Lagged(skipped)) => {
+                    self.skipped += skipped;
+                    self.lag = rx.lag();
+                    info!("A member of {} skipped {} events", self.room, skipped);
+                    if self.config.policy == Policy::Disconnect {
+                        return Err(Ended::TooSlow);
+                    }
+                    self.behind = true;
+                    return Ok(ChatEvent::Slow {
+                        lag: self.lag,
+                        skipped,
+                    });
+                }
+                Err(RecvError::Closed) => return Err(Ended::Closed),
+            };
+            self.lag = rx.lag();
+            let slow = self.config.lag_within(rx.capacity());
+            if self.lag < slow {
+                // Caught up well enough to be warned again.
+                if self.lag <= slow / 2 {
+                    self.behind = false;
+                }
+                return Ok(event);
+            }
+            match self.config.policy {
+                Policy::Disconnect => {
+                    info!(
+                        "Disconnecting a member of {} {} events behind",
+                        self.room, self.lag
+                    );
+                    return Err(Ended::TooSlow);
+                }
+                Policy::Shed if !event.is_essential() => continue,
+                Policy::Warn | Policy::Shed => {}
+            }
+            if self.behind {
+                return Ok(event);
+            }
+            self.behind = true;
+            self.pending = Some(event);
+            return Ok(ChatEvent::Slow {
+                lag: self.lag,
+                skipped: 0,
+            });
+        }
+    }
+}
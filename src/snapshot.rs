@@ -0,0 +1,203 @@
+//! Snapshots of a room for admins, `GET` and `PUT /admin/rooms/:name/snapshot`.
+//!
+//! A snapshot is a JSON blob of the room's settings, its members at the time
+//! and its recent history. Restoring it, into the same room or another one
+//! as a template, brings back the settings and the history; members are not
+//! restored, they join again.
+
+use axum::extract::{Path, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::Json;
+use log::info;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::sync::Arc;
+
+use crate::backpressure::Strategy;
+use crate::bots::helper;
+use crate::events::{unix_timestamp, RoomEvent};
+use crate::owners::{admin_forbidden, is_admin};
+use crate::room_names::RoomName;
+use crate::rooms::{self, RoomState, Stored, StoredMessage};
+use crate::system_messages::Templates;
+use crate::{archive, tags, ApiResponse, AppState};
+
+/// Format of the snapshots taken, the only one restored.
+const VERSION: u32 = 1;
+
+/// What the room's owner set, each `None` or empty where the server's
+/// default applies.
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct Settings {
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub motd: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub system_messages: Option<Templates>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub transforms: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub backpressure: Option<Strategy>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub helper: Option<helper::Config>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Snapshot {
+    pub version: u32,
+    pub room: String,
+    /// Unix time in seconds.
+    pub taken_at: u64,
+    #[serde(default)]
+    pub settings: Settings,
+    /// The members when it was taken, sorted.
+    #[serde(default)]
+    pub roster: Vec<String>,
+    /// Oldest first.
+    #[serde(default)]
+    pub history: Vec<StoredMessage>,
+}
+
+/// The snapshot of `room`, whose state is `room_state`.
+pub fn of(state: &AppState, room: &str, room_state: &RoomState) -> Snapshot {
+    let mut roster = room_state
+        .users
+        .lock()
+        .unwrap()
+        .keys()
+        .cloned()
+        .collect::<Vec<_>>();
+    roster.sort();
+    Snapshot {
+        version: VERSION,
+        room: room.to_owned(),
+        taken_at: unix_timestamp(),
+        settings: Settings {
+            tags: room_state.tags.lock().unwrap().clone(),
+            motd: state.motd.own(room),
+            system_messages: state.system_messages.own(room),
+            transforms: state.transforms.own(room),
+            backpressure: state.backpressure.own(room),
+            helper: state.helper.own(room),
+        },
+        roster,
+        history: room_state.history.lock().unwrap().iter().cloned().collect(),
+    }
+}
+
+/// `GET /admin/rooms/:name/snapshot`, the snapshot of an active room, with
+/// the `status` besides it so that the response restores as it is.
+pub async fn take_snapshot(
+    Path(room): Path<RoomName>,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> ApiResponse {
+    if !is_admin(&state, &headers) {
+        return admin_forbidden();
+    }
+    let taken = {
+        let rooms = state.rooms.lock().unwrap();
+        rooms
+            .get(room.as_str())
+            .map(|room_state| of(&state, &room, room_state))
+    };
+    let Some(snapshot) = taken else {
+        return (
+            StatusCode::NOT_FOUND,
+            Json(json!({ "status": "Room not found." })),
+        );
+    };
+    let mut body = json!(snapshot);
+    body["status"] = json!("Success!");
+    (StatusCode::OK, Json(body))
+}
+
+fn conflict(status: &str) -> ApiResponse {
+    (StatusCode::CONFLICT, Json(json!({ "status": status })))
+}
+
+/// `PUT /admin/rooms/:name/snapshot`, restores a snapshot into the room,
+/// which must have no members. The room is created if needed and replaced
+/// otherwise.
+pub async fn restore_snapshot(
+    Path(room): Path<RoomName>,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(snapshot): Json<Snapshot>,
+) -> ApiResponse {
+    if !is_admin(&state, &headers) {
+        return admin_forbidden();
+    }
+    restore(&state, &room, snapshot).await
+}
+
+/// Restores `snapshot` into `room`, see [`restore_snapshot`].
+pub async fn restore(state: &AppState, room: &RoomName, snapshot: Snapshot) -> ApiResponse {
+    if snapshot.version != VERSION {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "status": format!("Unknown snapshot version {}.", snapshot.version) })),
+        );
+    }
+    let tags = match tags::normalize(state, &snapshot.settings.tags) {
+        Ok(tags) => tags,
+        Err(status) => return (StatusCode::BAD_REQUEST, Json(json!({ "status": status }))),
+    };
+    if let Some(status) = snapshot
+        .settings
+        .helper
+        .as_ref()
+        .and_then(helper::Config::invalid)
+    {
+        return (StatusCode::BAD_REQUEST, Json(json!({ "status": status })));
+    }
+    let mut history = snapshot.history;
+    history.sort_by_key(|message| message.id);
+    history.drain(..history.len().saturating_sub(rooms::HISTORY_LEN));
+    let restored = history.len();
+
+    let created = {
+        let mut rooms = state.rooms.lock().unwrap();
+        if archive::is_archived(state, room) {
+            return conflict("Room is archived.");
+        }
+        let occupied = rooms
+            .get(room.as_str())
+            .is_some_and(|room_state| !room_state.users.lock().unwrap().is_empty());
+        if occupied {
+            return conflict("Room has members.");
+        }
+        let stored = Stored {
+            history,
+            tags: tags.clone(),
+        };
+        let room_state = RoomState::new(state, room, stored);
+        rooms.insert(room.to_string(), room_state).is_none()
+    };
+    let settings = snapshot.settings;
+    state.motd.set_own(room, settings.motd);
+    state
+        .system_messages
+        .set_own(room, settings.system_messages);
+    state.transforms.set_own(room, settings.transforms);
+    state.backpressure.set_own(room, settings.backpressure);
+    state.helper.set_own(room, settings.helper);
+    state.storage.save_tags(room, &tags).await;
+    if created {
+        rooms::publish(
+            state,
+            RoomEvent::RoomCreated {
+                room: room.to_string(),
+            },
+        );
+    }
+    info!(
+        "Restored the snapshot of {} taken at {} into {}",
+        snapshot.room, snapshot.taken_at, room
+    );
+    (
+        StatusCode::OK,
+        Json(json!({ "status": "Success!", "messages": restored })),
+    )
+}
@@ -0,0 +1,279 @@
+//! Optional Socket.IO endpoint on `/socket.io`, enabled with `SOCKETIO=1` or
+//! `ChatServerBuilder::socketio`.
+//!
+//! Clients emit `join` with `{ room, username }`, then `message` with
+//! `{ room, text }` and `leave` with `{ room }`. One socket may sit in several
+//! rooms; room events come back as `message`, `joined`, `left` and `direct`
+//! with the room name in the payload.
+
+use serde::Deserialize;
+use serde_json::{json, Value};
+use socketioxide::extract::{AckSender, Data, SocketRef};
+use socketioxide::layer::SocketIoLayer;
+use socketioxide::SocketIo;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+use crate::channels::Channel;
+use crate::events::{ChatEvent, Draft};
+use crate::room_names::RoomName;
+use crate::{backpressure, rooms, tasks, AppState};
+
+/// A room joined by one socket.
+struct Member {
+    username: String,
+    tx: Arc<Channel>,
+    forward: JoinHandle<()>,
+}
+
+type Memberships = Arc<Mutex<HashMap<String, Member>>>;
+
+#[derive(Deserialize)]
+struct JoinRequest {
+    room: String,
+    username: String,
+}
+
+#[derive(Deserialize)]
+struct MessageRequest {
+    room: String,
+    #[serde(flatten)]
+    message: Draft,
+}
+
+#[derive(Deserialize)]
+struct LeaveRequest {
+    room: String,
+}
+
+pub fn layer(state: &Arc<AppState>) -> SocketIoLayer {
+    let (layer, io) = SocketIo::builder()
+        .req_path(format!("{}/socket.io", state.base_path))
+        .build_layer();
+    let state = state.clone();
+    io.ns("/", move |socket: SocketRef| {
+        on_connect(socket, state.clone())
+    });
+    layer
+}
+
+fn payload(room: &str, event: &ChatEvent) -> (&'static str, Value) {
+    match event {
+        ChatEvent::Message {
+            id,
+            from,
+            text,
+            attachments,
+            previews,
+            gif,
+            format,
+            parts,
+            expires_at,
+            is_bot,
+            app,
+            components,
+        } => (
+            "message",
+            json!({
+                "room": room,
+                "id": id,
+                "from": from,
+                "text": text,
+                "attachments": attachments,
+                "previews": previews,
+                "gif": gif,
+                "format": format,
+                "parts": parts,
+                "expires_at": expires_at,
+                "is_bot": is_bot,
+                "app": app,
+                "components": components,
+            }),
+        ),
+        ChatEvent::Joined { username, text } => (
+            "joined",
+            json!({ "room": room, "username": username, "text": text }),
+        ),
+        ChatEvent::Left { username, text } => (
+            "left",
+            json!({ "room": room, "username": username, "text": text }),
+        ),
+        ChatEvent::Direct {
+            from,
+            text,
+            offline: false,
+            ..
+        } => (
+            "direct",
+            json!({ "room": room, "from": from, "text": text }),
+        ),
+        ChatEvent::Direct { .. } => ("direct", json!(event)),
+        ChatEvent::Deleted { id } => ("deleted", json!({ "room": room, "id": id })),
+        ChatEvent::Edited { .. } => ("edited", json!(event)),
+        ChatEvent::Translated { .. } => ("translated", json!(event)),
+        ChatEvent::Announcement { text } => ("announcement", json!({ "room": room, "text": text })),
+        ChatEvent::Motd { text } => ("motd", json!({ "room": room, "text": text })),
+        ChatEvent::Signal { .. } => ("signal", json!(event)),
+        ChatEvent::Voice { .. } => ("voice", json!(event)),
+        ChatEvent::Poll { poll } => ("poll", json!({ "room": room, "poll": poll })),
+        ChatEvent::Scheduled { .. } => ("scheduled", json!(event)),
+        ChatEvent::Unscheduled { .. } => ("unscheduled", json!(event)),
+        ChatEvent::Highlight { .. } => ("highlight", json!(event)),
+        ChatEvent::Read { .. } => ("read", json!(event)),
+        ChatEvent::Dismissed { .. } => ("dismissed", json!(event)),
+        ChatEvent::Archived => ("archived", json!({ "room": room })),
+        ChatEvent::Moved => ("moved", json!({ "room": room })),
+        ChatEvent::Slow { lag, skipped } => (
+            "slow",
+            json!({ "room": room, "lag": lag, "skipped": skipped }),
+        ),
+        ChatEvent::Limited { retry_after } => (
+            "limited",
+            json!({ "room": room, "retry_after": retry_after }),
+        ),
+        ChatEvent::Presence { username, status } => (
+            "presence",
+            json!({ "room": room, "username": username, "status": status }),
+        ),
+    }
+}
+
+async fn on_connect(socket: SocketRef, state: Arc<AppState>) {
+    let memberships: Memberships = Arc::default();
+
+    socket.on("join", {
+        let state = state.clone();
+        let memberships = memberships.clone();
+        move |socket: SocketRef, Data(request): Data<JoinRequest>, ack: AckSender| {
+            join(socket, state.clone(), memberships.clone(), request, ack)
+        }
+    });
+
+    socket.on("message", {
+        let state = state.clone();
+        let memberships = memberships.clone();
+        move |Data(request): Data<MessageRequest>, ack: AckSender| {
+            let state = state.clone();
+            let memberships = memberships.clone();
+            async move {
+                let member = memberships
+                    .lock()
+                    .unwrap()
+                    .get(&request.room)
+                    .map(|member| member.username.clone());
+                let status = match member {
+                    None => "Not in room.",
+                    Some(_) if request.message.is_empty() => "Message text must not be empty.",
+                    Some(username) => {
+                        rooms::post_message(&state, &request.room, &username, request.message)
+                            .await;
+                        "Success!"
+                    }
+                };
+                let _ = ack.send(&json!({ "status": status }));
+            }
+        }
+    });
+
+    socket.on("leave", {
+        let state = state.clone();
+        let memberships = memberships.clone();
+        move |Data(request): Data<LeaveRequest>, ack: AckSender| {
+            let state = state.clone();
+            let memberships = memberships.clone();
+            async move {
+                let member = memberships.lock().unwrap().remove(&request.room);
+                let status = match member {
+                    Some(member) => {
+                        close(&state, &request.room, member).await;
+                        "Success!"
+                    }
+                    None => "Not in room.",
+                };
+                let _ = ack.send(&json!({ "status": status }));
+            }
+        }
+    });
+
+    socket.on_disconnect(move || {
+        let state = state.clone();
+        let memberships = memberships.clone();
+        async move {
+            let members = memberships.lock().unwrap().drain().collect::<Vec<_>>();
+            for (room, member) in members {
+                close(&state, &room, member).await;
+            }
+        }
+    });
+}
+
+async fn join(
+    socket: SocketRef,
+    state: Arc<AppState>,
+    memberships: Memberships,
+    request: JoinRequest,
+    ack: AckSender,
+) {
+    let JoinRequest { room, username } = request;
+    let name = match RoomName::new(&room) {
+        Ok(name) => name,
+        Err(err) => {
+            let _ = ack.send(&json!({ "status": err.to_string() }));
+            return;
+        }
+    };
+    let room = name.to_string();
+    let status = if username.is_empty() {
+        "Username and room are required.".to_owned()
+    } else if memberships.lock().unwrap().contains_key(&room) {
+        "Already in room.".to_owned()
+    } else {
+        let (direct_tx, mut direct_rx) = mpsc::unbounded_channel();
+        match rooms::reserve(&state, &name, &username, None, direct_tx).await {
+            Err(err) => err.to_string(),
+            Ok(rooms::Membership { tx, mut rx, .. }) => {
+                let forward = {
+                    let room = room.clone();
+                    let state = state.clone();
+                    let mut subscriber = backpressure::slow_consumers(&state, &room)
+                        .without_disconnect()
+                        .subscriber(&room);
+                    tasks::spawn("socketio-forward", async move {
+                        loop {
+                            let event = tokio::select! {
+                                next = subscriber.recv(&mut rx) => match next {
+                                    Ok(event) => event,
+                                    Err(_) => break,
+                                },
+                                Some(event) = direct_rx.recv() => event,
+                            };
+                            let (name, data) = payload(&room, &event);
+                            if socket.emit(name, &data).is_err() {
+                                break;
+                            }
+                            state.metrics.delivered(&room, event.message_id());
+                        }
+                    })
+                };
+                memberships.lock().unwrap().insert(
+                    room.clone(),
+                    Member {
+                        username: username.clone(),
+                        tx: tx.clone(),
+                        forward,
+                    },
+                );
+                rooms::announce_join(&state, &room, &tx, &username).await;
+                "Success!".to_owned()
+            }
+        }
+    };
+    let _ = ack.send(&json!({ "status": status }));
+}
+
+async fn close(state: &AppState, room: &str, member: Member) {
+    member.forward.abort();
+    rooms::leave(state, room, &member.tx, &member.username).await;
+}
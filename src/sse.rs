@@ -0,0 +1,87 @@
+//! Read-only Server-Sent Events feed for clients that can't use WebSockets.
+
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use futures::stream;
+use serde_json::json;
+use std::convert::Infallible;
+use std::sync::Arc;
+
+use crate::events::ChatEvent;
+use crate::room_names::RoomName;
+use crate::{backpressure, AppState};
+
+/// `GET /rooms/:name/events`, streams the room's broadcast until it closes
+/// or the room is archived.
+pub async fn room_events(
+    Path(room): Path<RoomName>,
+    State(state): State<Arc<AppState>>,
+) -> Response {
+    let rx = match state.rooms.lock().unwrap().get(room.as_str()) {
+        Some(room) => room.tx.subscribe(),
+        None => {
+            return (
+                StatusCode::NOT_FOUND,
+                Json(json!({ "status": "Room not found." })),
+            )
+                .into_response()
+        }
+    };
+
+    let subscriber = backpressure::slow_consumers(&state, &room).subscriber(&room);
+    let events = stream::unfold(Some((rx, subscriber)), move |subscription| {
+        let state = state.clone();
+        let room = room.clone();
+        async move {
+            let (mut rx, mut subscriber) = subscription?;
+            match subscriber.recv(&mut rx).await {
+                Ok(event @ (ChatEvent::Archived | ChatEvent::Moved)) => {
+                    Some((Ok::<_, Infallible>(to_sse(&event)), None))
+                }
+                Ok(event) => {
+                    state.metrics.delivered(&room, event.message_id());
+                    Some((Ok(to_sse(&event)), Some((rx, subscriber))))
+                }
+                Err(_) => None,
+            }
+        }
+    });
+
+    Sse::new(events)
+        .keep_alive(KeepAlive::default())
+        .into_response()
+}
+
+fn to_sse(event: &ChatEvent) -> Event {
+    let name = match event {
+        ChatEvent::Message { .. } => "message",
+        ChatEvent::Joined { .. } => "joined",
+        ChatEvent::Left { .. } => "left",
+        ChatEvent::Direct { .. } => "direct",
+        ChatEvent::Deleted { .. } => "deleted",
+        ChatEvent::Edited { .. } => "edited",
+        ChatEvent::Translated { .. } => "translated",
+        ChatEvent::Announcement { .. } => "announcement",
+        ChatEvent::Motd { .. } => "motd",
+        ChatEvent::Signal { .. } => "signal",
+        ChatEvent::Voice { .. } => "voice",
+        ChatEvent::Poll { .. } => "poll",
+        ChatEvent::Scheduled { .. } => "scheduled",
+        ChatEvent::Unscheduled { .. } => "unscheduled",
+        ChatEvent::Highlight { .. } => "highlight",
+        ChatEvent::Presence { .. } => "presence",
+        ChatEvent::Read { .. } => "read",
+        ChatEvent::Dismissed { .. } => "dismissed",
+        ChatEvent::Archived => "archived",
+        ChatEvent::Moved => "moved",
+        ChatEvent::Slow { .. } => "slow",
+        ChatEvent::Limited { .. } => "limited",
+    };
+    Event::default()
+        .event(name)
+        .json_data(event)
+        .unwrap_or_else(|_| Event::default().event(name).data(event.to_string()))
+}
@@ -0,0 +1,213 @@
+//! Activity statistics of each room, `GET /rooms/:name/stats`.
+//!
+//! Messages are counted per hour of the last [`HOURS`] and per day of the
+//! last [`DAYS`], the top speakers over those days. The counts come from the
+//! storage when it implements [`Storage::load_since`], and otherwise from a
+//! rolling count kept in memory, which outlives the rooms. The peak of
+//! concurrent members is always kept in memory.
+//!
+//! [`Storage::load_since`]: crate::Storage::load_since
+
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::Json;
+use serde::Serialize;
+use serde_json::json;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+
+use crate::events::unix_timestamp;
+use crate::room_names::RoomName;
+use crate::{ApiResponse, AppState};
+
+/// Hours counted by the hour.
+pub const HOURS: u64 = 24;
+/// Days counted by the day, and for the top speakers.
+pub const DAYS: u64 = 30;
+const TOP_SPEAKERS: usize = 10;
+
+const HOUR: u64 = 60 * 60;
+const DAY: u64 = 24 * HOUR;
+
+#[derive(Clone, Copy, Serialize)]
+pub struct Peak {
+    pub users: usize,
+    /// When the room first had that many members, in Unix seconds.
+    pub at: u64,
+}
+
+#[derive(Default)]
+pub struct RoomStats {
+    /// Messages by hours since the epoch.
+    hours: BTreeMap<u64, u64>,
+    /// Messages of each member by days since the epoch.
+    days: BTreeMap<u64, HashMap<String, u64>>,
+    peak: Option<Peak>,
+    /// Messages ever counted.
+    messages: u64,
+}
+
+impl RoomStats {
+    /// Counts a message `from` sent at `timestamp`, in Unix seconds.
+    pub fn record(&mut self, from: &str, timestamp: u64) {
+        self.messages += 1;
+        *self.hours.entry(timestamp / HOUR).or_default() += 1;
+        *self
+            .days
+            .entry(timestamp / DAY)
+            .or_default()
+            .entry(from.to_owned())
+            .or_default() += 1;
+        self.prune(unix_timestamp());
+    }
+
+    /// Keeps the peak of concurrent members up to date with `users`.
+    pub fn members(&mut self, users: usize) {
+        if self.peak.is_none_or(|peak| users > peak.users) {
+            self.peak = Some(Peak {
+                users,
+                at: unix_timestamp(),
+            });
+        }
+    }
+
+    fn prune(&mut self, now: u64) {
+        self.hours = self
+            .hours
+            .split_off(&(now / HOUR).saturating_sub(HOURS - 1));
+        self.days = self.days.split_off(&(now / DAY).saturating_sub(DAYS - 1));
+    }
+}
+
+/// The statistics of every room that had members or messages.
+#[derive(Default)]
+pub struct Stats {
+    rooms: Mutex<HashMap<String, Arc<Mutex<RoomStats>>>>,
+}
+
+impl Stats {
+    /// The statistics of `room`, for the room to record into.
+    pub fn room(&self, room: &str) -> Arc<Mutex<RoomStats>> {
+        let mut rooms = self.rooms.lock().unwrap();
+        rooms.entry(room.to_owned()).or_default().clone()
+    }
+
+    /// The messages ever counted in each room, for the [metrics](crate::metrics).
+    pub fn messages(&self) -> Vec<(String, u64)> {
+        let rooms = self.rooms.lock().unwrap();
+        rooms
+            .iter()
+            .map(|(name, stats)| (name.clone(), stats.lock().unwrap().messages))
+            .collect()
+    }
+
+    fn get(&self, room: &str) -> Option<Arc<Mutex<RoomStats>>> {
+        self.rooms.lock().unwrap().get(room).cloned()
+    }
+}
+
+#[derive(Serialize)]
+struct Bucket {
+    /// Start of the hour or day, in Unix seconds.
+    start: u64,
+    messages: u64,
+}
+
+#[derive(Serialize)]
+struct Speaker {
+    username: String,
+    messages: u64,
+}
+
+/// One bucket per hour or day of the window ending at `now`, empty ones
+/// included, oldest first.
+fn buckets(counts: impl Fn(u64) -> u64, now: u64, length: u64, window: u64) -> Vec<Bucket> {
+    let last = now / length;
+    (last.saturating_sub(window - 1)..=last)
+        .map(|bucket| Bucket {
+            start: bucket * length,
+            messages: counts(bucket),
+        })
+        .collect()
+}
+
+struct Counts {
+    by_hour: Vec<Bucket>,
+    by_day: Vec<Bucket>,
+    top_speakers: Vec<Speaker>,
+}
+
+fn counts(stats: &mut RoomStats) -> Counts {
+    let now = unix_timestamp();
+    stats.prune(now);
+    let by_hour = buckets(
+        |hour| stats.hours.get(&hour).copied().unwrap_or(0),
+        now,
+        HOUR,
+        HOURS,
+    );
+    let by_day = buckets(
+        |day| stats.days.get(&day).map_or(0, |day| day.values().sum()),
+        now,
+        DAY,
+        DAYS,
+    );
+    let mut speakers = HashMap::<&str, u64>::new();
+    for (username, messages) in stats.days.values().flatten() {
+        *speakers.entry(username).or_default() += messages;
+    }
+    let mut speakers = speakers
+        .into_iter()
+        .map(|(username, messages)| Speaker {
+            username: username.to_owned(),
+            messages,
+        })
+        .collect::<Vec<_>>();
+    // Ties are broken by name, so that the list is stable.
+    speakers.sort_by(|a, b| {
+        b.messages
+            .cmp(&a.messages)
+            .then_with(|| a.username.cmp(&b.username))
+    });
+    speakers.truncate(TOP_SPEAKERS);
+    Counts {
+        by_hour,
+        by_day,
+        top_speakers: speakers,
+    }
+}
+
+/// `GET /rooms/:name/stats`
+pub async fn get_stats(
+    Path(room): Path<RoomName>,
+    State(state): State<Arc<AppState>>,
+) -> ApiResponse {
+    let Some(kept) = state.stats.get(&room) else {
+        return (
+            StatusCode::NOT_FOUND,
+            Json(json!({ "status": "Room not found." })),
+        );
+    };
+    let peak = kept.lock().unwrap().peak;
+    let since = (unix_timestamp() / DAY).saturating_sub(DAYS - 1) * DAY;
+    let counts = match state.storage.load_since(&room, since).await {
+        Some(messages) => {
+            let mut stored = RoomStats::default();
+            for message in &messages {
+                stored.record(&message.from, message.timestamp);
+            }
+            counts(&mut stored)
+        }
+        None => counts(&mut kept.lock().unwrap()),
+    };
+    (
+        StatusCode::OK,
+        Json(json!({
+            "status": "Success!",
+            "messages_by_hour": counts.by_hour,
+            "messages_by_day": counts.by_day,
+            "top_speakers": counts.top_speakers,
+            "peak_users": peak,
+        })),
+    )
+}
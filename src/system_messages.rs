@@ -0,0 +1,274 @@
+//! Templates for the messages announcing that a member joined or left.
+//!
+//! A template may use the `{username}` and `{room}` placeholders. The server
+//! sets its own with `JOIN_MESSAGE` and `LEAVE_MESSAGE`, and room owners
+//! theirs through `PUT /rooms/:name/system-messages`. An empty template
+//! suppresses the message, e.g. in rooms with many members coming and going.
+//!
+//! Members on flaky connections leave and rejoin over and over. With a grace
+//! window, set with `NOTICE_GRACE_SECONDS`, a leave is announced only once it
+//! has lasted that long, and a rejoin within it is not announced at all.
+//! Rooms with more than `NOTICE_MAX_MEMBERS` members announce nobody.
+
+use axum::extract::{Path, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::Json;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::task::AbortHandle;
+
+use crate::channels::Channel;
+use crate::events::ChatEvent;
+use crate::owners::{forbidden, is_owner};
+use crate::room_names::RoomName;
+use crate::{tasks, ApiResponse, AppState};
+
+const MAX_LEN: usize = 200;
+
+/// The join and leave templates, the built-in wording where unset.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Templates {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub join: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub leave: Option<String>,
+}
+
+/// When join and leave notices are left out.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Suppression {
+    /// How long a leave notice is held back, and dropped with the join notice
+    /// if the member is back in the meantime.
+    pub grace: Option<Duration>,
+    /// Rooms with more members announce no joins and leaves.
+    pub max_members: Option<usize>,
+}
+
+impl Suppression {
+    pub fn from_env() -> Self {
+        let var = |name| std::env::var(name).ok();
+        Self {
+            grace: var("NOTICE_GRACE_SECONDS")
+                .and_then(|seconds| seconds.parse().ok())
+                .map(Duration::from_secs),
+            max_members: var("NOTICE_MAX_MEMBERS").and_then(|count| count.parse().ok()),
+        }
+    }
+}
+
+/// Leave notices held back, by room and username, with the id of the hold.
+type Pending = Arc<Mutex<HashMap<(String, String), (u64, AbortHandle)>>>;
+
+/// The server's templates and the rooms' own ones.
+pub struct SystemMessages {
+    server: Templates,
+    rooms: Mutex<HashMap<String, Templates>>,
+    suppression: Suppression,
+    pending: Pending,
+    last_pending: AtomicU64,
+}
+
+impl SystemMessages {
+    pub fn new(server: Templates, suppression: Suppression) -> Self {
+        Self {
+            server,
+            rooms: Mutex::new(HashMap::new()),
+            suppression,
+            pending: Pending::default(),
+            last_pending: AtomicU64::new(0),
+        }
+    }
+
+    /// The templates the owner of `room` set, if any.
+    pub fn own(&self, room: &str) -> Option<Templates> {
+        self.rooms.lock().unwrap().get(room).cloned()
+    }
+
+    /// Replaces the server's templates in `room`, or goes back to them.
+    pub fn set_own(&self, room: &str, templates: Option<Templates>) {
+        let mut rooms = self.rooms.lock().unwrap();
+        match templates {
+            Some(templates) => rooms.insert(room.to_owned(), templates),
+            None => rooms.remove(room),
+        };
+    }
+
+    /// Broadcasts the `left` notice of `username` once the grace window has
+    /// passed without them rejoining, right away without a window.
+    pub fn announce_leave(&self, room: &str, username: &str, tx: &Arc<Channel>, left: ChatEvent) {
+        let Some(grace) = self.suppression.grace else {
+            tx.send(left);
+            return;
+        };
+        let key = (room.to_owned(), username.to_owned());
+        let id = self.last_pending.fetch_add(1, Ordering::Relaxed) + 1;
+        let pending = self.pending.clone();
+        let tx = tx.clone();
+        // Holding the lock while spawning, so that the task finds its entry.
+        let mut held = self.pending.lock().unwrap();
+        let task = tasks::spawn("leave-notice", {
+            let key = key.clone();
+            async move {
+                tokio::time::sleep(grace).await;
+                let mut pending = pending.lock().unwrap();
+                if pending.get(&key).is_some_and(|(held, _)| *held == id) {
+                    pending.remove(&key);
+                    tx.send(left);
+                }
+            }
+        });
+        if let Some((_, earlier)) = held.insert(key, (id, task.abort_handle())) {
+            earlier.abort();
+        }
+    }
+
+    /// Drops the leave notice held back for `username`, returning whether
+    /// there was one, in which case their join goes unannounced as well.
+    pub fn rejoined(&self, room: &str, username: &str) -> bool {
+        let key = (room.to_owned(), username.to_owned());
+        match self.pending.lock().unwrap().remove(&key) {
+            Some((_, task)) => {
+                task.abort();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// The templates `room` uses, its own where set.
+    fn templates(&self, room: &str) -> Templates {
+        let own = self.rooms.lock().unwrap().get(room).cloned();
+        let own = own.unwrap_or_default();
+        Templates {
+            join: own.join.or_else(|| self.server.join.clone()),
+            leave: own.leave.or_else(|| self.server.leave.clone()),
+        }
+    }
+}
+
+/// `None` when the template is empty, else the rendered text, `Some(None)`
+/// standing for the built-in wording.
+fn render(template: Option<String>, room: &str, username: &str) -> Option<Option<String>> {
+    match template {
+        Some(template) if template.is_empty() => None,
+        Some(template) => Some(Some(
+            template
+                .replace("{username}", username)
+                .replace("{room}", room),
+        )),
+        None => Some(None),
+    }
+}
+
+/// Whether `room` has more members than notices are sent for.
+fn crowded(state: &AppState, room: &str) -> bool {
+    let Some(max_members) = state.system_messages.suppression.max_members else {
+        return false;
+    };
+    let rooms = state.rooms.lock().unwrap();
+    rooms
+        .get(room)
+        .is_some_and(|room| room.users.lock().unwrap().len() > max_members)
+}
+
+/// The event announcing that `username` joined `room`, unless suppressed.
+pub fn joined(state: &AppState, room: &str, username: &str) -> Option<ChatEvent> {
+    if crowded(state, room) {
+        return None;
+    }
+    let template = state.system_messages.templates(room).join;
+    let text = render(template, room, username)?;
+    Some(ChatEvent::Joined {
+        username: username.to_owned(),
+        text,
+    })
+}
+
+/// The event announcing that `username` left `room`, unless suppressed.
+pub fn left(state: &AppState, room: &str, username: &str) -> Option<ChatEvent> {
+    if crowded(state, room) {
+        return None;
+    }
+    let template = state.system_messages.templates(room).leave;
+    let text = render(template, room, username)?;
+    Some(ChatEvent::Left {
+        username: username.to_owned(),
+        text,
+    })
+}
+
+/// `GET /rooms/:name/system-messages`, the room's own templates and the
+/// ones it uses.
+pub async fn get_system_messages(
+    Path(room): Path<RoomName>,
+    State(state): State<Arc<AppState>>,
+) -> ApiResponse {
+    let own = state
+        .system_messages
+        .rooms
+        .lock()
+        .unwrap()
+        .get(room.as_str())
+        .cloned();
+    (
+        StatusCode::OK,
+        Json(json!({
+            "status": "Success!",
+            "own": own.unwrap_or_default(),
+            "effective": state.system_messages.templates(&room),
+        })),
+    )
+}
+
+/// `PUT /rooms/:name/system-messages`, sets the room's templates.
+pub async fn set_system_messages(
+    Path(room): Path<RoomName>,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(body): Json<Templates>,
+) -> ApiResponse {
+    if !is_owner(&state, &room, &headers) {
+        return forbidden();
+    }
+    let too_long = [&body.join, &body.leave]
+        .into_iter()
+        .flatten()
+        .any(|template| template.chars().count() > MAX_LEN);
+    if too_long {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({
+                "status": format!("Templates are at most {} characters.", MAX_LEN),
+            })),
+        );
+    }
+    state
+        .system_messages
+        .rooms
+        .lock()
+        .unwrap()
+        .insert(room.into(), body);
+    (StatusCode::OK, Json(json!({ "status": "Success!" })))
+}
+
+/// `DELETE /rooms/:name/system-messages`, goes back to the server's templates.
+pub async fn reset_system_messages(
+    Path(room): Path<RoomName>,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> ApiResponse {
+    if !is_owner(&state, &room, &headers) {
+        return forbidden();
+    }
+    state
+        .system_messages
+        .rooms
+        .lock()
+        .unwrap()
+        .remove(room.as_str());
+    (StatusCode::OK, Json(json!({ "status": "Success!" })))
+}
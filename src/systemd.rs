@@ -0,0 +1,93 @@
+//! systemd integration: socket activation, where systemd binds the socket
+//! and passes it in (`LISTEN_FDS`), and `sd_notify` readiness and watchdog
+//! messages (`NOTIFY_SOCKET`, `WATCHDOG_USEC`). Both are no-ops outside of
+//! systemd.
+
+use log::{error, warn};
+use std::os::fd::{FromRawFd, IntoRawFd, RawFd};
+use std::os::unix::net::UnixDatagram;
+use std::time::Duration;
+
+/// The first descriptor systemd passes.
+const LISTEN_FDS_START: RawFd = 3;
+
+/// A listener systemd bound for the server.
+pub enum ActivatedListener {
+    Tcp(tokio::net::TcpListener),
+    Unix(tokio::net::UnixListener),
+}
+
+/// Whether a variable systemd sets for a process is meant for this one.
+fn for_this_process(var: &str) -> bool {
+    std::env::var(var).map_or(true, |pid| pid == std::process::id().to_string())
+}
+
+/// The listener systemd passed in, if any. Only the first of several is
+/// served. Must be called from within a Tokio runtime, at most once.
+pub fn activated_listener() -> Option<ActivatedListener> {
+    let count = std::env::var("LISTEN_FDS").ok()?.parse::<u32>().ok()?;
+    let ours = std::env::var_os("LISTEN_PID").is_some() && for_this_process("LISTEN_PID");
+    // So that child processes do not take the descriptors for theirs.
+    std::env::remove_var("LISTEN_PID");
+    std::env::remove_var("LISTEN_FDS");
+    std::env::remove_var("LISTEN_FDNAMES");
+    if !ours || count == 0 {
+        return None;
+    }
+    if count > 1 {
+        warn!("systemd passed {} sockets, serving the first only", count);
+    }
+    // Safety: systemd passes the descriptors from 3 on, owned by no one else.
+    let unix = unsafe { std::os::unix::net::UnixListener::from_raw_fd(LISTEN_FDS_START) };
+    // The address of a TCP socket is not a Unix socket address.
+    let listener = if unix.local_addr().is_ok() {
+        unix.set_nonblocking(true)
+            .and_then(|()| tokio::net::UnixListener::from_std(unix))
+            .map(ActivatedListener::Unix)
+    } else {
+        // Safety: as above, the descriptor was only borrowed for the check.
+        let tcp = unsafe { std::net::TcpListener::from_raw_fd(unix.into_raw_fd()) };
+        tcp.set_nonblocking(true)
+            .and_then(|()| tokio::net::TcpListener::from_std(tcp))
+            .map(ActivatedListener::Tcp)
+    };
+    listener
+        .map_err(|err| error!("Failed to use the socket systemd passed: {}", err))
+        .ok()
+}
+
+/// Sends `state`, e.g. `READY=1`, to the service manager.
+pub fn notify(state: &str) {
+    let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    let sent = UnixDatagram::unbound().and_then(|socket| {
+        let path = path.to_string_lossy();
+        #[cfg(target_os = "linux")]
+        if let Some(name) = path.strip_prefix('@') {
+            use std::os::linux::net::SocketAddrExt;
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            return socket.send_to_addr(state.as_bytes(), &addr);
+        }
+        socket.send_to(state.as_bytes(), &*path)
+    });
+    if let Err(err) = sent {
+        warn!("Failed to notify systemd of {}: {}", state, err);
+    }
+}
+
+/// How often to tell the service manager the server is alive, half its
+/// watchdog timeout, if it has one.
+pub fn watchdog_interval() -> Option<Duration> {
+    let usec = std::env::var("WATCHDOG_USEC").ok()?.parse::<u64>().ok()?;
+    (usec > 0 && for_this_process("WATCHDOG_PID")).then(|| Duration::from_micros(usec / 2))
+}
+
+/// Pings the watchdog every `interval`.
+pub async fn watchdog(interval: Duration) {
+    let mut ticks = tokio::time::interval(interval);
+    loop {
+        ticks.tick().await;
+        notify("WATCHDOG=1");
+    }
+}
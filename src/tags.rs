@@ -0,0 +1,126 @@
+//! Tags room owners put on their rooms so that the directory can be browsed
+//! by topic, e.g. `rust` or `help`.
+//!
+//! Tags are free-form by default: lowercase letters, digits and `-`, up to
+//! [`MAX_LEN`] characters. With `ROOM_TAGS` set, e.g. to `rust,python,help`,
+//! only those are accepted. A room keeps its tags in custom storages, and
+//! otherwise as long as it is active.
+
+use axum::extract::{Path, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::Json;
+use serde::Deserialize;
+use serde_json::json;
+use std::sync::Arc;
+
+use crate::owners::{forbidden, is_owner};
+use crate::room_names::RoomName;
+use crate::{ApiResponse, AppState};
+
+/// Longest tag, in characters.
+pub const MAX_LEN: usize = 32;
+/// Most tags a room has.
+pub const MAX_TAGS: usize = 10;
+
+/// Reads the allowed tags from `ROOM_TAGS`, `None` for free-form ones.
+pub fn allowed_from_env() -> Option<Vec<String>> {
+    let value = std::env::var("ROOM_TAGS").ok()?;
+    let allowed = value
+        .split(',')
+        .map(|tag| tag.trim().to_lowercase())
+        .filter(|tag| !tag.is_empty())
+        .collect::<Vec<_>>();
+    (!allowed.is_empty()).then_some(allowed)
+}
+
+/// `tags` lowercased, without duplicates, or why they cannot be set.
+pub fn normalize(state: &AppState, tags: &[String]) -> Result<Vec<String>, String> {
+    let mut normalized = Vec::new();
+    for tag in tags {
+        let tag = tag.trim().to_lowercase();
+        let valid = !tag.is_empty()
+            && tag.chars().count() <= MAX_LEN
+            && tag.chars().all(|c| c.is_alphanumeric() || c == '-');
+        if !valid {
+            return Err(format!(
+                "Tags are 1 to {} letters, digits or dashes.",
+                MAX_LEN
+            ));
+        }
+        if let Some(allowed) = &state.allowed_tags {
+            if !allowed.contains(&tag) {
+                return Err(format!("Tag `{}` is not allowed.", tag));
+            }
+        }
+        if !normalized.contains(&tag) {
+            normalized.push(tag);
+        }
+    }
+    if normalized.len() > MAX_TAGS {
+        return Err(format!("Rooms have at most {} tags.", MAX_TAGS));
+    }
+    Ok(normalized)
+}
+
+/// `GET /rooms/:name/tags`, the room's tags and, unless they are free-form,
+/// the allowed ones.
+pub async fn get_tags(
+    Path(room): Path<RoomName>,
+    State(state): State<Arc<AppState>>,
+) -> ApiResponse {
+    let tags = {
+        let rooms = state.rooms.lock().unwrap();
+        rooms
+            .get(room.as_str())
+            .map(|room| room.tags.lock().unwrap().clone())
+    };
+    match tags {
+        Some(tags) => (
+            StatusCode::OK,
+            Json(json!({ "status": "Success!", "tags": tags, "allowed": state.allowed_tags })),
+        ),
+        None => (
+            StatusCode::NOT_FOUND,
+            Json(json!({ "status": "Room not found." })),
+        ),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct SetTags {
+    tags: Vec<String>,
+}
+
+/// `PUT /rooms/:name/tags`, replaces the room's tags.
+pub async fn set_tags(
+    Path(room): Path<RoomName>,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(body): Json<SetTags>,
+) -> ApiResponse {
+    if !is_owner(&state, &room, &headers) {
+        return forbidden();
+    }
+    let tags = match normalize(&state, &body.tags) {
+        Ok(tags) => tags,
+        Err(status) => return (StatusCode::BAD_REQUEST, Json(json!({ "status": status }))),
+    };
+    let found = {
+        let rooms = state.rooms.lock().unwrap();
+        rooms
+            .get(room.as_str())
+            .map(|room| *room.tags.lock().unwrap() = tags.clone())
+            .is_some()
+    };
+    if !found {
+        return (
+            StatusCode::NOT_FOUND,
+            Json(json!({ "status": "Room not found." })),
+        );
+    }
+    state.storage.save_tags(&room, &tags).await;
+    (
+        StatusCode::OK,
+        Json(json!({ "status": "Success!", "tags": tags })),
+    )
+}
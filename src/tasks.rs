@@ -0,0 +1,84 @@
+//! The server's tasks, each spawned with a name, e.g. `ws-send` for the task
+//! sending a WebSocket connection its frames, so that the tasks piling up or
+//! stalling can be told apart. `GET /admin/tasks` counts the live and
+//! spawned tasks of each name.
+//!
+//! Built with the `console` feature and `RUSTFLAGS="--cfg tokio_unstable"`,
+//! the tasks also carry their names into Tokio's instrumentation, which
+//! tokio-console shows once the binary installs `console-subscriber`.
+
+use axum::extract::State;
+use axum::http::{HeaderMap, StatusCode};
+use axum::Json;
+use serde::Serialize;
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex, OnceLock};
+use tokio::task::JoinHandle;
+
+use crate::owners::{admin_forbidden, is_admin};
+use crate::{ApiResponse, AppState};
+
+#[derive(Clone, Copy, Default, Serialize)]
+pub struct Counts {
+    /// Spawned and not yet finished or aborted.
+    pub live: u64,
+    /// Spawned since the process started.
+    pub spawned: u64,
+}
+
+static COUNTS: OnceLock<Mutex<BTreeMap<&'static str, Counts>>> = OnceLock::new();
+
+fn counts() -> &'static Mutex<BTreeMap<&'static str, Counts>> {
+    COUNTS.get_or_init(Mutex::default)
+}
+
+/// Counts a task as live until dropped along with its future.
+struct Live(&'static str);
+
+impl Drop for Live {
+    fn drop(&mut self) {
+        if let Some(counts) = counts().lock().unwrap().get_mut(self.0) {
+            counts.live -= 1;
+        }
+    }
+}
+
+/// Spawns `future` as a task named `name`.
+pub fn spawn<F>(name: &'static str, future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    {
+        let mut counts = counts().lock().unwrap();
+        let counts = counts.entry(name).or_default();
+        counts.live += 1;
+        counts.spawned += 1;
+    }
+    let live = Live(name);
+    let task = async move {
+        let _live = live;
+        future.await
+    };
+    #[cfg(all(tokio_unstable, feature = "console"))]
+    return tokio::task::Builder::new()
+        .name(name)
+        .spawn(task)
+        .expect("spawning a task");
+    #[cfg(not(all(tokio_unstable, feature = "console")))]
+    tokio::spawn(task)
+}
+
+/// `GET /admin/tasks`
+pub async fn list_tasks(State(state): State<Arc<AppState>>, headers: HeaderMap) -> ApiResponse {
+    if !is_admin(&state, &headers) {
+        return admin_forbidden();
+    }
+    let tasks = counts().lock().unwrap().clone();
+    (
+        StatusCode::OK,
+        Json(json!({ "status": "Success!", "tasks": tasks })),
+    )
+}
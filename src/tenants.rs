@@ -0,0 +1,201 @@
+//! Tenants, isolated sets of rooms and members that one server hosts for
+//! different applications, each under `/tenants/<name>/`.
+//!
+//! A tenant is a server of its own, built with the settings of the main one
+//! and its own overrides, e.g. its admin token and quotas. It shares the
+//! storage, where its rooms and members are kept apart by a `<name>/` prefix,
+//! but none of the main server's rooms, members, admin token, plugins,
+//! scripts, emotes, IRC and gRPC listeners, bridges or Socket.IO endpoint.
+//!
+//! A tenant can also have hosts of its own, e.g. `chat.example.com`, whose
+//! requests it answers at the root, with its own CORS policy.
+//!
+//! `TENANTS` declares them as comma-separated names, each configured with
+//! `TENANT_<NAME>_ADMIN_TOKEN`, `TENANT_<NAME>_MAX_ROOMS`,
+//! `TENANT_<NAME>_ROOM_CREATIONS_PER_HOUR`, `TENANT_<NAME>_MOTD`,
+//! `TENANT_<NAME>_HOSTS`, `TENANT_<NAME>_CORS_ORIGINS` and
+//! `TENANT_<NAME>_BRANDING`, `<NAME>` being the name in uppercase with `_`
+//! for `-`.
+
+use async_trait::async_trait;
+use axum::extract::Request;
+use axum::http::header::HOST;
+use axum::http::uri::Authority;
+use axum::middleware::{self, Next};
+use axum::response::IntoResponse;
+use axum::Router;
+use log::{error, warn};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tower::ServiceExt;
+
+use crate::inbox::StoredDirect;
+use crate::rooms::{Storage, StoredMessage};
+use crate::server::ChatServerBuilder;
+
+/// Longest tenant name, in characters.
+pub const MAX_NAME_LEN: usize = 32;
+
+/// Applies a tenant's overrides to the main server's settings.
+pub type Configure = Arc<dyn Fn(ChatServerBuilder) -> ChatServerBuilder + Send + Sync>;
+
+/// Whether `name` is lowercase letters, digits and dashes, as path segments
+/// and storage prefixes need.
+pub fn is_valid(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_NAME_LEN
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+}
+
+/// Reads `TENANTS` and the overrides of each, skipping invalid names.
+pub fn from_env() -> Vec<(String, Configure)> {
+    let Ok(value) = std::env::var("TENANTS") else {
+        return Vec::new();
+    };
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .filter(|name| {
+            let valid = is_valid(name);
+            if !valid {
+                warn!(
+                    "Skipping tenant {:?}: not lowercase letters, digits and dashes",
+                    name
+                );
+            }
+            valid
+        })
+        .map(|name| {
+            let prefix = format!("TENANT_{}_", name.to_uppercase().replace('-', "_"));
+            let configure: Configure = Arc::new(move |mut builder| {
+                let var = |name: &str| {
+                    std::env::var(format!("{}{}", prefix, name))
+                        .ok()
+                        .filter(|value| !value.is_empty())
+                };
+                if let Some(token) = var("ADMIN_TOKEN") {
+                    builder = builder.admin_token(token);
+                }
+                if let Some(count) = var("MAX_ROOMS").and_then(|count| count.parse().ok()) {
+                    builder = builder.max_rooms(count);
+                }
+                let creations = var("ROOM_CREATIONS_PER_HOUR").and_then(|count| count.parse().ok());
+                if let Some(count) = creations {
+                    builder = builder.room_creations_per_hour(count);
+                }
+                if let Some(text) = var("MOTD") {
+                    builder = builder.motd(text);
+                }
+                for host in var("HOSTS").iter().flat_map(|hosts| hosts.split(',')) {
+                    if !host.trim().is_empty() {
+                        builder = builder.host(host.trim());
+                    }
+                }
+                if let Some(origins) = var("CORS_ORIGINS") {
+                    builder = builder.cors_origins(origins.split(','));
+                }
+                if let Some(branding) = branding_from_env(&format!("{}BRANDING", prefix)) {
+                    builder = builder.branding(branding);
+                }
+                builder
+            });
+            (name.to_owned(), configure)
+        })
+        .collect()
+}
+
+/// Reads the JSON object in `var`, the branding sent to WebSocket clients.
+pub fn branding_from_env(var: &str) -> Option<serde_json::Value> {
+    let value = std::env::var(var).ok().filter(|value| !value.is_empty())?;
+    match serde_json::from_str(&value) {
+        Ok(branding @ serde_json::Value::Object(_)) => Some(branding),
+        _ => {
+            error!("Ignoring {}: not a JSON object", var);
+            None
+        }
+    }
+}
+
+/// The host a request is for, lowercase and without the port.
+fn host(request: &Request) -> Option<String> {
+    let authority = match request.headers().get(HOST) {
+        Some(host) => host.to_str().ok()?.parse::<Authority>().ok()?,
+        None => request.uri().authority()?.clone(),
+    };
+    Some(authority.host().to_ascii_lowercase())
+}
+
+/// Sends the requests for each of `hosts` to its app, the others to `app`.
+pub fn route_hosts(app: Router, hosts: Arc<HashMap<String, Router>>) -> Router {
+    app.layer(middleware::from_fn(move |request: Request, next: Next| {
+        let hosts = hosts.clone();
+        async move {
+            match host(&request).and_then(|host| hosts.get(&host).cloned()) {
+                Some(app) => app.oneshot(request).await.into_response(),
+                None => next.run(request).await,
+            }
+        }
+    }))
+}
+
+/// The shared storage as a tenant sees it, its room names and usernames
+/// prefixed with `<tenant>/`.
+pub struct TenantStorage {
+    prefix: String,
+    inner: Arc<dyn Storage>,
+}
+
+impl TenantStorage {
+    pub fn new(tenant: &str, inner: Arc<dyn Storage>) -> Self {
+        Self {
+            prefix: format!("{}/", tenant),
+            inner,
+        }
+    }
+
+    fn key(&self, name: &str) -> String {
+        format!("{}{}", self.prefix, name)
+    }
+}
+
+#[async_trait]
+impl Storage for TenantStorage {
+    async fn save(&self, room: &str, message: &StoredMessage) {
+        self.inner.save(&self.key(room), message).await
+    }
+
+    async fn delete(&self, room: &str, id: u64) {
+        self.inner.delete(&self.key(room), id).await
+    }
+
+    async fn load(&self, room: &str, limit: usize) -> Vec<StoredMessage> {
+        self.inner.load(&self.key(room), limit).await
+    }
+
+    async fn save_direct(&self, to: &str, message: &StoredDirect) {
+        self.inner.save_direct(&self.key(to), message).await
+    }
+
+    async fn delete_direct(&self, to: &str, id: &str) {
+        self.inner.delete_direct(&self.key(to), id).await
+    }
+
+    async fn load_directs(&self, to: &str) -> Vec<StoredDirect> {
+        self.inner.load_directs(&self.key(to)).await
+    }
+
+    async fn save_tags(&self, room: &str, tags: &[String]) {
+        self.inner.save_tags(&self.key(room), tags).await
+    }
+
+    async fn load_tags(&self, room: &str) -> Vec<String> {
+        self.inner.load_tags(&self.key(room)).await
+    }
+
+    async fn load_since(&self, room: &str, since: u64) -> Option<Vec<StoredMessage>> {
+        self.inner.load_since(&self.key(room), since).await
+    }
+}
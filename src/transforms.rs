@@ -0,0 +1,203 @@
+//! Ordered transform stages applied to members' messages before broadcast.
+//!
+//! The server ships the `sanitize`, `profanity`, `emoji`, `mentions` and
+//! `emotes` stages, more can be registered with
+//! [`ChatServerBuilder::transform`].
+//! Every room runs the server's default pipeline unless its owner picked
+//! other stages through `PUT /rooms/:name/transforms`. Whatever the stages,
+//! [`strip_unsafe`] runs first.
+//!
+//! [`ChatServerBuilder::transform`]: crate::ChatServerBuilder::transform
+
+pub mod builtin;
+
+use axum::extract::{Path, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::Json;
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use crate::owners::{forbidden, is_owner};
+use crate::room_names::RoomName;
+use crate::{ApiResponse, AppState};
+
+/// What a stage knows about the message it transforms.
+pub struct TransformContext {
+    pub room: String,
+    pub from: String,
+    /// Members currently in the room.
+    pub members: Vec<String>,
+}
+
+pub trait Transform: Send + Sync {
+    /// Name rooms refer to the stage by.
+    fn name(&self) -> &str;
+
+    /// Returns the rewritten text, or `None` to drop the message.
+    fn apply(&self, ctx: &TransformContext, text: String) -> Option<String>;
+}
+
+/// Registered stages and which of them each room runs.
+pub struct Pipelines {
+    stages: HashMap<String, Arc<dyn Transform>>,
+    default: Vec<String>,
+    rooms: Mutex<HashMap<String, Vec<String>>>,
+}
+
+impl Pipelines {
+    pub fn new(stages: HashMap<String, Arc<dyn Transform>>, default: Vec<String>) -> Self {
+        Self {
+            stages,
+            default,
+            rooms: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Stage names `room` runs, in order.
+    fn names(&self, room: &str) -> Vec<String> {
+        self.rooms
+            .lock()
+            .unwrap()
+            .get(room)
+            .unwrap_or(&self.default)
+            .clone()
+    }
+
+    /// The pipeline the owner of `room` set, if any.
+    pub fn own(&self, room: &str) -> Option<Vec<String>> {
+        self.rooms.lock().unwrap().get(room).cloned()
+    }
+
+    /// Replaces the default pipeline in `room`, or goes back to it. Stages
+    /// that are not registered are skipped when messages pass.
+    pub fn set_own(&self, room: &str, stages: Option<Vec<String>>) {
+        let mut rooms = self.rooms.lock().unwrap();
+        match stages {
+            Some(stages) => rooms.insert(room.to_owned(), stages),
+            None => rooms.remove(room),
+        };
+    }
+
+    fn available(&self) -> Vec<&str> {
+        let mut names = self.stages.keys().map(String::as_str).collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+}
+
+/// Whether `c` could make a client misrender the text around it: control
+/// characters other than newlines and tabs, which terminals may take for
+/// escape sequences, the bidi embeddings, overrides and isolates that
+/// reorder text, and invisible characters other than the joiners emoji and
+/// some scripts need.
+fn is_unsafe(c: char) -> bool {
+    (c.is_control() && c != '\n' && c != '\t')
+        || matches!(
+            c,
+            '\u{180E}'
+                | '\u{200B}'
+                | '\u{202A}'..='\u{202E}'
+                | '\u{2060}'..='\u{2064}'
+                | '\u{2066}'..='\u{2069}'
+                | '\u{FEFF}'
+        )
+}
+
+/// Removes the characters that could make clients misrender message text,
+/// before bots, hooks and the other stages see it.
+pub fn strip_unsafe(text: &str) -> String {
+    text.chars().filter(|c| !is_unsafe(*c)).collect()
+}
+
+/// Runs `text` through the pipeline of `room`, `None` drops the message.
+pub fn apply(state: &AppState, room: &str, from: &str, text: String) -> Option<String> {
+    let stages = state
+        .transforms
+        .names(room)
+        .iter()
+        .filter_map(|name| state.transforms.stages.get(name).cloned())
+        .collect::<Vec<_>>();
+    if stages.is_empty() {
+        return Some(text);
+    }
+
+    let members = state
+        .rooms
+        .lock()
+        .unwrap()
+        .get(room)
+        .map(|room| room.users.lock().unwrap().keys().cloned().collect())
+        .unwrap_or_default();
+    let ctx = TransformContext {
+        room: room.to_owned(),
+        from: from.to_owned(),
+        members,
+    };
+    stages
+        .iter()
+        .try_fold(text, |text, stage| stage.apply(&ctx, text))
+}
+
+/// `GET /rooms/:name/transforms`, lists the room's stages and the available ones.
+pub async fn get_transforms(
+    Path(room): Path<RoomName>,
+    State(state): State<Arc<AppState>>,
+) -> ApiResponse {
+    (
+        StatusCode::OK,
+        Json(json!({
+            "status": "Success!",
+            "stages": state.transforms.names(&room),
+            "available": state.transforms.available(),
+        })),
+    )
+}
+
+#[derive(Deserialize)]
+pub struct SetTransforms {
+    stages: Vec<String>,
+}
+
+/// `PUT /rooms/:name/transforms`, replaces the room's pipeline.
+pub async fn set_transforms(
+    Path(room): Path<RoomName>,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(body): Json<SetTransforms>,
+) -> ApiResponse {
+    if !is_owner(&state, &room, &headers) {
+        return forbidden();
+    }
+    if let Some(unknown) = body
+        .stages
+        .iter()
+        .find(|name| !state.transforms.stages.contains_key(*name))
+    {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "status": format!("Unknown transform: {}.", unknown) })),
+        );
+    }
+    state
+        .transforms
+        .rooms
+        .lock()
+        .unwrap()
+        .insert(room.into(), body.stages);
+    (StatusCode::OK, Json(json!({ "status": "Success!" })))
+}
+
+/// `DELETE /rooms/:name/transforms`, goes back to the default pipeline.
+pub async fn reset_transforms(
+    Path(room): Path<RoomName>,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> ApiResponse {
+    if !is_owner(&state, &room, &headers) {
+        return forbidden();
+    }
+    state.transforms.rooms.lock().unwrap().remove(room.as_str());
+    (StatusCode::OK, Json(json!({ "status": "Success!" })))
+}
@@ -0,0 +1,164 @@
+use std::collections::HashSet;
+
+use super::{Transform, TransformContext};
+
+/// Strips control characters and collapses whitespace, dropping messages
+/// left empty.
+pub struct Sanitize;
+
+impl Transform for Sanitize {
+    fn name(&self) -> &str {
+        "sanitize"
+    }
+
+    fn apply(&self, _ctx: &TransformContext, text: String) -> Option<String> {
+        let text = text
+            .split(|c: char| c.is_whitespace() || c.is_control())
+            .filter(|word| !word.is_empty())
+            .collect::<Vec<_>>()
+            .join(" ");
+        (!text.is_empty()).then_some(text)
+    }
+}
+
+const DEFAULT_PROFANITY: &[&str] = &[
+    "arse", "asshole", "bastard", "bitch", "crap", "damn", "fuck", "shit",
+];
+
+/// Masks listed words, compared case-insensitively.
+pub struct Profanity {
+    words: HashSet<String>,
+}
+
+impl Profanity {
+    pub fn new(words: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        Self {
+            words: words
+                .into_iter()
+                .map(|word| word.into().to_lowercase())
+                .collect(),
+        }
+    }
+}
+
+impl Default for Profanity {
+    fn default() -> Self {
+        Self::new(DEFAULT_PROFANITY.iter().copied())
+    }
+}
+
+impl Transform for Profanity {
+    fn name(&self) -> &str {
+        "profanity"
+    }
+
+    fn apply(&self, _ctx: &TransformContext, text: String) -> Option<String> {
+        let mut masked = String::with_capacity(text.len());
+        let mut word = String::new();
+        for c in text.chars().chain(std::iter::once(' ')) {
+            if c.is_alphanumeric() {
+                word.push(c);
+                continue;
+            }
+            if self.words.contains(&word.to_lowercase()) {
+                masked.extend(word.chars().map(|_| '*'));
+            } else {
+                masked.push_str(&word);
+            }
+            word.clear();
+            masked.push(c);
+        }
+        masked.pop();
+        Some(masked)
+    }
+}
+
+const SHORTCODES: &[(&str, &str)] = &[
+    ("+1", "👍"),
+    ("-1", "👎"),
+    ("clap", "👏"),
+    ("cry", "😢"),
+    ("eyes", "👀"),
+    ("fire", "🔥"),
+    ("grin", "😁"),
+    ("heart", "❤️"),
+    ("joy", "😂"),
+    ("laughing", "😆"),
+    ("ok_hand", "👌"),
+    ("party", "🎉"),
+    ("pray", "🙏"),
+    ("rocket", "🚀"),
+    ("smile", "😄"),
+    ("sob", "😭"),
+    ("sunglasses", "😎"),
+    ("tada", "🎉"),
+    ("thinking", "🤔"),
+    ("thumbsup", "👍"),
+    ("wave", "👋"),
+    ("wink", "😉"),
+];
+
+/// Expands `:shortcode:` to its emoji.
+pub struct Emoji;
+
+impl Transform for Emoji {
+    fn name(&self) -> &str {
+        "emoji"
+    }
+
+    fn apply(&self, _ctx: &TransformContext, text: String) -> Option<String> {
+        let mut expanded = String::with_capacity(text.len());
+        let mut rest = text.as_str();
+        while let Some(start) = rest.find(':') {
+            expanded.push_str(&rest[..start]);
+            let after = &rest[start + 1..];
+            let emoji = after.find(':').and_then(|end| {
+                let code = &after[..end];
+                SHORTCODES
+                    .iter()
+                    .find(|(shortcode, _)| *shortcode == code)
+                    .map(|(_, emoji)| (*emoji, end))
+            });
+            match emoji {
+                Some((emoji, end)) => {
+                    expanded.push_str(emoji);
+                    rest = &after[end + 1..];
+                }
+                None => {
+                    expanded.push(':');
+                    rest = after;
+                }
+            }
+        }
+        expanded.push_str(rest);
+        Some(expanded)
+    }
+}
+
+/// Rewrites `@name` mentions of room members to their exact username.
+pub struct Mentions;
+
+impl Transform for Mentions {
+    fn name(&self) -> &str {
+        "mentions"
+    }
+
+    fn apply(&self, ctx: &TransformContext, text: String) -> Option<String> {
+        let words = text.split(' ').map(|word| {
+            let Some(mention) = word.strip_prefix('@') else {
+                return word.to_owned();
+            };
+            let name = mention.trim_end_matches(|c: char| c.is_ascii_punctuation());
+            let trailing = &mention[name.len()..];
+            match ctx
+                .members
+                .iter()
+                .find(|member| member.eq_ignore_ascii_case(name))
+            {
+                Some(member) => format!("@{}{}", member, trailing),
+                None => word.to_owned(),
+            }
+        });
+        Some(words.collect::<Vec<_>>().join(" "))
+    }
+}
@@ -0,0 +1,345 @@
+//! Translation of messages for the member reading them, through DeepL with
+//! `DEEPL_API_KEY` or LibreTranslate at `LIBRETRANSLATE_URL`, with
+//! `LIBRETRANSLATE_API_KEY` if it needs one.
+//!
+//! `/translate <language>` translates the room's last message, and
+//! `/translate <language> <text>` the text, for the member alone. With
+//! `/translate auto <language>`, or `translate` in their profile, every
+//! message of someone else reaches them translated too. Translations come as
+//! [`translated`](crate::events::ChatEvent::Translated) events to the member,
+//! the broadcast stays as it was.
+
+use async_trait::async_trait;
+use log::warn;
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::io;
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+
+use crate::bots::{Bot, BotContext};
+use crate::events::ChatEvent;
+use crate::{tasks, AppState};
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+/// Longest text translated, in characters.
+const MAX_LEN: usize = 5000;
+
+/// Translates text into a language given as its code, e.g. `de` or `pt-BR`.
+#[async_trait]
+pub trait Translator: Send + Sync {
+    async fn translate(&self, text: &str, language: &str) -> io::Result<String>;
+}
+
+/// Whether `code` is a language code, two or three letters and an optional
+/// region such as `-BR`.
+pub fn is_language(code: &str) -> bool {
+    let (language, region) = code.split_once('-').unwrap_or((code, "US"));
+    (2..=3).contains(&language.len())
+        && language.chars().all(|c| c.is_ascii_alphabetic())
+        && (2..=4).contains(&region.len())
+        && region.chars().all(|c| c.is_ascii_alphanumeric())
+}
+
+/// The translator of the environment, if any.
+pub fn from_env() -> Option<Arc<dyn Translator>> {
+    let var = |name: &str| std::env::var(name).ok().filter(|val| !val.is_empty());
+    if let Some(api_key) = var("DEEPL_API_KEY") {
+        return Some(Arc::new(DeepL::new(api_key)));
+    }
+    let url = var("LIBRETRANSLATE_URL")?;
+    Some(Arc::new(LibreTranslate::new(
+        url,
+        var("LIBRETRANSLATE_API_KEY"),
+    )))
+}
+
+fn client() -> reqwest::Client {
+    reqwest::Client::builder().timeout(TIMEOUT).build().unwrap()
+}
+
+pub struct DeepL {
+    api_key: String,
+    client: reqwest::Client,
+}
+
+impl DeepL {
+    pub fn new(api_key: impl Into<String>) -> Self {
+        Self {
+            api_key: api_key.into(),
+            client: client(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct DeepLResponse {
+    translations: Vec<DeepLTranslation>,
+}
+
+#[derive(Deserialize)]
+struct DeepLTranslation {
+    text: String,
+}
+
+#[async_trait]
+impl Translator for DeepL {
+    async fn translate(&self, text: &str, language: &str) -> io::Result<String> {
+        // Keys of the free plan are for a host of their own.
+        let host = if self.api_key.ends_with(":fx") {
+            "https://api-free.deepl.com"
+        } else {
+            "https://api.deepl.com"
+        };
+        let response = self
+            .client
+            .post(format!("{}/v2/translate", host))
+            .header("authorization", format!("DeepL-Auth-Key {}", self.api_key))
+            .json(&json!({ "text": [text], "target_lang": language.to_uppercase() }))
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(io::Error::other)?;
+        let response = response
+            .json::<DeepLResponse>()
+            .await
+            .map_err(io::Error::other)?;
+        response
+            .translations
+            .into_iter()
+            .next()
+            .map(|translation| translation.text)
+            .ok_or_else(|| io::Error::other("DeepL answered no translation"))
+    }
+}
+
+pub struct LibreTranslate {
+    url: String,
+    api_key: Option<String>,
+    client: reqwest::Client,
+}
+
+impl LibreTranslate {
+    pub fn new(url: impl Into<String>, api_key: Option<String>) -> Self {
+        Self {
+            url: url.into(),
+            api_key,
+            client: client(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct LibreTranslateResponse {
+    translated_text: String,
+}
+
+#[async_trait]
+impl Translator for LibreTranslate {
+    async fn translate(&self, text: &str, language: &str) -> io::Result<String> {
+        // LibreTranslate knows languages without their regions.
+        let language = language.split('-').next().unwrap_or(language);
+        let mut body = json!({
+            "q": text,
+            "source": "auto",
+            "target": language.to_lowercase(),
+            "format": "text",
+        });
+        if let Some(api_key) = &self.api_key {
+            body["api_key"] = json!(api_key);
+        }
+        let response = self
+            .client
+            .post(format!("{}/translate", self.url.trim_end_matches('/')))
+            .json(&body)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(io::Error::other)?;
+        let response = response
+            .json::<LibreTranslateResponse>()
+            .await
+            .map_err(io::Error::other)?;
+        Ok(response.translated_text)
+    }
+}
+
+/// Sends `event` to the connection of `username` in `room` alone.
+fn deliver(state: &AppState, room: &str, username: &str, event: ChatEvent) -> bool {
+    let rooms = state.rooms.lock().unwrap();
+    let Some(room) = rooms.get(room) else {
+        return false;
+    };
+    let users = room.users.lock().unwrap();
+    users
+        .get(username)
+        .is_some_and(|direct| direct.send(event).is_ok())
+}
+
+/// Translates message `id` of `from` for the members of `room` reading it
+/// in another language, once per language.
+pub fn message_posted(state: &Arc<AppState>, room: &str, id: u64, from: &str, text: &str) {
+    let Some(translator) = state.translator.clone() else {
+        return;
+    };
+    if text.trim().is_empty() || text.chars().count() > MAX_LEN {
+        return;
+    }
+    let mut readers = BTreeMap::<String, Vec<String>>::new();
+    {
+        let rooms = state.rooms.lock().unwrap();
+        let Some(room) = rooms.get(room) else {
+            return;
+        };
+        for username in room.users.lock().unwrap().keys() {
+            if username == from {
+                continue;
+            }
+            if let Some(language) = state.profiles.get(username).translate {
+                readers.entry(language).or_default().push(username.clone());
+            }
+        }
+    }
+    if readers.is_empty() {
+        return;
+    }
+    let (state, room, from, text) = (
+        state.clone(),
+        room.to_owned(),
+        from.to_owned(),
+        text.to_owned(),
+    );
+    tasks::spawn("translate", async move {
+        for (language, usernames) in readers {
+            let translated = match translator.translate(&text, &language).await {
+                Ok(translated) => translated,
+                Err(err) => {
+                    warn!("Could not translate into {}: {}", language, err);
+                    continue;
+                }
+            };
+            // Already in their language.
+            if translated.trim() == text.trim() {
+                continue;
+            }
+            for username in usernames {
+                let event = ChatEvent::Translated {
+                    id: Some(id),
+                    from: from.clone(),
+                    text: translated.clone(),
+                    language: language.clone(),
+                };
+                deliver(&state, &room, &username, event);
+            }
+        }
+    });
+}
+
+/// Answers `/translate`, on its own task so that the member's message is
+/// not held up by the translator.
+pub struct TranslateBot {
+    pub state: Weak<AppState>,
+}
+
+#[async_trait]
+impl Bot for TranslateBot {
+    fn name(&self) -> &str {
+        "translate"
+    }
+
+    fn commands(&self) -> &'static [(&'static str, &'static str)] {
+        &[
+            (
+                "translate <language> [text]",
+                "translates the text, or the last message, for you alone",
+            ),
+            (
+                "translate auto <language>|off",
+                "translates every message for you",
+            ),
+        ]
+    }
+
+    async fn on_command(&self, ctx: &BotContext, from: &str, command: &str, args: &str) -> bool {
+        if command != "translate" {
+            return false;
+        }
+        let Some(state) = self.state.upgrade() else {
+            return false;
+        };
+        let Some(translator) = state.translator.clone() else {
+            return false;
+        };
+        let (language, text) = args.split_once(' ').unwrap_or((args, ""));
+        let text = text.trim();
+        if language == "auto" {
+            let mut profile = state.profiles.get(from);
+            profile.translate = match text {
+                "off" => None,
+                language if is_language(language) => Some(language.to_owned()),
+                _ => {
+                    ctx.dm(from, "Usage: /translate auto <language>|off");
+                    return true;
+                }
+            };
+            let reply = match &profile.translate {
+                Some(language) => format!("Messages now reach you translated into {}.", language),
+                None => "Messages no longer reach you translated.".to_owned(),
+            };
+            state.profiles.set(from, profile);
+            ctx.dm(from, &reply);
+            return true;
+        }
+        if !is_language(language) {
+            ctx.dm(
+                from,
+                "Usage: /translate <language> [text], e.g. /translate de",
+            );
+            return true;
+        }
+        // The text given, or else the last message of someone else.
+        let (id, author, text) = if text.is_empty() {
+            let rooms = state.rooms.lock().unwrap();
+            let last = rooms.get(&ctx.room).and_then(|room| {
+                let history = room.history.lock().unwrap();
+                history
+                    .iter()
+                    .rev()
+                    .find(|message| message.from != from && !message.text.is_empty())
+                    .map(|message| (Some(message.id), message.from.clone(), message.text.clone()))
+            });
+            let Some(last) = last else {
+                ctx.dm(from, "There is no message to translate.");
+                return true;
+            };
+            last
+        } else {
+            (None, from.to_owned(), text.to_owned())
+        };
+        if text.chars().count() > MAX_LEN {
+            ctx.dm(from, "Too long to translate.");
+            return true;
+        }
+        let (ctx, from, language) = (ctx.clone(), from.to_owned(), language.to_owned());
+        tasks::spawn("translate", async move {
+            match translator.translate(&text, &language).await {
+                Ok(translated) => {
+                    let event = ChatEvent::Translated {
+                        id,
+                        from: author,
+                        text: translated,
+                        language,
+                    };
+                    deliver(&state, &ctx.room, &from, event);
+                }
+                Err(err) => {
+                    warn!("Could not translate into {}: {}", language, err);
+                    ctx.dm(&from, "Translation failed, try again later.");
+                }
+            }
+        });
+        true
+    }
+}
@@ -0,0 +1,90 @@
+//! Time-limited TURN credentials for voice chat participants behind NATs.
+//!
+//! Credentials follow coturn's `use-auth-secret` scheme: the username is the
+//! expiry time and the member's name, the password a Base64 HMAC-SHA1 of the
+//! username keyed with the secret shared with the TURN server. Configured
+//! through `TURN_SECRET`, `TURN_URIS` (comma-separated, e.g.
+//! `turn:turn.example.com:3478?transport=udp`) and `TURN_TTL` in seconds.
+
+use axum::extract::State;
+use axum::http::{HeaderMap, StatusCode};
+use axum::Json;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use serde_json::json;
+use sha1::Sha1;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::events::unix_timestamp;
+use crate::owners::bearer;
+use crate::{voice, ApiResponse, AppState};
+
+const DEFAULT_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[derive(Clone)]
+pub struct TurnConfig {
+    /// The `static-auth-secret` of the TURN server.
+    pub secret: String,
+    pub uris: Vec<String>,
+    /// How long minted credentials stay valid.
+    pub ttl: Duration,
+}
+
+impl TurnConfig {
+    pub fn from_env() -> Option<Self> {
+        let var = |name: &str| std::env::var(name).ok().filter(|val| !val.is_empty());
+        Some(Self {
+            secret: var("TURN_SECRET")?,
+            uris: var("TURN_URIS")?
+                .split(',')
+                .map(str::trim)
+                .filter(|uri| !uri.is_empty())
+                .map(str::to_owned)
+                .collect(),
+            ttl: var("TURN_TTL")
+                .map(|ttl| Duration::from_secs(ttl.parse().unwrap()))
+                .unwrap_or(DEFAULT_TTL),
+        })
+    }
+
+    /// A username and password for `user`, valid until `ttl` from now.
+    fn mint(&self, user: &str) -> (String, String) {
+        let username = format!("{}:{}", unix_timestamp() + self.ttl.as_secs(), user);
+        let mut mac =
+            Hmac::<Sha1>::new_from_slice(self.secret.as_bytes()).expect("HMAC takes any key size");
+        mac.update(username.as_bytes());
+        let password = STANDARD.encode(mac.finalize().into_bytes());
+        (username, password)
+    }
+}
+
+/// `GET /rtc/credentials`, authorized by the token voice chat participants
+/// receive with the roster.
+pub async fn credentials(State(state): State<Arc<AppState>>, headers: HeaderMap) -> ApiResponse {
+    let Some(config) = &state.turn else {
+        return (
+            StatusCode::NOT_FOUND,
+            Json(json!({ "status": "TURN is not configured." })),
+        );
+    };
+    let Some((_, username)) = bearer(&headers).and_then(|token| voice::participant(&state, token))
+    else {
+        return (
+            StatusCode::FORBIDDEN,
+            Json(json!({ "status": "Missing or invalid voice chat token." })),
+        );
+    };
+    let (username, password) = config.mint(&username);
+    (
+        StatusCode::OK,
+        Json(json!({
+            "status": "Success!",
+            "username": username,
+            "password": password,
+            "ttl": config.ttl.as_secs(),
+            "uris": config.uris,
+        })),
+    )
+}
@@ -0,0 +1,133 @@
+//! Which usernames may be taken, and when two are the same to the eye.
+//!
+//! Every transport's joins go through [`is_valid`]. A room takes a name
+//! only if its [`skeletons`] differ from those of its members, so that
+//! "alice" cannot be impersonated by "Alice", "ＡＬＩＣＥ", "aIice" with a
+//! capital `I` or "аlice" with a Cyrillic `а`.
+
+use icu_normalizer::ComposingNormalizerBorrowed;
+
+/// Characters of a username at most.
+pub const MAX_LEN: usize = 32;
+
+/// Whether `c` is a default-ignorable code point, drawn as nothing or only
+/// changing how its neighbours are drawn. Bidi controls are among them.
+pub fn is_invisible(c: char) -> bool {
+    matches!(
+        c,
+        '\u{00AD}'
+            | '\u{034F}'
+            | '\u{061C}'
+            | '\u{115F}'..='\u{1160}'
+            | '\u{17B4}'..='\u{17B5}'
+            | '\u{180B}'..='\u{180F}'
+            | '\u{200B}'..='\u{200F}'
+            | '\u{202A}'..='\u{202E}'
+            | '\u{2060}'..='\u{206F}'
+            | '\u{3164}'
+            | '\u{FE00}'..='\u{FE0F}'
+            | '\u{FEFF}'
+            | '\u{FFA0}'
+            | '\u{FFF0}'..='\u{FFF8}'
+            | '\u{1BCA0}'..='\u{1BCA3}'
+            | '\u{1D173}'..='\u{1D17A}'
+            | '\u{E0000}'..='\u{E0FFF}'
+    )
+}
+
+/// Whether `username` may be taken: 1 to [`MAX_LEN`] characters, not
+/// starting or ending with a space, and without control, bidi or invisible
+/// characters, which would let it pass for another or garble what follows
+/// it.
+pub fn is_valid(username: &str) -> bool {
+    let len = username.chars().count();
+    (1..=MAX_LEN).contains(&len)
+        && username.trim() == username
+        && !username.chars().any(|c| c.is_control() || is_invisible(c))
+}
+
+/// What `username` looks like, as two UTS #39 skeletons of its NFKC form:
+/// one lowercased after mapping look-alikes, so that `aIice` with a capital
+/// `I` reads as `alice`, and one before, so that `ALICE` does too. Two names
+/// look the same when either of their skeletons match.
+pub fn skeletons(username: &str) -> [String; 2] {
+    let normalized = ComposingNormalizerBorrowed::new_nfkc().normalize(username);
+    let lowered: String = normalized.chars().flat_map(char::to_lowercase).collect();
+    [
+        unicode_security::skeleton(&normalized)
+            .flat_map(char::to_lowercase)
+            .collect(),
+        unicode_security::skeleton(&lowered).collect(),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Whether `a` and `b` look the same, as a room compares them.
+    fn same(a: &str, b: &str) -> bool {
+        skeletons(a).iter().zip(&skeletons(b)).any(|(a, b)| a == b)
+    }
+
+    #[test]
+    fn case_folding() {
+        assert!(same("alice", "Alice"));
+        assert!(same("alice", "ALICE"));
+        assert!(same("alice", "aIice"));
+        assert!(!same("alice", "alicia"));
+    }
+
+    #[test]
+    fn nfkc() {
+        assert!(same("alice", "ＡＬＩＣＥ"));
+        assert!(same("alice", "ａｌｉｃｅ"));
+        assert!(same("ﬁona", "fiona"));
+        // Composed and decomposed accents.
+        assert!(same("zo\u{00EB}", "zoe\u{0308}"));
+        assert!(!same("zo\u{00EB}", "zoe"));
+    }
+
+    #[test]
+    fn confusables() {
+        assert!(same("alice", "\u{0430}lice"));
+        assert!(same("paypal", "\u{0440}\u{0430}y\u{0440}\u{0430}l"));
+        assert!(same("HAHA", "\u{041D}\u{0410}\u{041D}\u{0410}"));
+        assert!(same("bob", "b\u{043E}b"));
+        assert!(!same("bob", "bib"));
+    }
+
+    #[test]
+    fn validity() {
+        assert!(is_valid("alice"));
+        assert!(is_valid("Zoë B"));
+        assert!(is_valid(&"a".repeat(MAX_LEN)));
+        assert!(!is_valid(&"a".repeat(MAX_LEN + 1)));
+        assert!(!is_valid(""));
+        assert!(!is_valid(" alice"));
+        assert!(!is_valid("alice "));
+        assert!(!is_valid("ali\nce"));
+    }
+
+    #[test]
+    fn default_ignorables() {
+        for c in [
+            '\u{00AD}',
+            '\u{034F}',
+            '\u{061C}',
+            '\u{180E}',
+            '\u{200B}',
+            '\u{200D}',
+            '\u{202E}',
+            '\u{2066}',
+            '\u{3164}',
+            '\u{FE0F}',
+            '\u{FEFF}',
+            '\u{E0041}',
+        ] {
+            assert!(is_invisible(c), "{:?}", c);
+            assert!(!is_valid(&format!("ali{}ce", c)), "{:?}", c);
+        }
+        assert!(!is_invisible('a') && !is_invisible(' ') && !is_invisible('\u{00E9}'));
+    }
+}
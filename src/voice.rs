@@ -0,0 +1,165 @@
+//! WebRTC voice chat signaling relayed over the room WebSocket.
+//!
+//! Members join a room's voice chat with a `voice_join` frame and then
+//! exchange offers, answers and ICE candidates with the other participants
+//! through `signal` frames. Audio flows peer to peer, the server only keeps
+//! the roster, which it sends to every participant whenever it changes.
+//! With TURN configured, the roster also carries the participant's token for
+//! `GET /rtc/credentials`.
+
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::Json;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::sync::Arc;
+
+use crate::events::ChatEvent;
+use crate::owners::generate_token;
+use crate::room_names::RoomName;
+use crate::rooms::RoomState;
+use crate::{ApiResponse, AppState};
+
+/// Longest signal relayed, as JSON; SDP offers are usually a few KiB.
+const MAX_SIGNAL_LEN: usize = 16 << 10;
+
+/// WebRTC session negotiation passed between two participants untouched.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Signal {
+    Offer { sdp: String },
+    Answer { sdp: String },
+    IceCandidate { candidate: Value },
+}
+
+pub struct Participant {
+    pub username: String,
+    /// Proves the participant's identity to `GET /rtc/credentials`.
+    pub token: String,
+}
+
+/// The usernames of the voice chat participants in `room`.
+pub fn participants(room: &RoomState) -> Vec<String> {
+    let voice = room.voice.lock().unwrap();
+    voice
+        .iter()
+        .map(|participant| participant.username.clone())
+        .collect()
+}
+
+/// Sends the roster to everyone in it, and to `also` who just left.
+fn announce(state: &AppState, room: &RoomState, also: Option<&str>) {
+    let participants = participants(room);
+    let users = room.users.lock().unwrap();
+    let voice = room.voice.lock().unwrap();
+    let recipients = voice
+        .iter()
+        .map(|participant| (participant.username.as_str(), Some(&participant.token)))
+        .chain(also.map(|username| (username, None)));
+    for (username, token) in recipients {
+        if let Some(direct) = users.get(username) {
+            let _ = direct.send(ChatEvent::Voice {
+                participants: participants.clone(),
+                token: token.filter(|_| state.turn.is_some()).cloned(),
+            });
+        }
+    }
+}
+
+/// Adds a member of `room` to its voice chat.
+pub fn join(state: &AppState, room: &str, username: &str) {
+    let rooms = state.rooms.lock().unwrap();
+    let Some(room) = rooms.get(room) else {
+        return;
+    };
+    {
+        let mut voice = room.voice.lock().unwrap();
+        if voice
+            .iter()
+            .any(|participant| participant.username == username)
+        {
+            return;
+        }
+        voice.push(Participant {
+            username: username.to_owned(),
+            token: generate_token(),
+        });
+    }
+    announce(state, room, None);
+}
+
+/// The room and username of the participant holding `token`.
+pub fn participant(state: &AppState, token: &str) -> Option<(String, String)> {
+    let rooms = state.rooms.lock().unwrap();
+    rooms.iter().find_map(|(name, room)| {
+        let voice = room.voice.lock().unwrap();
+        voice
+            .iter()
+            .find(|participant| participant.token == token)
+            .map(|participant| (name.clone(), participant.username.clone()))
+    })
+}
+
+/// Removes `username` from the voice chat of `room`, if they were in it.
+pub fn leave(state: &AppState, room: &str, username: &str) {
+    let rooms = state.rooms.lock().unwrap();
+    let Some(room) = rooms.get(room) else {
+        return;
+    };
+    {
+        let mut voice = room.voice.lock().unwrap();
+        let before = voice.len();
+        voice.retain(|participant| participant.username != username);
+        if voice.len() == before {
+            return;
+        }
+    }
+    announce(state, room, Some(username));
+}
+
+/// Passes `signal` on to `to`, provided both are in the voice chat.
+pub fn relay(state: &AppState, room: &str, from: &str, to: &str, signal: Signal) {
+    if serde_json::to_string(&signal).map_or(true, |json| json.len() > MAX_SIGNAL_LEN) {
+        return;
+    }
+    let rooms = state.rooms.lock().unwrap();
+    let Some(room) = rooms.get(room) else {
+        return;
+    };
+    {
+        let voice = room.voice.lock().unwrap();
+        let joined = |username| {
+            voice
+                .iter()
+                .any(|participant| participant.username == username)
+        };
+        if from == to || !joined(from) || !joined(to) {
+            return;
+        }
+    }
+    let users = room.users.lock().unwrap();
+    if let Some(direct) = users.get(to) {
+        let _ = direct.send(ChatEvent::Signal {
+            from: from.to_owned(),
+            signal,
+        });
+    }
+}
+
+/// `GET /rooms/:name/voice`
+pub async fn roster(Path(room): Path<RoomName>, State(state): State<Arc<AppState>>) -> ApiResponse {
+    let rooms = state.rooms.lock().unwrap();
+    match rooms.get(room.as_str()) {
+        Some(room) => (
+            StatusCode::OK,
+            Json(json!({
+                "status": "Success!",
+                "participants": participants(room),
+            })),
+        ),
+        None => (
+            StatusCode::NOT_FOUND,
+            Json(json!({ "status": "Room not found." })),
+        ),
+    }
+}
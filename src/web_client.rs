@@ -0,0 +1,40 @@
+//! A minimal web client at `/app`, so that a server started with `cargo run`
+//! can be chatted on right away. It connects to the `/ws` of the server it
+//! is served from, under the same path prefix, e.g. a tenant's.
+//!
+//! The files under `web/` are compiled in, `web/index.html` at `/app` and
+//! the others, e.g. `web/style.css`, at `/app/style.css`.
+
+use axum::extract::Path;
+use axum::http::header::{CACHE_CONTROL, CONTENT_TYPE};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use rust_embed::RustEmbed;
+
+#[derive(RustEmbed)]
+#[folder = "web/"]
+struct Assets;
+
+fn serve(path: &str) -> Response {
+    match Assets::get(path) {
+        Some(file) => (
+            [
+                (CONTENT_TYPE, file.metadata.mimetype().to_owned()),
+                (CACHE_CONTROL, "no-cache".to_owned()),
+            ],
+            file.data,
+        )
+            .into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+/// `GET /app`
+pub async fn index() -> Response {
+    serve("index.html")
+}
+
+/// `GET /app/*path`
+pub async fn asset(Path(path): Path<String>) -> Response {
+    serve(&path)
+}
@@ -0,0 +1,275 @@
+use axum::body::Bytes;
+use axum::extract::{Path, State};
+use axum::http::header::CONTENT_TYPE;
+use axum::http::{HeaderMap, StatusCode};
+use axum::Json;
+use log::info;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::sync::Arc;
+
+use crate::components::{self, Component};
+use crate::events::App;
+use crate::owners::{forbidden, generate_token, is_owner};
+use crate::rate_limits::Sender;
+use crate::room_names::RoomName;
+use crate::{usernames, ApiResponse, AppState};
+
+/// A token bound to a room that lets external services post as a bot.
+#[derive(Serialize, Deserialize)]
+pub struct IncomingWebhook {
+    pub room: String,
+    pub name: String,
+    /// URL of the image clients show beside its messages.
+    pub avatar: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct CreateWebhook {
+    name: String,
+    avatar: Option<String>,
+}
+
+/// Incoming payload, a superset of the Slack incoming-webhook shape so
+/// existing alerting and CI integrations can post without adapters.
+/// Unsupported fields such as `icon_emoji` are accepted and ignored.
+#[derive(Deserialize)]
+pub struct WebhookPayload {
+    #[serde(default)]
+    text: String,
+    username: Option<String>,
+    /// Overrides the webhook's avatar for the message.
+    icon_url: Option<String>,
+    /// Buttons and select menus, whose picks reach the outgoing webhooks
+    /// of the room subscribed to `interaction`.
+    #[serde(default)]
+    components: Vec<Component>,
+    #[serde(default)]
+    attachments: Vec<SlackAttachment>,
+}
+
+#[derive(Deserialize)]
+struct SlackAttachment {
+    fallback: Option<String>,
+    pretext: Option<String>,
+    title: Option<String>,
+    title_link: Option<String>,
+    text: Option<String>,
+    #[serde(default)]
+    fields: Vec<SlackField>,
+}
+
+#[derive(Deserialize)]
+struct SlackField {
+    title: String,
+    value: String,
+}
+
+/// Slack tools also post `payload=<json>` form bodies.
+#[derive(Deserialize)]
+struct FormPayload {
+    payload: String,
+}
+
+impl SlackAttachment {
+    fn render(&self) -> String {
+        let mut lines = Vec::new();
+        lines.extend(self.pretext.clone());
+        match (&self.title, &self.title_link) {
+            (Some(title), Some(link)) => lines.push(format!("{} ({})", title, link)),
+            (Some(title), None) => lines.push(title.clone()),
+            _ => {}
+        }
+        lines.extend(self.text.clone());
+        for field in &self.fields {
+            lines.push(format!("{}: {}", field.title, field.value));
+        }
+        if lines.is_empty() {
+            lines.extend(self.fallback.clone());
+        }
+        lines.join("\n")
+    }
+}
+
+impl WebhookPayload {
+    fn parse(headers: &HeaderMap, body: &[u8]) -> Option<Self> {
+        let form = headers
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.starts_with("application/x-www-form-urlencoded"));
+        if form {
+            let form: FormPayload = serde_urlencoded::from_bytes(body).ok()?;
+            serde_json::from_str(&form.payload).ok()
+        } else {
+            serde_json::from_slice(body).ok()
+        }
+    }
+
+    /// Message text followed by the rendered attachments.
+    fn render(&self) -> String {
+        std::iter::once(self.text.clone())
+            .chain(self.attachments.iter().map(SlackAttachment::render))
+            .filter(|part| !part.trim().is_empty())
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+pub async fn create_webhook(
+    Path(room): Path<RoomName>,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(body): Json<CreateWebhook>,
+) -> ApiResponse {
+    if !is_owner(&state, &room, &headers) {
+        return forbidden();
+    }
+    let name = body.name.trim();
+    if name.is_empty() {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "status": "Webhook name must not be empty." })),
+        );
+    }
+
+    let token = generate_token();
+    state.webhooks.lock().unwrap().insert(
+        token.clone(),
+        IncomingWebhook {
+            room: room.into(),
+            name: name.to_owned(),
+            avatar: body.avatar.filter(|avatar| !avatar.trim().is_empty()),
+        },
+    );
+    (
+        StatusCode::CREATED,
+        Json(json!({
+            "status": "Success!",
+            "token": token,
+            "url": format!("{}/hooks/{}", state.base_path, token),
+        })),
+    )
+}
+
+pub async fn list_webhooks(
+    Path(room): Path<RoomName>,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> ApiResponse {
+    if !is_owner(&state, &room, &headers) {
+        return forbidden();
+    }
+    let webhooks = state.webhooks.lock().unwrap();
+    let hooks = webhooks
+        .iter()
+        .filter(|(_, hook)| hook.room == room)
+        .map(|(token, hook)| json!({ "token": token, "name": hook.name, "avatar": hook.avatar }))
+        .collect::<Vec<_>>();
+    (
+        StatusCode::OK,
+        Json(json!({ "status": "Success!", "webhooks": hooks })),
+    )
+}
+
+pub async fn revoke_webhook(
+    Path((room, token)): Path<(RoomName, String)>,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> ApiResponse {
+    if !is_owner(&state, &room, &headers) {
+        return forbidden();
+    }
+    let mut webhooks = state.webhooks.lock().unwrap();
+    match webhooks.get(&token) {
+        Some(hook) if hook.room == room => {
+            webhooks.remove(&token);
+            (StatusCode::OK, Json(json!({ "status": "Success!" })))
+        }
+        _ => (
+            StatusCode::NOT_FOUND,
+            Json(json!({ "status": "Webhook not found." })),
+        ),
+    }
+}
+
+/// `POST /hooks/:token`, posts the payload text into the bound room.
+pub async fn post_webhook(
+    Path(token): Path<String>,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> ApiResponse {
+    let Some(payload) = WebhookPayload::parse(&headers, &body) else {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "status": "Invalid webhook payload." })),
+        );
+    };
+    let (room, app) = match state.webhooks.lock().unwrap().get(&token) {
+        Some(hook) => (
+            hook.room.clone(),
+            App {
+                name: hook.name.clone(),
+                avatar: hook.avatar.clone(),
+            },
+        ),
+        None => {
+            return (
+                StatusCode::NOT_FOUND,
+                Json(json!({ "status": "Webhook not found." })),
+            )
+        }
+    };
+    if let Err(wait) = state.rate_limits.check(Sender::Webhook(&token)) {
+        return (
+            StatusCode::TOO_MANY_REQUESTS,
+            Json(json!({
+                "status": "Too many messages, retry later.",
+                "retry_after": wait.as_secs() + 1,
+            })),
+        );
+    }
+    let text = payload.render();
+    if text.is_empty() {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "status": "Message text must not be empty." })),
+        );
+    }
+    if let Err(err) = components::validate(&payload.components) {
+        return (StatusCode::BAD_REQUEST, Json(json!({ "status": err })));
+    }
+    let name = payload
+        .username
+        .filter(|username| !username.trim().is_empty())
+        .unwrap_or_else(|| app.name.clone());
+    if !usernames::is_valid(&name) {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "status": "Invalid username." })),
+        );
+    }
+    let app = App {
+        avatar: payload
+            .icon_url
+            .filter(|url| !url.trim().is_empty())
+            .or(app.avatar),
+        ..app
+    };
+
+    let rooms = state.rooms.lock().unwrap();
+    match rooms.get(&room) {
+        Some(room_state) => {
+            let id = room_state.send_components(app, &name, text, payload.components);
+            info!("Webhook {} posted to {}", name, room);
+            (
+                StatusCode::OK,
+                Json(json!({ "status": "Success!", "id": id })),
+            )
+        }
+        None => (
+            StatusCode::NOT_FOUND,
+            Json(json!({ "status": "Room is not active." })),
+        ),
+    }
+}